@@ -11,6 +11,7 @@ use std::env;
 use std::fs;
 use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -27,6 +28,12 @@ pub enum SpdmConfigError {
     #[error("Cert chain buffers cannot exceed 64 KiB")]
     CertChainBufferTooLarge,
 
+    #[error(
+        "Cert chain portions must be at least 1 byte, and no larger than \
+            the cert chain buffer"
+    )]
+    InvalidCertPortionSize,
+
     #[error("Invalid hash algorithm: {0}")]
     InvalidHashAlgorithm(String),
 
@@ -35,6 +42,21 @@ pub enum SpdmConfigError {
 
     #[error("Invalid capability: {0}")]
     InvalidCapability(String),
+
+    #[error("PSK_CAP and PSK_CAP_WITH_CONTEXT are mutually exclusive")]
+    ConflictingPskCapabilities,
+
+    #[error("Measurement record buffers cannot exceed 16 MiB")]
+    MeasurementRecordBufferTooLarge,
+
+    #[error("Secured message sequence numbers cannot exceed 8 bytes")]
+    SequenceNumberTooLarge,
+
+    #[error("A responder must allow at least 1 session")]
+    InvalidMaxSessions,
+
+    #[error("The time floor cannot be later than the build time")]
+    TimeFloorAfterBuildTime,
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,6 +65,9 @@ pub struct SpdmConfig {
     pub transcript: TranscriptConfig,
     pub capabilities: Vec<String>,
     pub algorithms: AlgorithmsConfig,
+    pub measurements: MeasurementsConfig,
+    pub sessions: SessionsConfig,
+    pub time: TimeConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -50,6 +75,7 @@ pub struct CertChainConfig {
     pub num_slots: usize,
     pub buf_size: usize,
     pub max_depth: usize,
+    pub portion_size: usize,
 }
 
 impl CertChainConfig {
@@ -63,6 +89,9 @@ impl CertChainConfig {
         if self.max_depth > 24 {
             return Err(SpdmConfigError::CertChainDepthTooLarge);
         }
+        if self.portion_size < 1 || self.portion_size > self.buf_size {
+            return Err(SpdmConfigError::InvalidCertPortionSize);
+        }
 
         Ok(())
     }
@@ -73,6 +102,61 @@ pub struct TranscriptConfig {
     pub buf_size: usize,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MeasurementsConfig {
+    pub record_buf_size: usize,
+}
+
+impl MeasurementsConfig {
+    fn validate(&self) -> Result<(), SpdmConfigError> {
+        // The MeasurementRecordLength field of a MEASUREMENTS msg is 3 bytes
+        if self.record_buf_size > 0xFFFFFF {
+            return Err(SpdmConfigError::MeasurementRecordBufferTooLarge);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionsConfig {
+    pub sequence_number_size: usize,
+    pub heartbeat_period: u8,
+    pub max_sessions: usize,
+}
+
+impl SessionsConfig {
+    fn validate(&self) -> Result<(), SpdmConfigError> {
+        // DSP0277 allows the transport to send up to 8 bytes of the sequence
+        // number in each secured message.
+        if self.sequence_number_size > 8 {
+            return Err(SpdmConfigError::SequenceNumberTooLarge);
+        }
+        if self.max_sessions < 1 {
+            return Err(SpdmConfigError::InvalidMaxSessions);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeConfig {
+    pub floor: u64,
+}
+
+impl TimeConfig {
+    fn validate(&self) -> Result<(), SpdmConfigError> {
+        // The library cannot run before it is built, so a later floor would
+        // reject certificates that are valid at the time it runs.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        if self.floor > now {
+            return Err(SpdmConfigError::TimeFloorAfterBuildTime);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AlgorithmsConfig {
     pub asymmetric_signing: Vec<String>,
@@ -137,13 +221,31 @@ fn max_signature_size(algos: &Vec<String>) -> Result<usize, SpdmConfigError> {
 fn validate_capabilities(caps: &Vec<String>) -> Result<(), SpdmConfigError> {
     for cap in caps {
         match cap.as_str() {
-            "CERT_CAP" | "CHAL_CAP" | "ENCRYPT_CAP" | "MAC_CAP"
-            | "MUT_AUTH_CAP" | "KEY_EX_CAP" | "KEY_UPD_CAP" => (),
+            "CERT_CAP"
+            | "CHAL_CAP"
+            | "ENCRYPT_CAP"
+            | "MAC_CAP"
+            | "MUT_AUTH_CAP"
+            | "ENCAP_CAP"
+            | "KEY_EX_CAP"
+            | "KEY_UPD_CAP"
+            | "HBEAT_CAP"
+            | "MEAS_CAP_NO_SIG"
+            | "MEAS_CAP_SIG"
+            | "PSK_CAP"
+            | "PSK_CAP_WITH_CONTEXT"
+            | "PUB_KEY_ID_CAP" => (),
             x => {
                 return Err(SpdmConfigError::InvalidCapability(x.into()));
             }
         }
     }
+    // Both share the 2-bit PSK_CAP field of a responder
+    if caps.iter().any(|c| c == "PSK_CAP")
+        && caps.iter().any(|c| c == "PSK_CAP_WITH_CONTEXT")
+    {
+        return Err(SpdmConfigError::ConflictingPskCapabilities);
+    }
     Ok(())
 }
 
@@ -161,12 +263,16 @@ pub fn gen_config(input: SpdmConfig) -> Result<String> {
     let max_signature_size =
         max_signature_size(&input.algorithms.asymmetric_signing)?;
     input.cert_chains.validate()?;
+    input.measurements.validate()?;
+    input.sessions.validate()?;
+    input.time.validate()?;
     validate_capabilities(&input.capabilities)?;
     let opaque_data_size = 0;
     let params = [
         input.cert_chains.num_slots.to_string(),
         input.cert_chains.buf_size.to_string(),
         input.cert_chains.max_depth.to_string(),
+        input.cert_chains.portion_size.to_string(),
         input.transcript.buf_size.to_string(),
         max_hash_size.to_string(),
         max_signature_size.to_string(),
@@ -174,6 +280,11 @@ pub fn gen_config(input: SpdmConfig) -> Result<String> {
         format!("{:?}", input.capabilities),
         format!("{:?}", input.algorithms.asymmetric_signing),
         format!("{:?}", input.algorithms.hash),
+        input.measurements.record_buf_size.to_string(),
+        input.sessions.sequence_number_size.to_string(),
+        input.sessions.heartbeat_period.to_string(),
+        input.sessions.max_sessions.to_string(),
+        input.time.floor.to_string(),
         // We use an empty string to zip the last `;` from the template.
         String::from(""),
     ];
@@ -0,0 +1,170 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Authenticated encryption, as used by secured messages
+//!
+//! Like signing and key exchange, encryption may be performed by hardware.
+//! Users can therefore plug in their own implementation of the `Aead` trait.
+
+use ring::aead::{
+    self, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM,
+    CHACHA20_POLY1305,
+};
+
+use crate::crypto::key_schedule::AEAD_IV_SIZE;
+use crate::msgs::algorithms::AeadFixedAlgorithms;
+
+/// The size of the authentication tag of all supported AEAD algorithms
+pub const TAG_SIZE: usize = 16;
+
+// Opaque error type
+#[derive(Debug)]
+pub struct Error {}
+
+/// Providers implement this trait to encrypt and decrypt secured messages.
+///
+/// An instance is bound to a single key of a single direction of a session.
+/// The caller is responsible for providing a unique nonce for every message.
+pub trait Aead: Sized {
+    /// Create a new instance for the given algorithm and key
+    fn new(algorithm: AeadFixedAlgorithms, key: &[u8]) -> Result<Self, Error>;
+
+    /// Encrypt `in_out` in place, and return the authentication tag over the
+    /// ciphertext and `aad`.
+    ///
+    /// If `in_out` is empty, this only authenticates `aad`.
+    fn seal_in_place(
+        &self,
+        nonce: &[u8; AEAD_IV_SIZE],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], Error>;
+
+    /// Verify and decrypt `in_out` in place, where `in_out` is the ciphertext
+    /// followed by the authentication tag. Return the plaintext.
+    fn open_in_place<'a>(
+        &self,
+        nonce: &[u8; AEAD_IV_SIZE],
+        aad: &[u8],
+        in_out: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Error>;
+}
+
+// Convert a SPDM algorithm to a Ring algorithm
+//
+// The AEAD algorithm is chosen by the peer, so we return an error rather than
+// panic for unsupported algorithms.
+fn spdm_to_ring(
+    algorithm: AeadFixedAlgorithms,
+) -> Result<&'static aead::Algorithm, Error> {
+    match algorithm {
+        AeadFixedAlgorithms::AES_128_GCM => Ok(&AES_128_GCM),
+        AeadFixedAlgorithms::AES_256_GCM => Ok(&AES_256_GCM),
+        AeadFixedAlgorithms::CHACHA20_POLY1305 => Ok(&CHACHA20_POLY1305),
+        _ => Err(Error {}),
+    }
+}
+
+/// An Aead backed by ring
+///
+/// TODO: Put behind a feature
+pub struct RingAead {
+    key: LessSafeKey,
+}
+
+impl Aead for RingAead {
+    fn new(algorithm: AeadFixedAlgorithms, key: &[u8]) -> Result<Self, Error> {
+        let key = UnboundKey::new(spdm_to_ring(algorithm)?, key)
+            .map_err(|_| Error {})?;
+        Ok(RingAead { key: LessSafeKey::new(key) })
+    }
+
+    fn seal_in_place(
+        &self,
+        nonce: &[u8; AEAD_IV_SIZE],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], Error> {
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(*nonce),
+                aead::Aad::from(aad),
+                in_out,
+            )
+            .map_err(|_| Error {})?;
+        let mut out = [0u8; TAG_SIZE];
+        out.copy_from_slice(tag.as_ref());
+        Ok(out)
+    }
+
+    fn open_in_place<'a>(
+        &self,
+        nonce: &[u8; AEAD_IV_SIZE],
+        aad: &[u8],
+        in_out: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Error> {
+        self.key
+            .open_in_place(
+                Nonce::assume_unique_for_key(*nonce),
+                aead::Aad::from(aad),
+                in_out,
+            )
+            .map_err(|_| Error {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(algorithm: AeadFixedAlgorithms) {
+        let key = [0x42; 32];
+        let aead =
+            RingAead::new(algorithm, &key[..algorithm.get_key_size()]).unwrap();
+        let nonce = [7u8; AEAD_IV_SIZE];
+        let plaintext = b"application data";
+
+        let mut buf = [0u8; 64];
+        buf[..plaintext.len()].copy_from_slice(plaintext);
+        let tag = aead
+            .seal_in_place(&nonce, b"header", &mut buf[..plaintext.len()])
+            .unwrap();
+        assert_ne!(&buf[..plaintext.len()], plaintext);
+        let end = plaintext.len() + TAG_SIZE;
+        buf[plaintext.len()..end].copy_from_slice(&tag);
+
+        // Tampering with the AAD fails
+        let mut tampered = buf;
+        assert!(aead
+            .open_in_place(&nonce, b"Header", &mut tampered[..end])
+            .is_err());
+
+        let opened =
+            aead.open_in_place(&nonce, b"header", &mut buf[..end]).unwrap();
+        assert_eq!(plaintext, opened);
+    }
+
+    #[test]
+    fn aes_128_gcm() {
+        roundtrip(AeadFixedAlgorithms::AES_128_GCM);
+    }
+
+    #[test]
+    fn aes_256_gcm() {
+        roundtrip(AeadFixedAlgorithms::AES_256_GCM);
+    }
+
+    #[test]
+    fn chacha20_poly1305() {
+        roundtrip(AeadFixedAlgorithms::CHACHA20_POLY1305);
+    }
+
+    #[test]
+    fn invalid_key_size() {
+        assert!(
+            RingAead::new(AeadFixedAlgorithms::AES_256_GCM, &[0; 16]).is_err()
+        );
+    }
+}
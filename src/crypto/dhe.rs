@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Ephemeral Diffie-Hellman key exchange, as used by KEY_EXCHANGE
+//!
+//! Like signing, key exchange may be performed by trusted hardware that never
+//! reveals the private key. Users can therefore plug in their own
+//! implementation of the `EphemeralKey` trait.
+
+use core::convert::AsRef;
+use core::fmt::{self, Debug, Formatter};
+
+use ring::agreement::{
+    self, EphemeralPrivateKey, UnparsedPublicKey, ECDH_P256, ECDH_P384,
+};
+use ring::rand::SystemRandom;
+
+use crate::msgs::algorithms::DheFixedAlgorithms;
+use crate::msgs::key_exchange::MAX_EXCHANGE_DATA_SIZE;
+
+/// The size of the largest shared secret of a supported group (secp384r1)
+pub const MAX_SHARED_SECRET_SIZE: usize = 48;
+
+// Opaque error type
+#[derive(Debug)]
+pub struct Error {}
+
+// Convert a SPDM algorithm to a Ring algorithm
+//
+// Unlike signing algorithms, the DHE group is chosen by the peer, so we return
+// an error rather than panic for unsupported groups.
+fn spdm_to_ring(
+    algorithm: DheFixedAlgorithms,
+) -> Result<&'static agreement::Algorithm, Error> {
+    match algorithm {
+        DheFixedAlgorithms::SECP_256_R1 => Ok(&ECDH_P256),
+        DheFixedAlgorithms::SECP_384_R1 => Ok(&ECDH_P384),
+        _ => Err(Error {}),
+    }
+}
+
+/// Providers implement this trait to perform an ephemeral Diffie-Hellman key
+/// exchange.
+///
+/// A new key is generated for every KEY_EXCHANGE, and is consumed when
+/// computing the shared secret.
+pub trait EphemeralKey: Sized {
+    type SharedSecret: AsRef<[u8]>;
+
+    /// Generate a new ephemeral key for the given group
+    fn generate(algorithm: DheFixedAlgorithms) -> Result<Self, Error>;
+
+    /// Write the public key into `buf` in the SPDM exchange data format, and
+    /// return the number of bytes written.
+    ///
+    /// For ECDHE, this is the X and Y coordinates of the public point, without
+    /// the leading 0x04 byte of the uncompressed SEC1 encoding. For FFDHE, it
+    /// is the big endian public value, padded to the size of the prime.
+    fn public_key(&self, buf: &mut [u8]) -> Result<usize, Error>;
+
+    /// Compute the shared secret from the peer's exchange data, consuming the
+    /// private key.
+    fn shared_secret(self, peer: &[u8]) -> Result<Self::SharedSecret, Error>;
+}
+
+/// The shared secret resulting from a key exchange
+pub struct SharedSecret {
+    buf: [u8; MAX_SHARED_SECRET_SIZE],
+    len: usize,
+}
+
+impl AsRef<[u8]> for SharedSecret {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// An ephemeral ECDHE private key backed by ring
+///
+/// TODO: Put behind a feature
+pub struct RingEphemeralKey {
+    algorithm: DheFixedAlgorithms,
+    private_key: EphemeralPrivateKey,
+}
+
+// Never print the private key
+impl Debug for RingEphemeralKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RingEphemeralKey")
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+impl EphemeralKey for RingEphemeralKey {
+    type SharedSecret = SharedSecret;
+
+    fn generate(algorithm: DheFixedAlgorithms) -> Result<Self, Error> {
+        let private_key = EphemeralPrivateKey::generate(
+            spdm_to_ring(algorithm)?,
+            &SystemRandom::new(),
+        )
+        .map_err(|_| Error {})?;
+        Ok(RingEphemeralKey { algorithm, private_key })
+    }
+
+    fn public_key(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let size = self.algorithm.get_exchange_data_size();
+        if buf.len() < size {
+            return Err(Error {});
+        }
+        let public_key =
+            self.private_key.compute_public_key().map_err(|_| Error {})?;
+        buf[..size].copy_from_slice(&public_key.as_ref()[1..]);
+        Ok(size)
+    }
+
+    fn shared_secret(self, peer: &[u8]) -> Result<SharedSecret, Error> {
+        let size = self.algorithm.get_exchange_data_size();
+        if peer.len() != size {
+            return Err(Error {});
+        }
+        let mut point = [0u8; MAX_EXCHANGE_DATA_SIZE + 1];
+        point[0] = 0x04;
+        point[1..=size].copy_from_slice(peer);
+        let peer = UnparsedPublicKey::new(
+            spdm_to_ring(self.algorithm)?,
+            &point[..=size],
+        );
+        agreement::agree_ephemeral(self.private_key, &peer, Error {}, |s| {
+            let mut buf = [0u8; MAX_SHARED_SECRET_SIZE];
+            buf[..s.len()].copy_from_slice(s);
+            Ok(SharedSecret { buf, len: s.len() })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agree(algorithm: DheFixedAlgorithms, secret_size: usize) {
+        let size = algorithm.get_exchange_data_size();
+        let a = RingEphemeralKey::generate(algorithm).unwrap();
+        let b = RingEphemeralKey::generate(algorithm).unwrap();
+        let mut a_pub = [0u8; MAX_EXCHANGE_DATA_SIZE];
+        let mut b_pub = [0u8; MAX_EXCHANGE_DATA_SIZE];
+        assert_eq!(size, a.public_key(&mut a_pub).unwrap());
+        assert_eq!(size, b.public_key(&mut b_pub).unwrap());
+
+        let a_secret = a.shared_secret(&b_pub[..size]).unwrap();
+        let b_secret = b.shared_secret(&a_pub[..size]).unwrap();
+        assert_eq!(secret_size, a_secret.as_ref().len());
+        assert_eq!(a_secret.as_ref(), b_secret.as_ref());
+    }
+
+    #[test]
+    fn agree_secp256r1() {
+        agree(DheFixedAlgorithms::SECP_256_R1, 32);
+    }
+
+    #[test]
+    fn agree_secp384r1() {
+        agree(DheFixedAlgorithms::SECP_384_R1, 48);
+    }
+
+    #[test]
+    fn reject_invalid_point() {
+        let algorithm = DheFixedAlgorithms::SECP_256_R1;
+        let key = RingEphemeralKey::generate(algorithm).unwrap();
+        assert!(key.shared_secret(&[0x5A; 64]).is_err());
+    }
+
+    #[test]
+    fn reject_unsupported_group() {
+        assert!(
+            RingEphemeralKey::generate(DheFixedAlgorithms::FFDHE_2048).is_err()
+        );
+    }
+}
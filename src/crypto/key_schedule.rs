@@ -0,0 +1,541 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The SPDM key schedule
+//!
+//! All secrets are derived with HKDF (RFC 5869) using the negotiated base hash
+//! algorithm, as described in the "Key schedule" section of the SPDM 1.1 spec:
+//!
+//! ```text
+//! HandshakeSecret   = HKDF-Extract(0, DHE secret)
+//! req/rsp hs secret = HKDF-Expand(HandshakeSecret, "req/rsp hs data", TH1)
+//! finished key      = HKDF-Expand(req/rsp hs secret, "finished", "")
+//! MasterSecret      = HKDF-Extract(
+//!                         HKDF-Expand(HandshakeSecret, "derived", ""), 0)
+//! req/rsp data      = HKDF-Expand(MasterSecret, "req/rsp app data", TH2)
+//! export master     = HKDF-Expand(MasterSecret, "exp master", TH2)
+//! key, iv           = HKDF-Expand(secret, "key" / "iv", "")
+//! updated secret    = HKDF-Expand(secret, "traffic upd", "")
+//! ```
+//!
+//! Every HKDF-Expand uses the hash length as output length, except for AEAD
+//! keys and IVs.
+
+use core::convert::AsRef;
+use core::fmt::{self, Debug, Formatter};
+
+use ring::{hkdf, hmac};
+
+use crate::config::MAX_DIGEST_SIZE;
+use crate::crypto::digest::{Digest, DigestImpl};
+use crate::msgs::algorithms::{AeadFixedAlgorithms, BaseHashAlgo};
+use crate::Transcript;
+
+// The version prefix of every HKDF label
+const BIN_STR_VERSION: &[u8] = b"spdm1.1 ";
+
+// The longest label, "req app data", plus some room to spare
+const MAX_LABEL_SIZE: usize = 16;
+
+/// The size of the largest key of a supported AEAD algorithm
+pub const MAX_AEAD_KEY_SIZE: usize = 32;
+
+/// The size of the IV of all supported AEAD algorithms
+pub const AEAD_IV_SIZE: usize = 12;
+
+/// A secret derived by the key schedule
+///
+/// Secrets are never larger than the largest supported digest.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret {
+    buf: [u8; MAX_DIGEST_SIZE],
+    len: usize,
+}
+
+impl Secret {
+    fn new(len: usize) -> Secret {
+        assert!(len <= MAX_DIGEST_SIZE);
+        Secret { buf: [0u8; MAX_DIGEST_SIZE], len }
+    }
+}
+
+impl AsRef<[u8]> for Secret {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+// Never print secrets
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secret").field("len", &self.len).finish()
+    }
+}
+
+fn hmac_algorithm(algorithm: BaseHashAlgo) -> hmac::Algorithm {
+    match algorithm {
+        BaseHashAlgo::SHA_256 => hmac::HMAC_SHA256,
+        BaseHashAlgo::SHA_384 => hmac::HMAC_SHA384,
+        BaseHashAlgo::SHA_512 => hmac::HMAC_SHA512,
+        _ => unimplemented!(),
+    }
+}
+
+fn hkdf_algorithm(algorithm: BaseHashAlgo) -> hkdf::Algorithm {
+    match algorithm {
+        BaseHashAlgo::SHA_256 => hkdf::HKDF_SHA256,
+        BaseHashAlgo::SHA_384 => hkdf::HKDF_SHA384,
+        BaseHashAlgo::SHA_512 => hkdf::HKDF_SHA512,
+        _ => unimplemented!(),
+    }
+}
+
+// The output length of HKDF-Expand
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Compute HMAC-Hash(key, msg)
+pub fn hmac(algorithm: BaseHashAlgo, key: &[u8], msg: &[u8]) -> Secret {
+    let key = hmac::Key::new(hmac_algorithm(algorithm), key);
+    let tag = hmac::sign(&key, msg);
+    let mut secret = Secret::new(tag.as_ref().len());
+    secret.buf[..secret.len].copy_from_slice(tag.as_ref());
+    secret
+}
+
+/// Verify `tag` against HMAC-Hash(key, msg) in constant time
+pub fn verify_hmac(
+    algorithm: BaseHashAlgo,
+    key: &[u8],
+    msg: &[u8],
+    tag: &[u8],
+) -> bool {
+    let key = hmac::Key::new(hmac_algorithm(algorithm), key);
+    hmac::verify(&key, msg, tag).is_ok()
+}
+
+/// HKDF-Extract(salt, ikm)
+fn extract(algorithm: BaseHashAlgo, salt: &[u8], ikm: &[u8]) -> Secret {
+    // HKDF-Extract is defined as HMAC-Hash(salt, IKM). We compute it directly,
+    // since ring does not expose the resulting PRK.
+    hmac(algorithm, salt, ikm)
+}
+
+/// HKDF-Expand(prk, info, len)
+fn hkdf_expand(
+    algorithm: BaseHashAlgo,
+    prk: &[u8],
+    info: &[u8],
+    len: usize,
+) -> Secret {
+    let prk = hkdf::Prk::new_less_safe(hkdf_algorithm(algorithm), prk);
+    let mut out = Secret::new(len);
+    // Expansion only fails if `len` is larger than 255 * HashLen
+    prk.expand(&[info], Len(len))
+        .and_then(|okm| okm.fill(&mut out.buf[..len]))
+        .unwrap();
+    out
+}
+
+/// HKDF-Expand(secret, BinConcat(len, version, label, context), len)
+fn expand(
+    algorithm: BaseHashAlgo,
+    secret: &[u8],
+    label: &[u8],
+    context: &[u8],
+    len: usize,
+) -> Secret {
+    assert!(label.len() <= MAX_LABEL_SIZE);
+    assert!(context.len() <= MAX_DIGEST_SIZE);
+
+    // BinConcat is the little endian length, followed by the version, label,
+    // and context.
+    let mut info = [0u8; 2 + 8 + MAX_LABEL_SIZE + MAX_DIGEST_SIZE];
+    let info_len = 2 + BIN_STR_VERSION.len() + label.len() + context.len();
+    info[..2].copy_from_slice(&(len as u16).to_le_bytes());
+    info[2..10].copy_from_slice(BIN_STR_VERSION);
+    info[10..10 + label.len()].copy_from_slice(label);
+    info[10 + label.len()..info_len].copy_from_slice(context);
+
+    hkdf_expand(algorithm, secret, &info[..info_len], len)
+}
+
+/// Compute TH1 from the handshake transcript
+///
+/// The transcript must contain the VCA messages, the hash of the responder's
+/// certificate chain (Ct), the KEY_EXCHANGE msg and the KEY_EXCHANGE_RSP msg
+/// through the signature.
+pub fn th1(algorithm: BaseHashAlgo, transcript: &Transcript) -> DigestImpl {
+    DigestImpl::hash(algorithm, transcript.get())
+}
+
+/// Compute TH2 from the handshake transcript
+///
+/// The transcript must contain everything in TH1, followed by the verify data
+/// of the KEY_EXCHANGE_RSP msg, and the FINISH and FINISH_RSP msgs.
+pub fn th2(algorithm: BaseHashAlgo, transcript: &Transcript) -> DigestImpl {
+    DigestImpl::hash(algorithm, transcript.get())
+}
+
+/// The secrets derived from the DHE shared secret and TH1 during KEY_EXCHANGE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeSecrets {
+    pub handshake_secret: Secret,
+    pub request: Secret,
+    pub response: Secret,
+}
+
+impl HandshakeSecrets {
+    /// `th1` is the hash of the transcript through the signature of the
+    /// KEY_EXCHANGE_RSP msg.
+    pub fn new(
+        algorithm: BaseHashAlgo,
+        dhe_secret: &[u8],
+        th1: &[u8],
+    ) -> HandshakeSecrets {
+        let hash_len = algorithm.get_digest_size() as usize;
+        let salt = [0u8; MAX_DIGEST_SIZE];
+        let handshake_secret =
+            extract(algorithm, &salt[..hash_len], dhe_secret);
+        let request = expand(
+            algorithm,
+            handshake_secret.as_ref(),
+            b"req hs data",
+            th1,
+            hash_len,
+        );
+        let response = expand(
+            algorithm,
+            handshake_secret.as_ref(),
+            b"rsp hs data",
+            th1,
+            hash_len,
+        );
+        HandshakeSecrets { handshake_secret, request, response }
+    }
+}
+
+/// Derive the finished key for a direction from its handshake secret
+pub fn finished_key(algorithm: BaseHashAlgo, secret: &Secret) -> Secret {
+    let hash_len = algorithm.get_digest_size() as usize;
+    expand(algorithm, secret.as_ref(), b"finished", &[], hash_len)
+}
+
+/// The AEAD key and IV for one direction of a session
+#[derive(Clone, PartialEq, Eq)]
+pub struct TrafficKeys {
+    key: [u8; MAX_AEAD_KEY_SIZE],
+    key_len: usize,
+    pub iv: [u8; AEAD_IV_SIZE],
+}
+
+// Never print keys
+impl Debug for TrafficKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrafficKeys").field("key_len", &self.key_len).finish()
+    }
+}
+
+impl TrafficKeys {
+    /// Derive the key and IV from a handshake or data secret
+    pub fn new(
+        hash_algo: BaseHashAlgo,
+        aead_algo: AeadFixedAlgorithms,
+        secret: &Secret,
+    ) -> TrafficKeys {
+        let key_len = aead_algo.get_key_size();
+        let mut keys = TrafficKeys {
+            key: [0u8; MAX_AEAD_KEY_SIZE],
+            key_len,
+            iv: [0u8; AEAD_IV_SIZE],
+        };
+        let key = expand(hash_algo, secret.as_ref(), b"key", &[], key_len);
+        keys.key[..key_len].copy_from_slice(key.as_ref());
+        let iv = expand(hash_algo, secret.as_ref(), b"iv", &[], AEAD_IV_SIZE);
+        keys.iv.copy_from_slice(iv.as_ref());
+        keys
+    }
+
+    /// Return the AEAD key
+    pub fn key(&self) -> &[u8] {
+        &self.key[..self.key_len]
+    }
+}
+
+/// Derive the next secret of a direction for KEY_UPDATE
+pub fn update_secret(algorithm: BaseHashAlgo, secret: &Secret) -> Secret {
+    let hash_len = algorithm.get_digest_size() as usize;
+    expand(algorithm, secret.as_ref(), b"traffic upd", &[], hash_len)
+}
+
+// Derive the master secret from the handshake secret
+fn master_secret(algorithm: BaseHashAlgo, handshake_secret: &Secret) -> Secret {
+    let hash_len = algorithm.get_digest_size() as usize;
+    let zeros = [0u8; MAX_DIGEST_SIZE];
+    let salt =
+        expand(algorithm, handshake_secret.as_ref(), b"derived", &[], hash_len);
+    extract(algorithm, salt.as_ref(), &zeros[..hash_len])
+}
+
+/// The application data secrets and keys of an established session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    pub request_secret: Secret,
+    pub response_secret: Secret,
+    pub export_master_secret: Secret,
+    pub request: TrafficKeys,
+    pub response: TrafficKeys,
+}
+
+impl SessionKeys {
+    /// `th2` is the hash of the transcript through the FINISH_RSP msg.
+    pub fn new(
+        hash_algo: BaseHashAlgo,
+        aead_algo: AeadFixedAlgorithms,
+        handshake_secret: &Secret,
+        th2: &[u8],
+    ) -> SessionKeys {
+        let hash_len = hash_algo.get_digest_size() as usize;
+        let master_secret = master_secret(hash_algo, handshake_secret);
+        let request_secret = expand(
+            hash_algo,
+            master_secret.as_ref(),
+            b"req app data",
+            th2,
+            hash_len,
+        );
+        let response_secret = expand(
+            hash_algo,
+            master_secret.as_ref(),
+            b"rsp app data",
+            th2,
+            hash_len,
+        );
+        let export_master_secret = expand(
+            hash_algo,
+            master_secret.as_ref(),
+            b"exp master",
+            th2,
+            hash_len,
+        );
+        SessionKeys {
+            request: TrafficKeys::new(hash_algo, aead_algo, &request_secret),
+            response: TrafficKeys::new(hash_algo, aead_algo, &response_secret),
+            request_secret,
+            response_secret,
+            export_master_secret,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::test::from_hex;
+
+    // Test Case 1 from RFC 4231
+    #[test]
+    fn hmac_sha256() {
+        let key = [0x0b; 20];
+        let expected = from_hex(
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+        )
+        .unwrap();
+        let tag = hmac(BaseHashAlgo::SHA_256, &key, b"Hi There");
+        assert_eq!(&expected, tag.as_ref());
+        assert!(verify_hmac(
+            BaseHashAlgo::SHA_256,
+            &key,
+            b"Hi There",
+            &expected
+        ));
+        assert!(!verify_hmac(BaseHashAlgo::SHA_256, &key, b"Hi", &expected));
+    }
+
+    // Test Case 1 from RFC 5869
+    #[test]
+    fn hkdf_sha256() {
+        let algorithm = BaseHashAlgo::SHA_256;
+        let ikm = [0x0b; 22];
+        let salt = from_hex("000102030405060708090a0b0c").unwrap();
+        let info = from_hex("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        let prk = extract(algorithm, &salt, &ikm);
+        assert_eq!(
+            from_hex(
+                "077709362c2e32df0ddc3f0dc47bba63\
+                 90b6c73bb50f9c3122ec844ad7c2b3e5"
+            )
+            .unwrap(),
+            prk.as_ref()
+        );
+        let okm = hkdf_expand(algorithm, prk.as_ref(), &info, 42);
+        assert_eq!(
+            from_hex(
+                "3cb25f25faacd57a90434f64d0362f2a\
+                 2d2d0a90cf1a5a4c5db02d56ecc4c5bf\
+                 34007208d5b887185865"
+            )
+            .unwrap(),
+            okm.as_ref()
+        );
+    }
+
+    // Regression values for the whole schedule, not conformance vectors: no
+    // published DMTF or libspdm vectors were available. They were computed
+    // from the key schedule definition in the SPDM 1.1 spec with Python's
+    // `hmac` and `hashlib` modules, over fixed byte patterns for the DHE
+    // secret and transcript hashes. The transcript hashes themselves are
+    // checked against the messages on the wire in tests/protocol.rs.
+    struct Expected {
+        handshake_secret: &'static str,
+        request_handshake: &'static str,
+        response_handshake: &'static str,
+        request_finished: &'static str,
+        response_finished: &'static str,
+        request_data: &'static str,
+        response_data: &'static str,
+        export_master: &'static str,
+        request_key: &'static str,
+        request_iv: &'static str,
+        request_update: &'static str,
+    }
+
+    fn check_schedule(algorithm: BaseHashAlgo, expected: Expected) {
+        let hash_len = algorithm.get_digest_size() as usize;
+        let dhe_secret = [0x11; MAX_DIGEST_SIZE];
+        let th1 = [0x22; MAX_DIGEST_SIZE];
+        let th2 = [0x33; MAX_DIGEST_SIZE];
+        let hex = |s| from_hex(s).unwrap();
+
+        let hs = HandshakeSecrets::new(
+            algorithm,
+            &dhe_secret[..hash_len],
+            &th1[..hash_len],
+        );
+        assert_eq!(
+            hex(expected.handshake_secret),
+            hs.handshake_secret.as_ref()
+        );
+        assert_eq!(hex(expected.request_handshake), hs.request.as_ref());
+        assert_eq!(hex(expected.response_handshake), hs.response.as_ref());
+        assert_eq!(
+            hex(expected.request_finished),
+            finished_key(algorithm, &hs.request).as_ref()
+        );
+        assert_eq!(
+            hex(expected.response_finished),
+            finished_key(algorithm, &hs.response).as_ref()
+        );
+
+        let keys = SessionKeys::new(
+            algorithm,
+            AeadFixedAlgorithms::AES_256_GCM,
+            &hs.handshake_secret,
+            &th2[..hash_len],
+        );
+        assert_eq!(hex(expected.request_data), keys.request_secret.as_ref());
+        assert_eq!(hex(expected.response_data), keys.response_secret.as_ref());
+        assert_eq!(
+            hex(expected.export_master),
+            keys.export_master_secret.as_ref()
+        );
+        assert_eq!(hex(expected.request_key), keys.request.key());
+        assert_eq!(hex(expected.request_iv), keys.request.iv);
+        assert_eq!(
+            hex(expected.request_update),
+            update_secret(algorithm, &keys.request_secret).as_ref()
+        );
+    }
+
+    #[test]
+    fn schedule_regression_sha256() {
+        check_schedule(
+            BaseHashAlgo::SHA_256,
+            Expected {
+                handshake_secret: "fbe4b1db0b7ee3c740efa1e88e5a3bd3\
+                                   7e9e63d5792752f5064b43555f97b0c9",
+                request_handshake: "c4878b6783dc3b83b4948db6b7b938dd\
+                                    255b6858be1def4fe2165c54e7355481",
+                response_handshake: "8e02caab58fdf4df761118c720eaab03\
+                                     b70aa70fde861c0f29f91b2de5a53030",
+                request_finished: "100886191343df1cac15e092368bd7d9\
+                                   3fef48d47eacaed4fdc5c37b46afe4b4",
+                response_finished: "62686e9566dba8f29f0a74ef76aa1bd1\
+                                    fa776d2ae776920ff96db3c5d5d32f97",
+                request_data: "694f2a762d62437a5f9a1c692e3de633\
+                               3978515a6a7c0617743ab31aae349656",
+                response_data: "81ae5c9a14dacdcc77941ad2df2785f3\
+                                a068a155adb51f381e9e4b91424e493d",
+                export_master: "0130253d9287b1999c06d1bfc23df12e\
+                                b2837125e5d42b613afde316789bcc63",
+                request_key: "badce63a3e1e240ac15266ceec10d776\
+                              5fbd158c793243dd438b67b06e64f246",
+                request_iv: "17b55f4204ee9cc1bfc45efb",
+                request_update: "6b1429362abd072abb13fd629cda1dee\
+                                 e223020bac2fca83d6c0705c4fc9a11d",
+            },
+        );
+    }
+
+    #[test]
+    fn schedule_regression_sha384() {
+        check_schedule(
+            BaseHashAlgo::SHA_384,
+            Expected {
+                handshake_secret: "939736eef8abca062cb7fe1e4eca54c8\
+                                   9a26eb8a5dcad3299bfa3e5d53af8b68\
+                                   eeea7c506eac1fc90830de7ec6198766",
+                request_handshake: "9161a00849da26a548abe93229cf18c3\
+                                    4c18da379830bde4103a10d5a95a4fc8\
+                                    d52079cc3972e4ed0f401391a1cff7d8",
+                response_handshake: "75fbef52cd2c10c7891338c87839f4ff\
+                                     5698f9c98c4e1556be58b7c8536a9c28\
+                                     b425b4818f6cd16ede2a86c3cf8a4377",
+                request_finished: "841ebc23a4985ccc83beee6c310c9257\
+                                   d85341d41fc73c9355b8a14739219dcd\
+                                   506e76033157693b8b490db92f8d7e34",
+                response_finished: "322743ae961a8eae5b8d1f5b835cb8db\
+                                    20c6e0496e5526be031fbfce9b693206\
+                                    f24bb3857e00b0ccd5f631a388951584",
+                request_data: "aa4a7dd357f8e2f214d30f6a926e5435\
+                               92789dfe01b4a355ed415c72ea42ce3a\
+                               573852bd426d95de38d7ae314070a8a0",
+                response_data: "8c5e8d1c65619116baacdabcbae68696\
+                                d90e43805a2ebc3c59e9902b694de937\
+                                1a32162b7fba080fdae7c13879e6c798",
+                export_master: "d79f5baed48836a33452dc454a861843\
+                                906664d42cf1c877521ce6827091979d\
+                                9ec160baea23b47d3a38eb553018eacd",
+                request_key: "942de52c034ebe85c00efa6964c72938\
+                              8e05d610334b5a0f06fd902b4c14b4ca",
+                request_iv: "44f1f132c6212aaa57fbeb6c",
+                request_update: "b273a7b3271e079ecbbaa6a10afbc9e1\
+                                 ed057b8aa8e02120c182ffea90342358\
+                                 afa62504d0dafb09cddaea6a9dff8df7",
+            },
+        );
+    }
+
+    #[test]
+    fn session_keys_sizes() {
+        let hash_algo = BaseHashAlgo::SHA_384;
+        let secrets = HandshakeSecrets::new(hash_algo, &[1; 48], &[2; 48]);
+        let keys = SessionKeys::new(
+            hash_algo,
+            AeadFixedAlgorithms::AES_128_GCM,
+            &secrets.handshake_secret,
+            &[3; 48],
+        );
+        assert_eq!(48, keys.request_secret.as_ref().len());
+        assert_eq!(48, keys.export_master_secret.as_ref().len());
+        assert_eq!(16, keys.request.key().len());
+        assert_ne!(keys.request.iv, keys.response.iv);
+        assert_ne!(keys.request, keys.response);
+    }
+}
@@ -2,7 +2,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use chrono::NaiveDateTime;
 use ring::io::der;
+use ring::signature::{
+    UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_FIXED,
+    ECDSA_P384_SHA384_FIXED,
+};
 
 use crate::config::MAX_SIGNATURE_SIZE;
 use core::convert::TryFrom;
@@ -43,13 +48,41 @@ pub trait EndEntityCert<'a> {
         signature: &[u8],
     ) -> bool;
 
+    /// Verify that the certificate leads to one of `root_certs` through
+    /// `intermediate_certs`, and return the index of that root cert.
+    ///
+    /// The leaf and intermediate certificates must be valid at
+    /// `seconds_since_unix_epoch`. If it is `None`, they must be valid at the
+    /// issuance time of the chain, the latest notBefore time among them, which
+    /// accepts certificates that have expired since, as long as all of them
+    /// were valid at once. The validity period of a root cert is never
+    /// checked, as it is a trust anchor.
     fn verify_chain_of_trust(
         &self,
         algorithm: BaseAsymAlgo,
         intermediate_certs: &[&[u8]],
-        root_cert: &[u8],
-        seconds_since_unix_epoch: u64,
-    ) -> Result<(), Error>;
+        root_certs: &[&[u8]],
+        seconds_since_unix_epoch: Option<u64>,
+    ) -> Result<usize, Error>;
+}
+
+/// Verify a signature with a public key that was provisioned in a trusted
+/// environment, rather than taken from a certificate.
+///
+/// The public key is an uncompressed SEC1 encoded elliptic curve point, and
+/// the signature is in the fixed size format of SPDM.
+pub fn verify_signature(
+    algorithm: BaseAsymAlgo,
+    public_key: &[u8],
+    msg: &[u8],
+    signature: &[u8],
+) -> bool {
+    let algo: &'static dyn VerificationAlgorithm = match algorithm {
+        BaseAsymAlgo::ECDSA_ECC_NIST_P256 => &ECDSA_P256_SHA256_FIXED,
+        BaseAsymAlgo::ECDSA_ECC_NIST_P384 => &ECDSA_P384_SHA384_FIXED,
+        _ => unimplemented!(),
+    };
+    UnparsedPublicKey::new(algo, public_key).verify(msg, signature).is_ok()
 }
 
 // We don't support any RSA algorithms via webpki because they are based on
@@ -70,14 +103,15 @@ fn spdm_to_webpki(algo: BaseAsymAlgo) -> &'static webpki::SignatureAlgorithm {
 ///
 /// TODO: put behind a feature flag
 pub struct WebpkiEndEntityCert<'a> {
+    der: &'a [u8],
     cert: webpki::EndEntityCert<'a>,
 }
 
 impl<'a> WebpkiEndEntityCert<'a> {
-    pub fn new(cert: &'a [u8]) -> Result<WebpkiEndEntityCert<'a>, Error> {
-        let cert = webpki::EndEntityCert::try_from(cert)
+    pub fn new(der: &'a [u8]) -> Result<WebpkiEndEntityCert<'a>, Error> {
+        let cert = webpki::EndEntityCert::try_from(der)
             .map_err(|_| Error::InvalidCert)?;
-        Ok(WebpkiEndEntityCert { cert })
+        Ok(WebpkiEndEntityCert { der, cert })
     }
 }
 
@@ -98,31 +132,55 @@ impl<'a> EndEntityCert<'a> for WebpkiEndEntityCert<'a> {
         &self,
         algorithm: BaseAsymAlgo,
         intermediate_certs: &[&[u8]],
-        root_cert: &[u8],
-        seconds_since_unix_epoch: u64,
-    ) -> Result<(), Error> {
-        let trust_anchors = [webpki::TrustAnchor::try_from_cert_der(root_cert)
-            .map_err(|_| Error::InvalidCert)?; 1];
-
-        // TODO: Does it matter if we use server or client here?
-        let server_trust_anchors =
-            webpki::TlsServerTrustAnchors(&trust_anchors);
-
+        root_certs: &[&[u8]],
+        seconds_since_unix_epoch: Option<u64>,
+    ) -> Result<usize, Error> {
+        // Without a time, the chain is validated at its issuance time, the
+        // earliest time it can be valid at as a whole. As webpki always checks
+        // validity periods, a certificate that expired before another one
+        // became valid is still rejected.
+        let seconds_since_unix_epoch = match seconds_since_unix_epoch {
+            Some(seconds) => seconds,
+            None => {
+                let mut latest = not_before(self.der)?;
+                for cert in intermediate_certs {
+                    latest = latest.max(not_before(cert)?);
+                }
+                latest
+            }
+        };
         let time = webpki::Time::from_seconds_since_unix_epoch(
             seconds_since_unix_epoch,
         );
 
         let algo = spdm_to_webpki(algorithm);
 
-        // TODO: Map error types for more info?
-        self.cert
-            .verify_is_valid_tls_server_cert(
-                &[algo],
-                &server_trust_anchors,
-                intermediate_certs,
-                time,
-            )
-            .map_err(|_| Error::ValidationFailed)
+        // Each root cert is tried on its own, as webpki does not report which
+        // trust anchor a chain leads to.
+        for (i, root_cert) in root_certs.iter().enumerate() {
+            let trust_anchors =
+                [webpki::TrustAnchor::try_from_cert_der(root_cert)
+                    .map_err(|_| Error::InvalidCert)?; 1];
+
+            // TODO: Does it matter if we use server or client here?
+            let server_trust_anchors =
+                webpki::TlsServerTrustAnchors(&trust_anchors);
+
+            // TODO: Map error types for more info?
+            if self
+                .cert
+                .verify_is_valid_tls_server_cert(
+                    &[algo],
+                    &server_trust_anchors,
+                    intermediate_certs,
+                    time,
+                )
+                .is_ok()
+            {
+                return Ok(i);
+            }
+        }
+        Err(Error::ValidationFailed)
     }
 }
 
@@ -260,3 +318,155 @@ const fn der_length(size: usize) -> usize {
         3
     }
 }
+
+// Return the notBefore time of an ASN.1 DER encoded x.509 v3 certificate, in
+// seconds since the unix epoch.
+//
+// Only the fields preceding the validity period are parsed:
+//
+// Certificate ::= SEQUENCE {
+//     tbsCertificate SEQUENCE {
+//         version         [0] EXPLICIT Version DEFAULT v1,
+//         serialNumber    INTEGER,
+//         signature       AlgorithmIdentifier,
+//         issuer          Name,
+//         validity        SEQUENCE {
+//             notBefore   Time,
+//             notAfter    Time
+//         },
+//         ...
+//     },
+//     ...
+// }
+fn not_before(cert: &[u8]) -> Result<u64, Error> {
+    let invalid = |_| Error::InvalidCert;
+    let mut cert = untrusted::Reader::new(untrusted::Input::from(cert));
+    let cert = der::expect_tag_and_get_value(&mut cert, der::Tag::Sequence)
+        .map_err(invalid)?;
+    let mut cert = untrusted::Reader::new(cert);
+    let tbs = der::expect_tag_and_get_value(&mut cert, der::Tag::Sequence)
+        .map_err(invalid)?;
+    let mut tbs = untrusted::Reader::new(tbs);
+    if tbs.peek(der::Tag::ContextSpecificConstructed0 as u8) {
+        der::read_tag_and_get_value(&mut tbs).map_err(invalid)?;
+    }
+    // Skip the serial number, signature algorithm and issuer
+    for _ in 0..3 {
+        der::read_tag_and_get_value(&mut tbs).map_err(invalid)?;
+    }
+    let validity = der::expect_tag_and_get_value(&mut tbs, der::Tag::Sequence)
+        .map_err(invalid)?;
+    let mut validity = untrusted::Reader::new(validity);
+    let (tag, time) =
+        der::read_tag_and_get_value(&mut validity).map_err(invalid)?;
+    parse_time(tag, time.as_slice_less_safe())
+}
+
+// Convert a UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ) to
+// seconds since the unix epoch. Times before the epoch are clamped to it.
+fn parse_time(tag: u8, time: &[u8]) -> Result<u64, Error> {
+    // UTCTime years before 50 are in the 21st century (RFC 5280). Prefix the
+    // century, so that both are parsed as GeneralizedTime.
+    let mut buf = [0u8; 15];
+    let time = if tag == der::Tag::UTCTime as u8 && time.len() == 13 {
+        let century = if &time[..2] < b"50" { b"20" } else { b"19" };
+        buf[..2].copy_from_slice(century);
+        buf[2..].copy_from_slice(time);
+        &buf[..]
+    } else if tag == der::Tag::GeneralizedTime as u8 && time.len() == 15 {
+        time
+    } else {
+        return Err(Error::InvalidCert);
+    };
+    let time = core::str::from_utf8(time).map_err(|_| Error::InvalidCert)?;
+    let time = NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%SZ")
+        .map_err(|_| Error::InvalidCert)?;
+    Ok(u64::try_from(time.and_utc().timestamp()).unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::date_time_ymd;
+    use test_utils::certs::cert_params_ecdsa_p256_sha256;
+
+    fn cert_not_before(year: i32, month: u32, day: u32) -> Vec<u8> {
+        let mut params = cert_params_ecdsa_p256_sha256(false, "Leaf");
+        params.not_before = date_time_ymd(year, month, day);
+        rcgen::Certificate::from_params(params)
+            .unwrap()
+            .serialize_der()
+            .unwrap()
+    }
+
+    #[test]
+    fn not_before_is_parsed() {
+        // UTCTime, including a leap day
+        let cert = cert_not_before(2024, 2, 29);
+        assert_eq!(1709164800, not_before(&cert).unwrap());
+
+        // GeneralizedTime is used from 2050
+        let cert = cert_not_before(2051, 3, 1);
+        assert_eq!(2561241600, not_before(&cert).unwrap());
+
+        // Times before the epoch are clamped to it
+        let cert = cert_not_before(1960, 1, 1);
+        assert_eq!(0, not_before(&cert).unwrap());
+
+        assert!(not_before(&cert[..cert.len() / 2]).is_err());
+    }
+
+    // Return a root, an intermediate valid from 2022 to 2023, and a leaf
+    // valid from `leaf_not_before`, as DER
+    fn chain(leaf_not_before: (i32, u32, u32)) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let root_params = cert_params_ecdsa_p256_sha256(true, "Root");
+        let root = rcgen::Certificate::from_params(root_params).unwrap();
+        let mut inter_params =
+            cert_params_ecdsa_p256_sha256(true, "Intermediate");
+        inter_params.not_before = date_time_ymd(2022, 1, 1);
+        inter_params.not_after = date_time_ymd(2023, 1, 1);
+        let inter = rcgen::Certificate::from_params(inter_params).unwrap();
+        let mut leaf_params = cert_params_ecdsa_p256_sha256(false, "Leaf");
+        let (year, month, day) = leaf_not_before;
+        leaf_params.not_before = date_time_ymd(year, month, day);
+        let leaf = rcgen::Certificate::from_params(leaf_params).unwrap();
+        (
+            root.serialize_der().unwrap(),
+            inter.serialize_der_with_signer(&root).unwrap(),
+            leaf.serialize_der_with_signer(&inter).unwrap(),
+        )
+    }
+
+    fn verify(
+        (root, inter, leaf): &(Vec<u8>, Vec<u8>, Vec<u8>),
+        time: Option<u64>,
+    ) -> Result<usize, Error> {
+        WebpkiEndEntityCert::new(leaf).unwrap().verify_chain_of_trust(
+            BaseAsymAlgo::ECDSA_ECC_NIST_P256,
+            &[&inter[..]],
+            &[&root[..]],
+            time,
+        )
+    }
+
+    // Without a time, a chain with an expired intermediate is accepted if all
+    // of its certificates were valid at once
+    #[test]
+    fn expired_intermediate_valid_with_leaf() {
+        const EXPIRED: u64 = 1704067200; // 2024-01-01
+        let certs = chain((2022, 6, 1));
+        assert_eq!(0, verify(&certs, None).unwrap());
+        assert!(matches!(
+            verify(&certs, Some(EXPIRED)),
+            Err(Error::ValidationFailed)
+        ));
+    }
+
+    // An intermediate that expired before the leaf became valid is rejected,
+    // even without a time
+    #[test]
+    fn expired_intermediate_before_leaf() {
+        let certs = chain((2024, 1, 1));
+        assert!(matches!(verify(&certs, None), Err(Error::ValidationFailed)));
+    }
+}
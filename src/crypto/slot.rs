@@ -31,3 +31,15 @@ pub struct FilledSlot<'a, S: Signer> {
     pub cert_chain: CertificateChain<'a>,
     pub signer: S,
 }
+
+/// A public key of a responder that is provisioned on requesters in a trusted
+/// environment, rather than certified by a certificate chain.
+///
+/// Requesters challenge it as slot 0xF. The public key is an uncompressed
+/// SEC1 encoded elliptic curve point, whose digest stands in for that of a
+/// certificate chain in transcripts.
+pub struct ProvisionedKey<'a, S: Signer> {
+    pub signing_algorithm: BaseAsymAlgo,
+    pub public_key: &'a [u8],
+    pub signer: S,
+}
@@ -9,6 +9,7 @@ pub mod config;
 pub mod crypto;
 pub mod requester;
 pub mod responder;
+pub mod secured_message;
 
 pub mod msgs;
 pub(crate) mod transcript;
@@ -140,6 +140,26 @@ bitflags! {
     }
 }
 
+impl DheFixedAlgorithms {
+    /// The size in bytes of the public key exchanged in KEY_EXCHANGE and
+    /// KEY_EXCHANGE_RSP msgs.
+    ///
+    /// For FFDHE this is the size of the prime. For ECDHE it is the X and Y
+    /// coordinates of the public point concatenated together.
+    pub fn get_exchange_data_size(&self) -> usize {
+        use DheFixedAlgorithms as D;
+        match *self {
+            D::FFDHE_2048 => 256,
+            D::FFDHE_3072 => 384,
+            D::FFDHE_4096 => 512,
+            D::SECP_256_R1 => 64,
+            D::SECP_384_R1 => 96,
+            D::SECP_521_R1 => 132,
+            _ => unreachable!(),
+        }
+    }
+}
+
 /// All defined key-exchange algorithms
 ///
 /// We don't currently support any external algorithms.
@@ -163,6 +183,19 @@ bitflags! {
     }
 }
 
+impl AeadFixedAlgorithms {
+    /// The size in bytes of the encryption key
+    pub fn get_key_size(&self) -> usize {
+        use AeadFixedAlgorithms as A;
+        match *self {
+            A::AES_128_GCM => 16,
+            A::AES_256_GCM => 32,
+            A::CHACHA20_POLY1305 => 32,
+            _ => unreachable!(),
+        }
+    }
+}
+
 /// All defined AEAD algorithms.
 ///
 /// We don't currently support any external algorithms
@@ -633,6 +666,43 @@ impl Algorithms {
         })
     }
 
+    /// Return the selected key-exchange algorithm, if one was negotiated.
+    pub fn dhe_algo_selected(&self) -> Option<DheFixedAlgorithms> {
+        self.algorithm_responses[..self.num_algorithm_responses as usize]
+            .iter()
+            .find_map(|rsp| match rsp {
+                AlgorithmResponse::Dhe(algo) => Some(algo.supported),
+                _ => None,
+            })
+    }
+
+    /// Return the selected AEAD algorithm, if one was negotiated.
+    pub fn aead_algo_selected(&self) -> Option<AeadFixedAlgorithms> {
+        self.algorithm_responses[..self.num_algorithm_responses as usize]
+            .iter()
+            .find_map(|rsp| match rsp {
+                AlgorithmResponse::Aead(algo) => Some(algo.supported),
+                _ => None,
+            })
+    }
+
+    /// Return the selected requester signing algorithm, if one was
+    /// negotiated.
+    ///
+    /// This is the algorithm used by the requester during mutual
+    /// authentication.
+    pub fn req_base_asym_algo_selected(&self) -> Option<BaseAsymAlgo> {
+        self.algorithm_responses[..self.num_algorithm_responses as usize]
+            .iter()
+            .find_map(|rsp| match rsp {
+                // The bits of both types identify the same algorithms
+                AlgorithmResponse::ReqBaseAsym(algo) => {
+                    BaseAsymAlgo::from_bits(algo.supported.bits() as u32)
+                }
+                _ => None,
+            })
+    }
+
     fn msg_length(&self) -> u16 {
         self.algorithm_responses[0..self.num_algorithm_responses as usize]
             .iter()
@@ -838,6 +908,21 @@ pub mod tests {
         assert_eq!(msg, msg2);
     }
 
+    #[test]
+    fn req_base_asym_algo_selected_maps_to_base_asym_algo() {
+        let mut responses =
+            [AlgorithmResponse::default(); MAX_ALGORITHM_REQUESTS];
+        algo_responses(&mut responses);
+        let mut msg = algo(responses);
+        assert_eq!(
+            Some(BaseAsymAlgo::ECDSA_ECC_NIST_P384),
+            msg.req_base_asym_algo_selected()
+        );
+
+        msg.num_algorithm_responses = 2;
+        assert_eq!(None, msg.req_base_asym_algo_selected());
+    }
+
     #[test]
     fn algorithms_with_more_than_one_selection_fails_to_parse() {
         let mut buf = [0u8; 128];
@@ -41,6 +41,8 @@ impl FromStr for ReqFlags {
             "MUT_AUTH_CAP" => ReqFlags::MUT_AUTH_CAP,
             "KEY_EX_CAP" => ReqFlags::KEY_EX_CAP,
             "PSK_CAP" => ReqFlags::PSK_CAP,
+            // A requester never provides a context, so only PSK_CAP applies
+            "PSK_CAP_WITH_CONTEXT" => ReqFlags::PSK_CAP,
             "ENCAP_CAP" => ReqFlags::ENCAP_CAP,
             "HBEAT_CAP" => ReqFlags::HBEAT_CAP,
             "KEY_UPD_CAP" => ReqFlags::KEY_UPD_CAP,
@@ -48,6 +50,11 @@ impl FromStr for ReqFlags {
                 ReqFlags::HANDSHAKE_IN_THE_CLEAR_CAP
             }
             "PUB_KEY_ID_CAP" => ReqFlags::PUB_KEY_ID_CAP,
+
+            // Capabilities are shared between roles in configuration, so we
+            // ignore those that only apply to responders.
+            "CACHE_CAP" | "MEAS_CAP_NO_SIG" | "MEAS_CAP_SIG"
+            | "MEAS_FRESH_CAP" => ReqFlags::empty(),
             _ => {
                 return Err(ReadError::new(
                     "CAPABILITIES",
@@ -127,12 +134,15 @@ impl FromStr for RspFlags {
             "CACHE_CAP" => RspFlags::CACHE_CAP,
             "CERT_CAP" => RspFlags::CERT_CAP,
             "CHAL_CAP" => RspFlags::CHAL_CAP,
+            "MEAS_CAP_NO_SIG" => RspFlags::MEAS_CAP_NO_SIG,
+            "MEAS_CAP_SIG" => RspFlags::MEAS_CAP_SIG,
             "MEAS_FRESH_CAP" => RspFlags::MEAS_FRESH_CAP,
             "ENCRYPT_CAP" => RspFlags::ENCRYPT_CAP,
             "MAC_CAP" => RspFlags::MAC_CAP,
             "MUT_AUTH_CAP" => RspFlags::MUT_AUTH_CAP,
             "KEY_EX_CAP" => RspFlags::KEY_EX_CAP,
             "PSK_CAP" => RspFlags::PSK_CAP,
+            "PSK_CAP_WITH_CONTEXT" => RspFlags::PSX_CAP_WITH_CONTEXT,
             "ENCAP_CAP" => RspFlags::ENCAP_CAP,
             "HBEAT_CAP" => RspFlags::HBEAT_CAP,
             "KEY_UPD_CAP" => RspFlags::KEY_UPD_CAP,
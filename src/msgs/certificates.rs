@@ -13,10 +13,9 @@ use super::Msg;
 
 /// A request for a certificate portion in a given slot
 ///
-/// The SPDM spec allows multiple messages to be used to transfer a certificate,
-/// although this implementation does not yet support that. This is the reason
-/// for the offset and length fields. This implementation always transfers an
-/// entire certificate in one message, implying that offset is always `0`.
+/// The SPDM spec allows multiple messages to be used to transfer a certificate.
+/// This is the reason for the offset and length fields. A requester asks for
+/// portions of at most `config::CERT_PORTION_SIZE` bytes.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetCertificate {
     pub slot: u8,
@@ -103,8 +102,7 @@ impl<const N: usize> Certificate<N> {
 }
 
 /// Represents a complete certificate chain. This may result from the
-/// concatenation of buffers from multiple `Certificate` messages. For now we
-/// only support retreiving cert chains in a single message.
+/// concatenation of buffers from multiple `Certificate` messages.
 ///
 /// Certificates inside the cert chain are verified outside of this module.
 ///
@@ -432,7 +430,7 @@ mod tests {
         let der_leaf =
             leaf_cert.serialize_der_with_signer(&intermediate2_cert).unwrap();
 
-        let intermediate_certs = vec![&der_inter1[..], &der_inter2[..]];
+        let intermediate_certs = [&der_inter1[..], &der_inter2[..]];
         let trust_anchors =
             vec![webpki::TrustAnchor::try_from_cert_der(&der_root).unwrap()];
         let server_trust_anchors =
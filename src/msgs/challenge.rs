@@ -37,6 +37,15 @@ impl TryFrom<u8> for MeasurementHashType {
     }
 }
 
+/// The slot of a responder public key provisioned on the requester in a
+/// trusted environment, as sent in CHALLENGE_AUTH, GET_MEASUREMENTS and
+/// MEASUREMENTS
+pub const PROVISIONED_KEY_SLOT: u8 = 0xF;
+
+/// The slot ID of a provisioned public key in CHALLENGE and KEY_EXCHANGE
+/// requests
+pub const PROVISIONED_KEY_SLOT_ID: u8 = 0xFF;
+
 /// Generate a 32 byte nonce
 pub fn nonce() -> [u8; 32] {
     let mut nonce = [0u8; 32];
@@ -129,9 +138,9 @@ impl Msg for ChallengeAuth {
     const SPDM_CODE: u8 = 0x03;
 
     fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
-        assert!(self.slot < 8);
+        assert!(self.slot < 8 || self.slot == PROVISIONED_KEY_SLOT);
         if self.use_mutual_auth {
-            w.put(self.slot & (1 << 7))?;
+            w.put(self.slot | (1 << 7))?;
         } else {
             w.put(self.slot)?;
         }
@@ -324,6 +333,32 @@ mod tests {
         )
         .unwrap();
 
+        assert_eq!(c, c2);
+    }
+    #[test]
+    fn round_trip_challenge_auth_with_mutual_auth() {
+        let digest_size = 32;
+        let signature_size = 64;
+        let c = ChallengeAuth::new(
+            3,
+            0x8,
+            true,
+            &[9u8; 32],
+            [0x13; 32],
+            &[0u8; 32],
+            &[],
+            &[1u8; 64],
+        );
+        let mut buf = [0u8; 256];
+        let _ = c.write(&mut buf).unwrap();
+        assert_eq!(0x83, buf[2]);
+        let c2 = ChallengeAuth::parse_body(
+            &buf[HEADER_SIZE..],
+            digest_size,
+            signature_size,
+        )
+        .unwrap();
+
         assert_eq!(c, c2);
     }
 }
@@ -0,0 +1,240 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Messages that let a responder send its own requests to a requester.
+//!
+//! Only a requester can start an exchange over some transports. A responder
+//! that needs to authenticate the requester therefore encapsulates its
+//! requests in responses to GET_ENCAPSULATED_REQUEST and
+//! DELIVER_ENCAPSULATED_RESPONSE, and the requester encapsulates its responses
+//! in DELIVER_ENCAPSULATED_RESPONSE.
+//!
+//! An encapsulated message directly follows the 4 byte header of the message
+//! carrying it. It is serialized separately into the same buffer, rather than
+//! copied, as it may be as large as a certificate chain.
+
+use super::encoding::{ReadError, ReadErrorKind, Reader, WriteError, Writer};
+use super::{Msg, HEADER_SIZE};
+
+/// A request for the first request of the responder
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GetEncapsulatedRequest {}
+
+impl Msg for GetEncapsulatedRequest {
+    const NAME: &'static str = "GET_ENCAPSULATED_REQUEST";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xEA;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put_reserved(2)
+    }
+}
+
+impl GetEncapsulatedRequest {
+    pub fn parse_body(buf: &[u8]) -> Result<GetEncapsulatedRequest, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        r.skip_reserved(2)?;
+        Ok(GetEncapsulatedRequest {})
+    }
+}
+
+/// The response to GET_ENCAPSULATED_REQUEST, carrying a request of the
+/// responder
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EncapsulatedRequest {
+    // Param1
+    // Identifies the request in the matching DELIVER_ENCAPSULATED_RESPONSE
+    pub request_id: u8,
+}
+
+impl Msg for EncapsulatedRequest {
+    const NAME: &'static str = "ENCAPSULATED_REQUEST";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x6A;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.request_id)?;
+        w.put_reserved(1)
+    }
+}
+
+impl EncapsulatedRequest {
+    /// Deserialize the body of an ENCAPSULATED_REQUEST message, and return it
+    /// along with the encapsulated request.
+    pub fn parse_body(
+        buf: &[u8],
+    ) -> Result<(EncapsulatedRequest, &[u8]), ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let request_id = r.get_byte()?;
+        r.skip_reserved(1)?;
+        let request = encapsulated_msg(Self::NAME, buf, &r)?;
+        Ok((EncapsulatedRequest { request_id }, request))
+    }
+}
+
+/// A request carrying the response of the requester to an encapsulated
+/// request
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeliverEncapsulatedResponse {
+    // Param1
+    // The ID of the encapsulated request being responded to
+    pub request_id: u8,
+}
+
+impl Msg for DeliverEncapsulatedResponse {
+    const NAME: &'static str = "DELIVER_ENCAPSULATED_RESPONSE";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xEB;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.request_id)?;
+        w.put_reserved(1)
+    }
+}
+
+impl DeliverEncapsulatedResponse {
+    /// Deserialize the body of a DELIVER_ENCAPSULATED_RESPONSE message, and
+    /// return it along with the encapsulated response.
+    pub fn parse_body(
+        buf: &[u8],
+    ) -> Result<(DeliverEncapsulatedResponse, &[u8]), ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let request_id = r.get_byte()?;
+        r.skip_reserved(1)?;
+        let response = encapsulated_msg(Self::NAME, buf, &r)?;
+        Ok((DeliverEncapsulatedResponse { request_id }, response))
+    }
+}
+
+/// The response to DELIVER_ENCAPSULATED_RESPONSE
+///
+/// It carries the next request of the responder, unless `request_id` is 0,
+/// in which case the responder has no further requests.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EncapsulatedResponseAck {
+    // Param1
+    pub request_id: u8,
+}
+
+impl Msg for EncapsulatedResponseAck {
+    const NAME: &'static str = "ENCAPSULATED_RESPONSE_ACK";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x6B;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.request_id)?;
+        w.put_reserved(1)
+    }
+}
+
+impl EncapsulatedResponseAck {
+    /// Deserialize the body of an ENCAPSULATED_RESPONSE_ACK message, and
+    /// return it along with the next encapsulated request, if any.
+    pub fn parse_body(
+        buf: &[u8],
+    ) -> Result<(EncapsulatedResponseAck, Option<&[u8]>), ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let request_id = r.get_byte()?;
+        r.skip_reserved(1)?;
+        if request_id == 0 {
+            if !r.is_empty() {
+                return Err(ReadError::new(
+                    Self::NAME,
+                    ReadErrorKind::UnexpectedValue,
+                ));
+            }
+            return Ok((EncapsulatedResponseAck { request_id }, None));
+        }
+        let request = encapsulated_msg(Self::NAME, buf, &r)?;
+        Ok((EncapsulatedResponseAck { request_id }, Some(request)))
+    }
+}
+
+// Return the rest of the message, which must contain at least an SPDM header
+fn encapsulated_msg<'a>(
+    msg: &'static str,
+    buf: &'a [u8],
+    r: &Reader,
+) -> Result<&'a [u8], ReadError> {
+    // The header is not enough to parse a message, but checking that it is
+    // present lets `Msg::parse_header` be used on the result.
+    if r.remaining() <= HEADER_SIZE {
+        return Err(ReadError::new(msg, ReadErrorKind::Empty));
+    }
+    Ok(&buf[r.byte_offset()..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{GetDigests, HEADER_SIZE};
+    use super::*;
+
+    #[test]
+    fn get_encapsulated_request_roundtrip() {
+        let mut buf = [0u8; 16];
+        assert_eq!(4, GetEncapsulatedRequest {}.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), GetEncapsulatedRequest::parse_header(&buf));
+        assert!(
+            GetEncapsulatedRequest::parse_body(&buf[HEADER_SIZE..4]).is_ok()
+        );
+    }
+
+    #[test]
+    fn encapsulated_request_roundtrip() {
+        let mut buf = [0u8; 16];
+        let msg = EncapsulatedRequest { request_id: 3 };
+        let size = msg.write(&mut buf).unwrap();
+        let size = size + GetDigests {}.write(&mut buf[size..]).unwrap();
+        assert_eq!(8, size);
+        assert_eq!(Ok(true), EncapsulatedRequest::parse_header(&buf));
+
+        let (msg2, request) =
+            EncapsulatedRequest::parse_body(&buf[HEADER_SIZE..size]).unwrap();
+        assert_eq!(msg, msg2);
+        assert_eq!(Ok(true), GetDigests::parse_header(request));
+
+        // An encapsulated request is required
+        assert!(EncapsulatedRequest::parse_body(&buf[HEADER_SIZE..4]).is_err());
+    }
+
+    #[test]
+    fn deliver_encapsulated_response_roundtrip() {
+        let mut buf = [0u8; 16];
+        let msg = DeliverEncapsulatedResponse { request_id: 1 };
+        let size = msg.write(&mut buf).unwrap();
+        let size = size + GetDigests {}.write(&mut buf[size..]).unwrap();
+        assert_eq!(Ok(true), DeliverEncapsulatedResponse::parse_header(&buf));
+
+        let (msg2, response) =
+            DeliverEncapsulatedResponse::parse_body(&buf[HEADER_SIZE..size])
+                .unwrap();
+        assert_eq!(msg, msg2);
+        assert_eq!(&buf[4..size], response);
+    }
+
+    #[test]
+    fn encapsulated_response_ack_roundtrip() {
+        let mut buf = [0u8; 16];
+        let msg = EncapsulatedResponseAck { request_id: 2 };
+        let size = msg.write(&mut buf).unwrap();
+        let size = size + GetDigests {}.write(&mut buf[size..]).unwrap();
+        assert_eq!(Ok(true), EncapsulatedResponseAck::parse_header(&buf));
+        let (msg2, request) =
+            EncapsulatedResponseAck::parse_body(&buf[HEADER_SIZE..size])
+                .unwrap();
+        assert_eq!(msg, msg2);
+        assert_eq!(Some(&buf[4..size]), request);
+
+        // The last ACK carries no request
+        let msg = EncapsulatedResponseAck { request_id: 0 };
+        assert_eq!(4, msg.write(&mut buf).unwrap());
+        let (msg2, request) =
+            EncapsulatedResponseAck::parse_body(&buf[HEADER_SIZE..4]).unwrap();
+        assert_eq!(msg, msg2);
+        assert_eq!(None, request);
+        assert!(
+            EncapsulatedResponseAck::parse_body(&buf[HEADER_SIZE..8]).is_err()
+        );
+    }
+}
@@ -89,6 +89,21 @@ impl<'a> Writer<'a> {
         Ok(self.offset)
     }
 
+    // Write the low 3 bytes of a u32 in little-endian byte order
+    pub fn put_u24(&mut self, num: u32) -> Result<usize, WriteError> {
+        if num > 0xFFFFFF {
+            return Err(WriteError::new(
+                self.msg,
+                WriteErrorKind::InvalidRange("u24"),
+            ));
+        }
+        let buf = num.to_le_bytes();
+        for b in &buf[..3] {
+            self.put(*b)?;
+        }
+        Ok(self.offset)
+    }
+
     // Write a u32 in little-endian byte order
     pub fn put_u32(&mut self, num: u32) -> Result<usize, WriteError> {
         let buf = num.to_le_bytes();
@@ -333,6 +348,23 @@ impl<'a> Reader<'a> {
         Ok(&self.buf[start..self.byte_offset])
     }
 
+    /// Read a 3 byte integer in little endian byte order
+    ///
+    /// This only works on aligned reads.
+    pub fn get_u24(&mut self) -> Result<u32, ReadError> {
+        if !self.is_aligned() {
+            return Err(self.err(ReadErrorKind::Unaligned));
+        }
+        if self.remaining() < 3 {
+            return Err(self.err(ReadErrorKind::Empty));
+        }
+        let pos = self.byte_offset;
+        let mut buf = [0u8; 4];
+        buf[..3].copy_from_slice(&self.buf[pos..pos + 3]);
+        self.byte_offset += 3;
+        Ok(u32::from_le_bytes(buf))
+    }
+
     /// Read a u32 in little endian byte order
     ///
     /// This only works on aligned reads.
@@ -446,6 +478,22 @@ mod tests {
         assert!(reader.get_bits(10).is_err());
     }
 
+    #[test]
+    fn u24_roundtrip() {
+        let mut buf = [0u8; 3];
+        let mut w = Writer::new("TEST_MSG", &mut buf);
+        assert_eq!(3, w.put_u24(0x123456).unwrap());
+        assert_eq!([0x56, 0x34, 0x12], buf);
+
+        let mut reader = Reader::new("TEST_MSG", &buf);
+        assert_eq!(0x123456, reader.get_u24().unwrap());
+        assert!(reader.is_empty());
+
+        let mut buf = [0u8; 3];
+        let mut w = Writer::new("TEST_MSG", &mut buf);
+        assert!(w.put_u24(0x1000000).is_err());
+    }
+
     #[test]
     fn get_bit_by_bit() {
         let buf = [0xF0, 0xF0];
@@ -0,0 +1,89 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::encoding::{ReadError, Reader, WriteError, Writer};
+use super::Msg;
+
+// Bit 0 of the attributes in Param1
+const NEGOTIATED_STATE_CLEARING: u8 = 0x1;
+
+/// A request to end the session it is sent in
+///
+/// If `negotiated_state_clearing` is set, the responder must forget the
+/// state negotiated by GET_VERSION, GET_CAPABILITIES and NEGOTIATE_ALGORITHMS
+/// once the session ends. This only matters to a responder that caches it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EndSession {
+    pub negotiated_state_clearing: bool,
+}
+
+impl Msg for EndSession {
+    const NAME: &'static str = "END_SESSION";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xEC;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        if self.negotiated_state_clearing {
+            w.put(NEGOTIATED_STATE_CLEARING)?;
+        } else {
+            w.put(0)?;
+        }
+        w.put_reserved(1)
+    }
+}
+
+impl EndSession {
+    pub fn parse_body(buf: &[u8]) -> Result<EndSession, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let attributes = r.get_byte()?;
+        r.skip_reserved(1)?;
+        Ok(EndSession {
+            negotiated_state_clearing: attributes & NEGOTIATED_STATE_CLEARING
+                != 0,
+        })
+    }
+}
+
+/// The response to an END_SESSION request
+///
+/// This is the last message sent in the session.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EndSessionAck {}
+
+impl Msg for EndSessionAck {
+    const NAME: &'static str = "END_SESSION_ACK";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x6C;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put_reserved(2)
+    }
+}
+
+impl EndSessionAck {
+    pub fn parse_body(buf: &[u8]) -> Result<EndSessionAck, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        r.skip_reserved(2)?;
+        Ok(EndSessionAck {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::HEADER_SIZE;
+    use super::*;
+
+    #[test]
+    fn end_session_roundtrip() {
+        let mut buf = [0u8; 16];
+        let msg = EndSession { negotiated_state_clearing: true };
+        assert_eq!(4, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), EndSession::parse_header(&buf));
+        assert_eq!(msg, EndSession::parse_body(&buf[HEADER_SIZE..4]).unwrap());
+
+        assert_eq!(4, EndSessionAck {}.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), EndSessionAck::parse_header(&buf));
+        assert!(EndSessionAck::parse_body(&buf[HEADER_SIZE..4]).is_ok());
+    }
+}
@@ -0,0 +1,262 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::cmp::PartialEq;
+
+use super::encoding::{ReadError, ReadErrorKind, Reader, WriteError, Writer};
+use super::Msg;
+use crate::config::{MAX_DIGEST_SIZE, MAX_SIGNATURE_SIZE};
+
+// Param1 of FINISH: set if the requester signed the transcript
+const SIGNATURE_INCLUDED: u8 = 0x1;
+
+/// The request to complete the handshake of a session created by
+/// KEY_EXCHANGE.
+///
+/// The signature is only present if mutual authentication was requested in
+/// the KEY_EXCHANGE_RSP. The RequesterVerifyData is an HMAC over the
+/// transcript through the signature, using the finished key derived from the
+/// request handshake secret.
+#[derive(Debug, Clone)]
+pub struct Finish {
+    // Param2
+    // The slot of the requester's certificate chain used for the signature
+    pub req_slot_id: u8,
+
+    // The size of the verify data
+    pub digest_size: u8,
+
+    // The signature, if mutual authentication was requested
+    pub signature_size: usize,
+    pub signature: Option<[u8; MAX_SIGNATURE_SIZE]>,
+
+    pub verify_data: [u8; MAX_DIGEST_SIZE],
+}
+
+// We can't derive PartialEq because the signature and verify data buffers may
+// only be partially full.
+impl PartialEq for Finish {
+    fn eq(&self, other: &Self) -> bool {
+        self.req_slot_id == other.req_slot_id
+            && self.signature() == other.signature()
+            && self.verify_data() == other.verify_data()
+    }
+}
+
+impl Eq for Finish {}
+
+impl Default for Finish {
+    fn default() -> Self {
+        Finish {
+            req_slot_id: 0,
+            digest_size: 0,
+            signature_size: 0,
+            signature: None,
+            verify_data: [0u8; MAX_DIGEST_SIZE],
+        }
+    }
+}
+
+impl Msg for Finish {
+    const NAME: &'static str = "FINISH";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xE5;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        if let Some(signature) = self.signature() {
+            w.put(SIGNATURE_INCLUDED)?;
+            w.put(self.req_slot_id)?;
+            w.extend(signature)?;
+        } else {
+            w.put(0)?;
+            w.put(self.req_slot_id)?;
+        }
+        w.extend(self.verify_data())
+    }
+}
+
+impl Finish {
+    /// Return the signature of the requester, or `None` if mutual
+    /// authentication was not requested.
+    pub fn signature(&self) -> Option<&[u8]> {
+        self.signature.as_ref().map(|sig| &sig[..self.signature_size])
+    }
+
+    /// Return the RequesterVerifyData HMAC
+    pub fn verify_data(&self) -> &[u8] {
+        &self.verify_data[..self.digest_size as usize]
+    }
+
+    /// Deserialize the body of a FINISH message.
+    ///
+    /// All sizes correspond to the algorithms negotiated in previous steps of
+    /// the protocol. `signature_size` is only used if the request contains a
+    /// signature.
+    pub fn parse_body(
+        buf: &[u8],
+        digest_size: u8,
+        signature_size: usize,
+    ) -> Result<Finish, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let attributes = r.get_byte()?;
+        if attributes & !SIGNATURE_INCLUDED != 0 {
+            return Err(ReadError::new(
+                Self::NAME,
+                ReadErrorKind::InvalidBitsSet,
+            ));
+        }
+        let req_slot_id = r.get_byte()?;
+
+        let (signature_size, signature) =
+            if attributes & SIGNATURE_INCLUDED != 0 {
+                let mut signature = [0u8; MAX_SIGNATURE_SIZE];
+                signature[..signature_size]
+                    .copy_from_slice(r.get_slice(signature_size)?);
+                (signature_size, Some(signature))
+            } else {
+                (0, None)
+            };
+
+        let mut verify_data = [0u8; MAX_DIGEST_SIZE];
+        verify_data[..digest_size as usize]
+            .copy_from_slice(r.get_slice(digest_size as usize)?);
+
+        Ok(Finish {
+            req_slot_id,
+            digest_size,
+            signature_size,
+            signature,
+            verify_data,
+        })
+    }
+}
+
+/// The response to a FINISH request
+///
+/// The ResponderVerifyData is only present if both the requester and responder
+/// set HANDSHAKE_IN_THE_CLEAR_CAP. Otherwise, FINISH and FINISH_RSP are sent
+/// within the session, and FINISH_RSP is authenticated by the MAC of its
+/// record, which is keyed by the response handshake secret.
+#[derive(Debug, Clone)]
+pub struct FinishRsp {
+    // The size of the verify data
+    pub digest_size: u8,
+    pub verify_data: Option<[u8; MAX_DIGEST_SIZE]>,
+}
+
+// We can't derive PartialEq because the verify data buffer may only be
+// partially full.
+impl PartialEq for FinishRsp {
+    fn eq(&self, other: &Self) -> bool {
+        self.verify_data() == other.verify_data()
+    }
+}
+
+impl Eq for FinishRsp {}
+
+impl Msg for FinishRsp {
+    const NAME: &'static str = "FINISH_RSP";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x65;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        let size = w.put_reserved(2)?;
+        match self.verify_data() {
+            Some(verify_data) => w.extend(verify_data),
+            None => Ok(size),
+        }
+    }
+}
+
+impl FinishRsp {
+    /// Return the ResponderVerifyData HMAC, or `None` if the handshake was not
+    /// in the clear.
+    pub fn verify_data(&self) -> Option<&[u8]> {
+        self.verify_data.as_ref().map(|data| &data[..self.digest_size as usize])
+    }
+
+    /// Deserialize the body of a FINISH_RSP message.
+    ///
+    /// `verify_data_included` must be true if both sides set
+    /// HANDSHAKE_IN_THE_CLEAR_CAP.
+    pub fn parse_body(
+        buf: &[u8],
+        digest_size: u8,
+        verify_data_included: bool,
+    ) -> Result<FinishRsp, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        r.skip_reserved(2)?;
+        let verify_data = if verify_data_included {
+            let mut verify_data = [0u8; MAX_DIGEST_SIZE];
+            verify_data[..digest_size as usize]
+                .copy_from_slice(r.get_slice(digest_size as usize)?);
+            Some(verify_data)
+        } else {
+            None
+        };
+        Ok(FinishRsp { digest_size, verify_data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::HEADER_SIZE;
+    use super::*;
+
+    #[test]
+    fn finish_roundtrip() {
+        let mut buf = [0u8; 256];
+        let mut msg = Finish { digest_size: 48, ..Finish::default() };
+        msg.verify_data[..48].copy_from_slice(&[0x17; 48]);
+
+        // No signature
+        assert_eq!(52, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), Finish::parse_header(&buf));
+        assert_eq!(
+            msg,
+            Finish::parse_body(&buf[HEADER_SIZE..], 48, 64).unwrap()
+        );
+
+        // Mutual authentication
+        msg.req_slot_id = 1;
+        msg.signature_size = 64;
+        msg.signature = Some([0x33; MAX_SIGNATURE_SIZE]);
+        assert_eq!(116, msg.write(&mut buf).unwrap());
+        assert_eq!(
+            msg,
+            Finish::parse_body(&buf[HEADER_SIZE..], 48, 64).unwrap()
+        );
+    }
+
+    #[test]
+    fn finish_invalid_attributes() {
+        let mut buf = [0u8; 256];
+        let msg = Finish { digest_size: 32, ..Finish::default() };
+        let size = msg.write(&mut buf).unwrap();
+        buf[HEADER_SIZE] = 0x2;
+        assert!(Finish::parse_body(&buf[HEADER_SIZE..size], 32, 64).is_err());
+    }
+
+    #[test]
+    fn finish_rsp_roundtrip() {
+        let mut buf = [0u8; 128];
+        let mut msg = FinishRsp { digest_size: 32, verify_data: None };
+
+        // The handshake is not in the clear
+        assert_eq!(4, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), FinishRsp::parse_header(&buf));
+        assert_eq!(
+            msg,
+            FinishRsp::parse_body(&buf[HEADER_SIZE..4], 32, false).unwrap()
+        );
+
+        // The handshake is in the clear
+        msg.verify_data = Some([0x5C; MAX_DIGEST_SIZE]);
+        assert_eq!(36, msg.write(&mut buf).unwrap());
+        assert_eq!(
+            msg,
+            FinishRsp::parse_body(&buf[HEADER_SIZE..36], 32, true).unwrap()
+        );
+    }
+}
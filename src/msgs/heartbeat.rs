@@ -0,0 +1,71 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::encoding::{ReadError, Reader, WriteError, Writer};
+use super::Msg;
+
+/// A request that keeps a session alive
+///
+/// HEARTBEAT is only ever sent within a session whose heartbeat period is
+/// not zero.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Heartbeat {}
+
+impl Msg for Heartbeat {
+    const NAME: &'static str = "HEARTBEAT";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xE8;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put_reserved(2)
+    }
+}
+
+impl Heartbeat {
+    pub fn parse_body(buf: &[u8]) -> Result<Heartbeat, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        r.skip_reserved(2)?;
+        Ok(Heartbeat {})
+    }
+}
+
+/// The response to a HEARTBEAT request
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HeartbeatAck {}
+
+impl Msg for HeartbeatAck {
+    const NAME: &'static str = "HEARTBEAT_ACK";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x68;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put_reserved(2)
+    }
+}
+
+impl HeartbeatAck {
+    pub fn parse_body(buf: &[u8]) -> Result<HeartbeatAck, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        r.skip_reserved(2)?;
+        Ok(HeartbeatAck {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::HEADER_SIZE;
+    use super::*;
+
+    #[test]
+    fn heartbeat_roundtrip() {
+        let mut buf = [0u8; 16];
+        assert_eq!(4, Heartbeat {}.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), Heartbeat::parse_header(&buf));
+        assert!(Heartbeat::parse_body(&buf[HEADER_SIZE..4]).is_ok());
+
+        assert_eq!(4, HeartbeatAck {}.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), HeartbeatAck::parse_header(&buf));
+        assert!(HeartbeatAck::parse_body(&buf[HEADER_SIZE..4]).is_ok());
+    }
+}
@@ -0,0 +1,464 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::cmp::PartialEq;
+use core::convert::TryInto;
+
+use bitflags::bitflags;
+
+use super::encoding::{ReadError, ReadErrorKind, Reader, WriteError, Writer};
+use super::{MeasurementHashType, Msg};
+use crate::config::{
+    MAX_DIGEST_SIZE, MAX_OPAQUE_DATA_SIZE, MAX_SIGNATURE_SIZE,
+};
+
+/// The size of the exchange data of the largest supported DHE group
+/// (secp384r1).
+pub const MAX_EXCHANGE_DATA_SIZE: usize = 96;
+
+/// Return the ID of a session given the ReqSessionID from KEY_EXCHANGE and
+/// the RspSessionID from KEY_EXCHANGE_RSP.
+pub fn session_id(req_session_id: u16, rsp_session_id: u16) -> u32 {
+    (u32::from(rsp_session_id) << 16) | u32::from(req_session_id)
+}
+
+// Read opaque data into a fixed size buffer
+fn read_opaque_data(
+    name: &'static str,
+    r: &mut Reader,
+) -> Result<(u16, [u8; MAX_OPAQUE_DATA_SIZE]), ReadError> {
+    let opaque_data_len = r.get_u16()?;
+    if opaque_data_len as usize > MAX_OPAQUE_DATA_SIZE {
+        return Err(ReadError::new(
+            name,
+            ReadErrorKind::ImplementationLimitReached,
+        ));
+    }
+    let mut opaque_data = [0u8; MAX_OPAQUE_DATA_SIZE];
+    opaque_data[..opaque_data_len as usize]
+        .copy_from_slice(r.get_slice(opaque_data_len as usize)?);
+    Ok((opaque_data_len, opaque_data))
+}
+
+// Read exchange data of the given size into a fixed size buffer
+fn read_exchange_data(
+    name: &'static str,
+    r: &mut Reader,
+    exchange_data_size: usize,
+) -> Result<[u8; MAX_EXCHANGE_DATA_SIZE], ReadError> {
+    if exchange_data_size > MAX_EXCHANGE_DATA_SIZE {
+        return Err(ReadError::new(
+            name,
+            ReadErrorKind::ImplementationLimitReached,
+        ));
+    }
+    let mut exchange_data = [0u8; MAX_EXCHANGE_DATA_SIZE];
+    exchange_data[..exchange_data_size]
+        .copy_from_slice(r.get_slice(exchange_data_size)?);
+    Ok(exchange_data)
+}
+
+/// The request to begin a secure session using an ephemeral Diffie-Hellman
+/// key exchange.
+#[derive(Debug, Clone)]
+pub struct KeyExchange {
+    // Param1
+    pub measurement_hash_type: MeasurementHashType,
+
+    // Param2
+    // Set to 0xFF if the responder's public key was pre-provisioned on the
+    // requester.
+    pub slot_id: u8,
+
+    pub req_session_id: u16,
+    pub random_data: [u8; 32],
+
+    // The size is determined by the negotiated DHE group
+    pub exchange_data_size: usize,
+    pub exchange_data: [u8; MAX_EXCHANGE_DATA_SIZE],
+
+    pub opaque_data_len: u16,
+    pub opaque_data: [u8; MAX_OPAQUE_DATA_SIZE],
+}
+
+// We can't derive PartialEq because the exchange and opaque data buffers may
+// only be partially full.
+impl PartialEq for KeyExchange {
+    fn eq(&self, other: &Self) -> bool {
+        self.measurement_hash_type == other.measurement_hash_type
+            && self.slot_id == other.slot_id
+            && self.req_session_id == other.req_session_id
+            && self.random_data == other.random_data
+            && self.exchange_data() == other.exchange_data()
+            && self.opaque_data() == other.opaque_data()
+    }
+}
+
+impl Eq for KeyExchange {}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        KeyExchange {
+            measurement_hash_type: MeasurementHashType::None,
+            slot_id: 0,
+            req_session_id: 0,
+            random_data: [0u8; 32],
+            exchange_data_size: 0,
+            exchange_data: [0u8; MAX_EXCHANGE_DATA_SIZE],
+            opaque_data_len: 0,
+            opaque_data: [0u8; MAX_OPAQUE_DATA_SIZE],
+        }
+    }
+}
+
+impl Msg for KeyExchange {
+    const NAME: &'static str = "KEY_EXCHANGE";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xE4;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.measurement_hash_type as u8)?;
+        w.put(self.slot_id)?;
+        w.put_u16(self.req_session_id)?;
+        w.put_reserved(2)?;
+        w.extend(&self.random_data)?;
+        w.extend(self.exchange_data())?;
+        w.put_u16(self.opaque_data_len)?;
+        w.extend(self.opaque_data())
+    }
+}
+
+impl KeyExchange {
+    /// Return the public key of the requester in the SPDM exchange data
+    /// format.
+    pub fn exchange_data(&self) -> &[u8] {
+        &self.exchange_data[..self.exchange_data_size]
+    }
+
+    /// Return any application level opaque data provided as part of the
+    /// request.
+    pub fn opaque_data(&self) -> &[u8] {
+        &self.opaque_data[..self.opaque_data_len as usize]
+    }
+
+    /// Deserialize the body of a KEY_EXCHANGE message.
+    ///
+    /// `exchange_data_size` corresponds to the negotiated DHE group.
+    pub fn parse_body(
+        buf: &[u8],
+        exchange_data_size: usize,
+    ) -> Result<KeyExchange, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let measurement_hash_type = r.get_byte()?.try_into()?;
+        let slot_id = r.get_byte()?;
+        let req_session_id = r.get_u16()?;
+        r.skip_reserved(2)?;
+        let mut random_data = [0u8; 32];
+        random_data.copy_from_slice(r.get_slice(32)?);
+        let exchange_data =
+            read_exchange_data(Self::NAME, &mut r, exchange_data_size)?;
+        let (opaque_data_len, opaque_data) =
+            read_opaque_data(Self::NAME, &mut r)?;
+
+        Ok(KeyExchange {
+            measurement_hash_type,
+            slot_id,
+            req_session_id,
+            random_data,
+            exchange_data_size,
+            exchange_data,
+            opaque_data_len,
+            opaque_data,
+        })
+    }
+}
+
+bitflags! {
+    /// The MutAuthRequested field of a KEY_EXCHANGE_RSP msg
+    #[derive(Default)]
+    pub struct MutAuthRequested: u8 {
+        const MUT_AUTH_REQUESTED = 0x1;
+        const WITH_ENCAP_REQUEST = 0x2;
+        const WITH_GET_DIGESTS = 0x4;
+    }
+}
+
+/// The response to a KEY_EXCHANGE request
+///
+/// The signature covers the transcript up to, but excluding the signature. The
+/// ResponderVerifyData is an HMAC over the transcript through the signature,
+/// using the finished key derived from the response handshake secret.
+#[derive(Debug, Clone)]
+pub struct KeyExchangeRsp {
+    // Param1
+    pub heartbeat_period: u8,
+
+    pub rsp_session_id: u16,
+    pub mut_auth_requested: MutAuthRequested,
+
+    // The slot of the requester's certificate chain to use for mutual
+    // authentication.
+    pub req_slot_id: u8,
+
+    pub random_data: [u8; 32],
+
+    // The size is determined by the negotiated DHE group
+    pub exchange_data_size: usize,
+    pub exchange_data: [u8; MAX_EXCHANGE_DATA_SIZE],
+
+    // The size of both the measurement summary hash and the verify data
+    pub digest_size: u8,
+
+    // This is only present if requested in the KEY_EXCHANGE msg
+    pub measurement_summary_hash: Option<[u8; MAX_DIGEST_SIZE]>,
+
+    pub opaque_data_len: u16,
+    pub opaque_data: [u8; MAX_OPAQUE_DATA_SIZE],
+    pub signature_size: usize,
+    pub signature: [u8; MAX_SIGNATURE_SIZE],
+    pub verify_data: [u8; MAX_DIGEST_SIZE],
+}
+
+// We can't derive PartialEq because hashes, exchange data, and signature
+// buffers may only be partially full.
+impl PartialEq for KeyExchangeRsp {
+    fn eq(&self, other: &Self) -> bool {
+        self.heartbeat_period == other.heartbeat_period
+            && self.rsp_session_id == other.rsp_session_id
+            && self.mut_auth_requested == other.mut_auth_requested
+            && self.req_slot_id == other.req_slot_id
+            && self.random_data == other.random_data
+            && self.exchange_data() == other.exchange_data()
+            && self.measurement_summary_hash()
+                == other.measurement_summary_hash()
+            && self.opaque_data() == other.opaque_data()
+            && self.signature() == other.signature()
+            && self.verify_data() == other.verify_data()
+    }
+}
+
+impl Eq for KeyExchangeRsp {}
+
+impl Default for KeyExchangeRsp {
+    fn default() -> Self {
+        KeyExchangeRsp {
+            heartbeat_period: 0,
+            rsp_session_id: 0,
+            mut_auth_requested: MutAuthRequested::default(),
+            req_slot_id: 0,
+            random_data: [0u8; 32],
+            exchange_data_size: 0,
+            exchange_data: [0u8; MAX_EXCHANGE_DATA_SIZE],
+            digest_size: 0,
+            measurement_summary_hash: None,
+            opaque_data_len: 0,
+            opaque_data: [0u8; MAX_OPAQUE_DATA_SIZE],
+            signature_size: 0,
+            signature: [0u8; MAX_SIGNATURE_SIZE],
+            verify_data: [0u8; MAX_DIGEST_SIZE],
+        }
+    }
+}
+
+impl Msg for KeyExchangeRsp {
+    const NAME: &'static str = "KEY_EXCHANGE_RSP";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x64;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.heartbeat_period)?;
+        w.put_reserved(1)?;
+        w.put_u16(self.rsp_session_id)?;
+        w.put(self.mut_auth_requested.bits())?;
+        w.put(self.req_slot_id)?;
+        w.extend(&self.random_data)?;
+        w.extend(self.exchange_data())?;
+        if let Some(hash) = self.measurement_summary_hash() {
+            w.extend(hash)?;
+        }
+        w.put_u16(self.opaque_data_len)?;
+        w.extend(self.opaque_data())?;
+        w.extend(self.signature())?;
+        w.extend(self.verify_data())
+    }
+}
+
+impl KeyExchangeRsp {
+    /// Return the public key of the responder in the SPDM exchange data
+    /// format.
+    pub fn exchange_data(&self) -> &[u8] {
+        &self.exchange_data[..self.exchange_data_size]
+    }
+
+    /// Return the measurement summary hash, or `None` if one was not
+    /// requested.
+    pub fn measurement_summary_hash(&self) -> Option<&[u8]> {
+        self.measurement_summary_hash
+            .as_ref()
+            .map(|hash| &hash[..self.digest_size as usize])
+    }
+
+    /// Return any application level opaque data provided as part of the
+    /// response.
+    pub fn opaque_data(&self) -> &[u8] {
+        &self.opaque_data[..self.opaque_data_len as usize]
+    }
+
+    /// Return the signature over the transcript
+    pub fn signature(&self) -> &[u8] {
+        &self.signature[..self.signature_size]
+    }
+
+    /// Return the ResponderVerifyData HMAC
+    pub fn verify_data(&self) -> &[u8] {
+        &self.verify_data[..self.digest_size as usize]
+    }
+
+    /// Deserialize the body of a KEY_EXCHANGE_RSP message.
+    ///
+    /// All sizes correspond to the algorithms negotiated in previous steps of
+    /// the protocol. `measurement_summary_hash_requested` must be true if the
+    /// KEY_EXCHANGE request asked for a measurement summary hash.
+    pub fn parse_body(
+        buf: &[u8],
+        exchange_data_size: usize,
+        digest_size: u8,
+        measurement_summary_hash_requested: bool,
+        signature_size: usize,
+    ) -> Result<KeyExchangeRsp, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let heartbeat_period = r.get_byte()?;
+        r.skip_reserved(1)?;
+        let rsp_session_id = r.get_u16()?;
+        let mut_auth_requested = MutAuthRequested::from_bits(r.get_byte()?)
+            .ok_or_else(|| {
+                ReadError::new(Self::NAME, ReadErrorKind::InvalidBitsSet)
+            })?;
+        let req_slot_id = r.get_byte()?;
+        let mut random_data = [0u8; 32];
+        random_data.copy_from_slice(r.get_slice(32)?);
+        let exchange_data =
+            read_exchange_data(Self::NAME, &mut r, exchange_data_size)?;
+
+        let measurement_summary_hash = if measurement_summary_hash_requested {
+            let mut hash = [0u8; MAX_DIGEST_SIZE];
+            hash[..digest_size as usize]
+                .copy_from_slice(r.get_slice(digest_size as usize)?);
+            Some(hash)
+        } else {
+            None
+        };
+
+        let (opaque_data_len, opaque_data) =
+            read_opaque_data(Self::NAME, &mut r)?;
+
+        let mut signature = [0u8; MAX_SIGNATURE_SIZE];
+        signature[..signature_size]
+            .copy_from_slice(r.get_slice(signature_size)?);
+
+        let mut verify_data = [0u8; MAX_DIGEST_SIZE];
+        verify_data[..digest_size as usize]
+            .copy_from_slice(r.get_slice(digest_size as usize)?);
+
+        Ok(KeyExchangeRsp {
+            heartbeat_period,
+            rsp_session_id,
+            mut_auth_requested,
+            req_slot_id,
+            random_data,
+            exchange_data_size,
+            exchange_data,
+            digest_size,
+            measurement_summary_hash,
+            opaque_data_len,
+            opaque_data,
+            signature_size,
+            signature,
+            verify_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::HEADER_SIZE;
+    use super::*;
+
+    #[test]
+    fn key_exchange_roundtrip() {
+        let mut buf = [0u8; 256];
+        let mut msg = KeyExchange {
+            measurement_hash_type: MeasurementHashType::Tcb,
+            slot_id: 0,
+            req_session_id: 0xABCD,
+            random_data: [0x13; 32],
+            exchange_data_size: 64,
+            ..KeyExchange::default()
+        };
+        msg.exchange_data[..64].copy_from_slice(&[7u8; 64]);
+
+        assert_eq!(106, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), KeyExchange::parse_header(&buf));
+        assert_eq!(
+            msg,
+            KeyExchange::parse_body(&buf[HEADER_SIZE..], 64).unwrap()
+        );
+    }
+
+    #[test]
+    fn key_exchange_rsp_roundtrip() {
+        let mut buf = [0u8; 512];
+        let mut msg = KeyExchangeRsp {
+            heartbeat_period: 5,
+            rsp_session_id: 0x1234,
+            random_data: [0x42; 32],
+            exchange_data_size: 96,
+            digest_size: 32,
+            signature_size: 64,
+            ..KeyExchangeRsp::default()
+        };
+        msg.exchange_data.copy_from_slice(&[9u8; 96]);
+        msg.signature[..64].copy_from_slice(&[1u8; 64]);
+        msg.verify_data[..32].copy_from_slice(&[2u8; 32]);
+
+        // No measurement summary hash was requested
+        assert_eq!(234, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), KeyExchangeRsp::parse_header(&buf));
+        assert_eq!(
+            msg,
+            KeyExchangeRsp::parse_body(&buf[HEADER_SIZE..], 96, 32, false, 64)
+                .unwrap()
+        );
+
+        // A measurement summary hash was requested
+        msg.measurement_summary_hash = Some([3u8; MAX_DIGEST_SIZE]);
+        assert_eq!(266, msg.write(&mut buf).unwrap());
+        assert_eq!(
+            msg,
+            KeyExchangeRsp::parse_body(&buf[HEADER_SIZE..], 96, 32, true, 64)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn key_exchange_rsp_invalid_mut_auth_bits() {
+        let mut buf = [0u8; 512];
+        let msg = KeyExchangeRsp {
+            exchange_data_size: 64,
+            digest_size: 32,
+            signature_size: 64,
+            ..KeyExchangeRsp::default()
+        };
+        let size = msg.write(&mut buf).unwrap();
+        buf[HEADER_SIZE + 4] = 0x80;
+        assert!(KeyExchangeRsp::parse_body(
+            &buf[HEADER_SIZE..size],
+            64,
+            32,
+            false,
+            64
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::convert::{TryFrom, TryInto};
+
+use super::encoding::{ReadError, ReadErrorKind, Reader, WriteError, Writer};
+use super::Msg;
+
+/// The key operation of a KEY_UPDATE request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyUpdateOperation {
+    // Update the keys of the request direction only
+    UpdateKey = 0x1,
+
+    // Update the keys of both directions
+    UpdateAllKeys = 0x2,
+
+    // Confirm that the new keys are in use
+    VerifyNewKey = 0x3,
+}
+
+impl TryFrom<u8> for KeyUpdateOperation {
+    type Error = ReadError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0x1 => Ok(KeyUpdateOperation::UpdateKey),
+            0x2 => Ok(KeyUpdateOperation::UpdateAllKeys),
+            0x3 => Ok(KeyUpdateOperation::VerifyNewKey),
+            _ => Err(ReadError::new(
+                "KEY_UPDATE",
+                ReadErrorKind::UnexpectedValue,
+            )),
+        }
+    }
+}
+
+/// A request to update the keys of an established session
+///
+/// KEY_UPDATE is only ever sent within the session whose keys are updated.
+/// The tag is chosen at random by the requester, and echoed in the
+/// KEY_UPDATE_ACK, so that a retried request can be told apart from a new one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyUpdate {
+    pub operation: KeyUpdateOperation,
+    pub tag: u8,
+}
+
+impl Msg for KeyUpdate {
+    const NAME: &'static str = "KEY_UPDATE";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xE9;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.operation as u8)?;
+        w.put(self.tag)
+    }
+}
+
+impl KeyUpdate {
+    pub fn parse_body(buf: &[u8]) -> Result<KeyUpdate, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let operation = r.get_byte()?.try_into()?;
+        let tag = r.get_byte()?;
+        Ok(KeyUpdate { operation, tag })
+    }
+}
+
+/// The response to a KEY_UPDATE request
+///
+/// The operation and tag are those of the request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyUpdateAck {
+    pub operation: KeyUpdateOperation,
+    pub tag: u8,
+}
+
+impl Msg for KeyUpdateAck {
+    const NAME: &'static str = "KEY_UPDATE_ACK";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x69;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.operation as u8)?;
+        w.put(self.tag)
+    }
+}
+
+impl KeyUpdateAck {
+    pub fn parse_body(buf: &[u8]) -> Result<KeyUpdateAck, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let operation = r.get_byte()?.try_into()?;
+        let tag = r.get_byte()?;
+        Ok(KeyUpdateAck { operation, tag })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::HEADER_SIZE;
+    use super::*;
+
+    #[test]
+    fn key_update_roundtrip() {
+        let mut buf = [0u8; 16];
+        let msg = KeyUpdate {
+            operation: KeyUpdateOperation::UpdateAllKeys,
+            tag: 0x7A,
+        };
+        assert_eq!(4, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), KeyUpdate::parse_header(&buf));
+        assert_eq!(msg, KeyUpdate::parse_body(&buf[HEADER_SIZE..4]).unwrap());
+
+        let ack = KeyUpdateAck { operation: msg.operation, tag: msg.tag };
+        assert_eq!(4, ack.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), KeyUpdateAck::parse_header(&buf));
+        assert_eq!(
+            ack,
+            KeyUpdateAck::parse_body(&buf[HEADER_SIZE..4]).unwrap()
+        );
+    }
+
+    #[test]
+    fn key_update_invalid_operation() {
+        let buf = [0x4, 0x7A];
+        assert!(KeyUpdate::parse_body(&buf).is_err());
+    }
+}
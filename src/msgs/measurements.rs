@@ -2,16 +2,18 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::challenge;
+use super::challenge::{self, PROVISIONED_KEY_SLOT};
 use super::encoding::{
     ReadError, ReadErrorKind, Reader, WriteError, WriteErrorKind, Writer,
 };
 use super::Msg;
-use crate::config;
+use crate::config::{
+    self, MAX_MEASUREMENT_RECORD_SIZE, MAX_OPAQUE_DATA_SIZE, MAX_SIGNATURE_SIZE,
+};
 
 use core::convert::{From, TryFrom, TryInto};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RequestAttributes {
     // If this field is set, the nonce field must be present.
     pub signature_requested: bool,
@@ -31,7 +33,7 @@ impl From<&RequestAttributes> for u8 {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MeasurementIndex {
     TotalNumberOfMeasurementsAvailable,
     AllMeasurements,
@@ -98,20 +100,20 @@ impl TryFrom<&MeasurementIndex> for u8 {
 }
 
 // Request measurements from a responder.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetMeasurements {
     // Param1
-    attributes: RequestAttributes,
+    pub attributes: RequestAttributes,
 
     // Param2
-    index: MeasurementIndex,
+    pub index: MeasurementIndex,
 
     // Only valid if signature_requested attribute is set
-    nonce: Option<[u8; 32]>,
+    pub nonce: Option<[u8; 32]>,
 
     // Slot number of the responder certificate chain used for measurement auth
     // Only present on the wire if signature_requested attribute is set.
-    slot_id: u8,
+    pub slot_id: u8,
 }
 
 impl GetMeasurements {
@@ -120,7 +122,7 @@ impl GetMeasurements {
         index: MeasurementIndex,
         slot_id: u8,
     ) -> Result<GetMeasurements, WriteError> {
-        if (slot_id as usize) >= config::NUM_SLOTS {
+        if !is_valid_slot_id(slot_id) {
             return Err(WriteError::new(
                 Self::NAME,
                 WriteErrorKind::InvalidRange("slot_id"),
@@ -173,7 +175,7 @@ impl GetMeasurements {
             nonce = Some([0u8; 32]);
             nonce.as_mut().unwrap().copy_from_slice(r.get_slice(32)?);
             slot_id = r.get_byte()?;
-            if (slot_id as usize) >= config::NUM_SLOTS {
+            if !is_valid_slot_id(slot_id) {
                 return Err(ReadError::new(
                     Self::NAME,
                     ReadErrorKind::UnexpectedValue,
@@ -185,6 +187,369 @@ impl GetMeasurements {
     }
 }
 
+// A certificate slot, or the slot of a provisioned public key
+fn is_valid_slot_id(slot_id: u8) -> bool {
+    (slot_id as usize) < config::NUM_SLOTS || slot_id == PROVISIONED_KEY_SLOT
+}
+
+/// The type of a measurement in the DMTF measurement specification format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DmtfMeasurementValueType {
+    ImmutableRom = 0x0,
+    MutableFirmware = 0x1,
+    HardwareConfig = 0x2,
+    FirmwareConfig = 0x3,
+    MeasurementManifest = 0x4,
+
+    // Debug and device mode of the responder
+    DeviceMode = 0x5,
+    MutableFirmwareVersion = 0x6,
+
+    // Security version number of the mutable firmware
+    MutableFirmwareSvn = 0x7,
+}
+
+impl TryFrom<u8> for DmtfMeasurementValueType {
+    type Error = ReadError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0x0 => Ok(DmtfMeasurementValueType::ImmutableRom),
+            0x1 => Ok(DmtfMeasurementValueType::MutableFirmware),
+            0x2 => Ok(DmtfMeasurementValueType::HardwareConfig),
+            0x3 => Ok(DmtfMeasurementValueType::FirmwareConfig),
+            0x4 => Ok(DmtfMeasurementValueType::MeasurementManifest),
+            0x5 => Ok(DmtfMeasurementValueType::DeviceMode),
+            0x6 => Ok(DmtfMeasurementValueType::MutableFirmwareVersion),
+            0x7 => Ok(DmtfMeasurementValueType::MutableFirmwareSvn),
+            _ => Err(ReadError::new(
+                "MEASUREMENT_BLOCK",
+                ReadErrorKind::UnexpectedValue,
+            )),
+        }
+    }
+}
+
+/// How a measurement value is represented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmtfMeasurementRepresentation {
+    // A digest using the negotiated measurement hash algorithm
+    Digest,
+
+    // The measured data itself
+    RawBitStream,
+}
+
+/// A measurement in the DMTF measurement specification format
+/// (`DMTFSpecMeasurementValue`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmtfMeasurement<'a> {
+    pub value_type: DmtfMeasurementValueType,
+    pub representation: DmtfMeasurementRepresentation,
+    pub value: &'a [u8],
+}
+
+impl<'a> DmtfMeasurement<'a> {
+    /// The serialized size of the measurement
+    pub fn size(&self) -> usize {
+        3 + self.value.len()
+    }
+
+    /// Serialize a DMTF measurement via a Writer
+    pub fn write(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        if self.value.len() > 65535 - 3 {
+            return Err(WriteError::new(
+                "MEASUREMENT_BLOCK",
+                WriteErrorKind::InvalidRange("DmtfMeasurement (size)"),
+            ));
+        }
+        let mut value_type = self.value_type as u8;
+        if self.representation == DmtfMeasurementRepresentation::RawBitStream {
+            value_type |= 0x80;
+        }
+        w.put(value_type)?;
+        w.put_u16(self.value.len() as u16)?;
+        w.extend(self.value)
+    }
+
+    /// Deserialize a DMTF measurement that exactly fills `buf`.
+    pub fn parse(buf: &'a [u8]) -> Result<DmtfMeasurement<'a>, ReadError> {
+        let mut r = Reader::new("MEASUREMENT_BLOCK", buf);
+        let value_type = r.get_bits(7)?.try_into()?;
+        let representation = if r.get_bit()? == 1 {
+            DmtfMeasurementRepresentation::RawBitStream
+        } else {
+            DmtfMeasurementRepresentation::Digest
+        };
+        let size = r.get_u16()? as usize;
+        if size != r.remaining() {
+            return Err(ReadError::new(
+                "MEASUREMENT_BLOCK",
+                ReadErrorKind::UnexpectedValue,
+            ));
+        }
+        let offset = r.byte_offset();
+        Ok(DmtfMeasurement {
+            value_type,
+            representation,
+            value: &buf[offset..],
+        })
+    }
+}
+
+/// A single measurement block as contained in the measurement record of a
+/// MEASUREMENTS response.
+///
+/// Only the DMTF measurement specification is supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeasurementBlock<'a> {
+    // The index of the measurement (0x01 - 0xFE)
+    pub index: u8,
+    pub measurement: DmtfMeasurement<'a>,
+}
+
+impl<'a> MeasurementBlock<'a> {
+    /// Bit 0 of the measurement specification indicates the DMTF format
+    pub const DMTF_SPECIFICATION: u8 = 0x1;
+
+    /// The serialized size of the block
+    pub fn size(&self) -> usize {
+        4 + self.measurement.size()
+    }
+
+    /// Serialize a measurement block via a Writer
+    pub fn write(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.index)?;
+        w.put(Self::DMTF_SPECIFICATION)?;
+        w.put_u16(self.measurement.size() as u16)?;
+        self.measurement.write(w)
+    }
+
+    /// Deserialize the measurement block at the start of `buf`.
+    ///
+    /// Use `size` to find the start of the next block in a record.
+    pub fn parse(buf: &'a [u8]) -> Result<MeasurementBlock<'a>, ReadError> {
+        let mut r = Reader::new("MEASUREMENT_BLOCK", buf);
+        let index = r.get_byte()?;
+        if r.get_byte()? != Self::DMTF_SPECIFICATION {
+            return Err(ReadError::new(
+                "MEASUREMENT_BLOCK",
+                ReadErrorKind::UnexpectedValue,
+            ));
+        }
+        let size = r.get_u16()? as usize;
+        let offset = r.byte_offset();
+        let _ = r.get_slice(size)?;
+        let measurement = DmtfMeasurement::parse(&buf[offset..offset + size])?;
+        Ok(MeasurementBlock { index, measurement })
+    }
+}
+
+/// An iterator over the measurement blocks in a measurement record
+pub struct MeasurementBlocks<'a> {
+    record: &'a [u8],
+    remaining: u8,
+}
+
+impl<'a> MeasurementBlocks<'a> {
+    /// Iterate over `num_blocks` serialized blocks in `record`
+    pub fn new(record: &'a [u8], num_blocks: u8) -> MeasurementBlocks<'a> {
+        MeasurementBlocks { record, remaining: num_blocks }
+    }
+}
+
+impl<'a> Iterator for MeasurementBlocks<'a> {
+    type Item = Result<MeasurementBlock<'a>, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match MeasurementBlock::parse(self.record) {
+            Ok(block) => {
+                self.record = &self.record[block.size()..];
+                self.remaining -= 1;
+                Some(Ok(block))
+            }
+            Err(e) => {
+                // Don't return the same error forever
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// The response to a GET_MEASUREMENTS request
+///
+/// The measurement record is kept in its serialized form, as the number and
+/// size of the measurement blocks in it are only known at runtime.
+#[derive(Debug, Clone)]
+pub struct Measurements {
+    // Param1
+    // This is only non-zero when the total number of measurement indices was
+    // requested via `MeasurementIndex::TotalNumberOfMeasurementsAvailable`.
+    pub total_indices: u8,
+
+    // Param2
+    // The slot of the certificate chain used to sign the response.
+    pub slot_id: u8,
+
+    pub num_blocks: u8,
+    pub record_len: u32,
+    pub record: [u8; MAX_MEASUREMENT_RECORD_SIZE],
+    pub nonce: [u8; 32],
+    pub opaque_data_len: u16,
+    pub opaque_data: [u8; MAX_OPAQUE_DATA_SIZE],
+
+    // This is 0 if no signature was requested
+    pub signature_size: usize,
+    pub signature: [u8; MAX_SIGNATURE_SIZE],
+}
+
+// We can't derive PartialEq because the record, opaque data and signature
+// buffers may only be partially full.
+impl PartialEq for Measurements {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_indices == other.total_indices
+            && self.slot_id == other.slot_id
+            && self.num_blocks == other.num_blocks
+            && self.record() == other.record()
+            && self.nonce == other.nonce
+            && self.opaque_data() == other.opaque_data()
+            && self.signature() == other.signature()
+    }
+}
+
+impl Eq for Measurements {}
+
+impl Default for Measurements {
+    fn default() -> Self {
+        Measurements {
+            total_indices: 0,
+            slot_id: 0,
+            num_blocks: 0,
+            record_len: 0,
+            record: [0u8; MAX_MEASUREMENT_RECORD_SIZE],
+            nonce: [0u8; 32],
+            opaque_data_len: 0,
+            opaque_data: [0u8; MAX_OPAQUE_DATA_SIZE],
+            signature_size: 0,
+            signature: [0u8; MAX_SIGNATURE_SIZE],
+        }
+    }
+}
+
+impl Msg for Measurements {
+    const NAME: &'static str = "MEASUREMENTS";
+    const SPDM_VERSION: u8 = 0x12;
+    const SPDM_CODE: u8 = 0x60;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        if self.slot_id > 0xF {
+            return Err(WriteError::new(
+                Self::NAME,
+                WriteErrorKind::InvalidRange("slot_id"),
+            ));
+        }
+        w.put(self.total_indices)?;
+        w.put(self.slot_id)?;
+        w.put(self.num_blocks)?;
+        w.put_u24(self.record_len)?;
+        w.extend(self.record())?;
+        w.extend(&self.nonce)?;
+        w.put_u16(self.opaque_data_len)?;
+        w.extend(self.opaque_data())?;
+        w.extend(self.signature())
+    }
+}
+
+impl Measurements {
+    /// Return the serialized measurement blocks
+    ///
+    /// Due to the variable size of the record, we don't allow direct access to
+    /// the underlying array, which may contain junk bytes.
+    pub fn record(&self) -> &[u8] {
+        &self.record[..self.record_len as usize]
+    }
+
+    /// Return an iterator over the deserialized measurement blocks in the
+    /// record.
+    pub fn blocks(&self) -> MeasurementBlocks<'_> {
+        MeasurementBlocks::new(self.record(), self.num_blocks)
+    }
+
+    /// Return any application level opaque data provided as part of the
+    /// response.
+    pub fn opaque_data(&self) -> &[u8] {
+        &self.opaque_data[..self.opaque_data_len as usize]
+    }
+
+    /// Return the signature over the L1/L2 transcript, or an empty slice if no
+    /// signature was requested.
+    pub fn signature(&self) -> &[u8] {
+        &self.signature[..self.signature_size]
+    }
+
+    /// Deserialize the body of a MEASUREMENTS message.
+    ///
+    /// `signature_size` corresponds to the negotiated asymmetric signing
+    /// algorithm, and must be 0 if the GET_MEASUREMENTS request did not ask for
+    /// a signature.
+    pub fn parse_body(
+        buf: &[u8],
+        signature_size: usize,
+    ) -> Result<Measurements, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let total_indices = r.get_byte()?;
+        let slot_id = r.get_bits(4)?;
+        let _ = r.get_bits(4)?;
+        let num_blocks = r.get_byte()?;
+        let record_len = r.get_u24()?;
+        if record_len as usize > MAX_MEASUREMENT_RECORD_SIZE {
+            return Err(ReadError::new(
+                Self::NAME,
+                ReadErrorKind::ImplementationLimitReached,
+            ));
+        }
+        let mut record = [0u8; MAX_MEASUREMENT_RECORD_SIZE];
+        record[..record_len as usize]
+            .copy_from_slice(r.get_slice(record_len as usize)?);
+
+        let mut nonce = [0u8; 32];
+        nonce.copy_from_slice(r.get_slice(32)?);
+
+        let opaque_data_len = r.get_u16()?;
+        if opaque_data_len as usize > MAX_OPAQUE_DATA_SIZE {
+            return Err(ReadError::new(
+                Self::NAME,
+                ReadErrorKind::ImplementationLimitReached,
+            ));
+        }
+        let mut opaque_data = [0u8; MAX_OPAQUE_DATA_SIZE];
+        opaque_data[..opaque_data_len as usize]
+            .copy_from_slice(r.get_slice(opaque_data_len as usize)?);
+
+        let mut signature = [0u8; MAX_SIGNATURE_SIZE];
+        signature[..signature_size]
+            .copy_from_slice(r.get_slice(signature_size)?);
+
+        Ok(Measurements {
+            total_indices,
+            slot_id,
+            num_blocks,
+            record_len,
+            record,
+            nonce,
+            opaque_data_len,
+            opaque_data,
+            signature_size,
+            signature,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -300,4 +665,140 @@ mod tests {
         buf[36] = config::NUM_SLOTS as u8;
         assert!(GetMeasurements::parse_body(&buf[HEADER_SIZE..]).is_err());
     }
+
+    #[test]
+    fn measurements_roundtrip() {
+        let mut buf = [0u8; 1024];
+        let mut msg = Measurements {
+            num_blocks: 2,
+            record_len: 20,
+            nonce: [0x13; 32],
+            ..Measurements::default()
+        };
+        msg.record[..20].copy_from_slice(&[7u8; 20]);
+
+        // No signature was requested
+        assert_eq!(62, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), Measurements::parse_header(&buf));
+        assert_eq!(
+            msg,
+            Measurements::parse_body(&buf[HEADER_SIZE..], 0).unwrap()
+        );
+
+        // A signature was requested
+        msg.slot_id = 0;
+        msg.signature_size = 64;
+        msg.signature[..64].copy_from_slice(&[1u8; 64]);
+        assert_eq!(126, msg.write(&mut buf).unwrap());
+        assert_eq!(
+            msg,
+            Measurements::parse_body(&buf[HEADER_SIZE..], 64).unwrap()
+        );
+    }
+
+    #[test]
+    fn measurements_total_indices_roundtrip() {
+        let mut buf = [0u8; 64];
+        let msg = Measurements {
+            total_indices: 5,
+            nonce: [0x42; 32],
+            ..Measurements::default()
+        };
+
+        assert_eq!(42, msg.write(&mut buf).unwrap());
+        assert_eq!(
+            msg,
+            Measurements::parse_body(&buf[HEADER_SIZE..], 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn measurements_read_err() {
+        let mut buf = [0u8; 64];
+        let msg = Measurements { nonce: [0x42; 32], ..Measurements::default() };
+        assert_eq!(42, msg.write(&mut buf).unwrap());
+
+        // Claim a record larger than we can handle
+        let len = (MAX_MEASUREMENT_RECORD_SIZE as u32 + 1).to_le_bytes();
+        buf[5..8].copy_from_slice(&len[..3]);
+        assert!(Measurements::parse_body(&buf[HEADER_SIZE..], 0).is_err());
+    }
+
+    #[test]
+    fn measurement_block_roundtrip() {
+        let mut buf = [0u8; 64];
+        let block = MeasurementBlock {
+            index: 3,
+            measurement: DmtfMeasurement {
+                value_type: DmtfMeasurementValueType::MutableFirmware,
+                representation: DmtfMeasurementRepresentation::Digest,
+                value: &[0xAB; 20],
+            },
+        };
+        let mut w = Writer::new("MEASUREMENT_BLOCK", &mut buf);
+        assert_eq!(27, block.write(&mut w).unwrap());
+        assert_eq!(27, block.size());
+        assert_eq!(&[3, 1, 23, 0, 0x01, 20, 0], &buf[..7]);
+        assert_eq!(block, MeasurementBlock::parse(&buf).unwrap());
+
+        // A truncated block fails to parse
+        assert!(MeasurementBlock::parse(&buf[..26]).is_err());
+    }
+
+    #[test]
+    fn dmtf_measurement_raw_bit_stream_roundtrip() {
+        let mut buf = [0u8; 16];
+        let measurement = DmtfMeasurement {
+            value_type: DmtfMeasurementValueType::MutableFirmwareSvn,
+            representation: DmtfMeasurementRepresentation::RawBitStream,
+            value: &[0x2, 0x0, 0x0, 0x0],
+        };
+        let mut w = Writer::new("MEASUREMENT_BLOCK", &mut buf);
+        assert_eq!(7, measurement.write(&mut w).unwrap());
+        assert_eq!(0x87, buf[0]);
+        assert_eq!(measurement, DmtfMeasurement::parse(&buf[..7]).unwrap());
+
+        // Unknown value types are rejected
+        buf[0] = 0x88;
+        assert!(DmtfMeasurement::parse(&buf[..7]).is_err());
+
+        // The size must match the buffer
+        assert!(DmtfMeasurement::parse(&buf[..8]).is_err());
+    }
+
+    #[test]
+    fn measurement_blocks_iter() {
+        let value_types = [
+            DmtfMeasurementValueType::ImmutableRom,
+            DmtfMeasurementValueType::HardwareConfig,
+            DmtfMeasurementValueType::DeviceMode,
+        ];
+        let mut msg = Measurements::default();
+        let mut w = Writer::new("MEASUREMENTS", &mut msg.record);
+        for (i, value_type) in value_types.iter().enumerate() {
+            let block = MeasurementBlock {
+                index: i as u8 + 1,
+                measurement: DmtfMeasurement {
+                    value_type: *value_type,
+                    representation: DmtfMeasurementRepresentation::Digest,
+                    value: &[i as u8; 32],
+                },
+            };
+            block.write(&mut w).unwrap();
+        }
+        msg.record_len = w.offset() as u32;
+        msg.num_blocks = 3;
+
+        for (i, block) in msg.blocks().enumerate() {
+            let block = block.unwrap();
+            assert_eq!(i as u8 + 1, block.index);
+            assert_eq!(value_types[i], block.measurement.value_type);
+            assert_eq!(&[i as u8; 32], block.measurement.value);
+        }
+        assert_eq!(3, msg.blocks().count());
+
+        // Claiming more blocks than are present results in an error
+        msg.num_blocks = 4;
+        assert!(msg.blocks().last().unwrap().is_err());
+    }
 }
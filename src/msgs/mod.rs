@@ -18,9 +18,16 @@ pub mod capabilities;
 pub mod certificates;
 pub mod challenge;
 pub mod digest;
+pub mod encapsulated;
 pub mod encoding;
+pub mod end_session;
 mod error;
-mod measurements;
+pub mod finish;
+pub mod heartbeat;
+pub mod key_exchange;
+pub mod key_update;
+pub mod measurements;
+pub mod psk_exchange;
 pub mod version;
 
 pub use algorithms::{Algorithms, NegotiateAlgorithms};
@@ -28,10 +35,22 @@ pub use capabilities::{Capabilities, GetCapabilities};
 pub use certificates::{Certificate, CertificateChain, GetCertificate};
 pub use challenge::{Challenge, ChallengeAuth, MeasurementHashType};
 pub use digest::{Digests, GetDigests};
+pub use encapsulated::{
+    DeliverEncapsulatedResponse, EncapsulatedRequest, EncapsulatedResponseAck,
+    GetEncapsulatedRequest,
+};
 use encoding::Writer;
 pub use encoding::{ReadError, ReadErrorKind, WriteError, WriteErrorKind};
+pub use end_session::{EndSession, EndSessionAck};
 pub use error::Error;
-pub use measurements::GetMeasurements;
+pub use finish::{Finish, FinishRsp};
+pub use heartbeat::{Heartbeat, HeartbeatAck};
+pub use key_exchange::{KeyExchange, KeyExchangeRsp};
+pub use key_update::{KeyUpdate, KeyUpdateAck, KeyUpdateOperation};
+pub use measurements::{
+    DmtfMeasurement, GetMeasurements, MeasurementBlock, Measurements,
+};
+pub use psk_exchange::{PskExchange, PskExchangeRsp, PskFinish, PskFinishRsp};
 pub use version::{GetVersion, Version, VersionEntry};
 
 pub const HEADER_SIZE: usize = 2;
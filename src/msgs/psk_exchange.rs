@@ -0,0 +1,464 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::cmp::PartialEq;
+use core::convert::TryInto;
+
+use super::encoding::{ReadError, ReadErrorKind, Reader, WriteError, Writer};
+use super::{MeasurementHashType, Msg};
+use crate::config::{MAX_DIGEST_SIZE, MAX_OPAQUE_DATA_SIZE};
+
+/// The largest PSK hint supported
+pub const MAX_PSK_HINT_SIZE: usize = 16;
+
+/// The largest requester or responder context supported
+///
+/// Contexts are random data that should be at least as large as the digest of
+/// the negotiated hash algorithm.
+pub const MAX_PSK_CONTEXT_SIZE: usize = MAX_DIGEST_SIZE;
+
+// Read variable size data of a previously read length into a fixed size buffer
+fn read_data<const N: usize>(
+    name: &'static str,
+    r: &mut Reader,
+    len: u16,
+) -> Result<[u8; N], ReadError> {
+    if len as usize > N {
+        return Err(ReadError::new(
+            name,
+            ReadErrorKind::ImplementationLimitReached,
+        ));
+    }
+    let mut data = [0u8; N];
+    data[..len as usize].copy_from_slice(r.get_slice(len as usize)?);
+    Ok(data)
+}
+
+/// The request to begin a secure session using a pre-shared key
+#[derive(Debug, Clone)]
+pub struct PskExchange {
+    // Param1
+    pub measurement_hash_type: MeasurementHashType,
+
+    pub req_session_id: u16,
+
+    // Identifies the PSK to the responder. It may be empty if the responder
+    // only has one.
+    pub psk_hint_len: u16,
+    pub psk_hint: [u8; MAX_PSK_HINT_SIZE],
+
+    pub requester_context_len: u16,
+    pub requester_context: [u8; MAX_PSK_CONTEXT_SIZE],
+
+    pub opaque_data_len: u16,
+    pub opaque_data: [u8; MAX_OPAQUE_DATA_SIZE],
+}
+
+// We can't derive PartialEq because the buffers may only be partially full.
+impl PartialEq for PskExchange {
+    fn eq(&self, other: &Self) -> bool {
+        self.measurement_hash_type == other.measurement_hash_type
+            && self.req_session_id == other.req_session_id
+            && self.psk_hint() == other.psk_hint()
+            && self.requester_context() == other.requester_context()
+            && self.opaque_data() == other.opaque_data()
+    }
+}
+
+impl Eq for PskExchange {}
+
+impl Default for PskExchange {
+    fn default() -> Self {
+        PskExchange {
+            measurement_hash_type: MeasurementHashType::None,
+            req_session_id: 0,
+            psk_hint_len: 0,
+            psk_hint: [0u8; MAX_PSK_HINT_SIZE],
+            requester_context_len: 0,
+            requester_context: [0u8; MAX_PSK_CONTEXT_SIZE],
+            opaque_data_len: 0,
+            opaque_data: [0u8; MAX_OPAQUE_DATA_SIZE],
+        }
+    }
+}
+
+impl Msg for PskExchange {
+    const NAME: &'static str = "PSK_EXCHANGE";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xE6;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.measurement_hash_type as u8)?;
+        w.put_reserved(1)?;
+        w.put_u16(self.req_session_id)?;
+        w.put_u16(self.psk_hint_len)?;
+        w.put_u16(self.requester_context_len)?;
+        w.put_u16(self.opaque_data_len)?;
+        w.extend(self.psk_hint())?;
+        w.extend(self.requester_context())?;
+        w.extend(self.opaque_data())
+    }
+}
+
+impl PskExchange {
+    /// Return the hint identifying the PSK
+    pub fn psk_hint(&self) -> &[u8] {
+        &self.psk_hint[..self.psk_hint_len as usize]
+    }
+
+    /// Return the random data provided by the requester
+    pub fn requester_context(&self) -> &[u8] {
+        &self.requester_context[..self.requester_context_len as usize]
+    }
+
+    /// Return any application level opaque data provided as part of the
+    /// request.
+    pub fn opaque_data(&self) -> &[u8] {
+        &self.opaque_data[..self.opaque_data_len as usize]
+    }
+
+    /// Deserialize the body of a PSK_EXCHANGE message.
+    pub fn parse_body(buf: &[u8]) -> Result<PskExchange, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let measurement_hash_type = r.get_byte()?.try_into()?;
+        r.skip_reserved(1)?;
+        let req_session_id = r.get_u16()?;
+        let psk_hint_len = r.get_u16()?;
+        let requester_context_len = r.get_u16()?;
+        let opaque_data_len = r.get_u16()?;
+        let psk_hint = read_data(Self::NAME, &mut r, psk_hint_len)?;
+        let requester_context =
+            read_data(Self::NAME, &mut r, requester_context_len)?;
+        let opaque_data = read_data(Self::NAME, &mut r, opaque_data_len)?;
+
+        Ok(PskExchange {
+            measurement_hash_type,
+            req_session_id,
+            psk_hint_len,
+            psk_hint,
+            requester_context_len,
+            requester_context,
+            opaque_data_len,
+            opaque_data,
+        })
+    }
+}
+
+/// The response to a PSK_EXCHANGE request
+///
+/// The ResponderVerifyData is an HMAC over the transcript through the opaque
+/// data, using the finished key derived from the response handshake secret.
+/// It proves that the responder knows the PSK.
+#[derive(Debug, Clone)]
+pub struct PskExchangeRsp {
+    // Param1
+    pub heartbeat_period: u8,
+
+    pub rsp_session_id: u16,
+
+    // The size of both the measurement summary hash and the verify data
+    pub digest_size: u8,
+
+    // This is only present if requested in the PSK_EXCHANGE msg
+    pub measurement_summary_hash: Option<[u8; MAX_DIGEST_SIZE]>,
+
+    // This is only present if the responder sets PSK_CAP with context, in
+    // which case the requester must send PSK_FINISH.
+    pub responder_context_len: u16,
+    pub responder_context: [u8; MAX_PSK_CONTEXT_SIZE],
+
+    pub opaque_data_len: u16,
+    pub opaque_data: [u8; MAX_OPAQUE_DATA_SIZE],
+    pub verify_data: [u8; MAX_DIGEST_SIZE],
+}
+
+// We can't derive PartialEq because the buffers may only be partially full.
+impl PartialEq for PskExchangeRsp {
+    fn eq(&self, other: &Self) -> bool {
+        self.heartbeat_period == other.heartbeat_period
+            && self.rsp_session_id == other.rsp_session_id
+            && self.measurement_summary_hash()
+                == other.measurement_summary_hash()
+            && self.responder_context() == other.responder_context()
+            && self.opaque_data() == other.opaque_data()
+            && self.verify_data() == other.verify_data()
+    }
+}
+
+impl Eq for PskExchangeRsp {}
+
+impl Default for PskExchangeRsp {
+    fn default() -> Self {
+        PskExchangeRsp {
+            heartbeat_period: 0,
+            rsp_session_id: 0,
+            digest_size: 0,
+            measurement_summary_hash: None,
+            responder_context_len: 0,
+            responder_context: [0u8; MAX_PSK_CONTEXT_SIZE],
+            opaque_data_len: 0,
+            opaque_data: [0u8; MAX_OPAQUE_DATA_SIZE],
+            verify_data: [0u8; MAX_DIGEST_SIZE],
+        }
+    }
+}
+
+impl Msg for PskExchangeRsp {
+    const NAME: &'static str = "PSK_EXCHANGE_RSP";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x66;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put(self.heartbeat_period)?;
+        w.put_reserved(1)?;
+        w.put_u16(self.rsp_session_id)?;
+        w.put_reserved(2)?;
+        w.put_u16(self.responder_context_len)?;
+        w.put_u16(self.opaque_data_len)?;
+        if let Some(hash) = self.measurement_summary_hash() {
+            w.extend(hash)?;
+        }
+        w.extend(self.responder_context())?;
+        w.extend(self.opaque_data())?;
+        w.extend(self.verify_data())
+    }
+}
+
+impl PskExchangeRsp {
+    /// Return the measurement summary hash, or `None` if one was not
+    /// requested.
+    pub fn measurement_summary_hash(&self) -> Option<&[u8]> {
+        self.measurement_summary_hash
+            .as_ref()
+            .map(|hash| &hash[..self.digest_size as usize])
+    }
+
+    /// Return the random data provided by the responder
+    pub fn responder_context(&self) -> &[u8] {
+        &self.responder_context[..self.responder_context_len as usize]
+    }
+
+    /// Return any application level opaque data provided as part of the
+    /// response.
+    pub fn opaque_data(&self) -> &[u8] {
+        &self.opaque_data[..self.opaque_data_len as usize]
+    }
+
+    /// Return the ResponderVerifyData HMAC
+    pub fn verify_data(&self) -> &[u8] {
+        &self.verify_data[..self.digest_size as usize]
+    }
+
+    /// Deserialize the body of a PSK_EXCHANGE_RSP message.
+    ///
+    /// `measurement_summary_hash_requested` must be true if the PSK_EXCHANGE
+    /// request asked for a measurement summary hash.
+    pub fn parse_body(
+        buf: &[u8],
+        digest_size: u8,
+        measurement_summary_hash_requested: bool,
+    ) -> Result<PskExchangeRsp, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        let heartbeat_period = r.get_byte()?;
+        r.skip_reserved(1)?;
+        let rsp_session_id = r.get_u16()?;
+        r.skip_reserved(2)?;
+        let responder_context_len = r.get_u16()?;
+        let opaque_data_len = r.get_u16()?;
+
+        let measurement_summary_hash = if measurement_summary_hash_requested {
+            let mut hash = [0u8; MAX_DIGEST_SIZE];
+            hash[..digest_size as usize]
+                .copy_from_slice(r.get_slice(digest_size as usize)?);
+            Some(hash)
+        } else {
+            None
+        };
+
+        let responder_context =
+            read_data(Self::NAME, &mut r, responder_context_len)?;
+        let opaque_data = read_data(Self::NAME, &mut r, opaque_data_len)?;
+
+        let mut verify_data = [0u8; MAX_DIGEST_SIZE];
+        verify_data[..digest_size as usize]
+            .copy_from_slice(r.get_slice(digest_size as usize)?);
+
+        Ok(PskExchangeRsp {
+            heartbeat_period,
+            rsp_session_id,
+            digest_size,
+            measurement_summary_hash,
+            responder_context_len,
+            responder_context,
+            opaque_data_len,
+            opaque_data,
+            verify_data,
+        })
+    }
+}
+
+/// The request completing the handshake of a PSK session
+///
+/// This is only sent if the responder provided a ResponderContext. The
+/// RequesterVerifyData is an HMAC over the transcript through the header of
+/// this message, using the finished key derived from the request handshake
+/// secret.
+#[derive(Debug, Clone)]
+pub struct PskFinish {
+    pub digest_size: u8,
+    pub verify_data: [u8; MAX_DIGEST_SIZE],
+}
+
+impl PartialEq for PskFinish {
+    fn eq(&self, other: &Self) -> bool {
+        self.verify_data() == other.verify_data()
+    }
+}
+
+impl Eq for PskFinish {}
+
+impl Default for PskFinish {
+    fn default() -> Self {
+        PskFinish { digest_size: 0, verify_data: [0u8; MAX_DIGEST_SIZE] }
+    }
+}
+
+impl Msg for PskFinish {
+    const NAME: &'static str = "PSK_FINISH";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0xE7;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put_reserved(2)?;
+        w.extend(self.verify_data())
+    }
+}
+
+impl PskFinish {
+    /// Return the RequesterVerifyData HMAC
+    pub fn verify_data(&self) -> &[u8] {
+        &self.verify_data[..self.digest_size as usize]
+    }
+
+    /// Deserialize the body of a PSK_FINISH message.
+    pub fn parse_body(
+        buf: &[u8],
+        digest_size: u8,
+    ) -> Result<PskFinish, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        r.skip_reserved(2)?;
+        let mut verify_data = [0u8; MAX_DIGEST_SIZE];
+        verify_data[..digest_size as usize]
+            .copy_from_slice(r.get_slice(digest_size as usize)?);
+        Ok(PskFinish { digest_size, verify_data })
+    }
+}
+
+/// The response to a PSK_FINISH request
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PskFinishRsp {}
+
+impl Msg for PskFinishRsp {
+    const NAME: &'static str = "PSK_FINISH_RSP";
+    const SPDM_VERSION: u8 = 0x11;
+    const SPDM_CODE: u8 = 0x67;
+
+    fn write_body(&self, w: &mut Writer) -> Result<usize, WriteError> {
+        w.put_reserved(2)
+    }
+}
+
+impl PskFinishRsp {
+    /// Deserialize the body of a PSK_FINISH_RSP message.
+    pub fn parse_body(buf: &[u8]) -> Result<PskFinishRsp, ReadError> {
+        let mut r = Reader::new(Self::NAME, buf);
+        r.skip_reserved(2)?;
+        Ok(PskFinishRsp {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::HEADER_SIZE;
+    use super::*;
+
+    #[test]
+    fn psk_exchange_roundtrip() {
+        let mut buf = [0u8; 128];
+        let mut msg = PskExchange {
+            measurement_hash_type: MeasurementHashType::All,
+            req_session_id: 0xABCD,
+            psk_hint_len: 5,
+            requester_context_len: 32,
+            ..PskExchange::default()
+        };
+        msg.psk_hint[..5].copy_from_slice(b"hint0");
+        msg.requester_context[..32].copy_from_slice(&[0x13; 32]);
+
+        assert_eq!(49, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), PskExchange::parse_header(&buf));
+        assert_eq!(msg, PskExchange::parse_body(&buf[HEADER_SIZE..]).unwrap());
+    }
+
+    #[test]
+    fn psk_exchange_hint_too_large() {
+        let mut buf = [0u8; 128];
+        let msg = PskExchange::default();
+        let size = msg.write(&mut buf).unwrap();
+
+        // Claim a hint larger than we support
+        buf[HEADER_SIZE + 4] = MAX_PSK_HINT_SIZE as u8 + 1;
+        assert!(PskExchange::parse_body(&buf[HEADER_SIZE..size]).is_err());
+    }
+
+    #[test]
+    fn psk_exchange_rsp_roundtrip() {
+        let mut buf = [0u8; 256];
+        let mut msg = PskExchangeRsp {
+            heartbeat_period: 5,
+            rsp_session_id: 0x1234,
+            digest_size: 32,
+            ..PskExchangeRsp::default()
+        };
+        msg.verify_data[..32].copy_from_slice(&[2u8; 32]);
+
+        // Neither a measurement summary hash nor a context
+        assert_eq!(44, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), PskExchangeRsp::parse_header(&buf));
+        assert_eq!(
+            msg,
+            PskExchangeRsp::parse_body(&buf[HEADER_SIZE..], 32, false).unwrap()
+        );
+
+        // Both a measurement summary hash and a context
+        msg.measurement_summary_hash = Some([3u8; MAX_DIGEST_SIZE]);
+        msg.responder_context_len = 32;
+        msg.responder_context[..32].copy_from_slice(&[4u8; 32]);
+        assert_eq!(108, msg.write(&mut buf).unwrap());
+        assert_eq!(
+            msg,
+            PskExchangeRsp::parse_body(&buf[HEADER_SIZE..], 32, true).unwrap()
+        );
+    }
+
+    #[test]
+    fn psk_finish_roundtrip() {
+        let mut buf = [0u8; 128];
+        let mut msg = PskFinish { digest_size: 48, ..PskFinish::default() };
+        msg.verify_data[..48].copy_from_slice(&[5u8; 48]);
+        assert_eq!(52, msg.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), PskFinish::parse_header(&buf));
+        assert_eq!(
+            msg,
+            PskFinish::parse_body(&buf[HEADER_SIZE..52], 48).unwrap()
+        );
+
+        assert_eq!(4, PskFinishRsp {}.write(&mut buf).unwrap());
+        assert_eq!(Ok(true), PskFinishRsp::parse_header(&buf));
+        assert_eq!(
+            PskFinishRsp {},
+            PskFinishRsp::parse_body(&buf[HEADER_SIZE..4]).unwrap()
+        );
+    }
+}
@@ -15,19 +15,49 @@
 pub mod algorithms;
 pub mod capabilities;
 pub mod challenge;
+pub mod end_session;
+pub mod finish;
+pub mod heartbeat;
 pub mod id_auth;
+pub mod key_exchange;
+pub mod key_update;
+pub mod measurements;
+pub mod mut_auth;
+pub mod psk_exchange;
+pub mod psk_finish;
+pub mod session;
 pub mod version;
 
 mod error;
 
-use crate::msgs::Msg;
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::challenge::PROVISIONED_KEY_SLOT;
+use crate::msgs::measurements::MeasurementBlocks;
+use crate::msgs::{
+    Algorithms, CertificateChain, KeyUpdateOperation, MeasurementHashType, Msg,
+    HEADER_SIZE,
+};
 use crate::Transcript;
+pub use challenge::{NoTimeSource, TimeSource, UnknownTimePolicy};
 pub use error::RequesterError;
+pub use id_auth::{CertCache, NoCertCache};
+pub use measurements::MeasurementRequest;
+pub use psk_exchange::Psk;
 
 use crate::config;
-use crate::crypto::{FilledSlot, Signer};
+use crate::crypto::{
+    aead::{RingAead, TAG_SIZE},
+    key_schedule::HandshakeSecrets,
+    pki::{self, new_end_entity_cert, EndEntityCert},
+    Aead, FilledSlot, Signer,
+};
+use crate::secured_message::{
+    self, Channels, HeartbeatTimer, Mode, RecordLayer,
+};
 
-use core::convert::From;
+use core::convert::{From, TryFrom, TryInto};
+use core::marker::PhantomData;
+use core::time::Duration;
 
 /// We expect a messsage of the given type.
 ///
@@ -46,15 +76,122 @@ pub fn expect<T: Msg>(buf: &[u8]) -> Result<(), RequesterError> {
     }
 }
 
+// Verify a signature of the responder, with its provisioned public key if
+// `slot` is 0xF, and with the leaf cert of its certificate chain otherwise.
+//
+// `identity` is the public key or the certificate chain.
+fn verify_responder_signature(
+    algorithms: &Algorithms,
+    slot: u8,
+    identity: &[u8],
+    msg: &[u8],
+    signature: &[u8],
+) -> Result<bool, RequesterError> {
+    let algorithm = algorithms.base_asym_algo_selected;
+    if slot == PROVISIONED_KEY_SLOT {
+        return Ok(pki::verify_signature(algorithm, identity, msg, signature));
+    }
+    let cert_chain = CertificateChain::parse(
+        identity,
+        algorithms.base_hash_algo_selected.get_digest_size(),
+    )?;
+    let end_entity_cert = new_end_entity_cert(cert_chain.leaf_cert)?;
+    Ok(end_entity_cert.verify_signature(algorithm, msg, signature))
+}
+
+// The size of the largest response to FINISH or PSK_FINISH sent within the
+// session
+const MAX_HANDSHAKE_RSP_RECORD_SIZE: usize =
+    secured_message::max_record_size(HEADER_SIZE + config::MAX_DIGEST_SIZE);
+
+// Return the channels of a session handshake, protected by the handshake keys.
+//
+// The handshake is completed by a single request and response, so the channels
+// are derived for each of them, starting from the first sequence number.
+fn handshake_channels(
+    session_id: u32,
+    requester_cap: ReqFlags,
+    responder_cap: RspFlags,
+    algorithms: &Algorithms,
+    secrets: &HandshakeSecrets,
+) -> Channels {
+    let record_layer = RecordLayer {
+        session_id,
+        // Unwraps are safe, as sessions are only created if a mode and an
+        // AEAD algorithm were negotiated.
+        mode: Mode::new(requester_cap, responder_cap).unwrap(),
+        aead_algo: algorithms.aead_algo_selected().unwrap(),
+        sequence_number_size: config::SEQUENCE_NUMBER_SIZE,
+    };
+    Channels::handshake(
+        record_layer,
+        algorithms.base_hash_algo_selected,
+        secrets,
+    )
+}
+
+// Write a request with `write` directly into a secured message in `buf`,
+// encoded with the request channel of `channels`.
+fn write_secured<'b, A: Aead>(
+    channels: &mut Channels,
+    buf: &'b mut [u8],
+    write: impl FnOnce(&mut [u8]) -> Result<usize, RequesterError>,
+) -> Result<&'b [u8], RequesterError> {
+    let record_layer = channels.record_layer;
+    let start = record_layer.app_data_offset();
+    if buf.len() < start + TAG_SIZE {
+        return Err(RequesterError::SecuredMessage(
+            secured_message::Error::BufferTooSmall,
+        ));
+    }
+    let end = buf.len() - TAG_SIZE;
+    let req_size = write(&mut buf[start..end])?;
+    let size = record_layer.encode_in_place::<A>(
+        &mut channels.request,
+        req_size,
+        buf,
+    )?;
+    Ok(&buf[..size])
+}
+
+// Decode the secured message `rsp` with the response channel of `channels`.
+// It is decrypted in `record`, as `rsp` is borrowed.
+fn open_secured<'b, A: Aead>(
+    channels: &mut Channels,
+    rsp: &[u8],
+    record: &'b mut [u8],
+) -> Result<&'b [u8], RequesterError> {
+    let record = record.get_mut(..rsp.len()).ok_or(
+        RequesterError::SecuredMessage(secured_message::Error::BufferTooSmall),
+    )?;
+    record.copy_from_slice(rsp);
+    Ok(channels.record_layer.decode::<A>(&mut channels.response, record)?)
+}
+
 // Internal data shared between `RequesterInit` and `RequesterSession` states.
 struct RequesterData<'a, S: Signer> {
-    // Do we need more than one of these? Do we expect that different slots for
-    // a responder will have different root certs?
-    root_cert: &'a [u8],
+    // The trust anchors, one of which the responder's certificate chain must
+    // lead to
+    root_certs: &'a [&'a [u8]],
 
-    // Will eventually be used for mutual auth
+    // Used to authenticate to the responder when it requests mutual
+    // authentication
     slots: [Option<FilledSlot<'a, S>>; config::NUM_SLOTS],
 
+    // The measurement summary hash to request during CHALLENGE
+    measurement_hash_type: MeasurementHashType,
+
+    // The PSK to establish a session with, instead of authenticating the
+    // responder with its certificate chain
+    psk: Option<Psk<'a>>,
+
+    // The responder's public key, if provisioned in a trusted environment.
+    // It is challenged instead of a certificate chain.
+    public_key: Option<&'a [u8]>,
+
+    // The certificate chains retrieved from the responder
+    cert_chains: id_auth::CertChains,
+
     transcript: Transcript,
     // This Option allows us to move between AllStates variants at runtime, without having
     // to take self by value.
@@ -65,35 +202,485 @@ struct RequesterData<'a, S: Signer> {
 /// protocol, as dictated by negotiated capabilities, until a secure session is
 /// established. Once a secure session is established, users can send and
 /// receive application specific messages from the `RequesterSession` state.
-pub struct RequesterInit<'a, S: Signer> {
+///
+/// Certificate chains of the responder are looked up in `C`, which by default
+/// caches nothing. The time at which they must be valid is taken from `T`,
+/// which by default does not know the time. The session handshake is
+/// encrypted and decrypted with `A`, which defaults to a software
+/// implementation backed by ring.
+pub struct RequesterInit<
+    'a,
+    S: Signer,
+    C: CertCache = NoCertCache,
+    T: TimeSource = NoTimeSource,
+    A: Aead = RingAead,
+> {
     data: RequesterData<'a, S>,
+
+    // Certificate chains are always retrieved unless a cache is set
+    cert_cache: Option<C>,
+
+    // The time is unknown unless a time source is set, and then handled
+    // according to the policy
+    time_source: Option<T>,
+    unknown_time_policy: UnknownTimePolicy,
+
+    // Keys are only instantiated while encoding or decoding a record
+    aead: PhantomData<fn() -> A>,
 }
 
 /// In the `RequesterSession` state, the a secure session has been
 /// established and the user can send encrypted messages and request
 /// measurements at will.
-pub struct RequesterSession<'a, S: Signer> {
-    _data: RequesterData<'a, S>,
+///
+/// Secured messages are encrypted and decrypted with `A`, which defaults to
+/// a software implementation backed by ring.
+pub struct RequesterSession<'a, S: Signer, A: Aead = RingAead> {
+    data: RequesterData<'a, S>,
+
+    // The measurements currently, or most recently, being retrieved
+    measurements: Option<measurements::State>,
+
+    // The VCA messages and the measurement messages sent within the secure
+    // session (L1/L2), which are kept apart from those sent outside of it
+    session_transcript: Transcript,
+
+    // The record layer of the secure session, if one was established
+    channels: Option<Channels>,
+
+    // The key update in progress
+    key_update: Option<key_update::State>,
+
+    // The outstanding HEARTBEAT, and the time since a secured message was
+    // last sent in the session
+    heartbeat: Option<heartbeat::State>,
+    heartbeat_timer: HeartbeatTimer,
+
+    // The outstanding END_SESSION
+    end_session: Option<end_session::State>,
+
+    // Keys are only instantiated while encoding or decoding a record
+    aead: PhantomData<fn() -> A>,
 }
 
-impl<'a, S: Signer> From<RequesterInit<'a, S>> for RequesterSession<'a, S> {
-    fn from(state: RequesterInit<'a, S>) -> Self {
-        RequesterSession { _data: state.data }
+impl<'a, S: Signer, C: CertCache, T: TimeSource, A: Aead>
+    TryFrom<RequesterInit<'a, S, C, T, A>> for RequesterSession<'a, S, A>
+{
+    type Error = RequesterError;
+
+    fn try_from(
+        state: RequesterInit<'a, S, C, T, A>,
+    ) -> Result<Self, RequesterError> {
+        let session = match &state.data.state {
+            Some(AllStates::NewSession(session)) => session,
+            _ => return Err(RequesterError::InitializationIncomplete),
+        };
+        let channels = match (session.session_id, &session.session_keys) {
+            (Some(session_id), Some(keys)) => {
+                let record_layer = RecordLayer {
+                    session_id,
+                    // Unwraps are safe, as sessions are only established if
+                    // a mode and an AEAD algorithm were negotiated.
+                    mode: Mode::new(
+                        session.requester_cap,
+                        session.responder_cap,
+                    )
+                    .unwrap(),
+                    aead_algo: session.algorithms.aead_algo_selected().unwrap(),
+                    sequence_number_size: config::SEQUENCE_NUMBER_SIZE,
+                };
+                Some(Channels::new(
+                    record_layer,
+                    session.algorithms.base_hash_algo_selected,
+                    keys,
+                ))
+            }
+            _ => None,
+        };
+        let heartbeat_timer = HeartbeatTimer::new(session.heartbeat_period);
+        let mut session_transcript = state.data.transcript.clone();
+        session_transcript.reset_to_vca();
+        Ok(RequesterSession {
+            data: state.data,
+            measurements: None,
+            session_transcript,
+            channels,
+            key_update: None,
+            heartbeat: None,
+            heartbeat_timer,
+            end_session: None,
+            aead: PhantomData,
+        })
     }
 }
 
-impl<'a, S: Signer> RequesterInit<'a, S> {
+impl<'a, S: Signer, A: Aead> RequesterSession<'a, S, A> {
+    /// Begin retrieving measurements from the responder.
+    ///
+    /// The user then calls `next_request` and `handle_msg` until `handle_msg`
+    /// returns `Ok(true)`, after which the verified measurement blocks can be
+    /// retrieved via `measurements`. If `signature_requested` is false, the
+    /// measurement blocks are parsed, but not authenticated.
+    pub fn request_measurements(
+        &mut self,
+        request: MeasurementRequest,
+        signature_requested: bool,
+    ) -> Result<(), RequesterError> {
+        let responder_cap = self.session().responder_cap;
+        let cap = if signature_requested {
+            RspFlags::MEAS_CAP_SIG
+        } else {
+            RspFlags::MEAS_CAP_NO_SIG | RspFlags::MEAS_CAP_SIG
+        };
+        if !responder_cap.intersects(cap) {
+            return Err(RequesterError::MeasurementsUnsupported);
+        }
+        if let MeasurementRequest::Index(0 | 0xFF) = request {
+            return Err(RequesterError::MeasurementsUnsupported);
+        }
+        self.measurements =
+            Some(measurements::State::new(request, signature_requested));
+        Ok(())
+    }
+
+    /// Begin updating the keys of the secure session.
+    ///
+    /// Only the keys for requests are updated, unless `update_all` is true.
+    /// The user then calls `next_secured_request` and `handle_secured_msg`
+    /// until `handle_secured_msg` returns `Ok(true)`, at which point the new
+    /// keys are in use by both sides. A KEY_UPDATE request that went
+    /// unacknowledged is retried by calling `next_secured_request` again.
+    pub fn update_keys(
+        &mut self,
+        update_all: bool,
+    ) -> Result<(), RequesterError> {
+        self.channels()?;
+        let session = self.session();
+        if !(session.requester_cap.contains(ReqFlags::KEY_UPD_CAP)
+            && session.responder_cap.contains(RspFlags::KEY_UPD_CAP))
+        {
+            return Err(RequesterError::KeyUpdateUnsupported);
+        }
+        self.key_update = Some(key_update::State::new(update_all));
+        Ok(())
+    }
+
+    /// Begin sending a HEARTBEAT to keep the secure session alive.
+    ///
+    /// The user then calls `next_secured_request` and `handle_secured_msg`
+    /// until `handle_secured_msg` returns `Ok(true)`. This is usually done
+    /// once `heartbeat_due` returns true.
+    pub fn send_heartbeat(&mut self) -> Result<(), RequesterError> {
+        self.channels()?;
+        if self.heartbeat_timer.period().is_none() {
+            return Err(RequesterError::HeartbeatUnsupported);
+        }
+        self.heartbeat = Some(heartbeat::State {});
+        Ok(())
+    }
+
+    /// Begin ending the secure session.
+    ///
+    /// The user then calls `next_secured_request` and `handle_secured_msg`
+    /// until `handle_secured_msg` returns `Ok(true)`, after which the session
+    /// is gone, and secured messages can no longer be sent. If
+    /// `negotiated_state_clearing` is true, the responder is also asked to
+    /// forget the negotiated state.
+    pub fn end_session(
+        &mut self,
+        negotiated_state_clearing: bool,
+    ) -> Result<(), RequesterError> {
+        self.channels()?;
+        self.end_session =
+            Some(end_session::State { negotiated_state_clearing });
+        Ok(())
+    }
+
+    /// Return the heartbeat period of the secure session, or `None` if the
+    /// session does not use heartbeats.
+    pub fn heartbeat_period(&self) -> Option<Duration> {
+        self.heartbeat_timer.period()
+    }
+
+    /// Return true if no secured message was sent or received for a heartbeat
+    /// period, and a HEARTBEAT must be sent to keep the session alive.
+    ///
+    /// `now` is the current time of the same monotonic clock that is passed
+    /// with each secured message.
+    pub fn heartbeat_due(&mut self, now: Duration) -> bool {
+        self.heartbeat_timer.is_due(now)
+    }
+
+    /// Return true if no secured message was sent or received for twice the
+    /// heartbeat period, after which the responder terminates the session.
+    pub fn session_expired(&mut self, now: Duration) -> bool {
+        self.heartbeat_timer.is_expired(now)
+    }
+
+    /// Write the next request for the operation in progress into `buf`.
+    pub fn next_request<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], RequesterError> {
+        self.write_measurements_req(buf, false)
+    }
+
+    /// Handle a response to the last request written by `next_request`.
+    ///
+    /// `Ok(true)` is returned when the operation in progress is complete.
+    pub fn handle_msg(&mut self, rsp: &[u8]) -> Result<bool, RequesterError> {
+        self.handle_measurements_rsp(rsp, false)
+    }
+
+    // Write the next GET_MEASUREMENTS request into `buf`, and append it to
+    // the transcript of the secure session if `secured`, or else to the
+    // transcript outside of any session.
+    fn write_measurements_req<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+        secured: bool,
+    ) -> Result<&'b [u8], RequesterError> {
+        let slot = self.session().cert_slot;
+        let transcript = if secured {
+            &mut self.session_transcript
+        } else {
+            &mut self.data.transcript
+        };
+        match self.measurements.as_mut() {
+            Some(state) => state.write_msg(slot, buf, transcript),
+            None => Err(RequesterError::NoRequestInProgress),
+        }
+    }
+
+    // Handle a MEASUREMENTS response to the request written by
+    // `write_measurements_req`
+    fn handle_measurements_rsp(
+        &mut self,
+        rsp: &[u8],
+        secured: bool,
+    ) -> Result<bool, RequesterError> {
+        let session = match &self.data.state {
+            Some(AllStates::NewSession(session)) => session,
+            _ => unreachable!(),
+        };
+        let transcript = if secured {
+            &mut self.session_transcript
+        } else {
+            &mut self.data.transcript
+        };
+        match self.measurements.as_mut() {
+            Some(state) => state.handle_msg(rsp, session, transcript),
+            None => Err(RequesterError::NoRequestInProgress),
+        }
+    }
+
+    /// Like `next_request`, but the request is sent within the secure
+    /// session. The secured message is written into `buf`.
+    ///
+    /// A key update in progress takes precedence over other operations,
+    /// followed by END_SESSION and HEARTBEAT. `now` is the current time of
+    /// the monotonic clock used for heartbeats.
+    pub fn next_secured_request<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+        now: Duration,
+    ) -> Result<&'b [u8], RequesterError> {
+        let record_layer = self.channels()?.record_layer;
+        let start = record_layer.app_data_offset();
+        if buf.len() < start + TAG_SIZE {
+            return Err(RequesterError::SecuredMessage(
+                secured_message::Error::BufferTooSmall,
+            ));
+        }
+        let end = buf.len() - TAG_SIZE;
+        let operation = self.key_update.as_ref().map(|state| state.operation);
+        let req_size = if let Some(state) = self.key_update.as_mut() {
+            // Only a retried UpdateKey or UpdateAllKeys request is sent with
+            // the old keys
+            if state.is_retry() {
+                // Unwrap is safe, as the secure session was checked above
+                self.channels.as_mut().unwrap().rollback();
+            }
+            state.write_msg(&mut buf[start..end])?.len()
+        } else if let Some(state) = &self.end_session {
+            state.write_msg(&mut buf[start..end])?.len()
+        } else if let Some(state) = &self.heartbeat {
+            state.write_msg(&mut buf[start..end])?.len()
+        } else {
+            self.write_measurements_req(&mut buf[start..end], true)?.len()
+        };
+        self.heartbeat_timer.mark_active(now);
+        let channels = self.channels_mut()?;
+        let size = record_layer.encode_in_place::<A>(
+            &mut channels.request,
+            req_size,
+            buf,
+        )?;
+
+        // The KEY_UPDATE_ACK of UpdateAllKeys is sent with the new keys
+        if operation == Some(KeyUpdateOperation::UpdateAllKeys) {
+            channels.update_response();
+        }
+        Ok(&buf[..size])
+    }
+
+    /// Like `handle_msg`, but the response was received within the secure
+    /// session. The secured message in `rsp` is decrypted in place. `now` is
+    /// the current time of the monotonic clock used for heartbeats.
+    pub fn handle_secured_msg(
+        &mut self,
+        rsp: &mut [u8],
+        now: Duration,
+    ) -> Result<bool, RequesterError> {
+        let channels =
+            self.channels.as_mut().ok_or(RequesterError::NoSecureSession)?;
+        let rsp =
+            channels.record_layer.decode::<A>(&mut channels.response, rsp)?;
+        self.heartbeat_timer.mark_active(now);
+        let state = match self.key_update.as_mut() {
+            Some(state) => state,
+            None => return self.handle_session_msg(rsp),
+        };
+
+        // Requests are only sent with the new keys once the update is
+        // acknowledged, and the old keys are only discarded once the new keys
+        // are verified.
+        let operation = state.operation;
+        let complete = state.handle_msg(rsp)?;
+        if operation == KeyUpdateOperation::VerifyNewKey {
+            channels.confirm_update();
+        } else {
+            channels.update_request();
+        }
+        if complete {
+            self.key_update = None;
+        }
+        Ok(complete)
+    }
+
+    // Handle a decrypted response to anything but KEY_UPDATE
+    fn handle_session_msg(
+        &mut self,
+        rsp: &[u8],
+    ) -> Result<bool, RequesterError> {
+        if let Some(state) = &self.end_session {
+            state.handle_msg(rsp)?;
+            self.end_session = None;
+            self.heartbeat = None;
+            self.heartbeat_timer = HeartbeatTimer::new(0);
+            self.channels = None;
+            return Ok(true);
+        }
+        if let Some(state) = &self.heartbeat {
+            state.handle_msg(rsp)?;
+            self.heartbeat = None;
+            return Ok(true);
+        }
+        self.handle_measurements_rsp(rsp, true)
+    }
+
+    /// Wrap application data in a secured message written into `buf`. `now`
+    /// is the current time of the monotonic clock used for heartbeats.
+    pub fn send<'b>(
+        &mut self,
+        app_data: &[u8],
+        buf: &'b mut [u8],
+        now: Duration,
+    ) -> Result<&'b [u8], RequesterError> {
+        let channels = self.channels_mut()?;
+        let size = channels.record_layer.encode::<A>(
+            &mut channels.request,
+            app_data,
+            buf,
+        )?;
+        self.heartbeat_timer.mark_active(now);
+        Ok(&buf[..size])
+    }
+
+    /// Unwrap the application data of a secured message received from the
+    /// responder. The message is decrypted in place. `now` is the current
+    /// time of the monotonic clock used for heartbeats.
+    pub fn recv<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+        now: Duration,
+    ) -> Result<&'b [u8], RequesterError> {
+        let channels = self.channels_mut()?;
+        let app_data =
+            channels.record_layer.decode::<A>(&mut channels.response, buf)?;
+        self.heartbeat_timer.mark_active(now);
+        Ok(app_data)
+    }
+
+    /// Return the verified measurement blocks of the last completed
+    /// measurement request.
+    pub fn measurements(&self) -> Option<MeasurementBlocks<'_>> {
+        self.measurements.as_ref().and_then(|s| s.blocks())
+    }
+
+    /// Return the verified measurement summary hash from CHALLENGE_AUTH, or
+    /// `None` if one was not requested.
+    pub fn measurement_summary_hash(&self) -> Option<&[u8]> {
+        self.session().measurement_summary_hash()
+    }
+
+    /// Return the certificate chain retrieved from `slot` of the responder
+    pub fn cert_chain(&self, slot: u8) -> Option<&[u8]> {
+        self.data.cert_chains.get(slot)
+    }
+
+    /// Return the state negotiated during initialization
+    pub fn session(&self) -> &session::State {
+        match &self.data.state {
+            Some(AllStates::NewSession(session)) => session,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn transcript(&self) -> &Transcript {
+        &self.data.transcript
+    }
+
+    /// Return the transcript of the measurement messages sent within the
+    /// secure session
+    pub fn session_transcript(&self) -> &Transcript {
+        &self.session_transcript
+    }
+
+    /// Return the record layer of the secure session, if one was established
+    pub fn channels(&self) -> Result<&Channels, RequesterError> {
+        self.channels.as_ref().ok_or(RequesterError::NoSecureSession)
+    }
+
+    fn channels_mut(&mut self) -> Result<&mut Channels, RequesterError> {
+        self.channels.as_mut().ok_or(RequesterError::NoSecureSession)
+    }
+}
+
+impl<'a, S: Signer, C: CertCache, T: TimeSource, A: Aead>
+    RequesterInit<'a, S, C, T, A>
+{
+    /// Create a requester that trusts certificate chains leading to any of
+    /// `root_certs`.
     pub fn new(
-        root_cert: &'a [u8],
+        root_certs: &'a [&'a [u8]],
         slots: [Option<FilledSlot<'a, S>>; config::NUM_SLOTS],
-    ) -> RequesterInit<'a, S> {
+    ) -> RequesterInit<'a, S, C, T, A> {
         RequesterInit {
             data: RequesterData {
-                root_cert,
+                root_certs,
                 slots,
+                measurement_hash_type: MeasurementHashType::None,
+                psk: None,
+                public_key: None,
+                cert_chains: id_auth::CertChains::new(),
                 transcript: Transcript::new(),
                 state: Some(version::State {}.into()),
             },
+            cert_cache: None,
+            time_source: None,
+            unknown_time_policy: UnknownTimePolicy::default(),
+            aead: PhantomData,
         }
     }
 
@@ -103,37 +690,174 @@ impl<'a, S: Signer> RequesterInit<'a, S> {
     /// A `RequesterError::InitializationComplete` error  will be returned if
     /// this method is called when initialization is complete. In this case,
     /// the user should call the `begin_session` method.
+    ///
+    /// The request is a secured message if `is_secured` returns true.
     pub fn next_request<'b>(
         &mut self,
         buf: &'b mut [u8],
     ) -> Result<&'b [u8], RequesterError> {
         let state = self.data.state.as_mut().unwrap();
-        if let AllStates::NewSession = state {
+        if let AllStates::NewSession(_) = state {
             return Err(RequesterError::InitializationComplete);
         }
-        state.write_req(buf, &mut self.data.transcript)
+        state.write_req::<S, A>(
+            &self.data.slots,
+            buf,
+            &mut self.data.transcript,
+            self.data.measurement_hash_type,
+            self.data.psk.as_ref(),
+            &self.data.cert_chains,
+        )
+    }
+
+    /// Request a measurement summary hash of the given type during CHALLENGE.
+    ///
+    /// This must be called before the CHALLENGE request is written. By default
+    /// no summary hash is requested. The verified summary hash is available
+    /// from the `RequesterSession` once initialization is complete.
+    pub fn set_measurement_hash_type(
+        &mut self,
+        measurement_hash_type: MeasurementHashType,
+    ) {
+        self.data.measurement_hash_type = measurement_hash_type;
+    }
+
+    /// Establish a session with the given pre-shared key.
+    ///
+    /// This must be called before the ALGORITHMS response is handled. If the
+    /// responder supports PSK sessions, PSK_EXCHANGE directly follows
+    /// algorithm negotiation, and the responder is not asked for its
+    /// certificates or challenged. The measurement hash type set via
+    /// `set_measurement_hash_type` is requested in PSK_EXCHANGE.
+    pub fn set_psk(&mut self, psk: Psk<'a>) {
+        self.data.psk = Some(psk);
+    }
+
+    /// Authenticate the responder with the given public key, provisioned in a
+    /// trusted environment.
+    ///
+    /// This must be called before the ALGORITHMS response is handled. If the
+    /// responder sets PUB_KEY_ID_CAP, its certificates are not retrieved, and
+    /// its signatures are verified with the public key, which is challenged
+    /// as slot 0xF. The public key is an uncompressed SEC1 encoded elliptic
+    /// curve point.
+    pub fn set_public_key(
+        &mut self,
+        public_key: &'a [u8],
+    ) -> Result<(), RequesterError> {
+        if public_key.len() > config::MAX_CERT_CHAIN_SIZE {
+            return Err(RequesterError::InvalidPublicKey);
+        }
+        self.data.public_key = Some(public_key);
+        Ok(())
+    }
+
+    /// Only retrieve the certificate chains of the slots in `slot_mask`.
+    ///
+    /// This must be called before the DIGESTS response is handled. By default
+    /// the chains of all slots the responder sent a digest for are retrieved.
+    /// An error is returned once DIGESTS is handled if none of them is in
+    /// `slot_mask`.
+    pub fn set_cert_slots(&mut self, slot_mask: u8) {
+        self.data.cert_chains.slot_mask = slot_mask;
+    }
+
+    /// Look up the certificate chains of the responder in `cert_cache`.
+    ///
+    /// This must be called before the DIGESTS response is handled. Only the
+    /// chains whose digest is not found in the cache are retrieved, and they
+    /// are then added to the cache. If all chains are found, CHALLENGE
+    /// directly follows DIGESTS.
+    pub fn set_cert_cache(&mut self, cert_cache: C) {
+        self.cert_cache = Some(cert_cache);
+    }
+
+    /// Return the cache set via `set_cert_cache`
+    pub fn cert_cache(&self) -> Option<&C> {
+        self.cert_cache.as_ref()
+    }
+
+    /// Check the validity periods of the responder's certificates at the time
+    /// returned by `time_source`.
+    ///
+    /// This must be called before the CHALLENGE_AUTH response is handled. By
+    /// default, or whenever `time_source` does not know the time, the
+    /// unknown time policy applies.
+    pub fn set_time_source(&mut self, time_source: T) {
+        self.time_source = Some(time_source);
+    }
+
+    /// Set how validity periods are checked when the time is unknown.
+    ///
+    /// By default, they are checked at `config::TIME_FLOOR`.
+    pub fn set_unknown_time_policy(&mut self, policy: UnknownTimePolicy) {
+        self.unknown_time_policy = policy;
+    }
+
+    /// Challenge the responder with the certificate chain of `slot`.
+    ///
+    /// This must be called in the Challenge state, before the CHALLENGE
+    /// request is written. By default, the first slot whose chain is rooted at
+    /// the root cert is challenged. The retrieved chains are available via
+    /// `cert_chain`, so that another slot can be chosen.
+    pub fn select_cert_slot(&mut self, slot: u8) -> Result<(), RequesterError> {
+        match (
+            self.data.cert_chains.chains.get(slot as usize),
+            self.data.state.as_mut(),
+        ) {
+            (Some(Some(cert)), Some(AllStates::Challenge(state))) => {
+                state.set_cert_chain(cert);
+                Ok(())
+            }
+            _ => Err(RequesterError::InvalidSlot),
+        }
     }
 
     /// The user calls `handle_msg` when a response is received over the
     /// transport.
     ///
-    /// `Ok(true)` will be returned when initialization is complete. At this
-    /// point the user should call the `begin_session` method.
+    /// `Ok(true)` will be returned when initialization is complete. If key
+    /// exchange is supported, this is only once the FINISH_RSP is verified
+    /// and the session keys are derived. At this point the user should call
+    /// the `begin_session` method.
+    ///
+    /// The response must be a secured message if `is_secured` returns true.
     pub fn handle_msg<'b>(
         &mut self,
         rsp: &[u8],
     ) -> Result<bool, RequesterError> {
         let state = self.data.state.take().unwrap();
-        let (next_state, result) = state.handle_msg(
+        let negotiating_algorithms = matches!(state, AllStates::Algorithms(_));
+        let validation = challenge::Validation {
+            root_certs: self.data.root_certs,
+            time: self
+                .unknown_time_policy
+                .time(self.time_source.as_ref().and_then(|t| t.now())),
+        };
+        let (next_state, result) = state.handle_msg::<C, A>(
             rsp,
             &mut self.data.transcript,
-            &self.data.root_cert,
+            &validation,
+            self.data.psk.as_ref(),
+            &mut self.data.cert_chains,
+            self.cert_cache.as_mut(),
         );
-        self.data.state = Some(next_state);
+        self.data.state = Some(match (next_state, self.data.public_key) {
+            // A provisioned public key replaces certificate retrieval
+            (AllStates::IdAuth(state), Some(public_key))
+                if negotiating_algorithms
+                    && state
+                        .responder_cap
+                        .contains(RspFlags::PUB_KEY_ID_CAP) =>
+            {
+                challenge::State::with_public_key(state, public_key).into()
+            }
+            (next_state, _) => next_state,
+        });
 
         match result {
             Ok(()) => {
-                if let Some(AllStates::NewSession) = self.data.state {
+                if let Some(AllStates::NewSession(_)) = self.data.state {
                     Ok(true)
                 } else {
                     Ok(false)
@@ -143,9 +867,27 @@ impl<'a, S: Signer> RequesterInit<'a, S> {
         }
     }
 
+    /// Return true if the session handshake in progress is completed within
+    /// the session, so that the transport must send the next request, and
+    /// receive its response, as secured messages.
+    ///
+    /// This is the case for PSK_FINISH, and for FINISH unless both sides set
+    /// HANDSHAKE_IN_THE_CLEAR_CAP.
+    pub fn is_secured(&self) -> bool {
+        match self.state() {
+            AllStates::Finish(state) => state.is_secured(),
+            AllStates::PskFinish(_) => true,
+            _ => false,
+        }
+    }
+
     /// Transition to the RequesterSession state
-    pub fn begin_session(self) -> RequesterSession<'a, S> {
-        self.into()
+    ///
+    /// Return an error if initialization is not complete.
+    pub fn begin_session(
+        self,
+    ) -> Result<RequesterSession<'a, S, A>, RequesterError> {
+        self.try_into()
     }
 
     // Return the current state of the requester
@@ -160,6 +902,11 @@ impl<'a, S: Signer> RequesterInit<'a, S> {
     pub fn slots(&self) -> &[Option<FilledSlot<'a, S>>; config::NUM_SLOTS] {
         &self.data.slots
     }
+
+    /// Return the certificate chain retrieved from `slot` of the responder
+    pub fn cert_chain(&self, slot: u8) -> Option<&[u8]> {
+        self.data.cert_chains.get(slot)
+    }
 }
 
 /// `AllStates` is a container for all the states in a Requester.
@@ -176,9 +923,14 @@ pub enum AllStates {
     Algorithms(algorithms::State),
     IdAuth(id_auth::State),
     Challenge(challenge::State),
+    MutAuth(mut_auth::State),
+    KeyExchange(key_exchange::State),
+    Finish(finish::State),
+    PskExchange(psk_exchange::State),
+    PskFinish(psk_finish::State),
 
-    // TODO: Fill this in with an actual state once sessions are implemented.
-    NewSession,
+    // Initialization is complete
+    NewSession(session::State),
 }
 impl From<version::State> for AllStates {
     fn from(state: version::State) -> AllStates {
@@ -210,11 +962,51 @@ impl From<challenge::State> for AllStates {
     }
 }
 
+impl From<mut_auth::State> for AllStates {
+    fn from(state: mut_auth::State) -> AllStates {
+        AllStates::MutAuth(state)
+    }
+}
+
+impl From<key_exchange::State> for AllStates {
+    fn from(state: key_exchange::State) -> AllStates {
+        AllStates::KeyExchange(state)
+    }
+}
+
+impl From<finish::State> for AllStates {
+    fn from(state: finish::State) -> AllStates {
+        AllStates::Finish(state)
+    }
+}
+
+impl From<psk_exchange::State> for AllStates {
+    fn from(state: psk_exchange::State) -> AllStates {
+        AllStates::PskExchange(state)
+    }
+}
+
+impl From<psk_finish::State> for AllStates {
+    fn from(state: psk_finish::State) -> AllStates {
+        AllStates::PskFinish(state)
+    }
+}
+
+impl From<session::State> for AllStates {
+    fn from(state: session::State) -> AllStates {
+        AllStates::NewSession(state)
+    }
+}
+
 impl AllStates {
-    fn write_req<'a>(
+    fn write_req<'a, 'b, S: Signer, A: Aead>(
         &mut self,
+        slots: &[Option<FilledSlot<'b, S>>; config::NUM_SLOTS],
         buf: &'a mut [u8],
         transcript: &mut Transcript,
+        measurement_hash_type: MeasurementHashType,
+        psk: Option<&Psk>,
+        cert_chains: &id_auth::CertChains,
     ) -> Result<&'a [u8], RequesterError> {
         match self {
             AllStates::Version(state) => {
@@ -227,27 +1019,46 @@ impl AllStates {
                     // We need to send the GET_DIGESTS request
                     state.write_get_digests_msg(buf, transcript)
                 } else {
-                    // TODO: Retrieve certs for all slots that have digests.
-                    // See SPDM 1.2 sec 10.4.1: Connection Behavior after VCA
-                    //
-                    // This requires changes to the id_auth state to not
-                    // automatically transition after retrieving one cert.
+                    // Retrieve the chains of all slots that have digests, one
+                    // after the other. See SPDM 1.2 sec 10.4.1: Connection
+                    // Behavior after VCA
                     //
-                    // Tracked in https://github.com/oxidecomputer/spdm/issues/29
-                    let slot = 0;
+                    // Unwrap is safe, as this state is left once all chains
+                    // are retrieved.
+                    let slot = state.next_slot(cert_chains.slot_mask).unwrap();
                     state.write_get_certificate_msg(slot, buf, transcript)
                 }
             }
-            AllStates::Challenge(state) => state.write_msg(buf, transcript),
+            AllStates::Challenge(state) => {
+                state.write_msg(measurement_hash_type, buf, transcript)
+            }
+            AllStates::MutAuth(state) => {
+                state.write_msg(slots, buf, transcript)
+            }
+            AllStates::KeyExchange(state) => state.write_msg(buf, transcript),
+            AllStates::Finish(state) => {
+                state.write_msg::<S, A>(slots, buf, transcript)
+            }
+            AllStates::PskExchange(state) => {
+                // Unwrap is safe, as this state is only entered with a PSK
+                let psk = psk.unwrap();
+                state.write_msg(psk, measurement_hash_type, buf, transcript)
+            }
+            AllStates::PskFinish(state) => {
+                state.write_msg::<A>(buf, transcript)
+            }
             _ => unimplemented!(),
         }
     }
 
-    fn handle_msg<'a>(
+    fn handle_msg<C: CertCache, A: Aead>(
         self,
         rsp: &[u8],
         transcript: &mut Transcript,
-        root_cert: &'a [u8],
+        validation: &challenge::Validation,
+        psk: Option<&Psk>,
+        cert_chains: &mut id_auth::CertChains,
+        cert_cache: Option<&mut C>,
     ) -> (AllStates, Result<(), RequesterError>) {
         let result = match self {
             AllStates::Version(state) => {
@@ -257,22 +1068,83 @@ impl AllStates {
                 state.handle_msg(rsp, transcript).map(|s| s.into())
             }
             AllStates::Algorithms(state) => {
-                state.handle_msg(rsp, transcript).map(|s| s.into())
+                state.handle_msg(rsp, transcript).map(|s| {
+                    // A PSK session replaces certificate based authentication
+                    if psk.is_some() && psk_exchange::is_supported(&s) {
+                        psk_exchange::State::from(s).into()
+                    } else {
+                        s.into()
+                    }
+                })
             }
             AllStates::IdAuth(mut state) => {
                 if state.digests.is_none() {
-                    // Self is taken by ref here so we return immediately.
                     let result = state.handle_digests(rsp, transcript);
-                    return (state.into(), result);
+                    if result.is_err() {
+                        // Self is taken by ref here so we return immediately.
+                        return (state.into(), result);
+                    }
+                    if let Some(cert_cache) = cert_cache.as_deref() {
+                        state.use_cached_chains(cert_chains, cert_cache);
+                    }
                 } else {
-                    state.handle_certificate(rsp, transcript).map(|s| s.into())
+                    match state.handle_certificate(rsp, transcript, cert_chains)
+                    {
+                        // Request the next portion of the certificate chain
+                        Ok(false) => return (state.into(), Ok(())),
+                        Ok(true) => (),
+                        Err(e) => return (AllStates::Error, Err(e)),
+                    }
                 }
+                // Request the chain of the next slot, unless all chains are
+                // retrieved or cached
+                if state.next_slot(cert_chains.slot_mask).is_some() {
+                    return (state.into(), Ok(()));
+                }
+                if let Some(cert_cache) = cert_cache {
+                    state.cache_chains(cert_chains, cert_cache);
+                }
+                state
+                    .select_slot(cert_chains, validation.root_certs)
+                    .map(|()| challenge::State::from(state).into())
             }
-            AllStates::Challenge(state) => {
-                // We haven't implemented any other states, so just go to
-                // `NewSession`.
-                let result = state.handle_msg(rsp, transcript, root_cert);
-                return (AllStates::NewSession, result.map(|_| ()));
+            AllStates::Challenge(state) => state
+                .handle_msg(rsp, transcript, validation)
+                .map(|(s, mutual_auth)| {
+                    if mutual_auth {
+                        mut_auth::State::from(s).into()
+                    } else {
+                        authenticated(s)
+                    }
+                }),
+            AllStates::MutAuth(mut state) => {
+                match state.handle_msg(rsp, transcript) {
+                    // Self is taken by ref here so we return immediately.
+                    Ok(false) => return (state.into(), Ok(())),
+                    Ok(true) => Ok(authenticated(state.session)),
+                    Err(e) => Err(e),
+                }
+            }
+            AllStates::KeyExchange(state) => {
+                state.handle_msg(rsp, transcript).map(|s| s.into())
+            }
+            AllStates::Finish(state) => {
+                state.handle_msg::<A>(rsp, transcript).map(|s| s.into())
+            }
+            AllStates::PskExchange(state) => {
+                // Unwrap is safe, as this state is only entered with a PSK
+                let psk = psk.unwrap();
+                state.handle_msg(psk, rsp, transcript).map(|s| {
+                    // Without a ResponderContext, there is no PSK_FINISH
+                    if s.is_required() {
+                        s.into()
+                    } else {
+                        s.establish(transcript).into()
+                    }
+                })
+            }
+            AllStates::PskFinish(state) => {
+                state.handle_msg::<A>(rsp, transcript).map(|s| s.into())
             }
             _ => unimplemented!(),
         };
@@ -291,7 +1163,23 @@ impl AllStates {
             AllStates::Algorithms(_) => "Algorithms",
             AllStates::IdAuth(_) => "IdAuth",
             AllStates::Challenge(_) => "Challenge",
-            AllStates::NewSession => "NewSession",
+            AllStates::MutAuth(_) => "MutAuth",
+            AllStates::KeyExchange(_) => "KeyExchange",
+            AllStates::Finish(_) => "Finish",
+            AllStates::PskExchange(_) => "PskExchange",
+            AllStates::PskFinish(_) => "PskFinish",
+            AllStates::NewSession(_) => "NewSession",
         }
     }
 }
+
+// Return the state following authentication of the responder, and of the
+// requester if requested
+fn authenticated(s: session::State) -> AllStates {
+    // Establish a secure session if possible
+    if key_exchange::is_supported(&s) {
+        key_exchange::State::from(s).into()
+    } else {
+        s.into()
+    }
+}
@@ -65,6 +65,7 @@ impl State {
         self.ensure_valid_algorithms_selected(&algorithms)?;
         self.algorithms = Some(algorithms);
         transcript.extend(buf)?;
+        transcript.mark_vca();
         Ok(self.into())
     }
 
@@ -192,7 +193,7 @@ fn config_to_negotiate_algorithms_msg(
         num_algorithm_requests: 4,
         algorithm_requests: [
             AlgorithmRequest::Dhe(DheAlgorithm {
-                supported: DheFixedAlgorithms::FFDHE_3072
+                supported: DheFixedAlgorithms::SECP_256_R1
                     | DheFixedAlgorithms::SECP_384_R1,
             }),
             AlgorithmRequest::Aead(AeadAlgorithm {
@@ -4,36 +4,86 @@
 
 use core::convert::From;
 
-use super::{expect, id_auth, RequesterError};
-use crate::config::MAX_CERT_CHAIN_SIZE;
+use super::{
+    expect, id_auth, session, verify_responder_signature, RequesterError,
+};
+use crate::config::{MAX_CERT_CHAIN_SIZE, MAX_DIGEST_SIZE, TIME_FLOOR};
 use crate::crypto::{
     digest::{Digest, DigestImpl},
     pki::{new_end_entity_cert, EndEntityCert},
 };
 use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::challenge::{PROVISIONED_KEY_SLOT, PROVISIONED_KEY_SLOT_ID};
 use crate::msgs::{
-    Algorithms, CertificateChain, Challenge, ChallengeAuth,
+    Algorithms, Certificate, CertificateChain, Challenge, ChallengeAuth,
     MeasurementHashType, Msg, VersionEntry, HEADER_SIZE,
 };
 
 use crate::Transcript;
 
-// This is purposefully hardcoded as device certs mostly will not expire and we
-// need *some* valid time. Furthermore, during early boot we will not have
-// access to a trusted source of time.
-//
-// An alternative would be to disable the time checck in a patched version of
-//  WebPKI.
-//
-// This may not work for all consumers of this library.
-// Tracked in https://github.com/oxidecomputer/spdm/issues/31
-//
-// December 1, 2021 00:00:00 GMT
-const UNIX_TIME: u64 = 1638316800;
-
-#[derive(Debug, PartialEq, Eq)]
-pub enum Transition {
-    Placeholder,
+/// A trusted source of the current time, used to check the validity periods
+/// of the responder's certificates
+pub trait TimeSource {
+    /// Return the number of seconds since the unix epoch, or `None` if the
+    /// time is unknown, such as during early boot.
+    fn now(&self) -> Option<u64>;
+}
+
+/// The time source of requesters without a clock, for which the time is
+/// always unknown
+pub struct NoTimeSource;
+
+impl TimeSource for NoTimeSource {
+    fn now(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// How validity periods of certificates are checked when the time is unknown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownTimePolicy {
+    /// The chain is validated at its issuance time: the latest notBefore time
+    /// of the leaf and intermediate certificates, the earliest time at which
+    /// the whole chain can be valid. Certificates that have expired since are
+    /// accepted, but not a chain in which a certificate expired before another
+    /// one became valid. The validity period of the root cert is never
+    /// checked, as with any other policy.
+    IssuanceTime,
+
+    /// Validity periods are checked at the given number of seconds since the
+    /// unix epoch, which should be a time the requester is known to run
+    /// after, such as its build time.
+    Floor(u64),
+}
+
+impl Default for UnknownTimePolicy {
+    fn default() -> Self {
+        UnknownTimePolicy::Floor(TIME_FLOOR)
+    }
+}
+
+impl UnknownTimePolicy {
+    /// Return the time to check validity periods at, given the current time
+    /// if known, or `None` to check them at the issuance time of the
+    /// certificate chain.
+    pub fn time(self, now: Option<u64>) -> Option<u64> {
+        match (now, self) {
+            (Some(now), _) => Some(now),
+            (None, UnknownTimePolicy::IssuanceTime) => None,
+            (None, UnknownTimePolicy::Floor(floor)) => Some(floor),
+        }
+    }
+}
+
+/// What the certificate chain of the responder is verified against
+#[derive(Debug, Clone, Copy)]
+pub struct Validation<'a> {
+    // The root certs, one of which the chain must lead to
+    pub root_certs: &'a [&'a [u8]],
+
+    // The time the certificates must be valid at, in seconds since the unix
+    // epoch, or `None` for the issuance time of the chain
+    pub time: Option<u64>,
 }
 
 /// Perform challenge-response authentication using the certificate chain
@@ -50,6 +100,15 @@ pub struct State {
     pub cert_chain: [u8; MAX_CERT_CHAIN_SIZE],
     pub cert_chain_size: u16,
     pub nonce: [u8; 32],
+
+    // The index of the root cert the certificate chain leads to, once
+    // verified
+    pub trust_anchor: Option<usize>,
+
+    // The measurement summary hash requested in CHALLENGE, and the verified
+    // summary hash returned in CHALLENGE_AUTH.
+    pub measurement_hash_type: MeasurementHashType,
+    pub measurement_summary_hash: [u8; MAX_DIGEST_SIZE],
 }
 
 impl From<id_auth::State> for State {
@@ -65,21 +124,64 @@ impl From<id_auth::State> for State {
             cert_chain: s.cert_chain.as_ref().unwrap().cert_chain,
             cert_chain_size: s.cert_chain.unwrap().portion_length,
             nonce: [0u8; 32],
+            trust_anchor: None,
+            measurement_hash_type: MeasurementHashType::None,
+            measurement_summary_hash: [0u8; MAX_DIGEST_SIZE],
         }
     }
 }
 
 impl State {
+    /// Challenge the responder as slot 0xF, with a public key provisioned in
+    /// a trusted environment instead of a retrieved certificate chain.
+    ///
+    /// The public key is kept in place of the certificate chain, as its digest
+    /// stands in for that of the chain in transcripts.
+    pub fn with_public_key(mut s: id_auth::State, public_key: &[u8]) -> State {
+        let mut cert = Certificate {
+            slot: PROVISIONED_KEY_SLOT,
+            portion_length: public_key.len() as u16,
+            remainder_length: 0,
+            cert_chain: [0u8; MAX_CERT_CHAIN_SIZE],
+        };
+        cert.cert_chain[..public_key.len()].copy_from_slice(public_key);
+        s.cert_chain = Some(cert);
+        s.into()
+    }
+
+    /// Challenge the responder with the certificate chain retrieved from
+    /// `cert.slot`, rather than the one selected when leaving IdAuth.
+    pub fn set_cert_chain(&mut self, cert: &Certificate<MAX_CERT_CHAIN_SIZE>) {
+        self.cert_slot = cert.slot;
+        self.cert_chain = cert.cert_chain;
+        self.cert_chain_size = cert.portion_length;
+    }
+
     /// Write a CHALLENGE msg to the buffer, and append it to the transcript.
+    ///
+    /// No measurement summary hash is requested if the responder does not
+    /// support measurements.
     pub fn write_msg<'a>(
         &mut self,
+        measurement_hash_type: MeasurementHashType,
         buf: &'a mut [u8],
         transcript: &mut Transcript,
     ) -> Result<&'a [u8], RequesterError> {
-        // We plan to have the user retrieve measurements in a secure channel
-        // Is there also a need to retrieve them during challenge-response?
-        let measurement_hash_type = MeasurementHashType::None;
-        let challenge = Challenge::new(self.cert_slot, measurement_hash_type);
+        let measurement_hash_type = if self
+            .responder_cap
+            .intersects(RspFlags::MEAS_CAP_NO_SIG | RspFlags::MEAS_CAP_SIG)
+        {
+            measurement_hash_type
+        } else {
+            MeasurementHashType::None
+        };
+        self.measurement_hash_type = measurement_hash_type;
+        let slot = if self.cert_slot == PROVISIONED_KEY_SLOT {
+            PROVISIONED_KEY_SLOT_ID
+        } else {
+            self.cert_slot
+        };
+        let challenge = Challenge::new(slot, measurement_hash_type);
         self.nonce = challenge.nonce;
         let size = challenge.write(buf).map_err(|e| RequesterError::from(e))?;
         transcript.extend(&buf[..size])?;
@@ -88,13 +190,15 @@ impl State {
 
     /// Process a received responder message.
     ///
-    /// Only CHALLENGE_AUTH msgs are acceptable here.
+    /// Only CHALLENGE_AUTH msgs are acceptable here. Return the verified
+    /// state, and whether the responder requested basic mutual
+    /// authentication.
     pub fn handle_msg(
-        self,
+        mut self,
         buf: &[u8],
         transcript: &mut Transcript,
-        root_cert: &[u8],
-    ) -> Result<Transition, RequesterError> {
+        validation: &Validation,
+    ) -> Result<(session::State, bool), RequesterError> {
         expect::<ChallengeAuth>(buf)?;
         let hash_algo = self.algorithms.base_hash_algo_selected;
 
@@ -109,12 +213,18 @@ impl State {
         if rsp.slot != self.cert_slot {
             return Err(RequesterError::BadChallengeAuth);
         }
+        // Basic mutual authentication requires encapsulated requests
+        if rsp.use_mutual_auth && !self.mutual_auth_supported() {
+            return Err(RequesterError::BadChallengeAuth);
+        }
         // Provisioned certs don't need retrieval
-        if (rsp.slot != 0x0F) && (rsp.slot_mask != (1 << rsp.slot)) {
+        if (rsp.slot != PROVISIONED_KEY_SLOT)
+            && (rsp.slot_mask & (1 << rsp.slot) == 0)
+        {
             return Err(RequesterError::BadChallengeAuth);
         }
 
-        // TODO: Handle pre-provisioned certs
+        // This is the digest of the public key for provisioned keys
         let digest = DigestImpl::hash(
             hash_algo,
             &self.cert_chain[..self.cert_chain_size as usize],
@@ -124,7 +234,13 @@ impl State {
             return Err(RequesterError::BadChallengeAuth);
         }
 
-        // TODO: Do something with returned measurements
+        // The summary hash is only trusted once the signature is verified
+        // below.
+        if self.measurement_hash_type != MeasurementHashType::None {
+            self.measurement_summary_hash[..digest_size as usize]
+                .copy_from_slice(rsp.measurement_summary_hash());
+        }
+
         // TODO: Do something with opaque data
 
         // Generate M2 as in the SPDM spec by extending the transcript with the
@@ -133,43 +249,78 @@ impl State {
         transcript.extend(&buf[..sig_start])?;
         let m2_hash = DigestImpl::hash(hash_algo, transcript.get());
 
-        self.verify_cert_chain_and_signature(
+        self.trust_anchor = self.verify_cert_chain_and_signature(
             digest_size,
             m2_hash.as_ref(),
             rsp.signature(),
-            root_cert,
-            UNIX_TIME,
-        )
+            validation,
+        )?;
+
+        // Any subsequent transcript starts after the VCA messages
+        transcript.reset_to_vca();
+
+        Ok((self.into(), rsp.use_mutual_auth))
     }
 
+    // Basic mutual authentication requires both sides to set MUT_AUTH_CAP and
+    // ENCAP_CAP, and a requester signing algorithm to be negotiated.
+    fn mutual_auth_supported(&self) -> bool {
+        self.requester_cap
+            .contains(ReqFlags::MUT_AUTH_CAP | ReqFlags::ENCAP_CAP)
+            && self
+                .responder_cap
+                .contains(RspFlags::MUT_AUTH_CAP | RspFlags::ENCAP_CAP)
+            && self.algorithms.req_base_asym_algo_selected().is_some()
+    }
+
+    // Verify the certificate chain, and return the index of the root cert it
+    // leads to, unless a provisioned public key was challenged.
     fn verify_cert_chain_and_signature(
         &self,
         digest_size: u8,
         m2_hash: &[u8],
         signature: &[u8],
-        root_cert: &[u8],
-        seconds_since_unix_epoch: u64,
-    ) -> Result<Transition, RequesterError> {
+        validation: &Validation,
+    ) -> Result<Option<usize>, RequesterError> {
         let cert_chain_buf = &self.cert_chain[..self.cert_chain_size as usize];
-        let cert_chain = CertificateChain::parse(cert_chain_buf, digest_size)?;
 
-        let end_entity_cert = new_end_entity_cert(cert_chain.leaf_cert)?;
+        // A provisioned public key is trusted as is
+        let mut trust_anchor = None;
+        if self.cert_slot != PROVISIONED_KEY_SLOT {
+            let cert_chain =
+                CertificateChain::parse(cert_chain_buf, digest_size)?;
 
-        end_entity_cert.verify_chain_of_trust(
-            self.algorithms.base_asym_algo_selected,
-            cert_chain.intermediate_certs(),
-            root_cert,
-            seconds_since_unix_epoch,
-        )?;
+            // The chain must claim to be rooted at a trusted root cert, and is
+            // only verified against that one.
+            let hash_algo = self.algorithms.base_hash_algo_selected;
+            let root_certs = validation.root_certs;
+            let i = match root_certs.iter().position(|r| {
+                DigestImpl::hash(hash_algo, r).as_ref() == cert_chain.root_hash
+            }) {
+                Some(i) => i,
+                None => return Err(RequesterError::UntrustedRootHash),
+            };
+
+            let end_entity_cert = new_end_entity_cert(cert_chain.leaf_cert)?;
+            end_entity_cert.verify_chain_of_trust(
+                self.algorithms.base_asym_algo_selected,
+                cert_chain.intermediate_certs(),
+                &root_certs[i..=i],
+                validation.time,
+            )?;
+            trust_anchor = Some(i);
+        }
 
-        if !end_entity_cert.verify_signature(
-            self.algorithms.base_asym_algo_selected,
+        if !verify_responder_signature(
+            &self.algorithms,
+            self.cert_slot,
+            cert_chain_buf,
             m2_hash,
             signature,
-        ) {
+        )? {
             return Err(RequesterError::BadChallengeAuth);
         }
 
-        Ok(Transition::Placeholder)
+        Ok(trust_anchor)
     }
 }
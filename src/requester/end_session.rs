@@ -0,0 +1,38 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{expect, RequesterError};
+use crate::msgs::{EndSession, EndSessionAck, Msg, HEADER_SIZE};
+
+/// End an established session via END_SESSION
+///
+/// The END_SESSION_ACK is the last message received in the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    // Ask the responder to also forget the negotiated state
+    pub negotiated_state_clearing: bool,
+}
+
+impl State {
+    /// Write an END_SESSION msg to the buffer
+    pub fn write_msg<'a>(
+        &self,
+        buf: &'a mut [u8],
+    ) -> Result<&'a [u8], RequesterError> {
+        let msg = EndSession {
+            negotiated_state_clearing: self.negotiated_state_clearing,
+        };
+        let size = msg.write(buf)?;
+        Ok(&buf[..size])
+    }
+
+    /// Process a received responder message.
+    ///
+    /// Only an END_SESSION_ACK is acceptable here.
+    pub fn handle_msg(&self, buf: &[u8]) -> Result<(), RequesterError> {
+        expect::<EndSessionAck>(buf)?;
+        EndSessionAck::parse_body(&buf[HEADER_SIZE..])?;
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@ use core::fmt::{self, Display, Formatter};
 
 use crate::crypto::pki;
 use crate::msgs::{ReadError, Version, WriteError};
+use crate::secured_message;
 
 /// A requester specific error returned from state machine methods
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -30,9 +31,77 @@ pub enum RequesterError {
     // A certificate could not be parsed properly
     InvalidCert,
 
+    // The root hash of the responder's certificate chain is not the hash of
+    // any trusted root cert
+    UntrustedRootHash,
+
+    // A CERTIFICATE response did not match the GET_CERTIFICATE request, or
+    // the certificate chain does not fit in MAX_CERT_CHAIN_SIZE
+    BadCertificate,
+
+    // None of the slots to retrieve has a certificate chain
+    NoCertChain,
+
     // Protocol initialization is complete and a secure session now exists.
     // The user must transition to the `RequesterSession` state.
     InitializationComplete,
+
+    // A session was begun before protocol initialization was complete
+    InitializationIncomplete,
+
+    // The responder does not support the requested measurements
+    MeasurementsUnsupported,
+
+    // The MEASUREMENTS response was invalid or its signature did not verify
+    BadMeasurements,
+
+    // A request or response was handled when none was expected
+    NoRequestInProgress,
+
+    // The KEY_EXCHANGE_RSP was invalid, or its signature or verify data did
+    // not verify
+    BadKeyExchange,
+
+    // An ephemeral key could not be generated
+    KeyExchangeFailed,
+
+    // The FINISH_RSP was invalid, or its verify data did not verify
+    BadFinish,
+
+    // The PSK_EXCHANGE_RSP was invalid, or its verify data did not verify
+    BadPskExchange,
+
+    // A secured message was sent or received without a secure session
+    NoSecureSession,
+
+    // The requester or responder does not support KEY_UPDATE
+    KeyUpdateUnsupported,
+
+    // The KEY_UPDATE_ACK does not match the outstanding KEY_UPDATE
+    BadKeyUpdate,
+
+    // The responder did not set a heartbeat period for the session
+    HeartbeatUnsupported,
+
+    // The responder requested mutual authentication with a slot that has no
+    // certificate chain, or whose signing algorithm was not negotiated. Also
+    // returned when a slot without a retrieved certificate chain is selected
+    // for CHALLENGE, or one is selected outside of the Challenge state.
+    InvalidSlot,
+
+    // The requester's signer failed to sign the transcript
+    SigningFailed,
+
+    // The provisioned public key of the responder is larger than
+    // MAX_CERT_CHAIN_SIZE
+    InvalidPublicKey,
+
+    // The responder sent an encapsulated request that is not supported, or
+    // cannot be answered
+    BadEncapsulatedRequest,
+
+    // A secured message could not be encoded or decoded
+    SecuredMessage(secured_message::Error),
 }
 
 impl From<WriteError> for RequesterError {
@@ -47,6 +116,12 @@ impl From<ReadError> for RequesterError {
     }
 }
 
+impl From<secured_message::Error> for RequesterError {
+    fn from(e: secured_message::Error) -> Self {
+        RequesterError::SecuredMessage(e)
+    }
+}
+
 impl From<pki::Error> for RequesterError {
     fn from(_: pki::Error) -> Self {
         RequesterError::InvalidCert
@@ -79,12 +154,72 @@ requester does not support"
             RequesterError::BadChallengeAuth => {
                 write!(f, "challenge authentication failed")
             }
+            RequesterError::BadCertificate => {
+                write!(f, "invalid certificate response")
+            }
+            RequesterError::NoCertChain => {
+                write!(f, "no certificate chain to retrieve")
+            }
             RequesterError::InvalidCert => {
                 write!(f, "invalid certificate")
             }
+            RequesterError::UntrustedRootHash => {
+                write!(f, "certificate chain root hash is not trusted")
+            }
             RequesterError::InitializationComplete => {
                 write!(f, "initialization complete")
             }
+            RequesterError::InitializationIncomplete => {
+                write!(f, "initialization is not complete")
+            }
+            RequesterError::MeasurementsUnsupported => {
+                write!(f, "the responder does not support the measurements")
+            }
+            RequesterError::BadMeasurements => {
+                write!(f, "measurement retrieval failed")
+            }
+            RequesterError::NoRequestInProgress => {
+                write!(f, "no request in progress")
+            }
+            RequesterError::BadKeyExchange => {
+                write!(f, "key exchange failed verification")
+            }
+            RequesterError::KeyExchangeFailed => {
+                write!(f, "key exchange failed")
+            }
+            RequesterError::BadFinish => {
+                write!(f, "session handshake failed verification")
+            }
+            RequesterError::BadPskExchange => {
+                write!(f, "PSK exchange failed verification")
+            }
+            RequesterError::NoSecureSession => {
+                write!(f, "no secure session established")
+            }
+            RequesterError::KeyUpdateUnsupported => {
+                write!(f, "key update is not supported")
+            }
+            RequesterError::BadKeyUpdate => {
+                write!(f, "key update was not acknowledged")
+            }
+            RequesterError::HeartbeatUnsupported => {
+                write!(f, "heartbeats are not used in this session")
+            }
+            RequesterError::InvalidSlot => {
+                write!(f, "invalid slot requested")
+            }
+            RequesterError::InvalidPublicKey => {
+                write!(f, "invalid provisioned public key")
+            }
+            RequesterError::SigningFailed => {
+                write!(f, "signing failed")
+            }
+            RequesterError::BadEncapsulatedRequest => {
+                write!(f, "bad encapsulated request")
+            }
+            RequesterError::SecuredMessage(e) => {
+                write!(f, "secured message error: {:?}", e)
+            }
         }
     }
 }
@@ -0,0 +1,266 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{
+    expect, handshake_channels, open_secured, session, write_secured,
+    RequesterError, MAX_HANDSHAKE_RSP_RECORD_SIZE,
+};
+use crate::config::{
+    MAX_CERT_CHAIN_SIZE, MAX_DIGEST_SIZE, MAX_SIGNATURE_SIZE, NUM_SLOTS,
+};
+use crate::crypto::{
+    digest::{Digest, DigestImpl},
+    key_schedule::{self, HandshakeSecrets, SessionKeys},
+    Aead, FilledSlot, Signer,
+};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::{
+    encoding::Writer, Algorithms, Finish, FinishRsp, MeasurementHashType, Msg,
+    VersionEntry, HEADER_SIZE,
+};
+use crate::secured_message::{handshake_in_the_clear, Channels};
+use crate::Transcript;
+
+/// Complete the handshake of the session created by KEY_EXCHANGE
+///
+/// The transcript contains the VCA messages, the hash of the responder's
+/// certificate chain, and the KEY_EXCHANGE and KEY_EXCHANGE_RSP msgs when this
+/// state is entered. With mutual authentication, the hash of the requester's
+/// certificate chain is added before FINISH.
+///
+/// Unless the handshake is in the clear, FINISH and FINISH_RSP are sent
+/// within the session, protected by the handshake keys.
+#[derive(Debug)]
+pub struct State {
+    pub version: VersionEntry,
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+    pub cert_slot: u8,
+    pub cert_chain: [u8; MAX_CERT_CHAIN_SIZE],
+    pub cert_chain_size: u16,
+    pub trust_anchor: Option<usize>,
+    pub measurement_hash_type: MeasurementHashType,
+    pub measurement_summary_hash: [u8; MAX_DIGEST_SIZE],
+
+    pub session_id: u32,
+    pub heartbeat_period: u8,
+
+    // The slot of the requester's certificate chain, if the responder
+    // requested mutual authentication
+    pub req_slot_id: Option<u8>,
+    pub handshake_secrets: HandshakeSecrets,
+}
+
+impl State {
+    /// Write a FINISH msg to the buffer, and extend the transcript with it.
+    ///
+    /// If the responder requested mutual authentication, the transcript is
+    /// signed with the key of the requested slot. Unless the handshake is in
+    /// the clear, the msg is written as a secured message of the session.
+    pub fn write_msg<'a, 'b, S: Signer, A: Aead>(
+        &mut self,
+        slots: &[Option<FilledSlot<'b, S>>; NUM_SLOTS],
+        buf: &'a mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<&'a [u8], RequesterError> {
+        match self.channels() {
+            Some(mut channels) => {
+                write_secured::<A>(&mut channels, buf, |buf| {
+                    Ok(self.write_finish(slots, buf, transcript)?.len())
+                })
+            }
+            None => self.write_finish(slots, buf, transcript),
+        }
+    }
+
+    /// Return true if FINISH and FINISH_RSP are sent within the session
+    pub fn is_secured(&self) -> bool {
+        !self.handshake_in_the_clear()
+    }
+
+    // Return the handshake keys, unless the handshake is in the clear
+    fn channels(&self) -> Option<Channels> {
+        if self.handshake_in_the_clear() {
+            return None;
+        }
+        Some(handshake_channels(
+            self.session_id,
+            self.requester_cap,
+            self.responder_cap,
+            &self.algorithms,
+            &self.handshake_secrets,
+        ))
+    }
+
+    // Write the FINISH msg itself
+    fn write_finish<'a, 'b, S: Signer>(
+        &mut self,
+        slots: &[Option<FilledSlot<'b, S>>; NUM_SLOTS],
+        buf: &'a mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<&'a [u8], RequesterError> {
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+
+        let slot = match self.req_slot_id {
+            Some(req_slot_id) => {
+                let slot = match slots.get(req_slot_id as usize) {
+                    Some(Some(slot)) => slot,
+                    _ => return Err(RequesterError::InvalidSlot),
+                };
+                if self.algorithms.req_base_asym_algo_selected()
+                    != Some(slot.signing_algorithm)
+                {
+                    return Err(RequesterError::InvalidSlot);
+                }
+                Some(slot)
+            }
+            None => None,
+        };
+
+        // The signature and verify data are computed over the serialized
+        // request, so we serialize with placeholders and overwrite them in
+        // place.
+        let signature_size =
+            slot.map_or(0, |slot| slot.signing_algorithm.get_signature_size());
+        let msg = Finish {
+            req_slot_id: self.req_slot_id.unwrap_or(0),
+            digest_size,
+            signature_size,
+            signature: slot.map(|_| [0u8; MAX_SIGNATURE_SIZE]),
+            ..Finish::default()
+        };
+        let size = msg.write(buf)?;
+        let verify_start = size - digest_size as usize;
+
+        if let Some(slot) = slot {
+            // The hash of the requester's certificate chain (CM in the SPDM
+            // spec) precedes FINISH in the transcript.
+            let mut cert_chain = [0u8; MAX_CERT_CHAIN_SIZE];
+            let mut w = Writer::new("CERTIFICATE_CHAIN", &mut cert_chain);
+            let cert_chain_size = slot.cert_chain.write(&mut w)?;
+            let cert_chain_digest =
+                DigestImpl::hash(hash_algo, &cert_chain[..cert_chain_size]);
+            transcript.extend(cert_chain_digest.as_ref())?;
+
+            let sig_start = verify_start - signature_size;
+            transcript.extend(&buf[..sig_start])?;
+            let th_hash = DigestImpl::hash(hash_algo, transcript.get());
+            let signature = slot
+                .signer
+                .sign(th_hash.as_ref())
+                .map_err(|_| RequesterError::SigningFailed)?;
+            buf[sig_start..verify_start].copy_from_slice(signature.as_ref());
+            transcript.extend(&buf[sig_start..verify_start])?;
+        } else {
+            transcript.extend(&buf[..verify_start])?;
+        }
+
+        let th_hash = DigestImpl::hash(hash_algo, transcript.get());
+        let finished_key = key_schedule::finished_key(
+            hash_algo,
+            &self.handshake_secrets.request,
+        );
+        let verify_data = key_schedule::hmac(
+            hash_algo,
+            finished_key.as_ref(),
+            th_hash.as_ref(),
+        );
+        buf[verify_start..size].copy_from_slice(verify_data.as_ref());
+        transcript.extend(&buf[verify_start..size])?;
+
+        Ok(&buf[..size])
+    }
+
+    /// Process a received responder message.
+    ///
+    /// Only FINISH_RSP msgs are acceptable here. Unless the handshake is in
+    /// the clear, the msg must be a secured message of the session. On
+    /// success, the session keys are derived and initialization is complete.
+    pub fn handle_msg<A: Aead>(
+        self,
+        buf: &[u8],
+        transcript: &mut Transcript,
+    ) -> Result<session::State, RequesterError> {
+        let mut record = [0u8; MAX_HANDSHAKE_RSP_RECORD_SIZE];
+        let buf = match self.channels() {
+            Some(mut channels) => {
+                open_secured::<A>(&mut channels, buf, &mut record)?
+            }
+            None => buf,
+        };
+        expect::<FinishRsp>(buf)?;
+
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+        let rsp = FinishRsp::parse_body(
+            &buf[HEADER_SIZE..],
+            digest_size,
+            self.handshake_in_the_clear(),
+        )?;
+
+        match rsp.verify_data() {
+            Some(verify_data) => {
+                let verify_start = buf.len() - digest_size as usize;
+                transcript.extend(&buf[..verify_start])?;
+                let th_hash = DigestImpl::hash(hash_algo, transcript.get());
+                let finished_key = key_schedule::finished_key(
+                    hash_algo,
+                    &self.handshake_secrets.response,
+                );
+                if !key_schedule::verify_hmac(
+                    hash_algo,
+                    finished_key.as_ref(),
+                    th_hash.as_ref(),
+                    verify_data,
+                ) {
+                    return Err(RequesterError::BadFinish);
+                }
+                transcript.extend(verify_data)?;
+            }
+            None => {
+                transcript.extend(buf)?;
+            }
+        }
+
+        let th2 = key_schedule::th2(hash_algo, transcript);
+        // Unwrap is safe, as we only perform a key exchange if an AEAD
+        // algorithm was negotiated.
+        let session_keys = SessionKeys::new(
+            hash_algo,
+            self.algorithms.aead_algo_selected().unwrap(),
+            &self.handshake_secrets.handshake_secret,
+            th2.as_ref(),
+        );
+
+        // Any subsequent measurement transcript starts after the VCA messages
+        transcript.reset_to_vca();
+
+        Ok(session::State {
+            version: self.version,
+            requester_ct_exponent: self.requester_ct_exponent,
+            requester_cap: self.requester_cap,
+            responder_ct_exponent: self.responder_ct_exponent,
+            responder_cap: self.responder_cap,
+            algorithms: self.algorithms,
+            cert_slot: self.cert_slot,
+            cert_chain: self.cert_chain,
+            cert_chain_size: self.cert_chain_size,
+            trust_anchor: self.trust_anchor,
+            measurement_hash_type: self.measurement_hash_type,
+            measurement_summary_hash: self.measurement_summary_hash,
+            session_id: Some(self.session_id),
+            session_keys: Some(session_keys),
+            heartbeat_period: self.heartbeat_period,
+        })
+    }
+
+    // The ResponderVerifyData is only sent if the handshake is in the clear
+    fn handshake_in_the_clear(&self) -> bool {
+        handshake_in_the_clear(self.requester_cap, self.responder_cap)
+    }
+}
@@ -0,0 +1,33 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{expect, RequesterError};
+use crate::msgs::{Heartbeat, HeartbeatAck, Msg, HEADER_SIZE};
+
+/// Keep an established session alive via HEARTBEAT
+///
+/// A HEARTBEAT is sent whenever the heartbeat period elapsed without any
+/// other secured message being sent in the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {}
+
+impl State {
+    /// Write a HEARTBEAT msg to the buffer
+    pub fn write_msg<'a>(
+        &self,
+        buf: &'a mut [u8],
+    ) -> Result<&'a [u8], RequesterError> {
+        let size = Heartbeat {}.write(buf)?;
+        Ok(&buf[..size])
+    }
+
+    /// Process a received responder message.
+    ///
+    /// Only a HEARTBEAT_ACK is acceptable here.
+    pub fn handle_msg(&self, buf: &[u8]) -> Result<(), RequesterError> {
+        expect::<HeartbeatAck>(buf)?;
+        HeartbeatAck::parse_body(&buf[HEADER_SIZE..])?;
+        Ok(())
+    }
+}
@@ -4,8 +4,11 @@
 
 use core::convert::From;
 
-use super::{algorithms, challenge, expect, RequesterError};
-use crate::config::{MAX_CERT_CHAIN_SIZE, NUM_SLOTS};
+use super::{algorithms, expect, RequesterError};
+use core::cmp::min;
+
+use crate::config::{CERT_PORTION_SIZE, MAX_CERT_CHAIN_SIZE, NUM_SLOTS};
+use crate::crypto::digest::{Digest, DigestImpl};
 use crate::msgs::capabilities::{ReqFlags, RspFlags};
 use crate::msgs::{
     Algorithms, Certificate, Digests, GetCertificate, GetDigests, Msg,
@@ -13,6 +16,83 @@ use crate::msgs::{
 };
 use crate::Transcript;
 
+/// A cache of responder certificate chains, keyed by their digest
+///
+/// A requester that finds the digest of a slot from DIGESTS in the cache uses
+/// the cached chain, rather than retrieving it with GET_CERTIFICATE. Cached
+/// chains are still verified in full before a responder is authenticated, as
+/// with retrieved ones. How chains are stored is up to the user of this
+/// library, so that a cache can outlive a requester, and be shared by the
+/// requesters of many responders.
+pub trait CertCache {
+    /// Return the certificate chain whose digest is `digest`, or `None` if it
+    /// is not cached.
+    fn get(&self, digest: &[u8]) -> Option<&[u8]>;
+
+    /// Cache `cert_chain`, whose digest is `digest`, replacing any chain
+    /// cached for the same digest.
+    fn insert(&mut self, digest: &[u8], cert_chain: &[u8]);
+}
+
+impl<T: CertCache + ?Sized> CertCache for &mut T {
+    fn get(&self, digest: &[u8]) -> Option<&[u8]> {
+        (**self).get(digest)
+    }
+
+    fn insert(&mut self, digest: &[u8], cert_chain: &[u8]) {
+        (**self).insert(digest, cert_chain)
+    }
+}
+
+/// A `CertCache` that caches nothing, for requesters that always retrieve
+/// certificate chains.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NoCertCache;
+
+impl CertCache for NoCertCache {
+    fn get(&self, _digest: &[u8]) -> Option<&[u8]> {
+        None
+    }
+
+    fn insert(&mut self, _digest: &[u8], _cert_chain: &[u8]) {}
+}
+
+/// The certificate chains of the responder
+///
+/// These are kept outside of the requester states, as they are too large to
+/// be moved between them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertChains {
+    // The slots to retrieve certificate chains from
+    pub slot_mask: u8,
+
+    // The chain of each slot, once retrieved. `portion_length` is the size of
+    // each chain.
+    pub chains: [Option<Certificate<MAX_CERT_CHAIN_SIZE>>; NUM_SLOTS],
+}
+
+impl Default for CertChains {
+    fn default() -> Self {
+        CertChains::new()
+    }
+}
+
+impl CertChains {
+    pub fn new() -> CertChains {
+        CertChains { slot_mask: 0xFF, chains: core::array::from_fn(|_| None) }
+    }
+
+    /// Return the certificate chain of `slot`, if it was retrieved
+    pub fn get(&self, slot: u8) -> Option<&[u8]> {
+        match self.chains.get(slot as usize) {
+            Some(Some(cert)) => {
+                Some(&cert.cert_chain[..cert.portion_length as usize])
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Id<'a> {
     PubKey(&'a [u8]),
@@ -24,9 +104,9 @@ pub enum Id<'a> {
 /// This state encapsulates the sending of GET_DIGESTS and GET_CERTIFICATE
 /// requests and their correspdonging repsonses.
 ///
-/// TODO: This code currently allows skipping both GET_DIGESTS and
-/// GET_CERTIFICATE, but certificate caching is currently not done, and so you
-/// cannot skip just GET_CERTIFICATE.
+/// GET_CERTIFICATE is skipped for the slots whose certificate chain is found
+/// in a `CertCache` by its digest. The transcript then only contains
+/// GET_DIGESTS and DIGESTS, like that of the responder.
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct State {
     pub version: VersionEntry,
@@ -39,7 +119,18 @@ pub struct State {
     // GET_DIGESTS and GET_CERTIFICATE messages will not be issued if the public
     // key of the responder was provisioned in a trusted environment.
     pub digests: Option<Digests<NUM_SLOTS>>,
+
+    // The certificate chain assembled from the portions received so far.
+    // `portion_length` is the size of the assembled chain, and
+    // `remainder_length` the size still to be retrieved. Once all chains are
+    // retrieved, this is the chain of the slot selected for CHALLENGE.
     pub cert_chain: Option<Certificate<MAX_CERT_CHAIN_SIZE>>,
+
+    // The outstanding GET_CERTIFICATE request
+    pub get_certificate: Option<GetCertificate>,
+
+    // The slots whose certificate chain was retrieved
+    pub retrieved_slots: u8,
 }
 
 impl From<algorithms::State> for State {
@@ -53,6 +144,8 @@ impl From<algorithms::State> for State {
             algorithms: s.algorithms.unwrap(),
             digests: None,
             cert_chain: None,
+            get_certificate: None,
+            retrieved_slots: 0,
         }
     }
 }
@@ -75,9 +168,8 @@ impl State {
 
     /// Handle a DIGESTS msg.
     ///
-    /// TODO: For now this takes &mut self since we don't support certificate caching
-    /// yet. In the future this will likely take self and return a Transition, or this
-    /// state will be split into 2 separate states.
+    /// This takes &mut self, as the state is only left once all certificate
+    /// chains are retrieved, or found in a cache.
     pub fn handle_digests(
         &mut self,
         buf: &[u8],
@@ -92,8 +184,11 @@ impl State {
         Ok(())
     }
 
-    /// Write a GET_CERTIFICATE msg to the buffer and record it in the
-    /// transcript.
+    /// Write a GET_CERTIFICATE msg for the next portion of the certificate
+    /// chain in `slot` to the buffer and record it in the transcript.
+    ///
+    /// At most `CERT_PORTION_SIZE` bytes are requested at once, so that
+    /// chains can be retrieved over transports with a small MTU.
     pub fn write_get_certificate_msg<'a>(
         &mut self,
         slot: u8,
@@ -102,28 +197,402 @@ impl State {
     ) -> Result<&'a [u8], RequesterError> {
         assert!(MAX_CERT_CHAIN_SIZE < 65536);
         assert!((slot as usize) < NUM_SLOTS);
-        // TODO: Allow retrieiving cert chains from offsets. We assume for now
-        // buffers are large enough to retrieve them in one round trip.
+        let (offset, remainder) = match &self.cert_chain {
+            Some(cert) => {
+                (cert.portion_length as usize, cert.remainder_length as usize)
+            }
+            None => (0, MAX_CERT_CHAIN_SIZE),
+        };
         let msg = GetCertificate {
             slot,
-            offset: 0,
-            length: MAX_CERT_CHAIN_SIZE as u16,
+            offset: offset as u16,
+            length: min(CERT_PORTION_SIZE, remainder) as u16,
         };
         let size = msg.write(buf)?;
         transcript.extend(&buf[..size])?;
+        self.get_certificate = Some(msg);
         Ok(&buf[..size])
     }
 
-    // Handle a CERTFICATE msg.
+    /// Return the slot whose certificate chain is retrieved next, out of the
+    /// slots in `slot_mask` that the responder sent a digest for.
+    ///
+    /// `None` is returned once all of their chains are retrieved.
+    pub fn next_slot(&self, slot_mask: u8) -> Option<u8> {
+        // A chain is retrieved entirely before moving to the next one
+        if let Some(cert) = &self.cert_chain {
+            return Some(cert.slot);
+        }
+        let digests_mask = self.digests.as_ref().map_or(0, |d| d.slot_mask);
+        let remaining = digests_mask & slot_mask & !self.retrieved_slots;
+        if remaining == 0 {
+            None
+        } else {
+            Some(remaining.trailing_zeros() as u8)
+        }
+    }
+
+    /// Handle a CERTIFICATE msg, and append its portion to the certificate
+    /// chain.
+    ///
+    /// Return true once the entire chain is retrieved, at which point it is
+    /// moved into `cert_chains`. Every portion must come from the slot of the
+    /// first one, and no portion may be larger than requested.
     pub fn handle_certificate(
-        mut self,
+        &mut self,
         buf: &[u8],
         transcript: &mut Transcript,
-    ) -> Result<challenge::State, RequesterError> {
+        cert_chains: &mut CertChains,
+    ) -> Result<bool, RequesterError> {
         expect::<Certificate<MAX_CERT_CHAIN_SIZE>>(buf)?;
-        let cert = Certificate::parse_body(&buf[HEADER_SIZE..])?;
-        self.cert_chain = Some(cert);
+        let req = match self.get_certificate.take() {
+            Some(req) => req,
+            None => return Err(RequesterError::NoRequestInProgress),
+        };
+        let cert = Certificate::<MAX_CERT_CHAIN_SIZE>::parse_body(
+            &buf[HEADER_SIZE..],
+        )?;
+        let portion_length = cert.portion_length as usize;
+        let remainder_length = cert.remainder_length as usize;
+        let offset = req.offset as usize;
+        // An empty portion never makes progress, and an empty first portion
+        // is an empty chain
+        if cert.slot != req.slot
+            || portion_length > req.length as usize
+            || (portion_length == 0 && (remainder_length != 0 || offset == 0))
+        {
+            return Err(RequesterError::BadCertificate);
+        }
+
+        let chain = self.cert_chain.get_or_insert(Certificate {
+            slot: cert.slot,
+            portion_length: 0,
+            remainder_length: 0,
+            cert_chain: [0u8; MAX_CERT_CHAIN_SIZE],
+        });
+        // The total size of the chain must not change between portions
+        if (offset != 0
+            && (chain.slot != cert.slot
+                || chain.remainder_length as usize
+                    != portion_length + remainder_length))
+            || offset + portion_length + remainder_length > MAX_CERT_CHAIN_SIZE
+        {
+            return Err(RequesterError::BadCertificate);
+        }
+        chain.cert_chain[offset..offset + portion_length]
+            .copy_from_slice(&cert.cert_chain[..portion_length]);
+        chain.portion_length = (offset + portion_length) as u16;
+        chain.remainder_length = cert.remainder_length;
+
         transcript.extend(buf)?;
-        Ok(self.into())
+        if remainder_length != 0 {
+            return Ok(false);
+        }
+        self.retrieved_slots |= 1 << cert.slot;
+        cert_chains.chains[cert.slot as usize] = self.cert_chain.take();
+        Ok(true)
+    }
+
+    /// Use the chains found in `cert_cache` for the slots in
+    /// `cert_chains.slot_mask`, rather than retrieving them.
+    ///
+    /// This is done once DIGESTS is handled. A cached chain is only used if it
+    /// matches the digest of its slot.
+    pub fn use_cached_chains<C: CertCache>(
+        &mut self,
+        cert_chains: &mut CertChains,
+        cert_cache: &C,
+    ) {
+        let digests = match &self.digests {
+            Some(digests) => digests,
+            None => return,
+        };
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = digests.digest_size as usize;
+        for slot in 0..NUM_SLOTS {
+            if digests.slot_mask & cert_chains.slot_mask & (1 << slot) == 0 {
+                continue;
+            }
+            let digest = digests.digests[slot].as_slice(digest_size);
+            let chain = match cert_cache.get(digest) {
+                Some(chain) if chain.len() <= MAX_CERT_CHAIN_SIZE => chain,
+                _ => continue,
+            };
+            if DigestImpl::hash(hash_algo, chain).as_ref() != digest {
+                continue;
+            }
+            let mut cert = Certificate {
+                slot: slot as u8,
+                portion_length: chain.len() as u16,
+                remainder_length: 0,
+                cert_chain: [0u8; MAX_CERT_CHAIN_SIZE],
+            };
+            cert.cert_chain[..chain.len()].copy_from_slice(chain);
+            cert_chains.chains[slot] = Some(cert);
+            self.retrieved_slots |= 1 << slot;
+        }
+    }
+
+    /// Add the retrieved chains that match the digests of their slot to
+    /// `cert_cache`, unless they are already cached.
+    pub fn cache_chains<C: CertCache>(
+        &self,
+        cert_chains: &CertChains,
+        cert_cache: &mut C,
+    ) {
+        let digests = match &self.digests {
+            Some(digests) => digests,
+            None => return,
+        };
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = digests.digest_size as usize;
+        for (slot, cert) in cert_chains.chains.iter().enumerate() {
+            let cert = match cert {
+                Some(cert) => cert,
+                None => continue,
+            };
+            let digest = digests.digests[slot].as_slice(digest_size);
+            let chain = &cert.cert_chain[..cert.portion_length as usize];
+            if cert_cache.get(digest) != Some(chain)
+                && DigestImpl::hash(hash_algo, chain).as_ref() == digest
+            {
+                cert_cache.insert(digest, chain);
+            }
+        }
+    }
+
+    /// Select the slot used for CHALLENGE once all certificate chains are
+    /// retrieved.
+    ///
+    /// This is the first slot whose chain is rooted at one of `root_certs`,
+    /// as indicated by its root hash, or else the first retrieved slot, which
+    /// is then rejected during CHALLENGE. The selected chain is kept in `cert_chain`.
+    pub fn select_slot(
+        &mut self,
+        cert_chains: &CertChains,
+        root_certs: &[&[u8]],
+    ) -> Result<(), RequesterError> {
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size() as usize;
+
+        // The root hash follows the length and reserved fields
+        let is_rooted = |cert: &&Certificate<MAX_CERT_CHAIN_SIZE>| {
+            cert.portion_length as usize >= 4 + digest_size
+                && root_certs.iter().any(|root_cert| {
+                    DigestImpl::hash(hash_algo, root_cert).as_ref()
+                        == &cert.cert_chain[4..4 + digest_size]
+                })
+        };
+        let mut retrieved = cert_chains.chains.iter().flatten();
+        let cert = match retrieved.clone().find(is_rooted) {
+            Some(cert) => cert,
+            None => match retrieved.next() {
+                Some(cert) => cert,
+                None => return Err(RequesterError::NoCertChain),
+            },
+        };
+        self.cert_chain = Some(cert.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Respond to the outstanding GET_CERTIFICATE with the portion of `chain`
+    // at its offset, of at most `max_portion` bytes
+    fn respond(
+        state: &mut State,
+        slot: u8,
+        chain: &[u8],
+        max_portion: usize,
+        transcript: &mut Transcript,
+        cert_chains: &mut CertChains,
+    ) -> Result<bool, RequesterError> {
+        let req = state.get_certificate.clone().unwrap();
+        let offset = req.offset as usize;
+        let portion_length =
+            min(min(req.length as usize, max_portion), chain.len() - offset);
+        let mut cert = Certificate::<MAX_CERT_CHAIN_SIZE> {
+            slot,
+            portion_length: portion_length as u16,
+            remainder_length: (chain.len() - offset - portion_length) as u16,
+            cert_chain: [0u8; MAX_CERT_CHAIN_SIZE],
+        };
+        cert.cert_chain[..portion_length]
+            .copy_from_slice(&chain[offset..offset + portion_length]);
+        let mut buf = [0u8; MAX_CERT_CHAIN_SIZE + 8];
+        let size = cert.write(&mut buf).unwrap();
+        state.handle_certificate(&buf[..size], transcript, cert_chains)
+    }
+
+    #[test]
+    fn certificate_chain_is_assembled_from_portions() {
+        let chain: Vec<u8> = (0..100).collect();
+        let mut state = State::default();
+        let mut transcript = Transcript::new();
+        let mut cert_chains = CertChains::new();
+        let mut buf = [0u8; 16];
+
+        let mut complete = false;
+        let mut requests = 0;
+        while !complete {
+            state
+                .write_get_certificate_msg(0, &mut buf, &mut transcript)
+                .unwrap();
+            complete = respond(
+                &mut state,
+                0,
+                &chain,
+                40,
+                &mut transcript,
+                &mut cert_chains,
+            )
+            .unwrap();
+            requests += 1;
+        }
+
+        assert_eq!(3, requests);
+        assert_eq!(1, state.retrieved_slots);
+        assert!(state.cert_chain.is_none());
+        let cert = cert_chains.chains[0].as_ref().unwrap();
+        assert_eq!(100, cert.portion_length);
+        assert_eq!(&chain[..], &cert.cert_chain[..100]);
+    }
+
+    #[test]
+    fn certificate_portions_must_come_from_the_same_slot() {
+        let chain: Vec<u8> = (0..100).collect();
+        let mut state = State::default();
+        let mut transcript = Transcript::new();
+        let mut cert_chains = CertChains::new();
+        let mut buf = [0u8; 16];
+
+        state.write_get_certificate_msg(0, &mut buf, &mut transcript).unwrap();
+        assert_eq!(
+            Ok(false),
+            respond(
+                &mut state,
+                0,
+                &chain,
+                40,
+                &mut transcript,
+                &mut cert_chains
+            )
+        );
+        state.write_get_certificate_msg(0, &mut buf, &mut transcript).unwrap();
+        assert_eq!(
+            Err(RequesterError::BadCertificate),
+            respond(
+                &mut state,
+                1,
+                &chain,
+                40,
+                &mut transcript,
+                &mut cert_chains
+            )
+        );
+    }
+
+    #[test]
+    fn empty_certificate_chain_is_rejected() {
+        let mut state = State::default();
+        let mut transcript = Transcript::new();
+        let mut cert_chains = CertChains::new();
+        let mut buf = [0u8; 16];
+
+        state.write_get_certificate_msg(0, &mut buf, &mut transcript).unwrap();
+        assert_eq!(
+            Err(RequesterError::BadCertificate),
+            respond(&mut state, 0, &[], 40, &mut transcript, &mut cert_chains)
+        );
+        assert_eq!(0, state.retrieved_slots);
+        assert!(cert_chains.chains[0].is_none());
+    }
+
+    #[test]
+    fn certificate_portion_must_not_exceed_request() {
+        let chain: Vec<u8> = (0..100).collect();
+        let mut state = State::default();
+        let mut transcript = Transcript::new();
+        let mut cert_chains = CertChains::new();
+        let mut buf = [0u8; 16];
+
+        state.write_get_certificate_msg(0, &mut buf, &mut transcript).unwrap();
+        state.get_certificate.as_mut().unwrap().length = 10;
+        let mut cert = Certificate::<MAX_CERT_CHAIN_SIZE> {
+            slot: 0,
+            portion_length: 20,
+            remainder_length: 80,
+            cert_chain: [0u8; MAX_CERT_CHAIN_SIZE],
+        };
+        cert.cert_chain[..20].copy_from_slice(&chain[..20]);
+        let mut buf = [0u8; MAX_CERT_CHAIN_SIZE + 8];
+        let size = cert.write(&mut buf).unwrap();
+        assert_eq!(
+            Err(RequesterError::BadCertificate),
+            state.handle_certificate(
+                &buf[..size],
+                &mut transcript,
+                &mut cert_chains
+            )
+        );
+    }
+
+    #[test]
+    fn certificate_chains_are_retrieved_for_requested_slots() {
+        let chain: Vec<u8> = (0..100).collect();
+        let mut state = State::default();
+        let mut transcript = Transcript::new();
+        let mut cert_chains = CertChains::new();
+        let mut buf = [0u8; 16];
+
+        // No digests were received yet
+        assert_eq!(None, state.next_slot(0xFF));
+
+        state.digests = Some(Digests {
+            digest_size: 32,
+            slot_mask: 0b11,
+            digests: Default::default(),
+        });
+        assert_eq!(Some(0), state.next_slot(0xFF));
+        assert_eq!(Some(1), state.next_slot(0b10));
+        assert_eq!(None, state.next_slot(0b100));
+
+        // A chain is completed before the next slot is retrieved
+        state.write_get_certificate_msg(1, &mut buf, &mut transcript).unwrap();
+        assert_eq!(
+            Ok(false),
+            respond(
+                &mut state,
+                1,
+                &chain,
+                40,
+                &mut transcript,
+                &mut cert_chains
+            )
+        );
+        assert_eq!(Some(1), state.next_slot(0b01));
+
+        let mut complete = false;
+        while !complete {
+            state
+                .write_get_certificate_msg(1, &mut buf, &mut transcript)
+                .unwrap();
+            complete = respond(
+                &mut state,
+                1,
+                &chain,
+                40,
+                &mut transcript,
+                &mut cert_chains,
+            )
+            .unwrap();
+        }
+        assert!(cert_chains.chains[0].is_none());
+        assert!(cert_chains.chains[1].is_some());
+        assert_eq!(Some(0), state.next_slot(0xFF));
+        assert_eq!(None, state.next_slot(0b10));
     }
 }
@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::convert::From;
+
+use rand::{rngs::OsRng, RngCore};
+
+use super::{
+    expect, finish, session, verify_responder_signature, RequesterError,
+};
+use crate::config::{MAX_CERT_CHAIN_SIZE, MAX_DIGEST_SIZE};
+use crate::crypto::{
+    dhe::{EphemeralKey, RingEphemeralKey},
+    digest::{Digest, DigestImpl},
+    key_schedule::{self, HandshakeSecrets},
+};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::challenge::{
+    nonce, PROVISIONED_KEY_SLOT, PROVISIONED_KEY_SLOT_ID,
+};
+use crate::msgs::key_exchange::{session_id, MutAuthRequested};
+use crate::msgs::{
+    Algorithms, KeyExchange, KeyExchangeRsp, MeasurementHashType, Msg,
+    VersionEntry, HEADER_SIZE,
+};
+use crate::secured_message::Mode;
+use crate::Transcript;
+
+/// Return true if both the requester and responder support key exchange, a DHE
+/// group and an AEAD algorithm were negotiated, and both sides set ENCRYPT_CAP
+/// or both set MAC_CAP.
+pub fn is_supported(s: &session::State) -> bool {
+    s.requester_cap.contains(ReqFlags::KEY_EX_CAP)
+        && s.responder_cap.contains(RspFlags::KEY_EX_CAP)
+        && s.algorithms.dhe_algo_selected().is_some()
+        && s.algorithms.aead_algo_selected().is_some()
+        && Mode::new(s.requester_cap, s.responder_cap).is_some()
+}
+
+/// Establish a secure session with the responder via KEY_EXCHANGE
+///
+/// Key exchange follows challenge authentication, and therefore uses the
+/// certificate chain of the responder that was already verified.
+#[derive(Debug)]
+pub struct State {
+    pub version: VersionEntry,
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+    pub cert_slot: u8,
+    pub cert_chain: [u8; MAX_CERT_CHAIN_SIZE],
+    pub cert_chain_size: u16,
+    pub trust_anchor: Option<usize>,
+
+    // The same type of measurement summary hash requested in CHALLENGE is
+    // requested in KEY_EXCHANGE.
+    pub measurement_hash_type: MeasurementHashType,
+    pub measurement_summary_hash: [u8; MAX_DIGEST_SIZE],
+
+    pub req_session_id: u16,
+
+    // The ephemeral key generated when the KEY_EXCHANGE msg is written
+    //
+    // TODO: Allow requesters to provide their own `EphemeralKey`
+    pub dhe_key: Option<RingEphemeralKey>,
+}
+
+impl From<session::State> for State {
+    fn from(s: session::State) -> Self {
+        State {
+            version: s.version,
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+            cert_slot: s.cert_slot,
+            cert_chain: s.cert_chain,
+            cert_chain_size: s.cert_chain_size,
+            trust_anchor: s.trust_anchor,
+            measurement_hash_type: s.measurement_hash_type,
+            measurement_summary_hash: s.measurement_summary_hash,
+            req_session_id: 0,
+            dhe_key: None,
+        }
+    }
+}
+
+impl State {
+    /// Write a KEY_EXCHANGE msg to the buffer.
+    ///
+    /// The transcript is reset to the VCA messages and extended with the hash
+    /// of the responder's certificate chain followed by the KEY_EXCHANGE msg.
+    pub fn write_msg<'a>(
+        &mut self,
+        buf: &'a mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<&'a [u8], RequesterError> {
+        // Unwrap is safe, as we only enter this state if `is_supported`
+        let dhe_algo = self.algorithms.dhe_algo_selected().unwrap();
+        let dhe_key = RingEphemeralKey::generate(dhe_algo)
+            .map_err(|_| RequesterError::KeyExchangeFailed)?;
+
+        let mut msg = KeyExchange {
+            measurement_hash_type: self.measurement_hash_type,
+            slot_id: if self.cert_slot == PROVISIONED_KEY_SLOT {
+                PROVISIONED_KEY_SLOT_ID
+            } else {
+                self.cert_slot
+            },
+            req_session_id: OsRng.next_u32() as u16,
+            random_data: nonce(),
+            exchange_data_size: dhe_algo.get_exchange_data_size(),
+            ..KeyExchange::default()
+        };
+        dhe_key
+            .public_key(&mut msg.exchange_data)
+            .map_err(|_| RequesterError::KeyExchangeFailed)?;
+        let size = msg.write(buf)?;
+
+        let cert_chain_digest = DigestImpl::hash(
+            self.algorithms.base_hash_algo_selected,
+            self.cert_chain(),
+        );
+        transcript.reset_to_vca();
+        transcript.extend(cert_chain_digest.as_ref())?;
+        transcript.extend(&buf[..size])?;
+
+        self.req_session_id = msg.req_session_id;
+        self.dhe_key = Some(dhe_key);
+        Ok(&buf[..size])
+    }
+
+    /// Process a received responder message.
+    ///
+    /// Only KEY_EXCHANGE_RSP msgs are acceptable here.
+    pub fn handle_msg(
+        mut self,
+        buf: &[u8],
+        transcript: &mut Transcript,
+    ) -> Result<finish::State, RequesterError> {
+        expect::<KeyExchangeRsp>(buf)?;
+        let dhe_key =
+            self.dhe_key.take().ok_or(RequesterError::NoRequestInProgress)?;
+
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+        let signature_size =
+            self.algorithms.base_asym_algo_selected.get_signature_size();
+        // Unwrap is safe, as we only enter this state if `is_supported`
+        let exchange_data_size = self
+            .algorithms
+            .dhe_algo_selected()
+            .unwrap()
+            .get_exchange_data_size();
+        let summary_hash_requested =
+            self.measurement_hash_type != MeasurementHashType::None;
+        let rsp = KeyExchangeRsp::parse_body(
+            &buf[HEADER_SIZE..],
+            exchange_data_size,
+            digest_size,
+            summary_hash_requested,
+            signature_size,
+        )?;
+
+        // The requester signs FINISH with the key of `req_slot_id` when mutual
+        // authentication is requested. Encapsulated requests are only
+        // supported after CHALLENGE, so a responder that needs them within
+        // the session handshake, to retrieve our certificate chain, is
+        // rejected.
+        let req_slot_id = if rsp.mut_auth_requested.is_empty() {
+            None
+        } else if rsp.mut_auth_requested == MutAuthRequested::MUT_AUTH_REQUESTED
+            && self.requester_cap.contains(ReqFlags::MUT_AUTH_CAP)
+            && self.responder_cap.contains(RspFlags::MUT_AUTH_CAP)
+        {
+            Some(rsp.req_slot_id)
+        } else {
+            return Err(RequesterError::BadKeyExchange);
+        };
+
+        let verify_start = buf.len() - digest_size as usize;
+        let sig_start = verify_start - signature_size;
+
+        transcript.extend(&buf[..sig_start])?;
+        let th_hash = DigestImpl::hash(hash_algo, transcript.get());
+        self.verify_signature(th_hash.as_ref(), rsp.signature())?;
+        transcript.extend(&buf[sig_start..verify_start])?;
+
+        let th1 = key_schedule::th1(hash_algo, transcript);
+        let dhe_secret = dhe_key
+            .shared_secret(rsp.exchange_data())
+            .map_err(|_| RequesterError::BadKeyExchange)?;
+        let handshake_secrets =
+            HandshakeSecrets::new(hash_algo, dhe_secret.as_ref(), th1.as_ref());
+        let finished_key =
+            key_schedule::finished_key(hash_algo, &handshake_secrets.response);
+        if !key_schedule::verify_hmac(
+            hash_algo,
+            finished_key.as_ref(),
+            th1.as_ref(),
+            rsp.verify_data(),
+        ) {
+            return Err(RequesterError::BadKeyExchange);
+        }
+
+        transcript.extend(rsp.verify_data())?;
+
+        // The summary hash is only trusted once the signature is verified
+        if let Some(hash) = rsp.measurement_summary_hash() {
+            self.measurement_summary_hash[..digest_size as usize]
+                .copy_from_slice(hash);
+        }
+
+        Ok(finish::State {
+            version: self.version,
+            requester_ct_exponent: self.requester_ct_exponent,
+            requester_cap: self.requester_cap,
+            responder_ct_exponent: self.responder_ct_exponent,
+            responder_cap: self.responder_cap,
+            algorithms: self.algorithms,
+            cert_slot: self.cert_slot,
+            cert_chain: self.cert_chain,
+            cert_chain_size: self.cert_chain_size,
+            trust_anchor: self.trust_anchor,
+            measurement_hash_type: self.measurement_hash_type,
+            measurement_summary_hash: self.measurement_summary_hash,
+            session_id: session_id(self.req_session_id, rsp.rsp_session_id),
+            heartbeat_period: rsp.heartbeat_period,
+            req_slot_id,
+            handshake_secrets,
+        })
+    }
+
+    fn cert_chain(&self) -> &[u8] {
+        &self.cert_chain[..self.cert_chain_size as usize]
+    }
+
+    // Verify the signature over the transcript using the leaf cert of the
+    // responder's certificate chain, or its provisioned public key. The chain
+    // itself was verified during CHALLENGE.
+    fn verify_signature(
+        &self,
+        th_hash: &[u8],
+        signature: &[u8],
+    ) -> Result<(), RequesterError> {
+        if !verify_responder_signature(
+            &self.algorithms,
+            self.cert_slot,
+            self.cert_chain(),
+            th_hash,
+            signature,
+        )? {
+            return Err(RequesterError::BadKeyExchange);
+        }
+        Ok(())
+    }
+}
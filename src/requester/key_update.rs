@@ -0,0 +1,81 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use rand::{rngs::OsRng, RngCore};
+
+use super::{expect, RequesterError};
+use crate::msgs::{
+    KeyUpdate, KeyUpdateAck, KeyUpdateOperation, Msg, HEADER_SIZE,
+};
+
+/// Update the keys of an established session via KEY_UPDATE
+///
+/// A key update takes two exchanges. The keys are first updated with
+/// UpdateKey or UpdateAllKeys, and the new keys are then verified with
+/// VerifyNewKey, which is sent with them. The keys of the record layer are
+/// updated by the `RequesterSession`, as the messages are sent and received,
+/// and the old keys are kept until the new keys are verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    // The operation of the next or outstanding KEY_UPDATE request
+    pub operation: KeyUpdateOperation,
+
+    // The tag of the next or outstanding KEY_UPDATE request. A retried
+    // request keeps its tag.
+    pub tag: u8,
+
+    // True once the request for the current operation was written, until its
+    // KEY_UPDATE_ACK is received
+    pub outstanding: bool,
+}
+
+impl State {
+    /// Update the request keys, or the keys of both directions if
+    /// `update_all` is true.
+    pub fn new(update_all: bool) -> State {
+        let operation = if update_all {
+            KeyUpdateOperation::UpdateAllKeys
+        } else {
+            KeyUpdateOperation::UpdateKey
+        };
+        State { operation, tag: OsRng.next_u32() as u8, outstanding: false }
+    }
+
+    /// Return true if the next request retries an UpdateKey or UpdateAllKeys
+    /// request that went unacknowledged. It must be sent with the keys from
+    /// before the update.
+    pub fn is_retry(&self) -> bool {
+        self.outstanding && self.operation != KeyUpdateOperation::VerifyNewKey
+    }
+
+    /// Write the KEY_UPDATE msg for the current operation to the buffer
+    pub fn write_msg<'a>(
+        &mut self,
+        buf: &'a mut [u8],
+    ) -> Result<&'a [u8], RequesterError> {
+        let msg = KeyUpdate { operation: self.operation, tag: self.tag };
+        let size = msg.write(buf)?;
+        self.outstanding = true;
+        Ok(&buf[..size])
+    }
+
+    /// Process a received responder message.
+    ///
+    /// Only a KEY_UPDATE_ACK for the outstanding request is acceptable here.
+    /// `Ok(true)` is returned once the new keys are verified.
+    pub fn handle_msg(&mut self, buf: &[u8]) -> Result<bool, RequesterError> {
+        expect::<KeyUpdateAck>(buf)?;
+        let ack = KeyUpdateAck::parse_body(&buf[HEADER_SIZE..])?;
+        if ack.operation != self.operation || ack.tag != self.tag {
+            return Err(RequesterError::BadKeyUpdate);
+        }
+        if self.operation == KeyUpdateOperation::VerifyNewKey {
+            return Ok(true);
+        }
+        self.operation = KeyUpdateOperation::VerifyNewKey;
+        self.tag = OsRng.next_u32() as u8;
+        self.outstanding = false;
+        Ok(false)
+    }
+}
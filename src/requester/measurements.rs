@@ -0,0 +1,290 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{expect, session, verify_responder_signature, RequesterError};
+use crate::config::MAX_MEASUREMENT_RECORD_SIZE;
+use crate::crypto::digest::{Digest, DigestImpl};
+use crate::msgs::measurements::{
+    MeasurementBlocks, MeasurementIndex, RequestAttributes,
+};
+use crate::msgs::{
+    self, encoding::Writer, GetMeasurements, MeasurementBlock, Measurements,
+    Msg, ReadError, ReadErrorKind, HEADER_SIZE,
+};
+use crate::Transcript;
+
+/// The measurements a requester can retrieve from a responder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeasurementRequest {
+    /// A single measurement at the given index (0x01 - 0xFE)
+    Index(u8),
+
+    /// All measurements in a single response
+    All,
+
+    /// The total number of measurements, followed by each measurement in its
+    /// own request.
+    ///
+    /// Measurement indices need not be contiguous, so indices are requested in
+    /// order starting at 0x01. Indices the responder reports as absent, with
+    /// an empty response or an InvalidRequest error, are skipped, until the
+    /// total number of measurements has been retrieved.
+    Each,
+}
+
+/// Retrieve measurements from a responder over one or more GET_MEASUREMENTS
+/// requests.
+///
+/// If a signature is requested, only the last GET_MEASUREMENTS request asks
+/// for one. The signature then covers all requests and responses (L1/L2 in the
+/// SPDM spec), and so verifies all retrieved measurement blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub request: MeasurementRequest,
+    pub signature_requested: bool,
+
+    // The outstanding GET_MEASUREMENTS request
+    pub pending: Option<GetMeasurements>,
+
+    // The length of the transcript before the outstanding request
+    pub request_start: usize,
+
+    // Only used for `MeasurementRequest::Each`
+    pub total_indices: Option<u8>,
+    pub next_index: u8,
+
+    // The serialized measurement blocks retrieved so far
+    pub num_blocks: u8,
+    pub record_len: usize,
+    pub record: [u8; MAX_MEASUREMENT_RECORD_SIZE],
+
+    pub complete: bool,
+}
+
+impl State {
+    pub fn new(
+        request: MeasurementRequest,
+        signature_requested: bool,
+    ) -> State {
+        State {
+            request,
+            signature_requested,
+            pending: None,
+            request_start: 0,
+            total_indices: None,
+            next_index: 0x01,
+            num_blocks: 0,
+            record_len: 0,
+            record: [0u8; MAX_MEASUREMENT_RECORD_SIZE],
+            complete: false,
+        }
+    }
+
+    /// Return the verified measurement blocks once all have been retrieved.
+    pub fn blocks(&self) -> Option<MeasurementBlocks<'_>> {
+        if self.complete {
+            Some(MeasurementBlocks::new(
+                &self.record[..self.record_len],
+                self.num_blocks,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Write the next GET_MEASUREMENTS msg to the buffer, and append it to the
+    /// transcript.
+    pub fn write_msg<'a>(
+        &mut self,
+        slot: u8,
+        buf: &'a mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<&'a [u8], RequesterError> {
+        if self.complete || self.pending.is_some() {
+            return Err(RequesterError::NoRequestInProgress);
+        }
+        let (index, last) = match self.request {
+            MeasurementRequest::Index(index) => (index.into(), true),
+            MeasurementRequest::All => {
+                (MeasurementIndex::AllMeasurements, true)
+            }
+            MeasurementRequest::Each => match self.total_indices {
+                None => (
+                    MeasurementIndex::TotalNumberOfMeasurementsAvailable,
+                    false,
+                ),
+                // Only sign the request for the last measurement, which is
+                // the next one found.
+                Some(total) => {
+                    (self.next_index.into(), self.num_blocks + 1 == total)
+                }
+            },
+        };
+        let attributes = RequestAttributes {
+            signature_requested: self.signature_requested && last,
+            raw_bit_stream_requested: false,
+        };
+        let msg = GetMeasurements::new(attributes, index, slot)?;
+        let size = msg.write(buf)?;
+        self.request_start = transcript.len();
+        transcript.extend(&buf[..size])?;
+        self.pending = Some(msg);
+        Ok(&buf[..size])
+    }
+
+    /// Process a MEASUREMENTS response.
+    ///
+    /// Return `Ok(true)` once all requested measurements have been retrieved
+    /// and verified.
+    pub fn handle_msg(
+        &mut self,
+        buf: &[u8],
+        session: &session::State,
+        transcript: &mut Transcript,
+    ) -> Result<bool, RequesterError> {
+        if self.is_absent_index(buf)? {
+            // The responder does not record a request it rejects in L1/L2, so
+            // neither do we.
+            self.pending = None;
+            transcript.truncate(self.request_start);
+            return self.next_index();
+        }
+        expect::<Measurements>(buf)?;
+        let req =
+            self.pending.take().ok_or(RequesterError::NoRequestInProgress)?;
+        let signature_size = if req.attributes.signature_requested {
+            session.algorithms.base_asym_algo_selected.get_signature_size()
+        } else {
+            0
+        };
+        let rsp =
+            Measurements::parse_body(&buf[HEADER_SIZE..], signature_size)?;
+
+        let sig_start = buf.len() - signature_size;
+        transcript.extend(&buf[..sig_start])?;
+
+        if req.attributes.signature_requested {
+            if rsp.slot_id != req.slot_id {
+                return Err(RequesterError::BadMeasurements);
+            }
+            self.verify_signature(rsp.signature(), session, transcript)?;
+
+            // A signed response terminates the measurement transcript
+            transcript.reset_to_vca();
+        }
+
+        match req.index {
+            MeasurementIndex::TotalNumberOfMeasurementsAvailable => {
+                // Indices range from 1 to 0xFE, so there can be at most 0xFE
+                // measurements.
+                if rsp.total_indices == 0xFF {
+                    return Err(RequesterError::BadMeasurements);
+                }
+                self.total_indices = Some(rsp.total_indices);
+                if rsp.total_indices == 0 {
+                    self.complete = true;
+                }
+            }
+            MeasurementIndex::AllMeasurements => {
+                for block in rsp.blocks() {
+                    self.append(&block?)?;
+                }
+                self.complete = true;
+            }
+            index => {
+                let mut blocks = rsp.blocks();
+                match (blocks.next(), rsp.num_blocks) {
+                    (Some(block), 1) => {
+                        let block = block?;
+                        if MeasurementIndex::from(block.index) != index {
+                            return Err(RequesterError::BadMeasurements);
+                        }
+                        self.append(&block)?;
+                    }
+                    (None, 0) if self.request == MeasurementRequest::Each => (),
+                    _ => return Err(RequesterError::BadMeasurements),
+                }
+                self.complete = self.next_index()?;
+            }
+        }
+
+        Ok(self.complete)
+    }
+
+    // Return true if `buf` is an InvalidRequest error reporting that the index
+    // requested while walking all indices does not exist.
+    fn is_absent_index(&self, buf: &[u8]) -> Result<bool, RequesterError> {
+        let walking = self.request == MeasurementRequest::Each
+            && matches!(
+                self.pending.as_ref().map(|req| req.index),
+                Some(MeasurementIndex::ImplementationDefined(_))
+            );
+        if !walking || !msgs::Error::parse_header(buf)? {
+            return Ok(false);
+        }
+        let err = msgs::Error::parse_body(&buf[HEADER_SIZE..])?;
+        Ok(err == msgs::Error::InvalidRequest)
+    }
+
+    // Move on to the next index when walking all indices. Return true once all
+    // requested measurements have been retrieved.
+    fn next_index(&mut self) -> Result<bool, RequesterError> {
+        let total = match self.total_indices {
+            Some(total) => total,
+            None => return Ok(true),
+        };
+        if self.num_blocks == total {
+            return Ok(true);
+        }
+        if self.next_index == 0xFE {
+            // The responder reported more measurements than it has
+            return Err(RequesterError::BadMeasurements);
+        }
+        self.next_index += 1;
+        Ok(false)
+    }
+
+    // Append a measurement block to the record
+    fn append(&mut self, block: &MeasurementBlock) -> Result<(), ReadError> {
+        let end = self.record_len + block.size();
+        if end > MAX_MEASUREMENT_RECORD_SIZE {
+            return Err(ReadError::new(
+                Measurements::NAME,
+                ReadErrorKind::ImplementationLimitReached,
+            ));
+        }
+        let mut w = Writer::new(
+            Measurements::NAME,
+            &mut self.record[self.record_len..end],
+        );
+        // This can't fail as we checked the size above
+        block.write(&mut w).unwrap();
+        self.record_len = end;
+        self.num_blocks += 1;
+        Ok(())
+    }
+
+    // Verify the signature over L1/L2 using the leaf cert of the responder's
+    // certificate chain, or its provisioned public key. The chain itself was
+    // verified during CHALLENGE.
+    fn verify_signature(
+        &self,
+        signature: &[u8],
+        session: &session::State,
+        transcript: &Transcript,
+    ) -> Result<(), RequesterError> {
+        let hash_algo = session.algorithms.base_hash_algo_selected;
+        let l2_hash = DigestImpl::hash(hash_algo, transcript.get());
+        if !verify_responder_signature(
+            &session.algorithms,
+            session.cert_slot,
+            session.cert_chain(),
+            l2_hash.as_ref(),
+            signature,
+        )? {
+            return Err(RequesterError::BadMeasurements);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::cmp::min;
+
+use super::{expect, session, RequesterError};
+use crate::config::{
+    MAX_CERT_CHAIN_SIZE, MAX_DIGEST_SIZE, MAX_SIGNATURE_SIZE, NUM_SLOTS,
+};
+use crate::crypto::{
+    digest::{Digest, DigestImpl},
+    FilledSlot, Signer,
+};
+use crate::msgs::digest::DigestBuf;
+use crate::msgs::{
+    challenge::nonce, encoding::Writer, Certificate, Challenge, ChallengeAuth,
+    DeliverEncapsulatedResponse, Digests, EncapsulatedRequest,
+    EncapsulatedResponseAck, GetCertificate, GetDigests,
+    GetEncapsulatedRequest, Msg, HEADER_SIZE,
+};
+use crate::responder::id_auth::create_slot_mask;
+use crate::Transcript;
+
+/// A request of the responder, received in an encapsulated message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    GetDigests,
+    GetCertificate(GetCertificate),
+    Challenge(Challenge),
+}
+
+impl Request {
+    // Parse an encapsulated request. Only the requests needed to authenticate
+    // the requester are supported.
+    fn parse(buf: &[u8]) -> Result<Request, RequesterError> {
+        if GetDigests::parse_header(buf) == Ok(true) {
+            GetDigests::parse_body(&buf[HEADER_SIZE..])?;
+            Ok(Request::GetDigests)
+        } else if GetCertificate::parse_header(buf) == Ok(true) {
+            let msg = GetCertificate::parse_body(&buf[HEADER_SIZE..])?;
+            Ok(Request::GetCertificate(msg))
+        } else if Challenge::parse_header(buf) == Ok(true) {
+            let msg = Challenge::parse_body(&buf[HEADER_SIZE..])?;
+            Ok(Request::Challenge(msg))
+        } else {
+            Err(RequesterError::BadEncapsulatedRequest)
+        }
+    }
+}
+
+/// Authenticate to the responder with encapsulated requests
+///
+/// This state is entered from the Challenge state when the responder requests
+/// basic mutual authentication in CHALLENGE_AUTH. The requester asks for the
+/// responder's requests with GET_ENCAPSULATED_REQUEST, and answers each of
+/// them with DELIVER_ENCAPSULATED_RESPONSE, until the responder has no further
+/// requests.
+///
+/// The transcript contains the VCA messages and the encapsulated messages, as
+/// the responder acts as a requester here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    // The state negotiated and verified so far
+    pub session: session::State,
+
+    // The ID and content of the outstanding encapsulated request, if the
+    // responder sent one
+    pub request: Option<(u8, Request)>,
+}
+
+impl From<session::State> for State {
+    fn from(session: session::State) -> Self {
+        State { session, request: None }
+    }
+}
+
+impl State {
+    /// Write a GET_ENCAPSULATED_REQUEST msg, or a DELIVER_ENCAPSULATED_RESPONSE
+    /// msg answering the outstanding encapsulated request, to the buffer.
+    ///
+    /// The transcript is extended with the encapsulated response. Responses
+    /// are built from the certificate chains and signers of `slots`.
+    pub fn write_msg<'a, 'b, S: Signer>(
+        &self,
+        slots: &[Option<FilledSlot<'b, S>>; NUM_SLOTS],
+        buf: &'a mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<&'a [u8], RequesterError> {
+        let (request_id, request) = match &self.request {
+            Some(request) => request,
+            None => {
+                let size = GetEncapsulatedRequest {}.write(buf)?;
+                return Ok(&buf[..size]);
+            }
+        };
+
+        let start = DeliverEncapsulatedResponse { request_id: *request_id }
+            .write(buf)?;
+        let size = match request {
+            Request::GetDigests => {
+                self.write_digests(slots, &mut buf[start..], transcript)?
+            }
+            Request::GetCertificate(req) => self.write_certificate(
+                slots,
+                req,
+                &mut buf[start..],
+                transcript,
+            )?,
+            Request::Challenge(req) => self.write_challenge_auth(
+                slots,
+                req,
+                &mut buf[start..],
+                transcript,
+            )?,
+        };
+        Ok(&buf[..start + size])
+    }
+
+    /// Process a received responder message.
+    ///
+    /// Only ENCAPSULATED_REQUEST msgs are acceptable in response to
+    /// GET_ENCAPSULATED_REQUEST, and ENCAPSULATED_RESPONSE_ACK msgs
+    /// otherwise. The transcript is extended with the encapsulated request.
+    ///
+    /// `Ok(true)` is returned once the responder has no further requests.
+    pub fn handle_msg(
+        &mut self,
+        buf: &[u8],
+        transcript: &mut Transcript,
+    ) -> Result<bool, RequesterError> {
+        let (request_id, request) = if self.request.is_none() {
+            expect::<EncapsulatedRequest>(buf)?;
+            let (msg, request) =
+                EncapsulatedRequest::parse_body(&buf[HEADER_SIZE..])?;
+            (msg.request_id, request)
+        } else {
+            expect::<EncapsulatedResponseAck>(buf)?;
+            match EncapsulatedResponseAck::parse_body(&buf[HEADER_SIZE..])? {
+                (msg, Some(request)) => (msg.request_id, request),
+                (_, None) => {
+                    // Any subsequent transcript starts after the VCA messages
+                    transcript.reset_to_vca();
+                    self.request = None;
+                    return Ok(true);
+                }
+            }
+        };
+
+        self.request = Some((request_id, Request::parse(request)?));
+        transcript.extend(request)?;
+        Ok(false)
+    }
+
+    // Write a DIGESTS msg for all slots with a certificate chain
+    fn write_digests<'b, S: Signer>(
+        &self,
+        slots: &[Option<FilledSlot<'b, S>>; NUM_SLOTS],
+        buf: &mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<usize, RequesterError> {
+        let hash_algo = self.session.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+        let mut digests = [DigestBuf::default(); NUM_SLOTS];
+        for (i, slot) in slots.iter().enumerate() {
+            if let Some(slot) = slot {
+                let mut cert_chain = [0u8; MAX_CERT_CHAIN_SIZE];
+                let size = serialize_cert_chain(slot, &mut cert_chain)?;
+                let digest = DigestImpl::hash(hash_algo, &cert_chain[..size]);
+                digests[i]
+                    .as_mut(digest_size as usize)
+                    .copy_from_slice(digest.as_ref());
+            }
+        }
+        let msg = Digests::<NUM_SLOTS> {
+            digest_size,
+            slot_mask: create_slot_mask(slots),
+            digests,
+        };
+        let size = msg.write(buf)?;
+        transcript.extend(&buf[..size])?;
+        Ok(size)
+    }
+
+    // Write a CERTIFICATE msg with the requested portion of a certificate
+    // chain
+    fn write_certificate<'b, S: Signer>(
+        &self,
+        slots: &[Option<FilledSlot<'b, S>>; NUM_SLOTS],
+        req: &GetCertificate,
+        buf: &mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<usize, RequesterError> {
+        let slot = match slots.get(req.slot as usize) {
+            Some(Some(slot)) => slot,
+            _ => return Err(RequesterError::InvalidSlot),
+        };
+        let mut cert_chain = [0u8; MAX_CERT_CHAIN_SIZE];
+        let cert_chain_size = serialize_cert_chain(slot, &mut cert_chain)?;
+        let offset = req.offset as usize;
+        if offset > cert_chain_size {
+            return Err(RequesterError::BadEncapsulatedRequest);
+        }
+        let portion_length = min(req.length as usize, cert_chain_size - offset);
+
+        let mut msg = Certificate::<MAX_CERT_CHAIN_SIZE> {
+            slot: req.slot,
+            portion_length: portion_length as u16,
+            remainder_length: (cert_chain_size - offset - portion_length)
+                as u16,
+            cert_chain: [0u8; MAX_CERT_CHAIN_SIZE],
+        };
+        msg.cert_chain[..portion_length]
+            .copy_from_slice(&cert_chain[offset..offset + portion_length]);
+        let size = msg.write(buf)?;
+        transcript.extend(&buf[..size])?;
+        Ok(size)
+    }
+
+    // Write a CHALLENGE_AUTH msg signed with the key of the requested slot
+    //
+    // The requester has no measurements, so the measurement summary hash is
+    // always zero.
+    fn write_challenge_auth<'b, S: Signer>(
+        &self,
+        slots: &[Option<FilledSlot<'b, S>>; NUM_SLOTS],
+        req: &Challenge,
+        buf: &mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<usize, RequesterError> {
+        let slot = match slots.get(req.slot as usize) {
+            Some(Some(slot))
+                if self.session.algorithms.req_base_asym_algo_selected()
+                    == Some(slot.signing_algorithm) =>
+            {
+                slot
+            }
+            _ => return Err(RequesterError::InvalidSlot),
+        };
+        let hash_algo = self.session.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size() as usize;
+        let signature_size = slot.signing_algorithm.get_signature_size();
+
+        let mut cert_chain = [0u8; MAX_CERT_CHAIN_SIZE];
+        let cert_chain_size = serialize_cert_chain(slot, &mut cert_chain)?;
+        let cert_chain_digest =
+            DigestImpl::hash(hash_algo, &cert_chain[..cert_chain_size]);
+
+        // Like the responder, we serialize with a placeholder signature, and
+        // overwrite it in place.
+        let dummy_sig = [0u8; MAX_SIGNATURE_SIZE];
+        let msg = ChallengeAuth::new(
+            req.slot,
+            create_slot_mask(slots),
+            false,
+            cert_chain_digest.as_ref(),
+            nonce(),
+            &[0u8; MAX_DIGEST_SIZE][..digest_size],
+            &[],
+            &dummy_sig[..signature_size],
+        );
+        let size = msg.write(buf)?;
+        let sig_start = size - signature_size;
+        transcript.extend(&buf[..sig_start])?;
+
+        let m_hash = DigestImpl::hash(hash_algo, transcript.get());
+        let signature = slot
+            .signer
+            .sign(m_hash.as_ref())
+            .map_err(|_| RequesterError::SigningFailed)?;
+        buf[sig_start..size].copy_from_slice(signature.as_ref());
+        transcript.extend(&buf[sig_start..size])?;
+        Ok(size)
+    }
+}
+
+// Serialize the certificate chain of `slot` into `buf`, and return its size
+fn serialize_cert_chain<S: Signer>(
+    slot: &FilledSlot<'_, S>,
+    buf: &mut [u8],
+) -> Result<usize, RequesterError> {
+    let mut w = Writer::new("CERTIFICATE_CHAIN", buf);
+    Ok(slot.cert_chain.write(&mut w)?)
+}
@@ -0,0 +1,179 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::convert::From;
+
+use rand::{rngs::OsRng, RngCore};
+
+use super::{expect, id_auth, psk_finish, RequesterError};
+use crate::config::MAX_DIGEST_SIZE;
+use crate::crypto::key_schedule::{self, HandshakeSecrets};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::encoding::{WriteError, WriteErrorKind};
+use crate::msgs::key_exchange::session_id;
+use crate::msgs::psk_exchange::MAX_PSK_HINT_SIZE;
+use crate::msgs::{
+    Algorithms, MeasurementHashType, Msg, PskExchange, PskExchangeRsp,
+    VersionEntry, HEADER_SIZE,
+};
+use crate::secured_message::Mode;
+use crate::Transcript;
+
+/// A pre-shared key, and the hint identifying it to the responder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Psk<'a> {
+    pub hint: &'a [u8],
+    pub key: &'a [u8],
+}
+
+/// Return true if a PSK session can be established with the responder
+pub fn is_supported(s: &id_auth::State) -> bool {
+    s.requester_cap.contains(ReqFlags::PSK_CAP)
+        && s.responder_cap
+            .intersects(RspFlags::PSK_CAP | RspFlags::PSX_CAP_WITH_CONTEXT)
+        && s.algorithms.aead_algo_selected().is_some()
+        && Mode::new(s.requester_cap, s.responder_cap).is_some()
+}
+
+/// Establish a secure session with the responder via PSK_EXCHANGE
+///
+/// A PSK session directly follows algorithm negotiation. The PSK
+/// authenticates the responder, so there is no certificate retrieval or
+/// CHALLENGE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub version: VersionEntry,
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+    pub measurement_hash_type: MeasurementHashType,
+
+    // Set once the PSK_EXCHANGE msg is written
+    pub req_session_id: Option<u16>,
+}
+
+impl From<id_auth::State> for State {
+    fn from(s: id_auth::State) -> Self {
+        State {
+            version: s.version,
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+            measurement_hash_type: MeasurementHashType::None,
+            req_session_id: None,
+        }
+    }
+}
+
+impl State {
+    /// Write a PSK_EXCHANGE msg to the buffer, and extend the transcript with
+    /// it.
+    pub fn write_msg<'a>(
+        &mut self,
+        psk: &Psk,
+        measurement_hash_type: MeasurementHashType,
+        buf: &'a mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<&'a [u8], RequesterError> {
+        if psk.hint.len() > MAX_PSK_HINT_SIZE {
+            return Err(WriteError::new(
+                PskExchange::NAME,
+                WriteErrorKind::InvalidRange("PSKHint"),
+            )
+            .into());
+        }
+        let digest_size =
+            self.algorithms.base_hash_algo_selected.get_digest_size();
+        let mut msg = PskExchange {
+            measurement_hash_type,
+            req_session_id: OsRng.next_u32() as u16,
+            psk_hint_len: psk.hint.len() as u16,
+            requester_context_len: digest_size.into(),
+            ..PskExchange::default()
+        };
+        msg.psk_hint[..psk.hint.len()].copy_from_slice(psk.hint);
+        OsRng.fill_bytes(&mut msg.requester_context[..digest_size.into()]);
+        let size = msg.write(buf)?;
+        transcript.extend(&buf[..size])?;
+
+        self.measurement_hash_type = measurement_hash_type;
+        self.req_session_id = Some(msg.req_session_id);
+        Ok(&buf[..size])
+    }
+
+    /// Process a received responder message.
+    ///
+    /// Only PSK_EXCHANGE_RSP msgs are acceptable here.
+    pub fn handle_msg(
+        self,
+        psk: &Psk,
+        buf: &[u8],
+        transcript: &mut Transcript,
+    ) -> Result<psk_finish::State, RequesterError> {
+        expect::<PskExchangeRsp>(buf)?;
+        let req_session_id =
+            self.req_session_id.ok_or(RequesterError::NoRequestInProgress)?;
+
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+        let summary_hash_requested =
+            self.measurement_hash_type != MeasurementHashType::None;
+        let rsp = PskExchangeRsp::parse_body(
+            &buf[HEADER_SIZE..],
+            digest_size,
+            summary_hash_requested,
+        )?;
+
+        // A ResponderContext must be present if, and only if, PSK_FINISH is
+        // required.
+        let context_required =
+            self.responder_cap.contains(RspFlags::PSX_CAP_WITH_CONTEXT);
+        if context_required == rsp.responder_context().is_empty() {
+            return Err(RequesterError::BadPskExchange);
+        }
+
+        let verify_start = buf.len() - digest_size as usize;
+        transcript.extend(&buf[..verify_start])?;
+
+        let th1 = key_schedule::th1(hash_algo, transcript);
+        let handshake_secrets =
+            HandshakeSecrets::new(hash_algo, psk.key, th1.as_ref());
+        let finished_key =
+            key_schedule::finished_key(hash_algo, &handshake_secrets.response);
+        if !key_schedule::verify_hmac(
+            hash_algo,
+            finished_key.as_ref(),
+            th1.as_ref(),
+            rsp.verify_data(),
+        ) {
+            return Err(RequesterError::BadPskExchange);
+        }
+        transcript.extend(rsp.verify_data())?;
+
+        // The summary hash is only trusted once the verify data is verified
+        let mut measurement_summary_hash = [0u8; MAX_DIGEST_SIZE];
+        if let Some(hash) = rsp.measurement_summary_hash() {
+            measurement_summary_hash[..digest_size as usize]
+                .copy_from_slice(hash);
+        }
+
+        Ok(psk_finish::State {
+            version: self.version,
+            requester_ct_exponent: self.requester_ct_exponent,
+            requester_cap: self.requester_cap,
+            responder_ct_exponent: self.responder_ct_exponent,
+            responder_cap: self.responder_cap,
+            algorithms: self.algorithms,
+            measurement_hash_type: self.measurement_hash_type,
+            measurement_summary_hash,
+            session_id: session_id(req_session_id, rsp.rsp_session_id),
+            heartbeat_period: rsp.heartbeat_period,
+            handshake_secrets,
+        })
+    }
+}
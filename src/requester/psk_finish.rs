@@ -0,0 +1,163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{
+    expect, handshake_channels, open_secured, session, write_secured,
+    RequesterError, MAX_HANDSHAKE_RSP_RECORD_SIZE,
+};
+use crate::config::{MAX_CERT_CHAIN_SIZE, MAX_DIGEST_SIZE};
+use crate::crypto::{
+    digest::{Digest, DigestImpl},
+    key_schedule::{self, HandshakeSecrets, SessionKeys},
+    Aead,
+};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::{
+    Algorithms, MeasurementHashType, Msg, PskFinish, PskFinishRsp,
+    VersionEntry, HEADER_SIZE,
+};
+use crate::secured_message::Channels;
+use crate::Transcript;
+
+/// Complete the handshake of the session created by PSK_EXCHANGE
+///
+/// The transcript contains the VCA messages, and the PSK_EXCHANGE and
+/// PSK_EXCHANGE_RSP msgs when this state is entered. This state is skipped if
+/// the responder did not provide a ResponderContext.
+///
+/// PSK_FINISH and PSK_FINISH_RSP are always sent within the session, protected
+/// by the handshake keys.
+#[derive(Debug)]
+pub struct State {
+    pub version: VersionEntry,
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+    pub measurement_hash_type: MeasurementHashType,
+    pub measurement_summary_hash: [u8; MAX_DIGEST_SIZE],
+
+    pub session_id: u32,
+    pub heartbeat_period: u8,
+    pub handshake_secrets: HandshakeSecrets,
+}
+
+impl State {
+    /// Return true if PSK_FINISH must be sent to establish the session
+    pub fn is_required(&self) -> bool {
+        self.responder_cap.contains(RspFlags::PSX_CAP_WITH_CONTEXT)
+    }
+
+    /// Write a PSK_FINISH msg to the buffer as a secured message of the
+    /// session, and extend the transcript with it.
+    pub fn write_msg<'a, A: Aead>(
+        &mut self,
+        buf: &'a mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<&'a [u8], RequesterError> {
+        write_secured::<A>(&mut self.channels(), buf, |buf| {
+            Ok(self.write_psk_finish(buf, transcript)?.len())
+        })
+    }
+
+    // Return the handshake keys
+    fn channels(&self) -> Channels {
+        handshake_channels(
+            self.session_id,
+            self.requester_cap,
+            self.responder_cap,
+            &self.algorithms,
+            &self.handshake_secrets,
+        )
+    }
+
+    // Write the PSK_FINISH msg itself
+    fn write_psk_finish<'a>(
+        &mut self,
+        buf: &'a mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<&'a [u8], RequesterError> {
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+
+        // The verify data is computed over the serialized request, so we
+        // serialize with a placeholder and overwrite it in place.
+        let msg = PskFinish { digest_size, ..PskFinish::default() };
+        let size = msg.write(buf)?;
+        let verify_start = size - digest_size as usize;
+        transcript.extend(&buf[..verify_start])?;
+
+        let th_hash = DigestImpl::hash(hash_algo, transcript.get());
+        let finished_key = key_schedule::finished_key(
+            hash_algo,
+            &self.handshake_secrets.request,
+        );
+        let verify_data = key_schedule::hmac(
+            hash_algo,
+            finished_key.as_ref(),
+            th_hash.as_ref(),
+        );
+        buf[verify_start..size].copy_from_slice(verify_data.as_ref());
+        transcript.extend(&buf[verify_start..size])?;
+
+        Ok(&buf[..size])
+    }
+
+    /// Process a received responder message.
+    ///
+    /// Only PSK_FINISH_RSP msgs, sent within the session, are acceptable
+    /// here. On success, the session keys are derived and initialization is
+    /// complete.
+    pub fn handle_msg<A: Aead>(
+        self,
+        buf: &[u8],
+        transcript: &mut Transcript,
+    ) -> Result<session::State, RequesterError> {
+        let mut record = [0u8; MAX_HANDSHAKE_RSP_RECORD_SIZE];
+        let buf = open_secured::<A>(&mut self.channels(), buf, &mut record)?;
+        expect::<PskFinishRsp>(buf)?;
+        PskFinishRsp::parse_body(&buf[HEADER_SIZE..])?;
+        transcript.extend(buf)?;
+        Ok(self.establish(transcript))
+    }
+
+    /// Derive the session keys from the complete handshake transcript, and
+    /// complete initialization.
+    pub fn establish(self, transcript: &mut Transcript) -> session::State {
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let th2 = key_schedule::th2(hash_algo, transcript);
+        // Unwrap is safe, as we only perform a PSK exchange if an AEAD
+        // algorithm was negotiated.
+        let session_keys = SessionKeys::new(
+            hash_algo,
+            self.algorithms.aead_algo_selected().unwrap(),
+            &self.handshake_secrets.handshake_secret,
+            th2.as_ref(),
+        );
+
+        // Any subsequent measurement transcript starts after the VCA messages
+        transcript.reset_to_vca();
+
+        // There is no certificate chain, as the responder was authenticated
+        // by the PSK.
+        session::State {
+            version: self.version,
+            requester_ct_exponent: self.requester_ct_exponent,
+            requester_cap: self.requester_cap,
+            responder_ct_exponent: self.responder_ct_exponent,
+            responder_cap: self.responder_cap,
+            algorithms: self.algorithms,
+            cert_slot: 0,
+            cert_chain: [0u8; MAX_CERT_CHAIN_SIZE],
+            cert_chain_size: 0,
+            trust_anchor: None,
+            measurement_hash_type: self.measurement_hash_type,
+            measurement_summary_hash: self.measurement_summary_hash,
+            session_id: Some(self.session_id),
+            session_keys: Some(session_keys),
+            heartbeat_period: self.heartbeat_period,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::convert::From;
+
+use super::challenge;
+use crate::config::{MAX_CERT_CHAIN_SIZE, MAX_DIGEST_SIZE};
+use crate::crypto::key_schedule::SessionKeys;
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::{Algorithms, MeasurementHashType, VersionEntry};
+
+/// The state of a requester after initialization is complete.
+///
+/// This contains everything negotiated and verified during initialization that
+/// is needed by the `RequesterSession`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub version: VersionEntry,
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+
+    // The slot and verified certificate chain of the responder
+    pub cert_slot: u8,
+    pub cert_chain: [u8; MAX_CERT_CHAIN_SIZE],
+    pub cert_chain_size: u16,
+
+    // The index of the root cert the responder's certificate chain leads to,
+    // or `None` if the responder was authenticated without a certificate
+    // chain.
+    pub trust_anchor: Option<usize>,
+
+    // The verified measurement summary hash returned in CHALLENGE_AUTH, or
+    // KEY_EXCHANGE_RSP if a key exchange took place.
+    pub measurement_hash_type: MeasurementHashType,
+    pub measurement_summary_hash: [u8; MAX_DIGEST_SIZE],
+
+    // The ID and keys of the secure session, if key exchange is supported
+    pub session_id: Option<u32>,
+    pub session_keys: Option<SessionKeys>,
+
+    // The heartbeat period in seconds set by the responder, or 0 if the
+    // session does not use heartbeats
+    pub heartbeat_period: u8,
+}
+
+impl From<challenge::State> for State {
+    fn from(s: challenge::State) -> Self {
+        State {
+            version: s.version,
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+            cert_slot: s.cert_slot,
+            cert_chain: s.cert_chain,
+            cert_chain_size: s.cert_chain_size,
+            trust_anchor: s.trust_anchor,
+            measurement_hash_type: s.measurement_hash_type,
+            measurement_summary_hash: s.measurement_summary_hash,
+            session_id: None,
+            session_keys: None,
+            heartbeat_period: 0,
+        }
+    }
+}
+
+impl State {
+    /// Return the serialized certificate chain of the responder
+    pub fn cert_chain(&self) -> &[u8] {
+        &self.cert_chain[..self.cert_chain_size as usize]
+    }
+
+    /// Return the verified measurement summary hash, or `None` if one was not
+    /// requested.
+    pub fn measurement_summary_hash(&self) -> Option<&[u8]> {
+        if self.measurement_hash_type == MeasurementHashType::None {
+            return None;
+        }
+        let digest_size =
+            self.algorithms.base_hash_algo_selected.get_digest_size();
+        Some(&self.measurement_summary_hash[..digest_size as usize])
+    }
+}
@@ -15,18 +15,44 @@
 pub mod algorithms;
 pub mod capabilities;
 pub mod challenge;
+pub mod end_session;
+pub mod finish;
+pub mod heartbeat;
 pub mod id_auth;
+pub mod key_exchange;
+pub mod key_update;
+pub mod measurements;
+pub mod mut_auth;
+pub mod psk_exchange;
+pub mod psk_finish;
+pub mod session;
 pub mod version;
 
 mod error;
 
 use crate::config;
-use crate::crypto::{FilledSlot, Signer};
-use crate::msgs::{self, CertificateChain, Msg};
+use crate::crypto::{
+    aead::{RingAead, TAG_SIZE},
+    dhe::RingEphemeralKey,
+    Aead, EphemeralKey, FilledSlot, ProvisionedKey, Signer,
+};
+use crate::msgs::{
+    self, Certificate, CertificateChain, Challenge, EndSession, Finish,
+    GetCertificate, GetMeasurements, GetVersion, Heartbeat, KeyExchange,
+    KeyUpdate, Msg, PskExchange, PskFinish,
+};
+use crate::secured_message::{self, Channels};
 use crate::Transcript;
 pub use error::ResponderError;
+pub use finish::MutualAuth;
+pub use measurements::MeasurementProvider;
+pub use psk_exchange::{NoPsk, PskProvider};
+use session::Phase;
+pub use session::{Session, Sessions};
 
 use core::convert::From;
+use core::marker::PhantomData;
+use core::time::Duration;
 
 /// `AllStates` is a container for all the states in a responder.
 ///
@@ -56,6 +82,8 @@ pub enum AllStates {
     Algorithms(algorithms::State),
     IdAuth(id_auth::State),
     Challenge(challenge::State),
+    MutAuth(mut_auth::State),
+    Measurements(measurements::State),
 }
 
 impl From<version::State> for AllStates {
@@ -88,14 +116,86 @@ impl From<challenge::State> for AllStates {
     }
 }
 
+impl From<mut_auth::State> for AllStates {
+    fn from(state: mut_auth::State) -> AllStates {
+        AllStates::MutAuth(state)
+    }
+}
+
+impl From<measurements::State> for AllStates {
+    fn from(state: measurements::State) -> AllStates {
+        AllStates::Measurements(state)
+    }
+}
+
 impl AllStates {
-    fn handle<'a, 'b, S: Signer>(
+    // Handle a request with the resources of `responder`, whose state is
+    // `self`, and has been taken out of it.
+    fn handle<
+        'a,
+        'b,
+        S: Signer,
+        M: MeasurementProvider,
+        D: EphemeralKey,
+        A: Aead,
+        P: PskProvider,
+    >(
         self,
         req: &[u8],
         rsp: &'a mut [u8],
-        transcript: &mut Transcript,
-        slots: &'b [Option<FilledSlot<'b, S>>; config::NUM_SLOTS],
+        responder: &mut Responder<'b, S, M, D, A, P>,
     ) -> (&'a [u8], AllStates, Result<(), ResponderError>) {
+        let transcript = &mut responder.transcript;
+        let slots = &responder.slots;
+        let keys = id_auth::Keys {
+            slots,
+            provisioned_key: responder.provisioned_key.as_ref(),
+        };
+        let measurements = &mut responder.measurements;
+        let sessions = &mut responder.sessions;
+        let psk_provider = responder.psk_provider.as_ref();
+        let mutual_auth = responder.mutual_auth.as_ref();
+        let requester_cert_chain = &mut responder.requester_cert_chain;
+
+        // A session requires mutual authentication once the requester's
+        // certificate chain is known, while CHALLENGE requires it until then.
+        let req_slot_id = mutual_auth
+            .filter(|m| {
+                m.cert_chain.is_some() || requester_cert_chain.is_some()
+            })
+            .map(|m| m.req_slot_id);
+        let encap_req_slot_id = mutual_auth
+            .filter(|m| m.cert_chain.is_none())
+            .map(|m| m.req_slot_id);
+
+        // A new session is rejected without affecting the state of the
+        // responder, or its other sessions.
+        if matches!(
+            self,
+            AllStates::IdAuth(_)
+                | AllStates::Challenge(_)
+                | AllStates::Measurements(_)
+        ) && (KeyExchange::parse_header(req) == Ok(true)
+            || PskExchange::parse_header(req) == Ok(true))
+            && sessions.is_full()
+        {
+            let err = ResponderError::SessionLimitExceeded;
+            return (write_error(&err, rsp), self, Err(err));
+        }
+
+        // Likewise for a GET_MEASUREMENTS request for an absent index, so that
+        // a requester can walk all indices to find the measurements.
+        if matches!(
+            self,
+            AllStates::IdAuth(_)
+                | AllStates::Challenge(_)
+                | AllStates::Measurements(_)
+        ) && measurements::is_absent_index(measurements, req)
+        {
+            let err = ResponderError::InvalidMeasurementIndex;
+            return (write_error(&err, rsp), self, Err(err));
+        }
+
         let res = match self {
             AllStates::Version(state) => state.handle_msg(req, rsp, transcript),
             AllStates::Capabilities(state) => {
@@ -104,17 +204,192 @@ impl AllStates {
             AllStates::Algorithms(state) => {
                 state.handle_msg(req, rsp, transcript)
             }
-            AllStates::IdAuth(state) => {
-                let mut cert_chains: [Option<CertificateChain<'b>>;
-                    config::NUM_SLOTS] = [None; config::NUM_SLOTS];
-                for i in 0..slots.len() {
-                    cert_chains[i] =
-                        slots[i].as_ref().map(|s| s.cert_chain.clone());
+            // GET_MEASUREMENTS may be sent any time after algorithm
+            // negotiation. It starts a new transcript (L1/L2) from the VCA
+            // messages.
+            AllStates::IdAuth(state)
+                if GetMeasurements::parse_header(req) == Ok(true) =>
+            {
+                transcript.reset_to_vca();
+                measurements::State::from(state).handle_msg(
+                    &keys,
+                    measurements,
+                    req,
+                    rsp,
+                    transcript,
+                )
+            }
+            AllStates::Challenge(state)
+                if GetMeasurements::parse_header(req) == Ok(true) =>
+            {
+                transcript.reset_to_vca();
+                measurements::State::from(state).handle_msg(
+                    &keys,
+                    measurements,
+                    req,
+                    rsp,
+                    transcript,
+                )
+            }
+            // KEY_EXCHANGE may also be sent any time after algorithm
+            // negotiation. The session is tracked outside of `AllStates`.
+            AllStates::IdAuth(state)
+                if KeyExchange::parse_header(req) == Ok(true) =>
+            {
+                key_exchange::State {
+                    req_slot_id,
+                    ..key_exchange::State::from(state)
+                }
+                .handle_msg::<S, M, D>(
+                    &keys,
+                    measurements,
+                    req,
+                    rsp,
+                    transcript,
+                    sessions,
+                )
+            }
+            AllStates::Challenge(state)
+                if KeyExchange::parse_header(req) == Ok(true) =>
+            {
+                key_exchange::State {
+                    req_slot_id,
+                    ..key_exchange::State::from(state)
+                }
+                .handle_msg::<S, M, D>(
+                    &keys,
+                    measurements,
+                    req,
+                    rsp,
+                    transcript,
+                    sessions,
+                )
+            }
+            AllStates::Measurements(state)
+                if KeyExchange::parse_header(req) == Ok(true) =>
+            {
+                key_exchange::State {
+                    req_slot_id,
+                    ..key_exchange::State::from(state)
+                }
+                .handle_msg::<S, M, D>(
+                    &keys,
+                    measurements,
+                    req,
+                    rsp,
+                    transcript,
+                    sessions,
+                )
+            }
+            // Likewise for PSK_EXCHANGE
+            AllStates::IdAuth(state)
+                if PskExchange::parse_header(req) == Ok(true) =>
+            {
+                psk_exchange::State::from(state).handle_msg(
+                    measurements,
+                    psk_provider,
+                    req,
+                    rsp,
+                    transcript,
+                    sessions,
+                )
+            }
+            AllStates::Challenge(state)
+                if PskExchange::parse_header(req) == Ok(true) =>
+            {
+                psk_exchange::State::from(state).handle_msg(
+                    measurements,
+                    psk_provider,
+                    req,
+                    rsp,
+                    transcript,
+                    sessions,
+                )
+            }
+            AllStates::Measurements(state)
+                if PskExchange::parse_header(req) == Ok(true) =>
+            {
+                psk_exchange::State::from(state).handle_msg(
+                    measurements,
+                    psk_provider,
+                    req,
+                    rsp,
+                    transcript,
+                    sessions,
+                )
+            }
+            // KEY_EXCHANGE always returns to the Measurements state. The
+            // session handshake is completed by FINISH within the session,
+            // unless the handshake is in the clear.
+            AllStates::Measurements(state)
+                if Finish::parse_header(req) == Ok(true) =>
+            {
+                finish::handle_clear_msg(
+                    req,
+                    rsp,
+                    sessions,
+                    mutual_auth,
+                    requester_cert_chain
+                        .as_ref()
+                        .map(|c| &c.cert_chain[..c.portion_length as usize]),
+                )
+                .map(|size| (size, state.into()))
+            }
+            // PSK_FINISH is always received within the session
+            AllStates::Measurements(state)
+                if PskFinish::parse_header(req) == Ok(true) =>
+            {
+                psk_finish::handle_clear_msg(sessions)
+                    .map(|size| (size, state.into()))
+            }
+            // A requester that cached our certificate chains sends CHALLENGE
+            // right after DIGESTS
+            AllStates::IdAuth(state)
+                if Challenge::parse_header(req) == Ok(true) =>
+            {
+                challenge::State {
+                    req_slot_id: encap_req_slot_id,
+                    ..challenge::State::from(state)
                 }
-                state.handle_msg(&cert_chains, req, rsp, transcript)
+                .handle_msg(
+                    &keys,
+                    measurements,
+                    req,
+                    rsp,
+                    transcript,
+                )
             }
+            AllStates::IdAuth(state) => {
+                state.handle_msg(&cert_chains(slots), req, rsp, transcript)
+            }
+            // The requester retrieves the certificate chains of all slots
+            // before CHALLENGE, but we move on after the first one.
+            AllStates::Challenge(state)
+                if GetCertificate::parse_header(req) == Ok(true) =>
+            {
+                id_auth::State::from(state).handle_msg(
+                    &cert_chains(slots),
+                    req,
+                    rsp,
+                    transcript,
+                )
+            }
+            // Basic mutual authentication is requested in CHALLENGE_AUTH
             AllStates::Challenge(state) => {
-                state.handle_msg(slots, req, rsp, transcript)
+                challenge::State { req_slot_id: encap_req_slot_id, ..state }
+                    .handle_msg(&keys, measurements, req, rsp, transcript)
+            }
+            // Unwrap is safe, as the MutAuth state is only entered when
+            // mutual authentication is set.
+            AllStates::MutAuth(state) => state.handle_msg(
+                req,
+                rsp,
+                transcript,
+                mutual_auth.unwrap(),
+                requester_cert_chain,
+            ),
+            AllStates::Measurements(state) => {
+                state.handle_msg(&keys, measurements, req, rsp, transcript)
             }
             _ => unimplemented!(),
         };
@@ -123,17 +398,11 @@ impl AllStates {
             Err(responder_err) => {
                 // Write an error message into `rsp` and return it along with
                 // the error.
-                let err_msg: msgs::Error = (&responder_err).into();
-
-                // If we fail writing the error, just return the empty slice.
-                match err_msg.write(rsp) {
-                    Ok(size) => {
-                        (&rsp[..size], AllStates::Error, Err(responder_err))
-                    }
-                    Err(_) => {
-                        (&rsp[0..0], AllStates::Error, Err(responder_err))
-                    }
-                }
+                (
+                    write_error(&responder_err, rsp),
+                    AllStates::Error,
+                    Err(responder_err),
+                )
             }
         }
     }
@@ -147,31 +416,131 @@ impl AllStates {
             AllStates::Algorithms(_) => "Algorithms",
             AllStates::IdAuth(_) => "IdAuth",
             AllStates::Challenge(_) => "Challenge",
+            AllStates::MutAuth(_) => "MutAuth",
+            AllStates::Measurements(_) => "Measurements",
         }
     }
 }
 
 /// A wrapper around the Responder state machine states contained in
 /// `AllStates`.
-pub struct Responder<'a, S: Signer> {
+///
+/// Ephemeral keys for KEY_EXCHANGE are generated with `D`, and secured
+/// messages are encrypted and decrypted with `A`. Both default to software
+/// implementations backed by ring. PSKs for PSK_EXCHANGE are looked up with
+/// `P`, which by default has no keys.
+pub struct Responder<
+    'a,
+    S: Signer,
+    M: MeasurementProvider,
+    D: EphemeralKey = RingEphemeralKey,
+    A: Aead = RingAead,
+    P: PskProvider = NoPsk,
+> {
     slots: [Option<FilledSlot<'a, S>>; config::NUM_SLOTS],
+    measurements: M,
     transcript: Transcript,
+
+    // A raw public key, provisioned to requesters in place of a certificate
+    // chain
+    provisioned_key: Option<ProvisionedKey<'a, S>>,
+
+    // PSK sessions are only supported once a provider is set
+    psk_provider: Option<P>,
+
+    // Requesters are only authenticated once their identity is set
+    mutual_auth: Option<MutualAuth<'a>>,
+
+    // The requester's certificate chain, once retrieved with encapsulated
+    // requests. It is only verified once the MutAuth state is left.
+    requester_cert_chain: Option<Certificate<{ config::MAX_CERT_CHAIN_SIZE }>>,
+
+    // The sessions created by KEY_EXCHANGE or PSK_EXCHANGE requests
+    sessions: Sessions,
+
     // This Option allows us to move between states at runtime, without having
     // to take self by value.
     state: Option<AllStates>,
+
+    // Keys are only generated, never stored
+    ephemeral_key: PhantomData<fn() -> D>,
+
+    // Keys are only instantiated while encoding or decoding a record
+    aead: PhantomData<fn() -> A>,
 }
 
-impl<'a, S: Signer> Responder<'a, S> {
+impl<
+        'a,
+        S: Signer,
+        M: MeasurementProvider,
+        D: EphemeralKey,
+        A: Aead,
+        P: PskProvider,
+    > Responder<'a, S, M, D, A, P>
+{
     pub fn new(
         slots: [Option<FilledSlot<'a, S>>; config::NUM_SLOTS],
-    ) -> Responder<'a, S> {
+        measurements: M,
+    ) -> Responder<'a, S, M, D, A, P> {
         Responder {
             slots,
+            measurements,
             transcript: Transcript::new(),
+            provisioned_key: None,
+            psk_provider: None,
+            mutual_auth: None,
+            requester_cert_chain: None,
+            sessions: Sessions::new(),
             state: Some(version::State {}.into()),
+            ephemeral_key: PhantomData,
+            aead: PhantomData,
         }
     }
 
+    /// Authenticate with `provisioned_key` to requesters that were provisioned
+    /// with its public key, and challenge slot 0xF.
+    ///
+    /// PUB_KEY_ID_CAP must also be set in the responder's capabilities.
+    pub fn set_provisioned_key(
+        &mut self,
+        provisioned_key: ProvisionedKey<'a, S>,
+    ) {
+        self.provisioned_key = Some(provisioned_key);
+    }
+
+    /// Accept PSK_EXCHANGE requests for the PSKs of `psk_provider`.
+    ///
+    /// PSK_CAP must also be set in the responder's capabilities.
+    pub fn set_psk_provider(&mut self, psk_provider: P) {
+        self.psk_provider = Some(psk_provider);
+    }
+
+    /// Require requesters to authenticate as `mutual_auth` when creating a
+    /// session with KEY_EXCHANGE.
+    ///
+    /// MUT_AUTH_CAP must also be set in the capabilities of both sides, and a
+    /// requester signing algorithm must be negotiated. Otherwise, sessions
+    /// are created without authenticating the requester.
+    ///
+    /// If the requester's certificate chain is not provisioned in
+    /// `mutual_auth`, it is retrieved with encapsulated requests after
+    /// CHALLENGE, which requires ENCAP_CAP on both sides. Until then, sessions
+    /// are created without authenticating the requester.
+    pub fn set_mutual_auth(&mut self, mutual_auth: MutualAuth<'a>) {
+        self.mutual_auth = Some(mutual_auth);
+    }
+
+    /// Return the requester's certificate chain, once it is retrieved and
+    /// verified with encapsulated requests.
+    pub fn requester_cert_chain(&self) -> Option<&[u8]> {
+        if let Some(AllStates::MutAuth(_)) = self.state {
+            return None;
+        }
+        self.requester_cert_chain
+            .as_ref()
+            .map(|c| &c.cert_chain[..c.portion_length as usize])
+    }
+
     // Return the serialized output message, including the serialized error
     // response, and an error if the responder should be shutdown.
     pub fn handle_msg<'b>(
@@ -179,13 +548,264 @@ impl<'a, S: Signer> Responder<'a, S> {
         req: &[u8],
         rsp: &'b mut [u8],
     ) -> (&'b [u8], Result<(), ResponderError>) {
+        // A GET_VERSION request terminates all sessions, and forgets the
+        // requester
+        if GetVersion::parse_header(req) == Ok(true) {
+            self.sessions.clear();
+            self.requester_cert_chain = None;
+        }
         let state = self.state.take().unwrap();
-        let (out, next_state, result) =
-            state.handle(req, rsp, &mut self.transcript, &self.slots);
+        let (out, next_state, result) = state.handle(req, rsp, self);
         self.state = Some(next_state);
         (out, result)
     }
 
+    /// Handle an SPDM request sent within a session.
+    ///
+    /// `req` is a secured message, which is decrypted in place. The response
+    /// is written into `rsp` as a secured message of the same session.
+    ///
+    /// If `req` cannot be decrypted, its session is terminated, and an ERROR
+    /// response is written in the clear. The state of the responder outside of
+    /// the session, and any other sessions, are unaffected.
+    ///
+    /// Unless the handshake is in the clear, a session created by KEY_EXCHANGE
+    /// is established by a FINISH request sent within the session, protected
+    /// by the handshake keys. No other request is accepted until then, and the
+    /// session is terminated if FINISH fails.
+    ///
+    /// Once the session is established, GET_MEASUREMENTS, KEY_UPDATE, HEARTBEAT
+    /// and END_SESSION requests are accepted. They are handled against the
+    /// session alone: measurement messages extend the transcript of the
+    /// session (L1/L2), and an error never affects the state of the responder
+    /// outside of the session. The session is terminated once END_SESSION_ACK
+    /// is written.
+    ///
+    /// `now` is the current time of the monotonic clock passed to
+    /// `expire_sessions`, and marks the session as active.
+    pub fn handle_secured_msg<'b>(
+        &mut self,
+        req: &mut [u8],
+        rsp: &'b mut [u8],
+        now: Duration,
+    ) -> (&'b [u8], Result<(), ResponderError>) {
+        let session_id = match secured_message::session_id(req) {
+            Ok(session_id) => session_id,
+            Err(e) => {
+                let err = e.into();
+                return (write_error(&err, rsp), Err(err));
+            }
+        };
+        let req = match self.open(session_id, req, now) {
+            Ok(req) => req,
+            Err(err) => {
+                self.sessions.remove(session_id);
+                return (write_error(&err, rsp), Err(err));
+            }
+        };
+
+        // Unwrap is safe, as the session was found by `open`
+        let record_layer = self.channels_mut(session_id).unwrap().record_layer;
+        let start = record_layer.app_data_offset();
+        if rsp.len() < start + TAG_SIZE {
+            let err = secured_message::Error::BufferTooSmall.into();
+            return (write_error(&err, rsp), Err(err));
+        }
+        let end = rsp.len() - TAG_SIZE;
+
+        // The response is serialized directly into the secured message
+        let mut end_session = false;
+        let mut handshake = false;
+        let (size, result) = if self.established(session_id).is_err() {
+            handshake = true;
+            let res = self.handle_handshake_msg(
+                session_id,
+                req,
+                &mut rsp[start..end],
+            );
+            if res.is_err() {
+                self.sessions.remove(session_id);
+            }
+            response_size(res, &mut rsp[start..end])
+        } else if GetMeasurements::parse_header(req) == Ok(true) {
+            let keys = id_auth::Keys {
+                slots: &self.slots,
+                provisioned_key: self.provisioned_key.as_ref(),
+            };
+            // Unwrap is safe, as the session was found by `open`
+            let session = self.sessions.get_mut(session_id).unwrap();
+            let res = measurements::handle_session_msg(
+                &keys,
+                &mut self.measurements,
+                req,
+                &mut rsp[start..end],
+                session,
+            );
+            response_size(res, &mut rsp[start..end])
+        } else if KeyUpdate::parse_header(req) == Ok(true) {
+            // Unwrap is safe, as the session was found by `open`
+            let session = self.sessions.get_mut(session_id).unwrap();
+            let res =
+                key_update::handle_msg(req, &mut rsp[start..end], session);
+            response_size(res, &mut rsp[start..end])
+        } else if Heartbeat::parse_header(req) == Ok(true) {
+            // Unwrap is safe, as the session was found by `open`
+            let session = self.sessions.get(session_id).unwrap();
+            let res = heartbeat::handle_msg(req, &mut rsp[start..end], session);
+            response_size(res, &mut rsp[start..end])
+        } else if EndSession::parse_header(req) == Ok(true) {
+            let res = end_session::handle_msg(req, &mut rsp[start..end]);
+            end_session = res.is_ok();
+            response_size(res, &mut rsp[start..end])
+        } else {
+            let err = ResponderError::UnexpectedRequestInSession;
+            (write_error(&err, &mut rsp[start..end]).len(), Err(err))
+        };
+
+        let channels = match self.channels_mut(session_id) {
+            Ok(channels) => channels,
+            // The request terminated the session
+            Err(_) => return (&rsp[start..start + size], result),
+        };
+        let out = match record_layer.encode_in_place::<A>(
+            &mut channels.response,
+            size,
+            rsp,
+        ) {
+            Ok(size) => (&rsp[..size], result),
+            Err(e) => {
+                let err = e.into();
+                (write_error(&err, rsp), Err(err))
+            }
+        };
+        if end_session {
+            self.sessions.remove(session_id);
+        }
+        // The session keys replace the handshake keys once FINISH_RSP is sent
+        if handshake {
+            // Unwrap is safe, as the session was found by `open`
+            self.sessions.get_mut(session_id).unwrap().establish();
+        }
+        out
+    }
+
+    /// Wrap application data in a secured message of the given session,
+    /// written into `buf`.
+    pub fn send<'b>(
+        &mut self,
+        session_id: u32,
+        app_data: &[u8],
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], ResponderError> {
+        self.established(session_id)?;
+        let channels = self.channels_mut(session_id)?;
+        let size = channels.record_layer.encode::<A>(
+            &mut channels.response,
+            app_data,
+            buf,
+        )?;
+        Ok(&buf[..size])
+    }
+
+    /// Unwrap the application data of a secured message received from the
+    /// requester. The message is decrypted in place.
+    ///
+    /// The session the application data belongs to is given by
+    /// `secured_message::session_id`. `now` is the current time of the
+    /// monotonic clock passed to `expire_sessions`, and marks the session as
+    /// active.
+    pub fn recv<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+        now: Duration,
+    ) -> Result<&'b [u8], ResponderError> {
+        let session_id = secured_message::session_id(buf)?;
+        self.established(session_id)?;
+        self.open(session_id, buf, now)
+    }
+
+    /// Terminate the sessions that the requester let expire, and return how
+    /// many were terminated.
+    ///
+    /// A session with a heartbeat period expires if no secured message was
+    /// received within twice that period. `now` is the current time of a
+    /// monotonic clock owned by the application.
+    pub fn expire_sessions(&mut self, now: Duration) -> usize {
+        self.sessions.remove_if(|s| s.heartbeat.is_expired(now))
+    }
+
+    // Decrypt a secured message of the given session from the requester in
+    // place, and return its application data. This marks the session as
+    // active at time `now`.
+    fn open<'b>(
+        &mut self,
+        session_id: u32,
+        buf: &'b mut [u8],
+        now: Duration,
+    ) -> Result<&'b [u8], ResponderError> {
+        let session = self
+            .sessions
+            .get_mut(session_id)
+            .ok_or(ResponderError::UnknownSession(session_id))?;
+        let channels = session
+            .channels
+            .as_mut()
+            .ok_or(ResponderError::UnknownSession(session_id))?;
+        let app_data = channels.decode_request::<A>(buf)?;
+        session.heartbeat.mark_active(now);
+        Ok(app_data)
+    }
+
+    // Handle a request within a session whose handshake is in progress. Only
+    // FINISH is accepted, and the session is established by the caller once
+    // FINISH_RSP is sent.
+    fn handle_handshake_msg(
+        &mut self,
+        session_id: u32,
+        req: &[u8],
+        rsp: &mut [u8],
+    ) -> Result<usize, ResponderError> {
+        // Unwrap is safe, as the session was found by `open`
+        let session = self.sessions.get_mut(session_id).unwrap();
+        match session.phase {
+            Phase::Handshake if Finish::parse_header(req) == Ok(true) => {
+                finish::handle_msg(
+                    req,
+                    rsp,
+                    session,
+                    self.mutual_auth.as_ref(),
+                    self.requester_cert_chain
+                        .as_ref()
+                        .map(|c| &c.cert_chain[..c.portion_length as usize]),
+                )
+            }
+            Phase::PskHandshake if PskFinish::parse_header(req) == Ok(true) => {
+                psk_finish::handle_msg(req, rsp, session)
+            }
+            _ => Err(ResponderError::UnexpectedRequestInSession),
+        }
+    }
+
+    // Return an error unless the given session is established. Application
+    // data is only sent once the handshake is complete.
+    fn established(&self, session_id: u32) -> Result<(), ResponderError> {
+        match self.sessions.get(session_id) {
+            Some(s) if s.phase == Phase::Established => Ok(()),
+            _ => Err(ResponderError::UnknownSession(session_id)),
+        }
+    }
+
+    // Return the record layer of the given session
+    fn channels_mut(
+        &mut self,
+        session_id: u32,
+    ) -> Result<&mut Channels, ResponderError> {
+        self.sessions
+            .get_mut(session_id)
+            .and_then(|s| s.channels.as_mut())
+            .ok_or(ResponderError::UnknownSession(session_id))
+    }
+
     // Return the current state of the responder
     //
     // It's safe to unwrap here, as the invariant of a Responder is that the
@@ -201,6 +821,49 @@ impl<'a, S: Signer> Responder<'a, S> {
     pub fn slots(&self) -> &[Option<FilledSlot<'a, S>>; config::NUM_SLOTS] {
         &self.slots
     }
+
+    pub fn measurements(&self) -> &M {
+        &self.measurements
+    }
+
+    /// Return the session with the given ID
+    pub fn session(&self, session_id: u32) -> Option<&Session> {
+        self.sessions.get(session_id)
+    }
+
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+}
+
+// Return the size of the response to a request handled within a session, or
+// of the ERROR response written into `rsp` in its place.
+fn response_size(
+    res: Result<usize, ResponderError>,
+    rsp: &mut [u8],
+) -> (usize, Result<(), ResponderError>) {
+    match res {
+        Ok(size) => (size, Ok(())),
+        Err(err) => (write_error(&err, rsp).len(), Err(err)),
+    }
+}
+
+// Return the certificate chain of each slot
+fn cert_chains<'a, S: Signer>(
+    slots: &[Option<FilledSlot<'a, S>>; config::NUM_SLOTS],
+) -> [Option<CertificateChain<'a>>; config::NUM_SLOTS] {
+    core::array::from_fn(|i| slots[i].as_ref().map(|s| s.cert_chain.clone()))
+}
+
+// Write the error message for `err` into `rsp`.
+//
+// If we fail writing the error, just return the empty slice.
+fn write_error<'a>(err: &ResponderError, rsp: &'a mut [u8]) -> &'a [u8] {
+    let err_msg: msgs::Error = err.into();
+    match err_msg.write(rsp) {
+        Ok(size) => &rsp[..size],
+        Err(_) => &rsp[0..0],
+    }
 }
 
 /// Go back to the Version state and process a GetVersion message.
@@ -49,6 +49,7 @@ impl State {
         let algorithms = self.choose_algorithms(req_msg);
         let size = algorithms.write(rsp)?;
         transcript.extend(&rsp[..size])?;
+        transcript.mark_vca();
         self.algorithms = Some(algorithms);
 
         Ok((size, id_auth::State::from(self).into()))
@@ -4,19 +4,20 @@
 
 use core::convert::From;
 
-use super::{expect, id_auth, AllStates, ResponderError};
+use super::measurements::{self, MeasurementProvider};
+use super::{expect, id_auth, mut_auth, AllStates, ResponderError};
 
-use crate::config::{
-    MAX_CERT_CHAIN_SIZE, MAX_DIGEST_SIZE, MAX_SIGNATURE_SIZE, NUM_SLOTS,
-};
+use crate::config::{MAX_DIGEST_SIZE, MAX_SIGNATURE_SIZE, NUM_SLOTS};
 use crate::crypto::{
     digest::{Digest, DigestImpl},
-    FilledSlot, Signer,
+    Signer,
 };
 use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::challenge::{
+    nonce, PROVISIONED_KEY_SLOT, PROVISIONED_KEY_SLOT_ID,
+};
 use crate::msgs::{
-    challenge::nonce, encoding::Writer, Algorithms, Challenge, ChallengeAuth,
-    Msg, HEADER_SIZE,
+    Algorithms, Challenge, ChallengeAuth, MeasurementHashType, Msg, HEADER_SIZE,
 };
 use crate::{reset_on_get_version, Transcript};
 
@@ -28,6 +29,10 @@ pub struct State {
     pub responder_ct_exponent: u8,
     pub responder_cap: RspFlags,
     pub algorithms: Algorithms,
+
+    // The slot of the requester's certificate chain, if the requester must be
+    // authenticated with encapsulated requests
+    pub req_slot_id: Option<u8>,
 }
 
 impl From<id_auth::State> for State {
@@ -38,6 +43,7 @@ impl From<id_auth::State> for State {
             responder_ct_exponent: s.responder_ct_exponent,
             responder_cap: s.responder_cap,
             algorithms: s.algorithms,
+            req_slot_id: None,
         }
     }
 }
@@ -45,10 +51,15 @@ impl From<id_auth::State> for State {
 impl State {
     /// Handle a message from a requester
     ///
-    /// Only CHALLENGE and GET_VERSION msgs are allowed here.
-    pub fn handle_msg<'a, S: Signer>(
+    /// Only CHALLENGE and GET_VERSION msgs are allowed here. GET_MEASUREMENTS
+    /// msgs are routed to the measurements state by `AllStates`.
+    ///
+    /// If basic mutual authentication is requested in CHALLENGE_AUTH, the
+    /// responder then authenticates the requester in the MutAuth state.
+    pub fn handle_msg<S: Signer, M: MeasurementProvider>(
         self,
-        slots: &[Option<FilledSlot<'a, S>>; NUM_SLOTS],
+        keys: &id_auth::Keys<'_, '_, S>,
+        measurements: &mut M,
         req: &[u8],
         rsp: &mut [u8],
         transcript: &mut Transcript,
@@ -62,29 +73,41 @@ impl State {
 
         let req_msg = Challenge::parse_body(&req[HEADER_SIZE..])?;
 
+        // TODO: Should we fail this if the selected hash algorithm does
+        // not match the slot?
+        // See https://github.com/oxidecomputer/spdm/issues/25
         let cert_chain_digest =
-            if let Some(Some(slot)) = slots.get(req_msg.slot as usize) {
-                let mut buf = [0u8; MAX_CERT_CHAIN_SIZE];
-                let mut w = Writer::new("CERTIFICATE_CHAIN", &mut buf);
-                let size = slot.cert_chain.write(&mut w)?;
-
-                // TODO: Should we fail this if the selected hash algorithm does
-                // not match the slot?
-                // See https://github.com/oxidecomputer/spdm/issues/25
-                DigestImpl::hash(
-                    self.algorithms.base_hash_algo_selected,
-                    &buf[..size],
-                )
-            } else {
-                return Err(ResponderError::InvalidSlot);
-            };
-
-        let use_mutual_auth =
-            self.requester_cap.contains(ReqFlags::MUT_AUTH_CAP)
-                && self.responder_cap.contains(RspFlags::MUT_AUTH_CAP);
-
-        // TODO: Actually return measurement hashes if requested
-        let measurement_summary_hash = [0u8; MAX_DIGEST_SIZE];
+            keys.digest(req_msg.slot, self.algorithms.base_hash_algo_selected)?;
+        let signer = keys.signer(req_msg.slot)?;
+
+        // A provisioned public key is 0xF in CHALLENGE_AUTH, and no
+        // certificate slots are reported alongside it
+        let (slot, slot_mask) = if req_msg.slot == PROVISIONED_KEY_SLOT_ID {
+            (PROVISIONED_KEY_SLOT, 0)
+        } else {
+            (req_msg.slot, id_auth::create_slot_mask(keys.slots))
+        };
+
+        let req_slot_id = self.mut_auth_slot();
+        let use_mutual_auth = req_slot_id.is_some();
+
+        // The summary hash is all zeros if none was requested
+        let mut measurement_summary_hash = [0u8; MAX_DIGEST_SIZE];
+        if req_msg.measurement_hash_type != MeasurementHashType::None
+            && !self
+                .responder_cap
+                .intersects(RspFlags::MEAS_CAP_NO_SIG | RspFlags::MEAS_CAP_SIG)
+        {
+            return Err(ResponderError::MeasurementsUnsupported);
+        }
+        if let Some(digest) = measurements::measurement_summary_hash(
+            measurements,
+            req_msg.measurement_hash_type,
+            self.algorithms.base_hash_algo_selected,
+        )? {
+            measurement_summary_hash[..digest_size as usize]
+                .copy_from_slice(digest.as_ref());
+        }
 
         transcript.extend(req)?;
 
@@ -98,8 +121,8 @@ impl State {
         let dummy_sig = [0u8; MAX_SIGNATURE_SIZE];
 
         let auth = ChallengeAuth::new(
-            req_msg.slot,
-            id_auth::create_slot_mask(slots),
+            slot,
+            slot_mask,
             use_mutual_auth,
             cert_chain_digest.as_ref(),
             nonce(),
@@ -118,15 +141,6 @@ impl State {
             transcript.get(),
         );
 
-        // Unwrap is safe because we are already using this slot
-        let signer = &slots
-            .get(req_msg.slot as usize)
-            .as_ref()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .signer;
-
         let signature = signer
             .sign(m1_hash.as_ref())
             .map_err(|_| ResponderError::SigningFailed)?;
@@ -134,6 +148,29 @@ impl State {
         // Attach the real signature to the CHALLENGE_AUTH message
         rsp[sig_start..size].copy_from_slice(signature.as_ref());
 
-        Ok((size, self.into()))
+        // Any subsequent transcript starts after the VCA messages
+        transcript.reset_to_vca();
+
+        match req_slot_id {
+            Some(slot) => Ok((size, mut_auth::State::new(self, slot).into())),
+            None => Ok((size, measurements::State::from(self).into())),
+        }
+    }
+
+    // Return the slot of the requester's certificate chain if the requester
+    // must be authenticated with encapsulated requests.
+    //
+    // This requires both sides to set MUT_AUTH_CAP and ENCAP_CAP, and a
+    // requester signing algorithm to be negotiated.
+    fn mut_auth_slot(&self) -> Option<u8> {
+        self.req_slot_id.filter(|&slot| {
+            self.requester_cap
+                .contains(ReqFlags::MUT_AUTH_CAP | ReqFlags::ENCAP_CAP)
+                && self
+                    .responder_cap
+                    .contains(RspFlags::MUT_AUTH_CAP | RspFlags::ENCAP_CAP)
+                && self.algorithms.req_base_asym_algo_selected().is_some()
+                && (slot as usize) < NUM_SLOTS
+        })
     }
 }
@@ -0,0 +1,19 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{expect, ResponderError};
+
+use crate::msgs::{EndSession, EndSessionAck, Msg, HEADER_SIZE};
+
+/// Handle an END_SESSION request received within a session
+///
+/// The END_SESSION_ACK is still sent within the session, which is terminated
+/// by the caller afterwards. This responder never caches the negotiated state
+/// across a reset, so there is nothing more to clear if the requester asks for
+/// it.
+pub fn handle_msg(req: &[u8], rsp: &mut [u8]) -> Result<usize, ResponderError> {
+    expect::<EndSession>(req)?;
+    EndSession::parse_body(&req[HEADER_SIZE..])?;
+    Ok(EndSessionAck {}.write(rsp)?)
+}
@@ -5,6 +5,7 @@
 use core::fmt::{self, Display, Formatter};
 
 use crate::msgs::{self, ReadError, ReadErrorKind, WriteError};
+use crate::secured_message;
 
 /// An error returned by a responder state
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -15,11 +16,78 @@ pub enum ResponderError {
     // `got` is the code. TODO: Try to map this to a message name?
     UnexpectedMsg { expected: &'static str, got: u8 },
 
-    // A challenge was sent with a slot that does not contain a cert
+    // A challenge was sent with a slot that does not contain a cert, or a
+    // provisioned public key
     InvalidSlot,
 
+    // A GET_CERTIFICATE request asked for a portion beyond the end of the
+    // certificate chain
+    InvalidCertificateOffset,
+
     // For some reason signing failed. This could be caused by a HW failure.
     SigningFailed,
+
+    // The request is not supported by this responder. `0` is the request
+    // code.
+    UnsupportedRequest(u8),
+
+    // Measurements were requested, but MEAS_CAP is not set
+    MeasurementsUnsupported,
+
+    // A signature was requested for measurements, but MEAS_CAP_SIG is not set
+    SignedMeasurementsUnsupported,
+
+    // The requested measurement index does not exist
+    InvalidMeasurementIndex,
+
+    // The exchange data of a KEY_EXCHANGE request is not a valid public key
+    InvalidExchangeData,
+
+    // An ephemeral key could not be generated. This could be caused by a HW
+    // failure.
+    KeyExchangeFailed,
+
+    // A FINISH request arrived without a session in the handshake phase
+    NoHandshakeInProgress,
+
+    // A FINISH or PSK_FINISH request arrived in the clear, but the handshake
+    // must be completed within the session
+    HandshakeNotInTheClear,
+
+    // A FINISH request contained a signature, but mutual authentication was
+    // not requested
+    MutAuthNotRequested,
+
+    // A FINISH request did not contain a signature, but mutual authentication
+    // was requested
+    MutAuthRequired,
+
+    // The requester's certificate chain or its signature in a FINISH request
+    // or an encapsulated CHALLENGE_AUTH did not verify
+    RequesterAuthFailed,
+
+    // A DELIVER_ENCAPSULATED_RESPONSE request did not answer the outstanding
+    // encapsulated request
+    BadEncapsulatedResponse,
+
+    // The RequesterVerifyData of a FINISH request did not verify
+    InvalidVerifyData,
+
+    // A secured message does not belong to an established session. `0` is
+    // the session ID.
+    UnknownSession(u32),
+
+    // A secured message could not be encoded or decoded
+    SecuredMessage(secured_message::Error),
+
+    // The request is not allowed within a session, such as GET_VERSION
+    UnexpectedRequestInSession,
+
+    // The PSK hint of a PSK_EXCHANGE request does not identify a known PSK
+    UnknownPskHint,
+
+    // The responder already holds as many sessions as it can
+    SessionLimitExceeded,
 }
 
 impl From<WriteError> for ResponderError {
@@ -34,6 +102,12 @@ impl From<ReadError> for ResponderError {
     }
 }
 
+impl From<secured_message::Error> for ResponderError {
+    fn from(e: secured_message::Error) -> Self {
+        ResponderError::SecuredMessage(e)
+    }
+}
+
 impl Display for ResponderError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -52,9 +126,66 @@ impl Display for ResponderError {
             ResponderError::InvalidSlot => {
                 write!(f, "the requested slot does not contain a certificate")
             }
+            ResponderError::InvalidCertificateOffset => {
+                write!(f, "certificate offset out of range")
+            }
             ResponderError::SigningFailed => {
                 write!(f, "signing failed")
             }
+            ResponderError::UnsupportedRequest(code) => {
+                write!(f, "unsupported request (code: {})", code)
+            }
+            ResponderError::MeasurementsUnsupported => {
+                write!(f, "measurements are not supported")
+            }
+            ResponderError::SignedMeasurementsUnsupported => {
+                write!(f, "signed measurements are not supported")
+            }
+            ResponderError::InvalidMeasurementIndex => {
+                write!(f, "the requested measurement index does not exist")
+            }
+            ResponderError::InvalidExchangeData => {
+                write!(f, "invalid key exchange data")
+            }
+            ResponderError::KeyExchangeFailed => {
+                write!(f, "key exchange failed")
+            }
+            ResponderError::NoHandshakeInProgress => {
+                write!(f, "no session handshake in progress")
+            }
+            ResponderError::HandshakeNotInTheClear => {
+                write!(f, "session handshake not in the clear")
+            }
+            ResponderError::MutAuthNotRequested => {
+                write!(f, "mutual authentication was not requested")
+            }
+            ResponderError::MutAuthRequired => {
+                write!(f, "mutual authentication was requested")
+            }
+            ResponderError::RequesterAuthFailed => {
+                write!(f, "requester authentication failed")
+            }
+            ResponderError::BadEncapsulatedResponse => {
+                write!(f, "bad encapsulated response")
+            }
+            ResponderError::InvalidVerifyData => {
+                write!(f, "invalid requester verify data")
+            }
+            ResponderError::UnknownSession(id) => {
+                write!(f, "unknown session (id: {:#x})", id)
+            }
+            ResponderError::SecuredMessage(e) => {
+                write!(f, "secured message error: {:?}", e)
+            }
+            ResponderError::UnexpectedRequestInSession => {
+                write!(f, "request not allowed within a session")
+            }
+            ResponderError::UnknownPskHint => {
+                write!(f, "unknown PSK hint")
+            }
+            ResponderError::SessionLimitExceeded => {
+                write!(f, "session limit exceeded")
+            }
         }
     }
 }
@@ -84,7 +215,46 @@ impl From<&ResponderError> for msgs::Error {
                 msgs::Error::UnexpectedRequest
             }
             ResponderError::InvalidSlot => msgs::Error::UnexpectedRequest,
+            ResponderError::InvalidCertificateOffset => {
+                msgs::Error::InvalidRequest
+            }
             ResponderError::SigningFailed => msgs::Error::Unspecified,
+            ResponderError::UnsupportedRequest(code) => {
+                msgs::Error::UnsupportedRequest(*code)
+            }
+            ResponderError::MeasurementsUnsupported => {
+                msgs::Error::InvalidRequest
+            }
+            ResponderError::SignedMeasurementsUnsupported => {
+                msgs::Error::InvalidRequest
+            }
+            ResponderError::InvalidMeasurementIndex => {
+                msgs::Error::InvalidRequest
+            }
+            ResponderError::InvalidExchangeData => msgs::Error::InvalidRequest,
+            ResponderError::KeyExchangeFailed => msgs::Error::Unspecified,
+            ResponderError::NoHandshakeInProgress => {
+                msgs::Error::UnexpectedRequest
+            }
+            ResponderError::HandshakeNotInTheClear => {
+                msgs::Error::UnexpectedRequest
+            }
+            ResponderError::MutAuthNotRequested => msgs::Error::InvalidRequest,
+            ResponderError::MutAuthRequired => msgs::Error::InvalidRequest,
+            ResponderError::RequesterAuthFailed => msgs::Error::DecryptError,
+            ResponderError::BadEncapsulatedResponse => {
+                msgs::Error::InvalidRequest
+            }
+            ResponderError::InvalidVerifyData => msgs::Error::DecryptError,
+            ResponderError::UnknownSession(_) => msgs::Error::InvalidRequest,
+            ResponderError::SecuredMessage(_) => msgs::Error::DecryptError,
+            ResponderError::UnexpectedRequestInSession => {
+                msgs::Error::UnexpectedRequest
+            }
+            ResponderError::UnknownPskHint => msgs::Error::InvalidRequest,
+            ResponderError::SessionLimitExceeded => {
+                msgs::Error::SessionLimitExceeded
+            }
         }
     }
 }
@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::session::{Phase, Session, Sessions};
+use super::{expect, ResponderError};
+
+use crate::config::{MAX_CERT_CHAIN_SIZE, MAX_DIGEST_SIZE, TIME_FLOOR};
+use crate::crypto::{
+    digest::{Digest, DigestImpl},
+    key_schedule,
+    pki::{new_end_entity_cert, EndEntityCert},
+};
+use crate::msgs::algorithms::BaseAsymAlgo;
+use crate::msgs::{
+    encoding::Writer, CertificateChain, Finish, FinishRsp, Msg, HEADER_SIZE,
+};
+use crate::secured_message::handshake_in_the_clear;
+
+/// The identity a requester must prove during the session handshake
+///
+/// The requester's certificate chain is either provisioned on the responder,
+/// or retrieved with encapsulated requests after CHALLENGE. It must be rooted
+/// at `root_cert`, and its leaf certificate must verify the signature in
+/// FINISH or in the encapsulated CHALLENGE_AUTH.
+#[derive(Debug, Clone)]
+pub struct MutualAuth<'a> {
+    // The root certificate of the requester's certificate chain
+    pub root_cert: &'a [u8],
+
+    // The slot requested in CHALLENGE_AUTH and KEY_EXCHANGE_RSP
+    pub req_slot_id: u8,
+
+    // The provisioned certificate chain of the requester. If this is `None`,
+    // the chain is retrieved with encapsulated requests after CHALLENGE.
+    pub cert_chain: Option<CertificateChain<'a>>,
+}
+
+impl<'a> MutualAuth<'a> {
+    /// Verify the requester's certificate chain, and its signature over a
+    /// transcript hash.
+    pub fn verify(
+        &self,
+        cert_chain: &CertificateChain,
+        algorithm: BaseAsymAlgo,
+        th_hash: &[u8],
+        signature: &[u8],
+    ) -> Result<(), ResponderError> {
+        let end_entity_cert = new_end_entity_cert(cert_chain.leaf_cert)
+            .map_err(|_| ResponderError::RequesterAuthFailed)?;
+        end_entity_cert
+            .verify_chain_of_trust(
+                algorithm,
+                cert_chain.intermediate_certs(),
+                core::slice::from_ref(&self.root_cert),
+                Some(TIME_FLOOR),
+            )
+            .map_err(|_| ResponderError::RequesterAuthFailed)?;
+        if !end_entity_cert.verify_signature(algorithm, th_hash, signature) {
+            return Err(ResponderError::RequesterAuthFailed);
+        }
+        Ok(())
+    }
+}
+
+/// Handle a FINISH request received in the clear
+///
+/// This is only allowed if both sides set HANDSHAKE_IN_THE_CLEAR_CAP.
+/// Otherwise, FINISH must be received within the session, and the handshake
+/// is terminated.
+///
+/// On success, the session is established and its keys are derived.
+pub fn handle_clear_msg(
+    req: &[u8],
+    rsp: &mut [u8],
+    sessions: &mut Sessions,
+    mutual_auth: Option<&MutualAuth>,
+    requester_cert_chain: Option<&[u8]>,
+) -> Result<usize, ResponderError> {
+    let s = match sessions.handshake_mut() {
+        Some(s) if s.phase == Phase::Handshake => s,
+        _ => return Err(ResponderError::NoHandshakeInProgress),
+    };
+    let res = if s.channels.is_some() {
+        Err(ResponderError::HandshakeNotInTheClear)
+    } else {
+        handle_msg(req, rsp, s, mutual_auth, requester_cert_chain)
+    };
+    match res {
+        Ok(size) => {
+            s.establish();
+            Ok(size)
+        }
+        Err(e) => {
+            sessions.remove_handshake();
+            Err(e)
+        }
+    }
+}
+
+/// Handle a FINISH request for the session `s`, whose handshake is in
+/// progress, and write FINISH_RSP.
+///
+/// The caller terminates the session if an error is returned. Otherwise, it
+/// establishes the session once FINISH_RSP is sent, as the session keys
+/// replace the handshake keys FINISH_RSP may be sent with.
+///
+/// If the RequesterVerifyData does not verify, an error is returned. If
+/// mutual authentication was requested in KEY_EXCHANGE_RSP, the request must
+/// be signed by the requester identified by `mutual_auth`. The requester's
+/// certificate chain is the provisioned one, or else `requester_cert_chain`,
+/// as retrieved with encapsulated requests.
+pub fn handle_msg(
+    req: &[u8],
+    rsp: &mut [u8],
+    s: &mut Session,
+    mutual_auth: Option<&MutualAuth>,
+    requester_cert_chain: Option<&[u8]>,
+) -> Result<usize, ResponderError> {
+    expect::<Finish>(req)?;
+
+    let hash_algo = s.hash_algo;
+    let digest_size = hash_algo.get_digest_size();
+    // The requester signing algorithm is negotiated whenever mutual
+    // authentication is requested.
+    let req_asym_algo = s.algorithms.req_base_asym_algo_selected();
+    let req_msg = Finish::parse_body(
+        &req[HEADER_SIZE..],
+        digest_size,
+        req_asym_algo.map_or(0, |algo| algo.get_signature_size()),
+    )?;
+
+    let verify_start = req.len() - digest_size as usize;
+    match (s.req_slot_id, req_msg.signature()) {
+        (None, None) => s.transcript.extend(&req[..verify_start])?,
+        (None, Some(_)) => return Err(ResponderError::MutAuthNotRequested),
+        (Some(_), None) => return Err(ResponderError::MutAuthRequired),
+        (Some(req_slot_id), Some(signature)) => {
+            let (mutual_auth, algo) = match (mutual_auth, req_asym_algo) {
+                (Some(m), Some(algo))
+                    if m.req_slot_id == req_slot_id
+                        && req_msg.req_slot_id == req_slot_id =>
+                {
+                    (m, algo)
+                }
+                _ => return Err(ResponderError::RequesterAuthFailed),
+            };
+
+            // The hash of the requester's certificate chain (CM in the
+            // SPDM spec) precedes FINISH in the transcript.
+            let mut buf = [0u8; MAX_CERT_CHAIN_SIZE];
+            let cert_chain_bytes =
+                match (&mutual_auth.cert_chain, requester_cert_chain) {
+                    (Some(cert_chain), _) => {
+                        let mut w = Writer::new("CERTIFICATE_CHAIN", &mut buf);
+                        let size = cert_chain.write(&mut w)?;
+                        &buf[..size]
+                    }
+                    (None, Some(bytes)) => bytes,
+                    (None, None) => {
+                        return Err(ResponderError::RequesterAuthFailed)
+                    }
+                };
+            let cert_chain =
+                CertificateChain::parse(cert_chain_bytes, digest_size)
+                    .map_err(|_| ResponderError::RequesterAuthFailed)?;
+            let cert_chain_digest =
+                DigestImpl::hash(hash_algo, cert_chain_bytes);
+            s.transcript.extend(cert_chain_digest.as_ref())?;
+
+            let sig_start = verify_start - signature.len();
+            s.transcript.extend(&req[..sig_start])?;
+            let th_hash = DigestImpl::hash(hash_algo, s.transcript.get());
+            mutual_auth.verify(
+                &cert_chain,
+                algo,
+                th_hash.as_ref(),
+                signature,
+            )?;
+            s.transcript.extend(signature)?;
+        }
+    }
+
+    let th_hash = DigestImpl::hash(hash_algo, s.transcript.get());
+    let finished_key =
+        key_schedule::finished_key(hash_algo, &s.handshake_secrets.request);
+    if !key_schedule::verify_hmac(
+        hash_algo,
+        finished_key.as_ref(),
+        th_hash.as_ref(),
+        req_msg.verify_data(),
+    ) {
+        return Err(ResponderError::InvalidVerifyData);
+    }
+    s.transcript.extend(req_msg.verify_data())?;
+
+    // The ResponderVerifyData is only sent if the handshake is in the
+    // clear. Like FINISH, it is computed over the serialized response. We
+    // therefore serialize with a placeholder, and overwrite it in place.
+    let msg = FinishRsp {
+        digest_size,
+        verify_data: if handshake_in_the_clear(s.requester_cap, s.responder_cap)
+        {
+            Some([0u8; MAX_DIGEST_SIZE])
+        } else {
+            None
+        },
+    };
+    let size = msg.write(rsp)?;
+    if msg.verify_data.is_some() {
+        let verify_start = size - digest_size as usize;
+        s.transcript.extend(&rsp[..verify_start])?;
+        let th_hash = DigestImpl::hash(hash_algo, s.transcript.get());
+        let finished_key = key_schedule::finished_key(
+            hash_algo,
+            &s.handshake_secrets.response,
+        );
+        let verify_data = key_schedule::hmac(
+            hash_algo,
+            finished_key.as_ref(),
+            th_hash.as_ref(),
+        );
+        rsp[verify_start..size].copy_from_slice(verify_data.as_ref());
+        s.transcript.extend(&rsp[verify_start..size])?;
+    } else {
+        s.transcript.extend(&rsp[..size])?;
+    }
+
+    Ok(size)
+}
@@ -0,0 +1,26 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::session::Session;
+use super::{expect, ResponderError};
+
+use crate::msgs::{Heartbeat, HeartbeatAck, Msg, HEADER_SIZE};
+
+/// Handle a HEARTBEAT request received within `session`
+///
+/// Like KEY_UPDATE, HEARTBEAT is handled outside of the responder state
+/// machine. The session was already marked active when the request was
+/// decoded, so all that's left is to acknowledge it.
+pub fn handle_msg(
+    req: &[u8],
+    rsp: &mut [u8],
+    session: &Session,
+) -> Result<usize, ResponderError> {
+    expect::<Heartbeat>(req)?;
+    if session.heartbeat.period().is_none() {
+        return Err(ResponderError::UnsupportedRequest(Heartbeat::SPDM_CODE));
+    }
+    Heartbeat::parse_body(&req[HEADER_SIZE..])?;
+    Ok(HeartbeatAck {}.write(rsp)?)
+}
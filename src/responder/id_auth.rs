@@ -2,11 +2,18 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use core::cmp::min;
+
 use super::{algorithms, challenge, expect, AllStates, ResponderError};
 
 use crate::config::{MAX_CERT_CHAIN_SIZE, NUM_SLOTS};
-use crate::crypto::digest::{Digest, DigestImpl};
+use crate::crypto::{
+    digest::{Digest, DigestImpl},
+    FilledSlot, ProvisionedKey, Signer,
+};
+use crate::msgs::algorithms::BaseHashAlgo;
 use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::challenge::{PROVISIONED_KEY_SLOT, PROVISIONED_KEY_SLOT_ID};
 use crate::msgs::digest::DigestBuf;
 use crate::msgs::{
     encoding::Writer, Algorithms, Certificate, CertificateChain, Digests,
@@ -14,6 +21,9 @@ use crate::msgs::{
 };
 use crate::{reset_on_get_version, Transcript};
 
+// The size of a CERTIFICATE msg without its certificate chain portion
+const CERTIFICATE_HEADER_SIZE: usize = 8;
+
 /// Create a slot mask where a `1` represents a present cert chain, and a `0`
 /// indicates absence.
 pub fn create_slot_mask<'a, T: 'a>(slots: &[Option<T>; NUM_SLOTS]) -> u8 {
@@ -26,6 +36,59 @@ pub fn create_slot_mask<'a, T: 'a>(slots: &[Option<T>; NUM_SLOTS]) -> u8 {
     return bits;
 }
 
+/// The keys a responder authenticates with: those of its certificate slots,
+/// and its provisioned public key, if any
+pub struct Keys<'r, 'a, S: Signer> {
+    pub slots: &'r [Option<FilledSlot<'a, S>>; NUM_SLOTS],
+    pub provisioned_key: Option<&'r ProvisionedKey<'a, S>>,
+}
+
+impl<'r, 'a, S: Signer> Keys<'r, 'a, S> {
+    /// Return the signer of `slot`.
+    ///
+    /// Slot 0xF is the provisioned public key, which is slot 0xFF in
+    /// CHALLENGE and KEY_EXCHANGE requests.
+    pub fn signer(&self, slot: u8) -> Result<&'r S, ResponderError> {
+        if is_provisioned_key_slot(slot) {
+            return match self.provisioned_key {
+                Some(key) => Ok(&key.signer),
+                None => Err(ResponderError::InvalidSlot),
+            };
+        }
+        match self.slots.get(slot as usize) {
+            Some(Some(slot)) => Ok(&slot.signer),
+            _ => Err(ResponderError::InvalidSlot),
+        }
+    }
+
+    /// Return the digest identifying `slot` in transcripts: that of its
+    /// certificate chain, or of the provisioned public key.
+    pub fn digest(
+        &self,
+        slot: u8,
+        hash_algo: BaseHashAlgo,
+    ) -> Result<DigestImpl, ResponderError> {
+        if is_provisioned_key_slot(slot) {
+            return match self.provisioned_key {
+                Some(key) => Ok(DigestImpl::hash(hash_algo, key.public_key)),
+                None => Err(ResponderError::InvalidSlot),
+            };
+        }
+        let slot = match self.slots.get(slot as usize) {
+            Some(Some(slot)) => slot,
+            _ => return Err(ResponderError::InvalidSlot),
+        };
+        let mut buf = [0u8; MAX_CERT_CHAIN_SIZE];
+        let mut w = Writer::new("CERTIFICATE_CHAIN", &mut buf);
+        let size = slot.cert_chain.write(&mut w)?;
+        Ok(DigestImpl::hash(hash_algo, &buf[..size]))
+    }
+}
+
+fn is_provisioned_key_slot(slot: u8) -> bool {
+    slot == PROVISIONED_KEY_SLOT || slot == PROVISIONED_KEY_SLOT_ID
+}
+
 /// The state where digests and certificates are sent to a requester in order to
 /// identify a responder.
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
@@ -49,6 +112,18 @@ impl From<algorithms::State> for State {
     }
 }
 
+impl From<challenge::State> for State {
+    fn from(s: challenge::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
 impl State {
     /// Handle a message from a requester.
     ///
@@ -67,8 +142,8 @@ impl State {
             return self.handle_get_digests(cert_chains, req, rsp, transcript);
         }
 
-        // TODO: Handle more than one CERTIFICATE message. How do we decide when
-        // to transfer to the next state then?
+        // The requester may retrieve the certificate chain in multiple
+        // portions. We transition once the last portion is sent.
         expect::<GetCertificate>(req)?;
         self.handle_get_certificate(cert_chains, req, rsp, transcript)
     }
@@ -89,19 +164,30 @@ impl State {
             .into());
         }
 
-        // TODO: Handle cert chains larger than response buffer (i.e. send
-        // responses in multiple messages)
         let mut cert_chain = [0u8; MAX_CERT_CHAIN_SIZE];
-        let mut portion_length: u16 = 0;
+        let mut cert_chain_size = 0;
         if let Some(chain) = &cert_chains[get_cert.slot as usize] {
             let mut w = Writer::new("CERTIFICATE_CHAIN", &mut cert_chain);
-            portion_length = chain.write(&mut w)? as u16;
+            cert_chain_size = chain.write(&mut w)?;
         }
 
+        // Serve the requested portion, as long as it fits in the response
+        // buffer. The requester asks for the remainder in subsequent requests.
+        let offset = get_cert.offset as usize;
+        if offset > cert_chain_size {
+            return Err(ResponderError::InvalidCertificateOffset);
+        }
+        let portion_length = min(
+            min(get_cert.length as usize, cert_chain_size - offset),
+            rsp.len().saturating_sub(CERTIFICATE_HEADER_SIZE),
+        );
+        let remainder_length = cert_chain_size - offset - portion_length;
+        cert_chain.copy_within(offset..offset + portion_length, 0);
+
         let cert = Certificate {
             slot: get_cert.slot,
-            portion_length,
-            remainder_length: 0,
+            portion_length: portion_length as u16,
+            remainder_length: remainder_length as u16,
             cert_chain,
         };
 
@@ -109,7 +195,11 @@ impl State {
         transcript.extend(req)?;
         transcript.extend(&rsp[..size])?;
 
-        Ok((size, challenge::State::from(self).into()))
+        if remainder_length == 0 {
+            Ok((size, challenge::State::from(self).into()))
+        } else {
+            Ok((size, self.into()))
+        }
     }
 
     fn handle_get_digests<'a>(
@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::convert::From;
+
+use super::measurements::{self, MeasurementProvider};
+use super::session::{heartbeat_period, Phase, Session, Sessions};
+use super::{challenge, expect, id_auth, AllStates, ResponderError};
+
+use crate::config::MAX_DIGEST_SIZE;
+use crate::crypto::{
+    dhe::EphemeralKey,
+    digest::{Digest, DigestImpl},
+    key_schedule::{self, HandshakeSecrets},
+    Signer,
+};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::key_exchange::{session_id, MutAuthRequested};
+use crate::msgs::{
+    challenge::nonce, Algorithms, KeyExchange, KeyExchangeRsp,
+    MeasurementHashType, Msg, HEADER_SIZE,
+};
+use crate::secured_message::{HeartbeatTimer, Mode};
+use crate::{reset_on_get_version, Transcript};
+
+/// KEY_EXCHANGE requests are handled and responded to in this state
+///
+/// This state is entered from the IdAuth, Challenge, or Measurements states
+/// when a KEY_EXCHANGE request arrives. A successful key exchange creates a
+/// new session in the handshake phase, and returns to the Measurements state.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+
+    // The slot of the requester's certificate chain, if the responder
+    // requires mutual authentication
+    pub req_slot_id: Option<u8>,
+}
+
+impl From<id_auth::State> for State {
+    fn from(s: id_auth::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+            req_slot_id: None,
+        }
+    }
+}
+
+impl From<challenge::State> for State {
+    fn from(s: challenge::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+            req_slot_id: None,
+        }
+    }
+}
+
+impl From<measurements::State> for State {
+    fn from(s: measurements::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+            req_slot_id: None,
+        }
+    }
+}
+
+impl From<State> for measurements::State {
+    fn from(s: State) -> Self {
+        measurements::State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
+impl State {
+    /// Handle a message from a requester
+    ///
+    /// Only KEY_EXCHANGE and GET_VERSION msgs are allowed here.
+    ///
+    /// On success, the new session is added to `sessions`, replacing any
+    /// session whose handshake was never completed. The request is rejected
+    /// if `sessions` is full.
+    ///
+    /// Mutual authentication is requested if `req_slot_id` is set, and both
+    /// sides set MUT_AUTH_CAP. The requester then signs FINISH.
+    pub fn handle_msg<S: Signer, M: MeasurementProvider, D: EphemeralKey>(
+        self,
+        keys: &id_auth::Keys<'_, '_, S>,
+        measurements: &mut M,
+        req: &[u8],
+        rsp: &mut [u8],
+        transcript: &mut Transcript,
+        sessions: &mut Sessions,
+    ) -> Result<(usize, AllStates), ResponderError> {
+        reset_on_get_version!(req, rsp, transcript);
+        expect::<KeyExchange>(req)?;
+
+        if !(self.requester_cap.contains(ReqFlags::KEY_EX_CAP)
+            && self.responder_cap.contains(RspFlags::KEY_EX_CAP))
+        {
+            return Err(ResponderError::UnsupportedRequest(
+                KeyExchange::SPDM_CODE,
+            ));
+        }
+        let dhe_algo = self.algorithms.dhe_algo_selected().ok_or(
+            ResponderError::UnsupportedRequest(KeyExchange::SPDM_CODE),
+        )?;
+        let aead_algo = self.algorithms.aead_algo_selected().ok_or(
+            ResponderError::UnsupportedRequest(KeyExchange::SPDM_CODE),
+        )?;
+        let mode = Mode::new(self.requester_cap, self.responder_cap).ok_or(
+            ResponderError::UnsupportedRequest(KeyExchange::SPDM_CODE),
+        )?;
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+        let signature_size =
+            self.algorithms.base_asym_algo_selected.get_signature_size();
+
+        let req_msg = KeyExchange::parse_body(
+            &req[HEADER_SIZE..],
+            dhe_algo.get_exchange_data_size(),
+        )?;
+
+        let signer = keys.signer(req_msg.slot_id)?;
+        let cert_chain_digest = keys.digest(req_msg.slot_id, hash_algo)?;
+
+        if req_msg.measurement_hash_type != MeasurementHashType::None
+            && !self
+                .responder_cap
+                .intersects(RspFlags::MEAS_CAP_NO_SIG | RspFlags::MEAS_CAP_SIG)
+        {
+            return Err(ResponderError::MeasurementsUnsupported);
+        }
+        let measurement_summary_hash = measurements::measurement_summary_hash(
+            measurements,
+            req_msg.measurement_hash_type,
+            hash_algo,
+        )?
+        .map(|digest| {
+            let mut hash = [0u8; MAX_DIGEST_SIZE];
+            hash[..digest_size as usize].copy_from_slice(digest.as_ref());
+            hash
+        });
+
+        let req_slot_id = self.req_slot_id.filter(|_| self.use_mutual_auth());
+
+        let dhe_key = D::generate(dhe_algo)
+            .map_err(|_| ResponderError::KeyExchangeFailed)?;
+
+        let mut msg = KeyExchangeRsp {
+            heartbeat_period: heartbeat_period(
+                self.requester_cap,
+                self.responder_cap,
+            ),
+            rsp_session_id: sessions
+                .new_rsp_session_id(req_msg.req_session_id)?,
+            random_data: nonce(),
+            exchange_data_size: dhe_algo.get_exchange_data_size(),
+            digest_size,
+            measurement_summary_hash,
+            signature_size,
+            mut_auth_requested: if req_slot_id.is_some() {
+                MutAuthRequested::MUT_AUTH_REQUESTED
+            } else {
+                MutAuthRequested::empty()
+            },
+            req_slot_id: req_slot_id.unwrap_or(0),
+            ..KeyExchangeRsp::default()
+        };
+        dhe_key
+            .public_key(&mut msg.exchange_data)
+            .map_err(|_| ResponderError::KeyExchangeFailed)?;
+
+        let dhe_secret = dhe_key
+            .shared_secret(req_msg.exchange_data())
+            .map_err(|_| ResponderError::InvalidExchangeData)?;
+
+        // Like CHALLENGE_AUTH, the signature and verify data are computed over
+        // the serialized response. We therefore serialize with placeholders,
+        // and overwrite them in place.
+        let size = msg.write(rsp)?;
+        let verify_start = size - digest_size as usize;
+        let sig_start = verify_start - signature_size;
+
+        // The handshake transcript starts with the VCA messages, followed by
+        // the hash of the certificate chain (Ct in the SPDM spec).
+        let mut th = Transcript::new();
+        th.extend(transcript.vca())?;
+        th.mark_vca();
+        th.extend(cert_chain_digest.as_ref())?;
+        th.extend(req)?;
+        th.extend(&rsp[..sig_start])?;
+
+        let th_hash = DigestImpl::hash(hash_algo, th.get());
+        let signature = signer
+            .sign(th_hash.as_ref())
+            .map_err(|_| ResponderError::SigningFailed)?;
+        rsp[sig_start..verify_start].copy_from_slice(signature.as_ref());
+        th.extend(&rsp[sig_start..verify_start])?;
+
+        let th1 = key_schedule::th1(hash_algo, &th);
+        let handshake_secrets =
+            HandshakeSecrets::new(hash_algo, dhe_secret.as_ref(), th1.as_ref());
+        let finished_key =
+            key_schedule::finished_key(hash_algo, &handshake_secrets.response);
+        let verify_data =
+            key_schedule::hmac(hash_algo, finished_key.as_ref(), th1.as_ref());
+        rsp[verify_start..size].copy_from_slice(verify_data.as_ref());
+        th.extend(&rsp[verify_start..size])?;
+
+        let mut session = Session {
+            id: session_id(req_msg.req_session_id, msg.rsp_session_id),
+            phase: Phase::Handshake,
+            requester_cap: self.requester_cap,
+            responder_cap: self.responder_cap,
+            algorithms: self.algorithms.clone(),
+            hash_algo,
+            aead_algo,
+            mode,
+            handshake_secrets,
+            session_keys: None,
+            channels: None,
+            heartbeat: HeartbeatTimer::new(msg.heartbeat_period),
+            req_slot_id,
+            transcript: th,
+        };
+        session.secure_handshake();
+        sessions.insert(session)?;
+
+        Ok((size, measurements::State::from(self).into()))
+    }
+
+    // Mutual authentication requires MUT_AUTH_CAP on both sides, and a
+    // negotiated requester signing algorithm.
+    fn use_mutual_auth(&self) -> bool {
+        self.requester_cap.contains(ReqFlags::MUT_AUTH_CAP)
+            && self.responder_cap.contains(RspFlags::MUT_AUTH_CAP)
+            && self.algorithms.req_base_asym_algo_selected().is_some()
+    }
+}
@@ -0,0 +1,51 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::session::Session;
+use super::{expect, ResponderError};
+
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::{
+    KeyUpdate, KeyUpdateAck, KeyUpdateOperation, Msg, HEADER_SIZE,
+};
+
+/// Handle a KEY_UPDATE request received within `session`
+///
+/// KEY_UPDATE only affects the keys of the session, and so it is handled
+/// outside of the responder state machine.
+///
+/// The request keys are updated immediately, as the next request is sent
+/// with them. With UpdateAllKeys, the KEY_UPDATE_ACK is already sent with the
+/// new response keys. The old keys are kept until the requester sends a
+/// record with the new keys, usually the VerifyNewKey request.
+pub fn handle_msg(
+    req: &[u8],
+    rsp: &mut [u8],
+    session: &mut Session,
+) -> Result<usize, ResponderError> {
+    expect::<KeyUpdate>(req)?;
+    if !(session.requester_cap.contains(ReqFlags::KEY_UPD_CAP)
+        && session.responder_cap.contains(RspFlags::KEY_UPD_CAP))
+    {
+        return Err(ResponderError::UnsupportedRequest(KeyUpdate::SPDM_CODE));
+    }
+    let channels = session
+        .channels
+        .as_mut()
+        .ok_or(ResponderError::UnknownSession(session.id))?;
+
+    let req_msg = KeyUpdate::parse_body(&req[HEADER_SIZE..])?;
+    match req_msg.operation {
+        KeyUpdateOperation::UpdateKey => channels.update_request(),
+        KeyUpdateOperation::UpdateAllKeys => {
+            channels.update_request();
+            channels.update_response();
+        }
+        // The request was decoded with the new keys, which confirmed them
+        KeyUpdateOperation::VerifyNewKey => (),
+    }
+
+    let msg = KeyUpdateAck { operation: req_msg.operation, tag: req_msg.tag };
+    Ok(msg.write(rsp)?)
+}
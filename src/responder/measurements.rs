@@ -0,0 +1,294 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::convert::From;
+
+use super::session::Session;
+use super::{challenge, expect, id_auth, AllStates, ResponderError};
+
+use crate::config::MAX_MEASUREMENT_RECORD_SIZE;
+use crate::crypto::{
+    digest::{Digest, DigestImpl},
+    Signer,
+};
+use crate::msgs::algorithms::BaseHashAlgo;
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::measurements::MeasurementIndex;
+use crate::msgs::{
+    challenge::nonce, encoding::Writer, Algorithms, GetMeasurements,
+    MeasurementBlock, MeasurementHashType, Measurements, Msg, HEADER_SIZE,
+};
+use crate::{reset_on_get_version, Transcript};
+
+/// A source of measurements for a responder
+///
+/// Each platform measures different things in different ways. Some
+/// measurements may be fixed at build time, while others, such as hashes of
+/// mutable firmware, may need to be computed at runtime. A `MeasurementProvider`
+/// is therefore implemented by the user of this library and handed to the
+/// `Responder` alongside its certificate slots.
+pub trait MeasurementProvider {
+    /// Return the number of measurement indices available
+    fn num_measurements(&self) -> u8;
+
+    /// Return the measurement block for the given index, or `None` if there is
+    /// no measurement at that index.
+    ///
+    /// This is only called with specific indices (0x01 - 0xFE), never with
+    /// `TotalNumberOfMeasurementsAvailable` or `AllMeasurements`. The `index`
+    /// of the returned block must match the requested index.
+    ///
+    /// If `raw_bit_stream_requested` is true, the requester would like the
+    /// measured data itself rather than a digest. A provider that cannot, or
+    /// will not, return the raw bit stream for a measurement returns a digest
+    /// instead, as allowed by the SPDM spec.
+    fn measurement(
+        &mut self,
+        index: MeasurementIndex,
+        raw_bit_stream_requested: bool,
+    ) -> Option<MeasurementBlock<'_>>;
+
+    /// Return true if the measurement at `index` is of a component in the
+    /// Trusted Computing Base (TCB) of the responder.
+    ///
+    /// Only measurements of TCB components are included in a TCB measurement
+    /// summary hash.
+    fn is_tcb_component(&self, index: u8) -> bool;
+}
+
+/// Compute the measurement summary hash for a CHALLENGE_AUTH response
+///
+/// The summary hash is the hash of the concatenation of all measurement
+/// blocks, or only those of TCB components, in index order. Measurements are
+/// always in digest form. `None` is returned if no summary hash was requested.
+pub fn measurement_summary_hash<M: MeasurementProvider>(
+    measurements: &mut M,
+    hash_type: MeasurementHashType,
+    hash_algo: BaseHashAlgo,
+) -> Result<Option<DigestImpl>, ResponderError> {
+    let tcb_only = match hash_type {
+        MeasurementHashType::None => return Ok(None),
+        MeasurementHashType::Tcb => true,
+        MeasurementHashType::All => false,
+    };
+    let mut buf = [0u8; MAX_MEASUREMENT_RECORD_SIZE];
+    let mut w = Writer::new("MEASUREMENT_SUMMARY", &mut buf);
+    for i in 0x01..=0xFEu8 {
+        if tcb_only && !measurements.is_tcb_component(i) {
+            continue;
+        }
+        if let Some(block) = measurements.measurement(i.into(), false) {
+            block.write(&mut w)?;
+        }
+    }
+    let size = w.offset();
+    Ok(Some(DigestImpl::hash(hash_algo, &buf[..size])))
+}
+
+/// Measurement requests are handled and responded to in this state
+///
+/// This state is entered from the IdAuth or Challenge states when a
+/// GET_MEASUREMENTS request arrives, and after a successful CHALLENGE or
+/// KEY_EXCHANGE.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+}
+
+impl From<id_auth::State> for State {
+    fn from(s: id_auth::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
+impl From<challenge::State> for State {
+    fn from(s: challenge::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
+impl State {
+    /// Handle a message from a requester
+    ///
+    /// Only GET_MEASUREMENTS and GET_VERSION msgs are allowed here.
+    ///
+    /// The transcript must only contain the VCA messages and any unsigned
+    /// GET_MEASUREMENTS/MEASUREMENTS exchanges since the last signed response
+    /// (L1/L2 in the SPDM spec). It is reset to the VCA messages after every
+    /// signed response.
+    pub fn handle_msg<S: Signer, M: MeasurementProvider>(
+        self,
+        keys: &id_auth::Keys<'_, '_, S>,
+        measurements: &mut M,
+        req: &[u8],
+        rsp: &mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<(usize, AllStates), ResponderError> {
+        reset_on_get_version!(req, rsp, transcript);
+        let size = respond(
+            self.responder_cap,
+            &self.algorithms,
+            keys,
+            measurements,
+            req,
+            rsp,
+            transcript,
+        )?;
+        Ok((size, self.into()))
+    }
+}
+
+/// Handle a GET_MEASUREMENTS request sent within the established session `s`
+///
+/// The measurement messages of a session are kept in its own transcript
+/// (L1/L2), which starts from the VCA messages, rather than in the transcript
+/// of the responder.
+pub fn handle_session_msg<S: Signer, M: MeasurementProvider>(
+    keys: &id_auth::Keys<'_, '_, S>,
+    measurements: &mut M,
+    req: &[u8],
+    rsp: &mut [u8],
+    s: &mut Session,
+) -> Result<usize, ResponderError> {
+    respond(
+        s.responder_cap,
+        &s.algorithms,
+        keys,
+        measurements,
+        req,
+        rsp,
+        &mut s.transcript,
+    )
+}
+
+/// Return true if `req` is a GET_MEASUREMENTS request for a single index that
+/// has no measurement.
+pub fn is_absent_index<M: MeasurementProvider>(
+    measurements: &mut M,
+    req: &[u8],
+) -> bool {
+    if GetMeasurements::parse_header(req) != Ok(true) {
+        return false;
+    }
+    match GetMeasurements::parse_body(&req[HEADER_SIZE..]) {
+        Ok(msg) => match msg.index {
+            MeasurementIndex::ImplementationDefined(_) => measurements
+                .measurement(msg.index, msg.attributes.raw_bit_stream_requested)
+                .is_none(),
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+// Write a MEASUREMENTS response to a GET_MEASUREMENTS request, and append both
+// to `transcript`, which is reset to the VCA messages after a signed response.
+fn respond<S: Signer, M: MeasurementProvider>(
+    responder_cap: RspFlags,
+    algorithms: &Algorithms,
+    keys: &id_auth::Keys<'_, '_, S>,
+    measurements: &mut M,
+    req: &[u8],
+    rsp: &mut [u8],
+    transcript: &mut Transcript,
+) -> Result<usize, ResponderError> {
+    expect::<GetMeasurements>(req)?;
+
+    if !responder_cap
+        .intersects(RspFlags::MEAS_CAP_NO_SIG | RspFlags::MEAS_CAP_SIG)
+    {
+        return Err(ResponderError::UnsupportedRequest(
+            GetMeasurements::SPDM_CODE,
+        ));
+    }
+
+    let req_msg = GetMeasurements::parse_body(&req[HEADER_SIZE..])?;
+    let signature_requested = req_msg.attributes.signature_requested;
+    let raw = req_msg.attributes.raw_bit_stream_requested;
+    if signature_requested && !responder_cap.contains(RspFlags::MEAS_CAP_SIG) {
+        return Err(ResponderError::SignedMeasurementsUnsupported);
+    }
+
+    let signer = if signature_requested {
+        Some(keys.signer(req_msg.slot_id)?)
+    } else {
+        None
+    };
+
+    let mut msg = Measurements {
+        slot_id: req_msg.slot_id,
+        nonce: nonce(),
+        ..Measurements::default()
+    };
+    let mut w = Writer::new(Measurements::NAME, &mut msg.record);
+    match req_msg.index {
+        MeasurementIndex::TotalNumberOfMeasurementsAvailable => {
+            msg.total_indices = measurements.num_measurements();
+        }
+        MeasurementIndex::AllMeasurements => {
+            for i in 0x01..=0xFEu8 {
+                if let Some(block) = measurements.measurement(i.into(), raw) {
+                    block.write(&mut w)?;
+                    msg.num_blocks += 1;
+                }
+            }
+        }
+        index => match measurements.measurement(index, raw) {
+            Some(block) => {
+                block.write(&mut w)?;
+                msg.num_blocks = 1;
+            }
+            None => return Err(ResponderError::InvalidMeasurementIndex),
+        },
+    }
+    msg.record_len = w.offset() as u32;
+
+    // Like CHALLENGE_AUTH, the signature covers the transcript up to, but
+    // excluding, the signature itself. We therefore serialize with a
+    // placeholder signature and overwrite it once signed.
+    if signer.is_some() {
+        msg.signature_size =
+            algorithms.base_asym_algo_selected.get_signature_size();
+    }
+
+    let size = msg.write(rsp)?;
+    transcript.extend(req)?;
+
+    if let Some(signer) = signer {
+        let sig_start = size - msg.signature_size;
+        transcript.extend(&rsp[..sig_start])?;
+
+        let l2_hash = DigestImpl::hash(
+            algorithms.base_hash_algo_selected,
+            transcript.get(),
+        );
+        let signature = signer
+            .sign(l2_hash.as_ref())
+            .map_err(|_| ResponderError::SigningFailed)?;
+        rsp[sig_start..size].copy_from_slice(signature.as_ref());
+
+        // A signed response terminates the measurement transcript
+        transcript.reset_to_vca();
+    } else {
+        transcript.extend(&rsp[..size])?;
+    }
+
+    Ok(size)
+}
@@ -0,0 +1,290 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::convert::From;
+
+use super::finish::MutualAuth;
+use super::{challenge, expect, measurements, AllStates, ResponderError};
+
+use crate::config::{MAX_CERT_CHAIN_SIZE, NUM_SLOTS};
+use crate::crypto::digest::{Digest, DigestImpl};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::digest::DigestBuf;
+use crate::msgs::{
+    Algorithms, Certificate, CertificateChain, Challenge, ChallengeAuth,
+    DeliverEncapsulatedResponse, Digests, EncapsulatedRequest,
+    EncapsulatedResponseAck, GetCertificate, GetDigests,
+    GetEncapsulatedRequest, MeasurementHashType, Msg, HEADER_SIZE,
+};
+use crate::{reset_on_get_version, Transcript};
+
+// The IDs of the encapsulated requests, which are sent in this order
+const GET_DIGESTS_ID: u8 = 1;
+const GET_CERTIFICATE_ID: u8 = 2;
+const CHALLENGE_ID: u8 = 3;
+
+/// The responder authenticates the requester in this state
+///
+/// This state is entered from the Challenge state when basic mutual
+/// authentication was requested in CHALLENGE_AUTH. The responder acts as a
+/// requester, and encapsulates GET_DIGESTS, GET_CERTIFICATE and CHALLENGE
+/// requests in its responses to GET_ENCAPSULATED_REQUEST and
+/// DELIVER_ENCAPSULATED_RESPONSE. Once the requester's CHALLENGE_AUTH is
+/// verified, it returns to the Measurements state.
+///
+/// Like a requester, the responder builds the transcript from the VCA
+/// messages and the encapsulated messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+
+    // The slot of the requester's certificate chain
+    pub req_slot_id: u8,
+
+    // The ID of the outstanding encapsulated request, or 0 until the
+    // requester sends GET_ENCAPSULATED_REQUEST
+    pub request_id: u8,
+
+    // The digest of the requester's certificate chain, from DIGESTS
+    pub digest: DigestBuf,
+}
+
+impl State {
+    pub fn new(s: challenge::State, req_slot_id: u8) -> State {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+            req_slot_id,
+            request_id: 0,
+            digest: DigestBuf::default(),
+        }
+    }
+}
+
+impl From<State> for measurements::State {
+    fn from(s: State) -> Self {
+        measurements::State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
+impl State {
+    /// Handle a message from a requester
+    ///
+    /// Only GET_ENCAPSULATED_REQUEST, DELIVER_ENCAPSULATED_RESPONSE and
+    /// GET_VERSION msgs are allowed here.
+    ///
+    /// The requester's certificate chain is stored in `requester_cert_chain`
+    /// once received, as it is too large to be kept in the state. It must
+    /// lead to `mutual_auth.root_cert`, and is cleared if the requester is not
+    /// authenticated.
+    pub fn handle_msg(
+        self,
+        req: &[u8],
+        rsp: &mut [u8],
+        transcript: &mut Transcript,
+        mutual_auth: &MutualAuth,
+        requester_cert_chain: &mut Option<Certificate<MAX_CERT_CHAIN_SIZE>>,
+    ) -> Result<(usize, AllStates), ResponderError> {
+        let res = self.handle(
+            req,
+            rsp,
+            transcript,
+            mutual_auth,
+            requester_cert_chain,
+        );
+        if res.is_err() {
+            *requester_cert_chain = None;
+        }
+        res
+    }
+
+    fn handle(
+        mut self,
+        req: &[u8],
+        rsp: &mut [u8],
+        transcript: &mut Transcript,
+        mutual_auth: &MutualAuth,
+        requester_cert_chain: &mut Option<Certificate<MAX_CERT_CHAIN_SIZE>>,
+    ) -> Result<(usize, AllStates), ResponderError> {
+        reset_on_get_version!(req, rsp, transcript);
+
+        if self.request_id == 0 {
+            expect::<GetEncapsulatedRequest>(req)?;
+            GetEncapsulatedRequest::parse_body(&req[HEADER_SIZE..])?;
+            self.request_id = GET_DIGESTS_ID;
+            let start = EncapsulatedRequest { request_id: self.request_id }
+                .write(rsp)?;
+            let size = start + GetDigests {}.write(&mut rsp[start..])?;
+            transcript.extend(&rsp[start..size])?;
+            return Ok((size, self.into()));
+        }
+
+        expect::<DeliverEncapsulatedResponse>(req)?;
+        let (msg, response) =
+            DeliverEncapsulatedResponse::parse_body(&req[HEADER_SIZE..])?;
+        if msg.request_id != self.request_id {
+            return Err(ResponderError::BadEncapsulatedResponse);
+        }
+
+        match self.request_id {
+            GET_DIGESTS_ID => self.handle_digests(response, rsp, transcript),
+            GET_CERTIFICATE_ID => self.handle_certificate(
+                response,
+                rsp,
+                transcript,
+                requester_cert_chain,
+            ),
+            _ => {
+                // Unwrap is safe, as CHALLENGE is only sent once the chain is
+                // received
+                let cert = requester_cert_chain.as_ref().unwrap();
+                let size = self.verify_challenge_auth(
+                    response,
+                    rsp,
+                    transcript,
+                    &cert.cert_chain[..cert.portion_length as usize],
+                    mutual_auth.root_cert,
+                )?;
+                Ok((size, measurements::State::from(self).into()))
+            }
+        }
+    }
+
+    // Handle the requester's DIGESTS, and request its certificate chain
+    fn handle_digests(
+        mut self,
+        response: &[u8],
+        rsp: &mut [u8],
+        transcript: &mut Transcript,
+    ) -> Result<(usize, AllStates), ResponderError> {
+        expect::<Digests<NUM_SLOTS>>(response)?;
+        let digest_size =
+            self.algorithms.base_hash_algo_selected.get_digest_size();
+        let digests = Digests::<NUM_SLOTS>::parse_body(
+            digest_size,
+            &response[HEADER_SIZE..],
+        )?;
+        let slot = self.req_slot_id as usize;
+        if slot >= NUM_SLOTS || digests.slot_mask & (1 << slot) == 0 {
+            return Err(ResponderError::BadEncapsulatedResponse);
+        }
+        self.digest = digests.digests[slot];
+        transcript.extend(response)?;
+
+        self.request_id = GET_CERTIFICATE_ID;
+        let start = EncapsulatedResponseAck { request_id: self.request_id }
+            .write(rsp)?;
+        let msg = GetCertificate {
+            slot: self.req_slot_id,
+            offset: 0,
+            length: MAX_CERT_CHAIN_SIZE as u16,
+        };
+        let size = start + msg.write(&mut rsp[start..])?;
+        transcript.extend(&rsp[start..size])?;
+        Ok((size, self.into()))
+    }
+
+    // Handle the requester's CERTIFICATE, and challenge it
+    fn handle_certificate(
+        mut self,
+        response: &[u8],
+        rsp: &mut [u8],
+        transcript: &mut Transcript,
+        requester_cert_chain: &mut Option<Certificate<MAX_CERT_CHAIN_SIZE>>,
+    ) -> Result<(usize, AllStates), ResponderError> {
+        expect::<Certificate<MAX_CERT_CHAIN_SIZE>>(response)?;
+        let cert = Certificate::<MAX_CERT_CHAIN_SIZE>::parse_body(
+            &response[HEADER_SIZE..],
+        )?;
+
+        // The chain must be sent in one message, and match its digest
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size() as usize;
+        let digest = DigestImpl::hash(
+            hash_algo,
+            &cert.cert_chain[..cert.portion_length as usize],
+        );
+        if cert.slot != self.req_slot_id
+            || cert.remainder_length != 0
+            || digest.as_ref() != self.digest.as_slice(digest_size)
+        {
+            return Err(ResponderError::BadEncapsulatedResponse);
+        }
+        *requester_cert_chain = Some(cert);
+        transcript.extend(response)?;
+
+        self.request_id = CHALLENGE_ID;
+        let start = EncapsulatedResponseAck { request_id: self.request_id }
+            .write(rsp)?;
+        let msg = Challenge::new(self.req_slot_id, MeasurementHashType::None);
+        let size = start + msg.write(&mut rsp[start..])?;
+        transcript.extend(&rsp[start..size])?;
+        Ok((size, self.into()))
+    }
+
+    // Verify the requester's CHALLENGE_AUTH, and end mutual authentication
+    fn verify_challenge_auth(
+        &self,
+        response: &[u8],
+        rsp: &mut [u8],
+        transcript: &mut Transcript,
+        cert_chain: &[u8],
+        root_cert: &[u8],
+    ) -> Result<usize, ResponderError> {
+        expect::<ChallengeAuth>(response)?;
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+        // Unwrap is safe, as basic mutual authentication is only requested
+        // when a requester signing algorithm was negotiated.
+        let algo = self.algorithms.req_base_asym_algo_selected().unwrap();
+        let signature_size = algo.get_signature_size();
+        let auth = ChallengeAuth::parse_body(
+            &response[HEADER_SIZE..],
+            digest_size,
+            signature_size,
+        )?;
+        if auth.slot != self.req_slot_id
+            || auth.cert_chain_hash()
+                != self.digest.as_slice(digest_size as usize)
+        {
+            return Err(ResponderError::BadEncapsulatedResponse);
+        }
+
+        let sig_start = response.len() - signature_size;
+        transcript.extend(&response[..sig_start])?;
+        let m_hash = DigestImpl::hash(hash_algo, transcript.get());
+
+        let cert_chain = CertificateChain::parse(cert_chain, digest_size)
+            .map_err(|_| ResponderError::RequesterAuthFailed)?;
+        let mutual_auth = MutualAuth {
+            root_cert,
+            req_slot_id: self.req_slot_id,
+            cert_chain: None,
+        };
+        mutual_auth.verify(
+            &cert_chain,
+            algo,
+            m_hash.as_ref(),
+            auth.signature(),
+        )?;
+
+        // Any subsequent measurement transcript starts after the VCA messages
+        transcript.reset_to_vca();
+
+        Ok(EncapsulatedResponseAck { request_id: 0 }.write(rsp)?)
+    }
+}
@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::convert::From;
+
+use rand::{rngs::OsRng, RngCore};
+
+use super::measurements::{self, MeasurementProvider};
+use super::session::{heartbeat_period, Phase, Session, Sessions};
+use super::{challenge, expect, id_auth, AllStates, ResponderError};
+
+use crate::config::MAX_DIGEST_SIZE;
+use crate::crypto::key_schedule::{self, HandshakeSecrets};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::key_exchange::session_id;
+use crate::msgs::{
+    Algorithms, MeasurementHashType, Msg, PskExchange, PskExchangeRsp,
+    HEADER_SIZE,
+};
+use crate::secured_message::{HeartbeatTimer, Mode};
+use crate::{reset_on_get_version, Transcript};
+
+/// A source of pre-shared keys for a responder
+///
+/// PSKs are provisioned out of band, and a requester identifies the PSK it
+/// wants to use by a hint in the PSK_EXCHANGE request. Like measurements, how
+/// PSKs are stored is platform specific, and so a `PskProvider` is implemented
+/// by the user of this library.
+pub trait PskProvider {
+    /// Return the PSK identified by `hint`, or `None` if it is unknown.
+    fn psk(&self, hint: &[u8]) -> Option<&[u8]>;
+}
+
+/// A `PskProvider` without any keys, for responders that don't support PSK
+/// sessions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NoPsk;
+
+impl PskProvider for NoPsk {
+    fn psk(&self, _hint: &[u8]) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// PSK_EXCHANGE requests are handled and responded to in this state
+///
+/// This state is entered from the IdAuth, Challenge, or Measurements states
+/// when a PSK_EXCHANGE request arrives. A successful exchange creates a new
+/// session and returns to the Measurements state. If the responder provides a
+/// ResponderContext, the session is established by a subsequent PSK_FINISH.
+/// Otherwise it is established immediately.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub requester_ct_exponent: u8,
+    pub requester_cap: ReqFlags,
+    pub responder_ct_exponent: u8,
+    pub responder_cap: RspFlags,
+    pub algorithms: Algorithms,
+}
+
+impl From<id_auth::State> for State {
+    fn from(s: id_auth::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
+impl From<challenge::State> for State {
+    fn from(s: challenge::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
+impl From<measurements::State> for State {
+    fn from(s: measurements::State) -> Self {
+        State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
+impl From<State> for measurements::State {
+    fn from(s: State) -> Self {
+        measurements::State {
+            requester_ct_exponent: s.requester_ct_exponent,
+            requester_cap: s.requester_cap,
+            responder_ct_exponent: s.responder_ct_exponent,
+            responder_cap: s.responder_cap,
+            algorithms: s.algorithms,
+        }
+    }
+}
+
+impl State {
+    /// Handle a message from a requester
+    ///
+    /// Only PSK_EXCHANGE and GET_VERSION msgs are allowed here.
+    ///
+    /// On success, the new session is added to `sessions`, replacing any
+    /// session whose handshake was never completed. The request is rejected
+    /// if `sessions` is full.
+    pub fn handle_msg<M: MeasurementProvider, P: PskProvider>(
+        self,
+        measurements: &mut M,
+        psk_provider: Option<&P>,
+        req: &[u8],
+        rsp: &mut [u8],
+        transcript: &mut Transcript,
+        sessions: &mut Sessions,
+    ) -> Result<(usize, AllStates), ResponderError> {
+        reset_on_get_version!(req, rsp, transcript);
+        expect::<PskExchange>(req)?;
+
+        let unsupported =
+            ResponderError::UnsupportedRequest(PskExchange::SPDM_CODE);
+        let psk_cap = RspFlags::PSK_CAP | RspFlags::PSX_CAP_WITH_CONTEXT;
+        if !(self.requester_cap.contains(ReqFlags::PSK_CAP)
+            && self.responder_cap.intersects(psk_cap))
+        {
+            return Err(unsupported);
+        }
+        let psk_provider = psk_provider.ok_or(unsupported.clone())?;
+        let aead_algo =
+            self.algorithms.aead_algo_selected().ok_or(unsupported.clone())?;
+        let mode = Mode::new(self.requester_cap, self.responder_cap)
+            .ok_or(unsupported)?;
+        let hash_algo = self.algorithms.base_hash_algo_selected;
+        let digest_size = hash_algo.get_digest_size();
+
+        let req_msg = PskExchange::parse_body(&req[HEADER_SIZE..])?;
+        let psk = psk_provider
+            .psk(req_msg.psk_hint())
+            .ok_or(ResponderError::UnknownPskHint)?;
+
+        if req_msg.measurement_hash_type != MeasurementHashType::None
+            && !self
+                .responder_cap
+                .intersects(RspFlags::MEAS_CAP_NO_SIG | RspFlags::MEAS_CAP_SIG)
+        {
+            return Err(ResponderError::MeasurementsUnsupported);
+        }
+        let measurement_summary_hash = measurements::measurement_summary_hash(
+            measurements,
+            req_msg.measurement_hash_type,
+            hash_algo,
+        )?
+        .map(|digest| {
+            let mut hash = [0u8; MAX_DIGEST_SIZE];
+            hash[..digest_size as usize].copy_from_slice(digest.as_ref());
+            hash
+        });
+
+        let mut msg = PskExchangeRsp {
+            heartbeat_period: heartbeat_period(
+                self.requester_cap,
+                self.responder_cap,
+            ),
+            rsp_session_id: sessions
+                .new_rsp_session_id(req_msg.req_session_id)?,
+            digest_size,
+            measurement_summary_hash,
+            ..PskExchangeRsp::default()
+        };
+        let context_required = self.context_required();
+        if context_required {
+            msg.responder_context_len = digest_size.into();
+            OsRng.fill_bytes(&mut msg.responder_context[..digest_size.into()]);
+        }
+
+        // The verify data is computed over the serialized response, so we
+        // serialize with a placeholder, and overwrite it in place.
+        let size = msg.write(rsp)?;
+        let verify_start = size - digest_size as usize;
+
+        // Unlike KEY_EXCHANGE, there is no certificate chain in the handshake
+        // transcript.
+        let mut th = Transcript::new();
+        th.extend(transcript.vca())?;
+        th.mark_vca();
+        th.extend(req)?;
+        th.extend(&rsp[..verify_start])?;
+
+        let th1 = key_schedule::th1(hash_algo, &th);
+        let handshake_secrets =
+            HandshakeSecrets::new(hash_algo, psk, th1.as_ref());
+        let finished_key =
+            key_schedule::finished_key(hash_algo, &handshake_secrets.response);
+        let verify_data =
+            key_schedule::hmac(hash_algo, finished_key.as_ref(), th1.as_ref());
+        rsp[verify_start..size].copy_from_slice(verify_data.as_ref());
+        th.extend(&rsp[verify_start..size])?;
+
+        let mut new_session = Session {
+            id: session_id(req_msg.req_session_id, msg.rsp_session_id),
+            phase: Phase::PskHandshake,
+            requester_cap: self.requester_cap,
+            responder_cap: self.responder_cap,
+            algorithms: self.algorithms.clone(),
+            hash_algo,
+            aead_algo,
+            mode,
+            handshake_secrets,
+            session_keys: None,
+            channels: None,
+            heartbeat: HeartbeatTimer::new(msg.heartbeat_period),
+            req_slot_id: None,
+            transcript: th,
+        };
+
+        // Without a ResponderContext, there is no PSK_FINISH
+        if context_required {
+            new_session.secure_handshake();
+        } else {
+            new_session.establish();
+        }
+        sessions.insert(new_session)?;
+
+        Ok((size, measurements::State::from(self).into()))
+    }
+
+    // A ResponderContext, and therefore PSK_FINISH, is only required if
+    // the responder sets PSK_CAP with context.
+    fn context_required(&self) -> bool {
+        self.responder_cap.contains(RspFlags::PSX_CAP_WITH_CONTEXT)
+    }
+}
@@ -0,0 +1,63 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::session::{Phase, Session, Sessions};
+use super::{expect, ResponderError};
+
+use crate::crypto::{
+    digest::{Digest, DigestImpl},
+    key_schedule,
+};
+use crate::msgs::{Msg, PskFinish, PskFinishRsp, HEADER_SIZE};
+
+/// Reject a PSK_FINISH request received in the clear, and terminate the
+/// handshake, as PSK_FINISH must be received within the session.
+pub fn handle_clear_msg(
+    sessions: &mut Sessions,
+) -> Result<usize, ResponderError> {
+    match sessions.handshake_mut() {
+        Some(s) if s.phase == Phase::PskHandshake => {
+            sessions.remove_handshake();
+            Err(ResponderError::HandshakeNotInTheClear)
+        }
+        _ => Err(ResponderError::NoHandshakeInProgress),
+    }
+}
+
+/// Handle a PSK_FINISH request for the session `s`, whose handshake is in
+/// progress, and write PSK_FINISH_RSP.
+///
+/// The caller terminates the session if an error is returned, such as when
+/// the RequesterVerifyData does not verify. Otherwise, it establishes the
+/// session once PSK_FINISH_RSP is sent with the handshake keys.
+pub fn handle_msg(
+    req: &[u8],
+    rsp: &mut [u8],
+    s: &mut Session,
+) -> Result<usize, ResponderError> {
+    expect::<PskFinish>(req)?;
+
+    let hash_algo = s.hash_algo;
+    let digest_size = hash_algo.get_digest_size();
+    let req_msg = PskFinish::parse_body(&req[HEADER_SIZE..], digest_size)?;
+
+    let verify_start = req.len() - digest_size as usize;
+    s.transcript.extend(&req[..verify_start])?;
+    let th_hash = DigestImpl::hash(hash_algo, s.transcript.get());
+    let finished_key =
+        key_schedule::finished_key(hash_algo, &s.handshake_secrets.request);
+    if !key_schedule::verify_hmac(
+        hash_algo,
+        finished_key.as_ref(),
+        th_hash.as_ref(),
+        req_msg.verify_data(),
+    ) {
+        return Err(ResponderError::InvalidVerifyData);
+    }
+    s.transcript.extend(req_msg.verify_data())?;
+
+    let size = PskFinishRsp {}.write(rsp)?;
+    s.transcript.extend(&rsp[..size])?;
+    Ok(size)
+}
@@ -0,0 +1,277 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use rand::{rngs::OsRng, RngCore};
+
+use super::ResponderError;
+use crate::config;
+use crate::crypto::key_schedule::{self, HandshakeSecrets, SessionKeys};
+use crate::msgs::algorithms::{AeadFixedAlgorithms, BaseHashAlgo};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::key_exchange::session_id;
+use crate::msgs::Algorithms;
+use crate::secured_message::{
+    handshake_in_the_clear, Channels, HeartbeatTimer, Mode, RecordLayer,
+};
+use crate::Transcript;
+
+/// The phase of a secure session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    // KEY_EXCHANGE_RSP was sent, and FINISH is expected next
+    Handshake,
+
+    // PSK_EXCHANGE_RSP was sent, and PSK_FINISH is expected next
+    PskHandshake,
+
+    // FINISH_RSP was sent, and the session keys are derived
+    Established,
+}
+
+/// A secure session created by a KEY_EXCHANGE or PSK_EXCHANGE request
+///
+/// Sessions live alongside the responder state machine, rather than inside
+/// it, as other requests, such as GET_MEASUREMENTS, may still be made outside
+/// of a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub id: u32,
+    pub phase: Phase,
+
+    // The capabilities negotiated when the session was created
+    pub requester_cap: ReqFlags,
+    pub responder_cap: RspFlags,
+
+    // The algorithms negotiated when the session was created
+    pub algorithms: Algorithms,
+
+    pub hash_algo: BaseHashAlgo,
+    pub aead_algo: AeadFixedAlgorithms,
+    pub mode: Mode,
+    pub handshake_secrets: HandshakeSecrets,
+
+    // Derived once the session is established
+    pub session_keys: Option<SessionKeys>,
+
+    // The handshake keys, unless the handshake is in the clear, and then the
+    // session keys once the session is established
+    pub channels: Option<Channels>,
+
+    // Marked active by every secured message received in the session
+    pub heartbeat: HeartbeatTimer,
+
+    // The slot of the requester's certificate chain, if mutual authentication
+    // was requested in KEY_EXCHANGE_RSP
+    pub req_slot_id: Option<u8>,
+
+    // The VCA messages, the hash of the responder's certificate chain and all
+    // handshake messages. This is kept separate from the responder transcript,
+    // which is reset by other requests. Once the session is established, it
+    // holds the VCA messages and the measurement messages of the session
+    // (L1/L2) instead.
+    pub transcript: Transcript,
+}
+
+/// Return the heartbeat period to set for a new session
+///
+/// Heartbeats are only used if both sides set HBEAT_CAP.
+pub fn heartbeat_period(
+    requester_cap: ReqFlags,
+    responder_cap: RspFlags,
+) -> u8 {
+    if requester_cap.contains(ReqFlags::HBEAT_CAP)
+        && responder_cap.contains(RspFlags::HBEAT_CAP)
+    {
+        config::HEARTBEAT_PERIOD
+    } else {
+        0
+    }
+}
+
+impl Session {
+    /// Protect the rest of the handshake with the handshake keys.
+    ///
+    /// PSK_FINISH and PSK_FINISH_RSP are always sent within the session, and
+    /// FINISH and FINISH_RSP are unless both sides set
+    /// HANDSHAKE_IN_THE_CLEAR_CAP.
+    pub fn secure_handshake(&mut self) {
+        if self.phase == Phase::Handshake
+            && handshake_in_the_clear(self.requester_cap, self.responder_cap)
+        {
+            return;
+        }
+        self.channels = Some(Channels::handshake(
+            self.record_layer(),
+            self.hash_algo,
+            &self.handshake_secrets,
+        ));
+    }
+
+    /// Derive the session keys from the complete handshake transcript, and
+    /// establish the session.
+    pub fn establish(&mut self) {
+        let th2 = key_schedule::th2(self.hash_algo, &self.transcript);
+        let session_keys = SessionKeys::new(
+            self.hash_algo,
+            self.aead_algo,
+            &self.handshake_secrets.handshake_secret,
+            th2.as_ref(),
+        );
+        self.channels = Some(Channels::new(
+            self.record_layer(),
+            self.hash_algo,
+            &session_keys,
+        ));
+        self.session_keys = Some(session_keys);
+        self.phase = Phase::Established;
+        self.transcript.reset_to_vca();
+    }
+
+    fn record_layer(&self) -> RecordLayer {
+        RecordLayer {
+            session_id: self.id,
+            mode: self.mode,
+            aead_algo: self.aead_algo,
+            sequence_number_size: config::SEQUENCE_NUMBER_SIZE,
+        }
+    }
+}
+
+/// The sessions of a responder, keyed by session ID
+///
+/// Sessions are kept in a fixed size table, so that a responder can hold up
+/// to `config::MAX_SESSIONS` sessions at once without allocating. At most one
+/// session is in its handshake phase, as the handshake is driven by the
+/// responder state machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sessions {
+    sessions: [Option<Session>; config::MAX_SESSIONS],
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions::new()
+    }
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions { sessions: core::array::from_fn(|_| None) }
+    }
+
+    /// Choose the responder half of the ID of a new session.
+    ///
+    /// The resulting session ID is unique among the current sessions. Return
+    /// an error if the table is full, in which case a new session cannot be
+    /// created until another one ends.
+    pub fn new_rsp_session_id(
+        &self,
+        req_session_id: u16,
+    ) -> Result<u16, ResponderError> {
+        if self.is_full() {
+            return Err(ResponderError::SessionLimitExceeded);
+        }
+        loop {
+            let rsp_session_id = OsRng.next_u32() as u16;
+            if self.get(session_id(req_session_id, rsp_session_id)).is_none() {
+                return Ok(rsp_session_id);
+            }
+        }
+    }
+
+    /// Return true if no session can be added until another one ends.
+    ///
+    /// A session whose handshake is in progress does not count, as it is
+    /// replaced by the next one.
+    pub fn is_full(&self) -> bool {
+        self.sessions
+            .iter()
+            .all(|s| matches!(s, Some(s) if s.phase == Phase::Established))
+    }
+
+    /// Add a session whose handshake just started.
+    ///
+    /// A previous handshake that was never completed is abandoned, as only
+    /// one handshake can be in progress.
+    pub fn insert(&mut self, session: Session) -> Result<(), ResponderError> {
+        self.remove_handshake();
+        match self.sessions.iter_mut().find(|s| s.is_none()) {
+            Some(entry) => {
+                *entry = Some(session);
+                Ok(())
+            }
+            None => Err(ResponderError::SessionLimitExceeded),
+        }
+    }
+
+    /// Return the session with the given ID
+    pub fn get(&self, id: u32) -> Option<&Session> {
+        self.iter().find(|s| s.id == id)
+    }
+
+    /// Return the session with the given ID
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Session> {
+        self.sessions.iter_mut().flatten().find(|s| s.id == id)
+    }
+
+    /// Return the session whose handshake is in progress
+    pub fn handshake_mut(&mut self) -> Option<&mut Session> {
+        self.sessions
+            .iter_mut()
+            .flatten()
+            .find(|s| s.phase != Phase::Established)
+    }
+
+    /// Terminate the session with the given ID, and return it
+    pub fn remove(&mut self, id: u32) -> Option<Session> {
+        self.sessions
+            .iter_mut()
+            .find(|s| matches!(s, Some(s) if s.id == id))
+            .and_then(|s| s.take())
+    }
+
+    /// Terminate the session whose handshake is in progress
+    pub fn remove_handshake(&mut self) {
+        for entry in self.sessions.iter_mut() {
+            if matches!(entry, Some(s) if s.phase != Phase::Established) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Terminate all sessions
+    pub fn clear(&mut self) {
+        self.sessions = core::array::from_fn(|_| None);
+    }
+
+    /// Terminate the sessions for which `f` returns true, and return how
+    /// many were terminated.
+    pub fn remove_if<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&mut Session) -> bool,
+    {
+        let mut removed = 0;
+        for entry in self.sessions.iter_mut() {
+            if let Some(s) = entry {
+                if f(s) {
+                    *entry = None;
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
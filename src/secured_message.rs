@@ -0,0 +1,966 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The secured message record layer, as defined in DSP0277
+//!
+//! Once a session is established, SPDM messages and application data are
+//! wrapped in records protected by the session keys:
+//!
+//! ```text
+//! +------------+-----------------+---------+-------------------------+-----+
+//! | Session ID | Sequence Number | Length  | Application Data Length | MAC |
+//! | (4 bytes)  | (0 - 8 bytes)   | (2 bytes)| Application Data       |     |
+//! +------------+-----------------+---------+-------------------------+-----+
+//!                                           \_______ encrypted _______/
+//! ```
+//!
+//! The size of the sequence number is defined by the transport. All integers
+//! are little endian. The Length field covers everything after it, including
+//! the MAC. The header is authenticated, but not encrypted.
+//!
+//! In MAC only mode, the application data is neither encrypted nor preceded by
+//! its length. The MAC then authenticates the header and the application data.
+//!
+//! KEY_UPDATE replaces the keys of one or both directions with keys derived
+//! from the next secret of that direction, and restarts its sequence numbers.
+//!
+//! A session may also have a heartbeat period, after which the requester must
+//! show that it is alive. This is tracked by a `HeartbeatTimer`.
+
+use core::convert::TryInto;
+use core::time::Duration;
+
+use crate::crypto::aead::{Aead, TAG_SIZE};
+use crate::crypto::key_schedule::{
+    update_secret, HandshakeSecrets, Secret, SessionKeys, TrafficKeys,
+    AEAD_IV_SIZE,
+};
+use crate::msgs::algorithms::{AeadFixedAlgorithms, BaseHashAlgo};
+use crate::msgs::capabilities::{ReqFlags, RspFlags};
+use crate::msgs::HEADER_SIZE;
+
+/// The size of the session ID at the start of every record
+pub const SESSION_ID_SIZE: usize = 4;
+
+/// The largest sequence number size allowed by DSP0277
+pub const MAX_SEQUENCE_NUMBER_SIZE: usize = 8;
+
+// The size of the Length and Application Data Length fields
+const LENGTH_SIZE: usize = 2;
+
+/// Return the size of the largest record containing `app_data_size` bytes of
+/// application data, whatever the mode and sequence number size.
+pub const fn max_record_size(app_data_size: usize) -> usize {
+    SESSION_ID_SIZE
+        + MAX_SEQUENCE_NUMBER_SIZE
+        + 2 * LENGTH_SIZE
+        + app_data_size
+        + TAG_SIZE
+}
+
+// The largest record that may be a retried KEY_UPDATE request, which is only
+// an SPDM header
+const MAX_KEY_UPDATE_RECORD_SIZE: usize = max_record_size(HEADER_SIZE);
+
+/// An error encoding or decoding a secured message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    // The output buffer is too small to hold the record
+    BufferTooSmall,
+
+    // The application data does not fit in the Length field
+    AppDataTooLarge,
+
+    // The record is shorter than its header or Length field indicates
+    Truncated,
+
+    // The record belongs to a different session
+    SessionIdMismatch(u32),
+
+    // The sequence number in the record is not the expected one
+    SequenceNumberMismatch,
+
+    // The sequence number would wrap. The keys must be updated, or the
+    // session ended.
+    SequenceNumberExhausted,
+
+    // The record failed authentication
+    DecryptFailed,
+
+    // The decrypted Application Data Length is larger than the record
+    InvalidAppDataLength,
+
+    // The AEAD implementation failed
+    Aead,
+}
+
+/// Whether the application data of a record is encrypted
+///
+/// Records are encrypted if both sides set ENCRYPT_CAP, and only authenticated
+/// if both sides set MAC_CAP but not ENCRYPT_CAP. Otherwise, records cannot be
+/// protected, and no session can be established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Encrypt,
+    MacOnly,
+}
+
+impl Mode {
+    /// Return the mode negotiated via GET_CAPABILITIES, or `None` if the two
+    /// sides have no mode in common.
+    pub fn new(
+        requester_cap: ReqFlags,
+        responder_cap: RspFlags,
+    ) -> Option<Mode> {
+        if requester_cap.contains(ReqFlags::ENCRYPT_CAP)
+            && responder_cap.contains(RspFlags::ENCRYPT_CAP)
+        {
+            Some(Mode::Encrypt)
+        } else if requester_cap.contains(ReqFlags::MAC_CAP)
+            && responder_cap.contains(RspFlags::MAC_CAP)
+        {
+            Some(Mode::MacOnly)
+        } else {
+            None
+        }
+    }
+}
+
+/// Return true if the session handshake is sent in the clear
+///
+/// FINISH and FINISH_RSP are otherwise sent within the session, protected by
+/// the handshake keys. This requires HANDSHAKE_IN_THE_CLEAR_CAP on both sides.
+pub fn handshake_in_the_clear(
+    requester_cap: ReqFlags,
+    responder_cap: RspFlags,
+) -> bool {
+    requester_cap.contains(ReqFlags::HANDSHAKE_IN_THE_CLEAR_CAP)
+        && responder_cap.contains(RspFlags::HANDSHAKE_IN_THE_CLEAR_CAP)
+}
+
+/// The keys and next sequence number of one direction of a session
+///
+/// The secret the keys are derived from is kept for KEY_UPDATE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Channel {
+    pub secret: Secret,
+    pub keys: TrafficKeys,
+    pub sequence_number: u64,
+}
+
+impl Channel {
+    pub fn new(secret: Secret, keys: TrafficKeys) -> Channel {
+        Channel { secret, keys, sequence_number: 0 }
+    }
+
+    /// Replace the keys with those derived from the next secret, and restart
+    /// the sequence number.
+    pub fn update(
+        &mut self,
+        hash_algo: BaseHashAlgo,
+        aead_algo: AeadFixedAlgorithms,
+    ) {
+        self.secret = update_secret(hash_algo, &self.secret);
+        self.keys = TrafficKeys::new(hash_algo, aead_algo, &self.secret);
+        self.sequence_number = 0;
+    }
+
+    /// Return the nonce for the current sequence number
+    ///
+    /// The little endian sequence number is XORed into the leading bytes of
+    /// the IV.
+    pub fn nonce(&self) -> [u8; AEAD_IV_SIZE] {
+        let mut nonce = self.keys.iv;
+        for (n, s) in nonce.iter_mut().zip(self.sequence_number.to_le_bytes()) {
+            *n ^= s;
+        }
+        nonce
+    }
+
+    // Advance to the next sequence number after a record was processed
+    fn advance(&mut self) {
+        self.sequence_number += 1;
+    }
+
+    // Return an error if the sequence number cannot be used. The last
+    // sequence number is never used, so that `advance` cannot overflow.
+    fn check_sequence_number(&self) -> Result<(), Error> {
+        if self.sequence_number == u64::MAX {
+            return Err(Error::SequenceNumberExhausted);
+        }
+        Ok(())
+    }
+}
+
+/// The parameters shared by all records of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLayer {
+    pub session_id: u32,
+    pub mode: Mode,
+    pub aead_algo: AeadFixedAlgorithms,
+
+    // Defined by the transport, and at most `MAX_SEQUENCE_NUMBER_SIZE`. This
+    // is usually `config::SEQUENCE_NUMBER_SIZE`.
+    pub sequence_number_size: usize,
+}
+
+impl RecordLayer {
+    /// Return the size of the record header
+    pub fn header_size(&self) -> usize {
+        SESSION_ID_SIZE + self.sequence_number_size + LENGTH_SIZE
+    }
+
+    /// Return the size of a record containing `app_data_size` bytes of
+    /// application data.
+    pub fn record_size(&self, app_data_size: usize) -> usize {
+        let body_size = match self.mode {
+            Mode::Encrypt => LENGTH_SIZE + app_data_size,
+            Mode::MacOnly => app_data_size,
+        };
+        self.header_size() + body_size + TAG_SIZE
+    }
+
+    /// Return the offset of the application data in a record
+    pub fn app_data_offset(&self) -> usize {
+        match self.mode {
+            Mode::Encrypt => self.header_size() + LENGTH_SIZE,
+            Mode::MacOnly => self.header_size(),
+        }
+    }
+
+    /// Wrap `app_data` in a record written to `buf`, and return the size of
+    /// the record.
+    ///
+    /// The sequence number of `channel` is advanced on success.
+    pub fn encode<A: Aead>(
+        &self,
+        channel: &mut Channel,
+        app_data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        if self.record_size(app_data.len()) > buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        let start = self.app_data_offset();
+        buf[start..start + app_data.len()].copy_from_slice(app_data);
+        self.encode_in_place::<A>(channel, app_data.len(), buf)
+    }
+
+    /// Like `encode`, but the application data was already written to `buf`
+    /// at `app_data_offset`.
+    ///
+    /// This allows SPDM messages to be serialized directly into the record.
+    pub fn encode_in_place<A: Aead>(
+        &self,
+        channel: &mut Channel,
+        app_data_size: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        assert!(self.sequence_number_size <= MAX_SEQUENCE_NUMBER_SIZE);
+        channel.check_sequence_number()?;
+
+        let header_size = self.header_size();
+        let size = self.record_size(app_data_size);
+        if size > buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        let length: u16 = (size - header_size)
+            .try_into()
+            .map_err(|_| Error::AppDataTooLarge)?;
+
+        self.write_header(channel.sequence_number, length, buf);
+        let aead = A::new(self.aead_algo, channel.keys.key())
+            .map_err(|_| Error::Aead)?;
+        let nonce = channel.nonce();
+        let tag_start = size - TAG_SIZE;
+        let tag = match self.mode {
+            Mode::Encrypt => {
+                let app_data_length = app_data_size as u16;
+                buf[header_size..header_size + LENGTH_SIZE]
+                    .copy_from_slice(&app_data_length.to_le_bytes());
+                let (header, body) = buf.split_at_mut(header_size);
+                aead.seal_in_place(
+                    &nonce,
+                    header,
+                    &mut body[..tag_start - header_size],
+                )
+            }
+            Mode::MacOnly => {
+                aead.seal_in_place(&nonce, &buf[..tag_start], &mut [])
+            }
+        }
+        .map_err(|_| Error::Aead)?;
+        buf[tag_start..size].copy_from_slice(&tag);
+
+        channel.advance();
+        Ok(size)
+    }
+
+    /// Authenticate and, if necessary, decrypt the record in `buf` in place.
+    /// Return the application data.
+    ///
+    /// The sequence number of `channel` is advanced on success.
+    pub fn decode<'a, A: Aead>(
+        &self,
+        channel: &mut Channel,
+        buf: &'a mut [u8],
+    ) -> Result<&'a [u8], Error> {
+        assert!(self.sequence_number_size <= MAX_SEQUENCE_NUMBER_SIZE);
+        channel.check_sequence_number()?;
+
+        let header_size = self.header_size();
+        if buf.len() < header_size {
+            return Err(Error::Truncated);
+        }
+        let session_id = session_id(buf)?;
+        if session_id != self.session_id {
+            return Err(Error::SessionIdMismatch(session_id));
+        }
+        if !self.is_next_record(channel, buf) {
+            return Err(Error::SequenceNumberMismatch);
+        }
+        let length = u16::from_le_bytes([
+            buf[header_size - LENGTH_SIZE],
+            buf[header_size - 1],
+        ]) as usize;
+        let min_length = match self.mode {
+            Mode::Encrypt => LENGTH_SIZE + TAG_SIZE,
+            Mode::MacOnly => TAG_SIZE,
+        };
+        if length < min_length || buf.len() < header_size + length {
+            return Err(Error::Truncated);
+        }
+
+        let aead = A::new(self.aead_algo, channel.keys.key())
+            .map_err(|_| Error::Aead)?;
+        let nonce = channel.nonce();
+        let end = header_size + length;
+        let app_data = match self.mode {
+            Mode::Encrypt => {
+                let (header, body) = buf.split_at_mut(header_size);
+                let plaintext = aead
+                    .open_in_place(&nonce, header, &mut body[..length])
+                    .map_err(|_| Error::DecryptFailed)?;
+                let app_data_length =
+                    u16::from_le_bytes([plaintext[0], plaintext[1]]) as usize;
+                if LENGTH_SIZE + app_data_length > plaintext.len() {
+                    return Err(Error::InvalidAppDataLength);
+                }
+                &plaintext[LENGTH_SIZE..LENGTH_SIZE + app_data_length]
+            }
+            Mode::MacOnly => {
+                let tag_start = end - TAG_SIZE;
+                let (aad, tag) = buf[..end].split_at_mut(tag_start);
+                aead.open_in_place(&nonce, aad, tag)
+                    .map_err(|_| Error::DecryptFailed)?;
+                &aad[header_size..]
+            }
+        };
+
+        channel.advance();
+        Ok(app_data)
+    }
+
+    /// Return true if the sequence number of the record in `buf` is the next
+    /// one expected by `channel`.
+    ///
+    /// `buf` must be at least `header_size` bytes.
+    pub fn is_next_record(&self, channel: &Channel, buf: &[u8]) -> bool {
+        let start = SESSION_ID_SIZE;
+        let end = start + self.sequence_number_size;
+        let expected = channel.sequence_number.to_le_bytes();
+        buf[start..end] == expected[..self.sequence_number_size]
+    }
+
+    fn write_header(&self, sequence_number: u64, length: u16, buf: &mut [u8]) {
+        buf[..SESSION_ID_SIZE].copy_from_slice(&self.session_id.to_le_bytes());
+        let start = SESSION_ID_SIZE;
+        let end = start + self.sequence_number_size;
+        buf[start..end].copy_from_slice(
+            &sequence_number.to_le_bytes()[..self.sequence_number_size],
+        );
+        buf[end..end + LENGTH_SIZE].copy_from_slice(&length.to_le_bytes());
+    }
+}
+
+/// The record layer and both directions of a session
+///
+/// The requester encodes with `request` and decodes with `response`, and the
+/// responder does the opposite. During the handshake, the channels use the
+/// handshake keys, which are replaced by the session keys once the session is
+/// established.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Channels {
+    pub record_layer: RecordLayer,
+    pub hash_algo: BaseHashAlgo,
+    pub request: Channel,
+    pub response: Channel,
+
+    // The request and response channels from before a key update, kept until
+    // the update is known to be in effect on both sides.
+    pub previous: Option<(Channel, Channel)>,
+}
+
+impl Channels {
+    pub fn new(
+        record_layer: RecordLayer,
+        hash_algo: BaseHashAlgo,
+        keys: &SessionKeys,
+    ) -> Channels {
+        Channels {
+            record_layer,
+            hash_algo,
+            request: Channel::new(
+                keys.request_secret.clone(),
+                keys.request.clone(),
+            ),
+            response: Channel::new(
+                keys.response_secret.clone(),
+                keys.response.clone(),
+            ),
+            previous: None,
+        }
+    }
+
+    /// Return the channels of a session handshake, which is protected by keys
+    /// derived from the request and response handshake secrets.
+    pub fn handshake(
+        record_layer: RecordLayer,
+        hash_algo: BaseHashAlgo,
+        secrets: &HandshakeSecrets,
+    ) -> Channels {
+        let aead_algo = record_layer.aead_algo;
+        Channels {
+            record_layer,
+            hash_algo,
+            request: Channel::new(
+                secrets.request.clone(),
+                TrafficKeys::new(hash_algo, aead_algo, &secrets.request),
+            ),
+            response: Channel::new(
+                secrets.response.clone(),
+                TrafficKeys::new(hash_algo, aead_algo, &secrets.response),
+            ),
+            previous: None,
+        }
+    }
+
+    /// Update the keys of the request direction
+    ///
+    /// The current channels are kept until `confirm_update` or `rollback` is
+    /// called.
+    pub fn update_request(&mut self) {
+        self.save();
+        self.request.update(self.hash_algo, self.record_layer.aead_algo);
+    }
+
+    /// Update the keys of the response direction
+    ///
+    /// The current channels are kept until `confirm_update` or `rollback` is
+    /// called.
+    pub fn update_response(&mut self) {
+        self.save();
+        self.response.update(self.hash_algo, self.record_layer.aead_algo);
+    }
+
+    /// Discard the channels from before the last key update
+    pub fn confirm_update(&mut self) {
+        self.previous = None;
+    }
+
+    /// Undo any key update that was not confirmed
+    pub fn rollback(&mut self) {
+        if let Some((request, response)) = self.previous.take() {
+            self.request = request;
+            self.response = response;
+        }
+    }
+
+    /// Decode a record from the requester. This is the responder side of
+    /// `RecordLayer::decode`.
+    ///
+    /// If the requester didn't receive the KEY_UPDATE_ACK for a key update, it
+    /// may retry with the old keys. Such a record rolls back the update. A
+    /// record sent with the new keys confirms the update.
+    pub fn decode_request<'a, A: Aead>(
+        &mut self,
+        buf: &'a mut [u8],
+    ) -> Result<&'a [u8], Error> {
+        if buf.len() < self.record_layer.header_size() {
+            return Err(Error::Truncated);
+        }
+        if let Some((request, _)) = &self.previous {
+            if self.is_retry::<A>(request, buf) {
+                let mut request = request.clone();
+                let app_data =
+                    self.record_layer.decode::<A>(&mut request, buf)?;
+                self.rollback();
+                self.request = request;
+                return Ok(app_data);
+            }
+        }
+        let app_data = self.record_layer.decode::<A>(&mut self.request, buf)?;
+        self.confirm_update();
+        Ok(app_data)
+    }
+
+    // Return true if the record in `buf` was sent with `previous`, the request
+    // channel from before the key update in progress.
+    //
+    // A retry is usually recognized by its sequence number. Without one to
+    // tell the channels apart, a record small enough to be a KEY_UPDATE
+    // request is a retry if it does not authenticate with the new keys. It is
+    // decoded from a copy, as `buf` is decrypted in place.
+    fn is_retry<A: Aead>(&self, previous: &Channel, buf: &[u8]) -> bool {
+        if !self.record_layer.is_next_record(previous, buf) {
+            return false;
+        }
+        if !self.record_layer.is_next_record(&self.request, buf) {
+            return true;
+        }
+        if buf.len() > MAX_KEY_UPDATE_RECORD_SIZE {
+            return false;
+        }
+        let mut record = [0u8; MAX_KEY_UPDATE_RECORD_SIZE];
+        let record = &mut record[..buf.len()];
+        record.copy_from_slice(buf);
+        let mut request = self.request.clone();
+        self.record_layer.decode::<A>(&mut request, record).is_err()
+    }
+
+    // Keep the current channels, unless an update is already in progress
+    fn save(&mut self) {
+        if self.previous.is_none() {
+            self.previous = Some((self.request.clone(), self.response.clone()));
+        }
+    }
+}
+
+/// Tracks the liveness of a session with a heartbeat period
+///
+/// There is no clock in this library. Instead, the application passes the
+/// current time of its monotonic clock with every record sent by the
+/// requester, or received by either side, which marks the session as active
+/// at that time. Until then, the session counts as active from the first
+/// time the timer is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatTimer {
+    period: Duration,
+    last_active: Option<Duration>,
+}
+
+impl HeartbeatTimer {
+    /// `period` is the HeartbeatPeriod in seconds negotiated when the session
+    /// was created. A period of 0 disables heartbeats.
+    pub fn new(period: u8) -> HeartbeatTimer {
+        HeartbeatTimer {
+            period: Duration::from_secs(period.into()),
+            last_active: None,
+        }
+    }
+
+    /// Return the heartbeat period, or `None` if heartbeats are disabled
+    pub fn period(&self) -> Option<Duration> {
+        if self.period.is_zero() {
+            None
+        } else {
+            Some(self.period)
+        }
+    }
+
+    /// Mark the session as active at time `now`
+    pub fn mark_active(&mut self, now: Duration) {
+        self.last_active = Some(now);
+    }
+
+    /// Return true if the heartbeat period elapsed since the session was last
+    /// active, and a HEARTBEAT must be sent to keep the session alive.
+    pub fn is_due(&mut self, now: Duration) -> bool {
+        self.elapsed(now).is_some_and(|elapsed| elapsed >= self.period)
+    }
+
+    /// Return true if twice the heartbeat period elapsed since the session
+    /// was last active, after which a responder terminates the session.
+    pub fn is_expired(&mut self, now: Duration) -> bool {
+        self.elapsed(now).is_some_and(|elapsed| elapsed > 2 * self.period)
+    }
+
+    // Return the time since the session was last active, or `None` if
+    // heartbeats are disabled.
+    fn elapsed(&mut self, now: Duration) -> Option<Duration> {
+        self.period()?;
+        let last_active = *self.last_active.get_or_insert(now);
+        Some(now.saturating_sub(last_active))
+    }
+}
+
+/// Return the session ID of the record in `buf`
+///
+/// This allows a receiver to find the session of a record before decoding it.
+pub fn session_id(buf: &[u8]) -> Result<u32, Error> {
+    if buf.len() < SESSION_ID_SIZE {
+        return Err(Error::Truncated);
+    }
+    Ok(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::aead::RingAead;
+    use crate::crypto::key_schedule::{HandshakeSecrets, SessionKeys};
+    use crate::msgs::algorithms::BaseHashAlgo;
+
+    const APP_DATA: &[u8] = b"GET_MEASUREMENTS in a session";
+
+    fn channels(aead_algo: AeadFixedAlgorithms) -> (Channel, Channel) {
+        let hash_algo = BaseHashAlgo::SHA_256;
+        let secrets = HandshakeSecrets::new(hash_algo, &[1; 32], &[2; 32]);
+        let keys = SessionKeys::new(
+            hash_algo,
+            aead_algo,
+            &secrets.handshake_secret,
+            &[3; 32],
+        );
+        (
+            Channel::new(keys.request_secret.clone(), keys.request.clone()),
+            Channel::new(keys.request_secret, keys.request),
+        )
+    }
+
+    fn roundtrip(record_layer: RecordLayer) {
+        let (mut sender, mut receiver) = channels(record_layer.aead_algo);
+        let mut buf = [0u8; 128];
+        for i in 0..3 {
+            let size = record_layer
+                .encode::<RingAead>(&mut sender, APP_DATA, &mut buf)
+                .unwrap();
+            assert_eq!(record_layer.record_size(APP_DATA.len()), size);
+            assert_eq!(Ok(0xABCD_0001), session_id(&buf));
+            if record_layer.mode == Mode::Encrypt {
+                assert!(!buf[..size]
+                    .windows(APP_DATA.len())
+                    .any(|w| w == APP_DATA));
+            }
+            let app_data = record_layer
+                .decode::<RingAead>(&mut receiver, &mut buf[..size])
+                .unwrap();
+            assert_eq!(APP_DATA, app_data);
+            assert_eq!(i + 1, sender.sequence_number);
+            assert_eq!(i + 1, receiver.sequence_number);
+        }
+    }
+
+    // Records are only protected in a mode both sides set, and never default
+    // to MAC only
+    #[test]
+    fn negotiated_mode() {
+        let req_both = ReqFlags::ENCRYPT_CAP | ReqFlags::MAC_CAP;
+        let rsp_both = RspFlags::ENCRYPT_CAP | RspFlags::MAC_CAP;
+        assert_eq!(Some(Mode::Encrypt), Mode::new(req_both, rsp_both));
+        assert_eq!(Some(Mode::MacOnly), Mode::new(req_both, RspFlags::MAC_CAP));
+        assert_eq!(None, Mode::new(ReqFlags::ENCRYPT_CAP, RspFlags::MAC_CAP));
+        assert_eq!(None, Mode::new(ReqFlags::empty(), RspFlags::empty()));
+    }
+
+    #[test]
+    fn encrypted_roundtrip() {
+        for aead_algo in [
+            AeadFixedAlgorithms::AES_128_GCM,
+            AeadFixedAlgorithms::AES_256_GCM,
+            AeadFixedAlgorithms::CHACHA20_POLY1305,
+        ] {
+            for sequence_number_size in [0, 2, 8] {
+                roundtrip(RecordLayer {
+                    session_id: 0xABCD_0001,
+                    mode: Mode::Encrypt,
+                    aead_algo,
+                    sequence_number_size,
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn mac_only_roundtrip() {
+        let record_layer = RecordLayer {
+            session_id: 0xABCD_0001,
+            mode: Mode::MacOnly,
+            aead_algo: AeadFixedAlgorithms::AES_256_GCM,
+            sequence_number_size: 0,
+        };
+        roundtrip(record_layer);
+
+        // The application data is sent in the clear
+        let (mut sender, _) = channels(record_layer.aead_algo);
+        let mut buf = [0u8; 128];
+        record_layer
+            .encode::<RingAead>(&mut sender, APP_DATA, &mut buf)
+            .unwrap();
+        let start = record_layer.header_size();
+        assert_eq!(APP_DATA, &buf[start..start + APP_DATA.len()]);
+    }
+
+    #[test]
+    fn reject_tampered_record() {
+        for mode in [Mode::Encrypt, Mode::MacOnly] {
+            let record_layer = RecordLayer {
+                session_id: 0xABCD_0001,
+                mode,
+                aead_algo: AeadFixedAlgorithms::AES_128_GCM,
+                sequence_number_size: 0,
+            };
+            let (mut sender, mut receiver) = channels(record_layer.aead_algo);
+            let mut buf = [0u8; 128];
+            let size = record_layer
+                .encode::<RingAead>(&mut sender, APP_DATA, &mut buf)
+                .unwrap();
+            buf[record_layer.header_size() + 3] ^= 0x1;
+            assert_eq!(
+                Err(Error::DecryptFailed),
+                record_layer
+                    .decode::<RingAead>(&mut receiver, &mut buf[..size])
+            );
+            assert_eq!(0, receiver.sequence_number);
+        }
+    }
+
+    #[test]
+    fn reject_replayed_record() {
+        let record_layer = RecordLayer {
+            session_id: 0xABCD_0001,
+            mode: Mode::Encrypt,
+            aead_algo: AeadFixedAlgorithms::AES_256_GCM,
+            sequence_number_size: 0,
+        };
+        let (mut sender, mut receiver) = channels(record_layer.aead_algo);
+        let mut buf = [0u8; 128];
+        let size = record_layer
+            .encode::<RingAead>(&mut sender, APP_DATA, &mut buf)
+            .unwrap();
+        let copy = buf;
+        record_layer
+            .decode::<RingAead>(&mut receiver, &mut buf[..size])
+            .unwrap();
+
+        // Without a sequence number in the header, the nonce no longer matches
+        let mut buf = copy;
+        assert_eq!(
+            Err(Error::DecryptFailed),
+            record_layer.decode::<RingAead>(&mut receiver, &mut buf[..size])
+        );
+
+        // With a sequence number in the header, the mismatch is detected
+        // before decryption.
+        let record_layer =
+            RecordLayer { sequence_number_size: 8, ..record_layer };
+        let (mut sender, mut receiver) = channels(record_layer.aead_algo);
+        let size = record_layer
+            .encode::<RingAead>(&mut sender, APP_DATA, &mut buf)
+            .unwrap();
+        receiver.sequence_number = 1;
+        assert_eq!(
+            Err(Error::SequenceNumberMismatch),
+            record_layer.decode::<RingAead>(&mut receiver, &mut buf[..size])
+        );
+    }
+
+    #[test]
+    fn reject_wrong_session() {
+        let record_layer = RecordLayer {
+            session_id: 0xABCD_0001,
+            mode: Mode::Encrypt,
+            aead_algo: AeadFixedAlgorithms::AES_256_GCM,
+            sequence_number_size: 0,
+        };
+        let (mut sender, mut receiver) = channels(record_layer.aead_algo);
+        let mut buf = [0u8; 128];
+        let size = record_layer
+            .encode::<RingAead>(&mut sender, APP_DATA, &mut buf)
+            .unwrap();
+        let other = RecordLayer { session_id: 0x1234_0001, ..record_layer };
+        assert_eq!(
+            Err(Error::SessionIdMismatch(0xABCD_0001)),
+            other.decode::<RingAead>(&mut receiver, &mut buf[..size])
+        );
+        assert_eq!(
+            Err(Error::Truncated),
+            record_layer
+                .decode::<RingAead>(&mut receiver, &mut buf[..size - 1])
+        );
+    }
+
+    #[test]
+    fn nonce_uses_sequence_number() {
+        let (mut channel, _) = channels(AeadFixedAlgorithms::AES_256_GCM);
+        let iv = channel.keys.iv;
+        assert_eq!(iv, channel.nonce());
+        channel.sequence_number = 0x0102;
+        let nonce = channel.nonce();
+        assert_eq!(iv[0] ^ 0x02, nonce[0]);
+        assert_eq!(iv[1] ^ 0x01, nonce[1]);
+        assert_eq!(iv[2..], nonce[2..]);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let record_layer = RecordLayer {
+            session_id: 1,
+            mode: Mode::Encrypt,
+            aead_algo: AeadFixedAlgorithms::AES_256_GCM,
+            sequence_number_size: 0,
+        };
+        let (mut sender, _) = channels(record_layer.aead_algo);
+        let mut buf = [0u8; 32];
+        assert_eq!(
+            Err(Error::BufferTooSmall),
+            record_layer.encode::<RingAead>(&mut sender, APP_DATA, &mut buf)
+        );
+        assert_eq!(0, sender.sequence_number);
+    }
+
+    // Return the channels of a requester and responder
+    fn session_channels() -> (Channels, Channels) {
+        let hash_algo = BaseHashAlgo::SHA_256;
+        let aead_algo = AeadFixedAlgorithms::AES_256_GCM;
+        let secrets = HandshakeSecrets::new(hash_algo, &[1; 32], &[2; 32]);
+        let keys = SessionKeys::new(
+            hash_algo,
+            aead_algo,
+            &secrets.handshake_secret,
+            &[3; 32],
+        );
+        let record_layer = RecordLayer {
+            session_id: 0xABCD_0001,
+            mode: Mode::Encrypt,
+            aead_algo,
+            sequence_number_size: 2,
+        };
+        let channels = Channels::new(record_layer, hash_algo, &keys);
+        (channels.clone(), channels)
+    }
+
+    #[test]
+    fn key_update() {
+        let (mut requester, mut responder) = session_channels();
+        let record_layer = requester.record_layer;
+        let mut buf = [0u8; 128];
+        let size = record_layer
+            .encode::<RingAead>(&mut requester.request, APP_DATA, &mut buf)
+            .unwrap();
+        responder.decode_request::<RingAead>(&mut buf[..size]).unwrap();
+
+        // The new keys restart the sequence number
+        let old = responder.request.clone();
+        responder.update_request();
+        assert_ne!(old.keys, responder.request.keys);
+        assert_eq!(0, responder.request.sequence_number);
+        assert_eq!(Some((old, responder.response.clone())), responder.previous);
+
+        // A record with the new keys confirms the update
+        requester.update_request();
+        requester.confirm_update();
+        let size = record_layer
+            .encode::<RingAead>(&mut requester.request, APP_DATA, &mut buf)
+            .unwrap();
+        responder.decode_request::<RingAead>(&mut buf[..size]).unwrap();
+        assert!(responder.previous.is_none());
+        assert_eq!(requester, responder);
+    }
+
+    #[test]
+    fn heartbeat_timer() {
+        let secs = Duration::from_secs;
+        let mut timer = HeartbeatTimer::new(10);
+        assert_eq!(Some(secs(10)), timer.period());
+
+        // Without any activity, the first check starts the period
+        assert!(!timer.is_due(secs(100)));
+        assert!(!timer.is_due(secs(109)));
+        assert!(timer.is_due(secs(110)));
+        assert!(!timer.is_expired(secs(120)));
+        assert!(timer.is_expired(secs(121)));
+
+        // Activity counts from the time it was marked, not the next check
+        timer.mark_active(secs(115));
+        assert!(!timer.is_due(secs(124)));
+        assert!(timer.is_due(secs(125)));
+        assert!(!timer.is_expired(secs(135)));
+        assert!(timer.is_expired(secs(136)));
+
+        // Heartbeats are disabled
+        let mut timer = HeartbeatTimer::new(0);
+        assert_eq!(None, timer.period());
+        assert!(!timer.is_due(secs(1000)));
+        assert!(!timer.is_expired(secs(1000)));
+    }
+
+    #[test]
+    fn key_update_rollback() {
+        let (mut requester, mut responder) = session_channels();
+        let record_layer = requester.record_layer;
+        let mut buf = [0u8; 128];
+        let size = record_layer
+            .encode::<RingAead>(&mut requester.request, APP_DATA, &mut buf)
+            .unwrap();
+        responder.decode_request::<RingAead>(&mut buf[..size]).unwrap();
+        let before = responder.clone();
+        responder.update_request();
+        responder.update_response();
+
+        // The requester never saw the update, and keeps the old keys
+        let size = record_layer
+            .encode::<RingAead>(&mut requester.request, APP_DATA, &mut buf)
+            .unwrap();
+        let app_data =
+            responder.decode_request::<RingAead>(&mut buf[..size]).unwrap();
+        assert_eq!(APP_DATA, app_data);
+        assert_eq!(before.response, responder.response);
+        assert_eq!(requester, responder);
+    }
+
+    // Without sequence numbers, a retried KEY_UPDATE is told apart from a
+    // record sent with the new keys by the keys it authenticates with
+    #[test]
+    fn key_update_rollback_without_sequence_number() {
+        const KEY_UPDATE: &[u8] = &[0x12, 0xE9, 0x01, 0x5A];
+        let (mut requester, mut responder) = session_channels();
+        requester.record_layer.sequence_number_size = 0;
+        responder.record_layer.sequence_number_size = 0;
+        let record_layer = requester.record_layer;
+        let mut buf = [0u8; 128];
+
+        // The KEY_UPDATE_ACK of the first attempt is lost
+        let size = record_layer
+            .encode::<RingAead>(&mut requester.request, KEY_UPDATE, &mut buf)
+            .unwrap();
+        responder.decode_request::<RingAead>(&mut buf[..size]).unwrap();
+        let before = responder.clone();
+        responder.update_request();
+
+        // The retry with the old keys rolls back the update
+        let size = record_layer
+            .encode::<RingAead>(&mut requester.request, KEY_UPDATE, &mut buf)
+            .unwrap();
+        let app_data =
+            responder.decode_request::<RingAead>(&mut buf[..size]).unwrap();
+        assert_eq!(KEY_UPDATE, app_data);
+        assert!(responder.previous.is_none());
+        assert_eq!(before.request.keys, responder.request.keys);
+        assert_eq!(requester, responder);
+
+        // A record with the new keys confirms the update
+        responder.update_request();
+        requester.update_request();
+        let size = record_layer
+            .encode::<RingAead>(&mut requester.request, KEY_UPDATE, &mut buf)
+            .unwrap();
+        let app_data =
+            responder.decode_request::<RingAead>(&mut buf[..size]).unwrap();
+        assert_eq!(KEY_UPDATE, app_data);
+        assert!(responder.previous.is_none());
+        requester.confirm_update();
+        assert_eq!(requester, responder);
+    }
+}
@@ -11,15 +11,22 @@ use crate::msgs::{WriteError, WriteErrorKind};
 /// A Transcript spans multiple states, and is purposefully kept outside those
 /// states to reduce the cost of the typestate pattern which takes and returns
 /// states by value.
+///
+/// Every transcript defined by the SPDM spec after algorithm negotiation starts
+/// with the same VCA messages (GET_VERSION, VERSION, GET_CAPABILITIES,
+/// CAPABILITIES, NEGOTIATE_ALGORITHMS, ALGORITHMS). We therefore remember where
+/// those messages end, so that later phases of the protocol can start a new
+/// transcript without having to renegotiate.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Transcript {
     buf: [u8; TRANSCRIPT_SIZE],
     offset: usize,
+    vca_offset: usize,
 }
 
 impl Transcript {
     pub fn new() -> Transcript {
-        Transcript { buf: [0; TRANSCRIPT_SIZE], offset: 0 }
+        Transcript { buf: [0; TRANSCRIPT_SIZE], offset: 0, vca_offset: 0 }
     }
 
     /// Append a serialized message onto the transcript
@@ -37,6 +44,30 @@ impl Transcript {
     /// Empty the transcript
     pub fn clear(&mut self) {
         self.offset = 0;
+        self.vca_offset = 0;
+    }
+
+    /// Record that all VCA messages have been appended to the transcript.
+    pub fn mark_vca(&mut self) {
+        self.vca_offset = self.offset;
+    }
+
+    /// Discard everything appended to the transcript after the VCA messages.
+    pub fn reset_to_vca(&mut self) {
+        self.offset = self.vca_offset;
+    }
+
+    /// Discard everything appended to the transcript after its first `len`
+    /// bytes.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.offset {
+            self.offset = len;
+        }
+    }
+
+    /// Retrieve the VCA messages at the start of the transcript
+    pub fn vca(&self) -> &[u8] {
+        &self.buf[0..self.vca_offset]
     }
 
     /// Retrieve the transcript
@@ -2,24 +2,45 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use spdm::config::{MAX_CERT_CHAIN_SIZE, NUM_SLOTS};
+use spdm::config::{
+    HEARTBEAT_PERIOD, MAX_CERT_CHAIN_SIZE, MAX_SESSIONS, NUM_SLOTS,
+};
 use spdm::crypto::{
+    aead::RingAead,
+    dhe::RingEphemeralKey,
     digest::{Digest, DigestImpl},
+    key_schedule::{self, SessionKeys},
     signing::{new_signer, RingSigner},
-    FilledSlot, Signer,
+    FilledSlot, ProvisionedKey, Signer,
 };
 use spdm::msgs::algorithms::*;
+use spdm::msgs::measurements::{
+    DmtfMeasurement, DmtfMeasurementRepresentation, DmtfMeasurementValueType,
+    MeasurementBlock, MeasurementIndex,
+};
 use spdm::msgs::{
-    digest::Digests, encoding::Writer, CertificateChain, GetVersion, Msg,
+    self, digest::Digests, encoding::Writer, Certificate, CertificateChain,
+    GetCertificate, GetVersion, MeasurementHashType, Msg, HEADER_SIZE,
+};
+use spdm::requester::{
+    self, CertCache, MeasurementRequest, NoCertCache, Psk, RequesterError,
+    RequesterInit, RequesterSession, TimeSource, UnknownTimePolicy,
+};
+use spdm::responder::{
+    self, AllStates, MeasurementProvider, MutualAuth, PskProvider, Responder,
+    ResponderError,
 };
-use spdm::requester::{self, RequesterInit};
-use spdm::responder::{self, AllStates, Responder};
-use spdm::Transcript;
+use spdm::{secured_message, Transcript};
 
 use test_utils::certs::*;
 
+use std::time::Duration;
+
 const BUF_SIZE: usize = 2048;
 
+// The time passed with secured messages, outside of heartbeat tests
+const NOW: Duration = Duration::ZERO;
+
 // Mutable data used by the requester and responder
 pub struct Data {
     req_buf: [u8; BUF_SIZE],
@@ -37,6 +58,7 @@ struct Certs {
     pub intermediate_der: Vec<u8>,
     pub leaf_der: Vec<u8>,
     pub leaf_private_der: Vec<u8>,
+    pub leaf_public_key: Vec<u8>,
     pub root_hash: DigestImpl,
 }
 
@@ -60,6 +82,7 @@ impl Certs {
                 .unwrap(),
             leaf_der: leaf.serialize_der_with_signer(&intermediate).unwrap(),
             leaf_private_der: leaf.serialize_private_key_der(),
+            leaf_public_key: leaf.get_key_pair().public_key_raw().to_vec(),
             root_hash,
         }
     }
@@ -72,6 +95,89 @@ impl Certs {
     }
 }
 
+// Measurements provided by the responder
+pub struct TestMeasurements {
+    firmware: [u8; 32],
+    config: [u8; 48],
+
+    // Indices need not be contiguous
+    config_index: u8,
+}
+
+impl Default for TestMeasurements {
+    fn default() -> TestMeasurements {
+        TestMeasurements {
+            firmware: [0x11; 32],
+            config: [0x22; 48],
+            config_index: 2,
+        }
+    }
+}
+
+impl MeasurementProvider for TestMeasurements {
+    fn num_measurements(&self) -> u8 {
+        2
+    }
+
+    fn measurement(
+        &mut self,
+        index: MeasurementIndex,
+        _raw_bit_stream_requested: bool,
+    ) -> Option<MeasurementBlock<'_>> {
+        let (index, value_type, value) = match index {
+            MeasurementIndex::ImplementationDefined(1) => (
+                1,
+                DmtfMeasurementValueType::MutableFirmware,
+                &self.firmware[..],
+            ),
+            MeasurementIndex::ImplementationDefined(i)
+                if i == self.config_index =>
+            {
+                (i, DmtfMeasurementValueType::FirmwareConfig, &self.config[..])
+            }
+            _ => return None,
+        };
+        Some(MeasurementBlock {
+            index,
+            measurement: DmtfMeasurement {
+                value_type,
+                representation: DmtfMeasurementRepresentation::Digest,
+                value,
+            },
+        })
+    }
+
+    fn is_tcb_component(&self, index: u8) -> bool {
+        // Only the firmware is part of the TCB
+        index == 1
+    }
+}
+
+const PSK_HINT: &[u8] = b"test psk";
+const PSK: [u8; 32] = [0x5A; 32];
+
+// PSKs provisioned on the responder
+pub struct TestPsk;
+
+impl PskProvider for TestPsk {
+    fn psk(&self, hint: &[u8]) -> Option<&[u8]> {
+        if hint == PSK_HINT {
+            Some(&PSK)
+        } else {
+            None
+        }
+    }
+}
+
+type PskResponder<'a> = Responder<
+    'a,
+    RingSigner,
+    TestMeasurements,
+    RingEphemeralKey,
+    RingAead,
+    TestPsk,
+>;
+
 fn create_certs_per_slot() -> Vec<Certs> {
     (0..NUM_SLOTS).map(|_| Certs::new()).collect()
 }
@@ -84,11 +190,20 @@ fn create_certs_per_slot() -> Vec<Certs> {
 /// capabilities, such as during rolling upgrade of software or hardware.
 fn create_slots<'a>(
     certs: &'a [Certs],
+) -> [Option<FilledSlot<'a, RingSigner>>; NUM_SLOTS] {
+    create_slots_where(certs, |i| i % 2 == 0)
+}
+
+/// Create a set of cert chains and signers for the slots where `filled`
+/// returns true
+fn create_slots_where<'a>(
+    certs: &'a [Certs],
+    filled: impl Fn(usize) -> bool,
 ) -> [Option<FilledSlot<'a, RingSigner>>; NUM_SLOTS] {
     assert_eq!(certs.len(), NUM_SLOTS);
-    let mut slots = [None; NUM_SLOTS];
+    let mut slots = core::array::from_fn(|_| None);
     for i in 0..NUM_SLOTS {
-        if i % 2 == 0 {
+        if filled(i) {
             let private_key = &certs[i].leaf_private_der;
             slots[i] = Some(FilledSlot {
                 signing_algorithm: BaseAsymAlgo::ECDSA_ECC_NIST_P256,
@@ -110,7 +225,7 @@ fn create_slots<'a>(
 fn negotiate_versions<'a, S: Signer>(
     data: &mut Data,
     requester: &mut RequesterInit<'a, S>,
-    responder: &mut Responder<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
 ) {
     // Create a version request and write it into the request buffer
     let req_data = requester.next_request(&mut data.req_buf).unwrap();
@@ -151,7 +266,7 @@ fn negotiate_versions<'a, S: Signer>(
 // algorithm negotiation states.
 fn negotiate_capabilities<'a, S: Signer>(
     requester: &mut RequesterInit<'a, S>,
-    responder: &mut Responder<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
     data: &mut Data,
 ) {
     // Serialize the GetCapabilities  message to send to the responder and
@@ -179,7 +294,7 @@ fn negotiate_capabilities<'a, S: Signer>(
 // algorithm negotiation states.
 fn negotiate_algorithms<'a, S: Signer>(
     requester: &mut RequesterInit<'a, S>,
-    responder: &mut Responder<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
     data: &mut Data,
 ) {
     // Serialize the request
@@ -222,7 +337,7 @@ fn negotiate_algorithms<'a, S: Signer>(
         assert!(matches!(
             req_state.algorithms.algorithm_responses[0],
             AlgorithmResponse::Dhe(DheAlgorithm {
-                supported: DheFixedAlgorithms::FFDHE_3072
+                supported: DheFixedAlgorithms::SECP_256_R1
             })
         ));
         assert!(matches!(
@@ -250,7 +365,7 @@ fn negotiate_algorithms<'a, S: Signer>(
 
 fn identify_responder<'a, S: Signer>(
     requester: &mut RequesterInit<'a, S>,
-    responder: &mut Responder<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
     data: &mut Data,
 ) {
     // Generate the GET_DIGESTS request at the requester
@@ -280,29 +395,53 @@ fn identify_responder<'a, S: Signer>(
         assert!(false);
     }
 
-    // Get the first cert chain (slot 0 always exists)
-    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    // Get the first cert chain (slot 0 always exists). It is larger than
+    // CERT_PORTION_SIZE, so it is retrieved in multiple portions.
+    let mut portions = 0;
+    while requester.state().name() == "IdAuth" {
+        // The responder stays in the IdAuth state until the last portion
+        assert_eq!("IdAuth", responder.state().name());
 
-    // Handle the GET_CERTIFICATE request at the responder
-    let (rsp_data, response) =
-        responder.handle_msg(req_data, &mut data.rsp_buf);
-    response.unwrap();
+        let req_data = requester.next_request(&mut data.req_buf).unwrap();
 
-    // The responder transitioned to the the challenge state
-    assert_eq!("Challenge", responder.state().name());
+        // Handle the GET_CERTIFICATE request at the responder
+        let (rsp_data, response) =
+            responder.handle_msg(req_data, &mut data.rsp_buf);
+        response.unwrap();
 
-    // Handle the CERTIFICATE response at the requester
-    let initialization_complete = requester.handle_msg(rsp_data).unwrap();
-    assert_eq!(initialization_complete, false);
+        // Handle the CERTIFICATE response at the requester
+        let initialization_complete = requester.handle_msg(rsp_data).unwrap();
+        assert!(!initialization_complete);
 
-    assert_eq!(requester.transcript().get(), responder.transcript().get());
+        assert_eq!(requester.transcript().get(), responder.transcript().get());
+        portions += 1;
+    }
+    assert!(portions > 1);
 
+    // Both sides transitioned to the the challenge state
+    assert_eq!("Challenge", responder.state().name());
     assert_eq!("Challenge", requester.state().name());
+    if let requester::AllStates::Challenge(ref req_state) = requester.state() {
+        let mut buf = [0u8; MAX_CERT_CHAIN_SIZE];
+        let mut w = Writer::new("CERTIFICATE_CHAIN", &mut buf);
+        let size = responder.slots()[0]
+            .as_ref()
+            .unwrap()
+            .cert_chain
+            .write(&mut w)
+            .unwrap();
+        assert_eq!(
+            &buf[..size],
+            &req_state.cert_chain[..req_state.cert_chain_size as usize]
+        );
+    } else {
+        panic!("the requester is not in the Challenge state");
+    }
 }
 
 fn challenge_auth<'a, S: Signer>(
     requester: &mut RequesterInit<'a, S>,
-    responder: &mut Responder<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
     data: &mut Data,
 ) {
     // Create the CHALLENGE request at the requester
@@ -312,17 +451,245 @@ fn challenge_auth<'a, S: Signer>(
     let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
     result.unwrap();
 
-    // The rest of the states have not yet been implemented, so we don't transition
-    // here.
-    //
-    // This will change to "Measurement" when that state is implemented.
-    assert_eq!("Challenge", responder.state().name());
+    // The responder is now ready to serve measurements
+    assert_eq!("Measurements", responder.state().name());
+
+    // Deliver the response to the requester
+    let initialization_complete = requester.handle_msg(rsp_data).unwrap();
+    assert!(!initialization_complete);
+
+    // Both sides support key exchange, so a secure session is established next
+    assert_eq!("KeyExchange", requester.state().name());
+}
+
+fn key_exchange<'a, S: Signer>(
+    requester: &mut RequesterInit<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+) {
+    // Create the KEY_EXCHANGE request at the requester
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+
+    // Handle the KEY_EXCHANGE request at the responder
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+
+    // The responder created a session, and can still serve measurements
+    // outside of it.
+    assert_eq!("Measurements", responder.state().name());
+
+    // Deliver the response to the requester
+    let initialization_complete = requester.handle_msg(rsp_data).unwrap();
+    assert!(!initialization_complete);
+    assert_eq!("Finish", requester.state().name());
+    if let requester::AllStates::Finish(ref req_state) = requester.state() {
+        let session = responder.session(req_state.session_id).unwrap();
+        assert_eq!(responder::session::Phase::Handshake, session.phase);
+    } else {
+        panic!("the requester is not in the Finish state");
+    }
+}
+
+fn finish<'a, S: Signer>(
+    requester: &mut RequesterInit<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+) {
+    // The handshake is not in the clear, so FINISH is sent within the session
+    assert!(requester.is_secured());
+    let (rsp_data, result) = deliver(requester, responder, data);
+    result.unwrap();
+    assert_eq!("Measurements", responder.state().name());
+
+    // The FINISH_RSP record has no verify data
+    let session_id = secured_message::session_id(rsp_data).unwrap();
+    let session = responder.session(session_id).unwrap();
+    assert_eq!(
+        session.channels.as_ref().unwrap().record_layer.record_size(4),
+        rsp_data.len()
+    );
 
     // Deliver the response to the requester
     let initialization_complete = requester.handle_msg(rsp_data).unwrap();
     assert_eq!(true, initialization_complete);
 
     assert_eq!("NewSession", requester.state().name());
+    if let requester::AllStates::NewSession(ref req_state) = requester.state() {
+        assert_eq!(Some(session_id), req_state.session_id);
+        let session = responder.session(session_id).unwrap();
+        assert_eq!(responder::session::Phase::Established, session.phase);
+        assert!(req_state.session_keys.is_some());
+        assert_eq!(session.session_keys, req_state.session_keys);
+    } else {
+        panic!("the requester is not in the NewSession state");
+    }
+
+    // Both sides start any measurement transcript from the VCA messages
+    assert_eq!(requester.transcript().get(), responder.transcript().vca());
+}
+
+// Deliver the next request of the requester to the responder, within the
+// session if the session handshake requires it, and return the response
+fn deliver<'a, 'b, S: Signer, C: CertCache, T: TimeSource>(
+    requester: &mut RequesterInit<'a, S, C, T>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &'b mut Data,
+) -> (&'b [u8], Result<(), ResponderError>) {
+    let size = requester.next_request(&mut data.req_buf).unwrap().len();
+    if requester.is_secured() {
+        responder.handle_secured_msg(
+            &mut data.req_buf[..size],
+            &mut data.rsp_buf,
+            NOW,
+        )
+    } else {
+        responder.handle_msg(&data.req_buf[..size], &mut data.rsp_buf)
+    }
+}
+
+// Drive a measurement request to completion, returning the number of
+// round trips.
+fn retrieve_measurements<'a, S: Signer>(
+    requester: &mut RequesterSession<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+    request: MeasurementRequest,
+    signature_requested: bool,
+) -> usize {
+    requester.request_measurements(request, signature_requested).unwrap();
+    let mut round_trips = 0;
+    loop {
+        let req_data = requester.next_request(&mut data.req_buf).unwrap();
+        let (rsp_data, result) =
+            responder.handle_msg(req_data, &mut data.rsp_buf);
+        // Absent indices are reported with an error when walking all indices
+        if let Err(err) = result {
+            assert_eq!(MeasurementRequest::Each, request);
+            assert_eq!(ResponderError::InvalidMeasurementIndex, err);
+        }
+        assert_eq!("Measurements", responder.state().name());
+        round_trips += 1;
+        if requester.handle_msg(rsp_data).unwrap() {
+            return round_trips;
+        }
+    }
+}
+
+fn get_measurements<'a, S: Signer>(
+    requester: &mut RequesterSession<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+) {
+    // Retrieve a single unsigned measurement
+    let round_trips = retrieve_measurements(
+        requester,
+        responder,
+        data,
+        MeasurementRequest::Index(2),
+        false,
+    );
+    assert_eq!(1, round_trips);
+    let mut blocks = requester.measurements().unwrap();
+    let block = blocks.next().unwrap().unwrap();
+    assert_eq!(2, block.index);
+    assert_eq!(
+        DmtfMeasurementValueType::FirmwareConfig,
+        block.measurement.value_type
+    );
+    assert_eq!(&[0x22; 48][..], block.measurement.value);
+    assert!(blocks.next().is_none());
+
+    // Retrieve all measurements in a single signed response. This also covers
+    // the unsigned exchange above in its signature.
+    let round_trips = retrieve_measurements(
+        requester,
+        responder,
+        data,
+        MeasurementRequest::All,
+        true,
+    );
+    assert_eq!(1, round_trips);
+    assert_eq!(2, requester.measurements().unwrap().count());
+
+    // The signed response ends the measurement transcript
+    assert_eq!(responder.transcript().vca(), responder.transcript().get());
+    assert_eq!(requester.transcript().get(), responder.transcript().get());
+
+    // Retrieve the number of measurements, then each of them, with only the
+    // last response signed.
+    let round_trips = retrieve_measurements(
+        requester,
+        responder,
+        data,
+        MeasurementRequest::Each,
+        true,
+    );
+    assert_eq!(3, round_trips);
+    let indices: Vec<u8> = requester
+        .measurements()
+        .unwrap()
+        .map(|block| block.unwrap().index)
+        .collect();
+    assert_eq!(vec![1, 2], indices);
+
+    // The signature covered all three exchanges
+    assert_eq!(responder.transcript().vca(), responder.transcript().get());
+    assert_eq!(requester.transcript().get(), responder.transcript().get());
+}
+
+// Exchange application data and SPDM requests within the secure session
+fn secured_messages<'a, S: Signer>(
+    requester: &mut RequesterSession<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+) {
+    let session_id = responder.sessions().iter().next().unwrap().id;
+    let app_data = b"application specific request";
+
+    // Application data from the requester to the responder
+    let size = requester.send(app_data, &mut data.req_buf, NOW).unwrap().len();
+    assert_eq!(Ok(session_id), secured_message::session_id(&data.req_buf));
+    assert!(!data.req_buf[..size]
+        .windows(app_data.len())
+        .any(|w| w == app_data));
+    let received = responder.recv(&mut data.req_buf[..size], NOW).unwrap();
+    assert_eq!(&app_data[..], received);
+
+    // Application data from the responder to the requester
+    let size =
+        responder.send(session_id, app_data, &mut data.rsp_buf).unwrap().len();
+    let received = requester.recv(&mut data.rsp_buf[..size], NOW).unwrap();
+    assert_eq!(&app_data[..], received);
+
+    // Data can only be sent in an established session
+    assert_eq!(
+        Err(ResponderError::UnknownSession(session_id + 1)),
+        responder.send(session_id + 1, app_data, &mut data.rsp_buf)
+    );
+
+    // Retrieve signed measurements within the session
+    requester.request_measurements(MeasurementRequest::All, true).unwrap();
+    let size =
+        requester.next_secured_request(&mut data.req_buf, NOW).unwrap().len();
+    let (rsp_data, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    );
+    result.unwrap();
+    assert_eq!("Measurements", responder.state().name());
+    let mut rsp = rsp_data.to_vec();
+    assert!(requester.handle_secured_msg(&mut rsp, NOW).unwrap());
+    assert_eq!(2, requester.measurements().unwrap().count());
+
+    // Every record advanced the sequence numbers of both sides equally
+    let channels = requester.channels().unwrap();
+    assert_eq!(2, channels.request.sequence_number);
+    assert_eq!(2, channels.response.sequence_number);
+    assert_eq!(
+        channels,
+        responder.sessions().iter().next().unwrap().channels.as_ref().unwrap()
+    );
 }
 
 // Verify that there is a proper digest for each cert chain
@@ -356,16 +723,1844 @@ fn successful_e2e() {
     let slots = create_slots(&certs);
     let slots2 = create_slots(&certs);
 
-    let mut responder = Responder::new(slots);
+    let mut responder = Responder::new(slots, TestMeasurements::default());
 
     // TODO: Should the root be the same for all slots?
-    let mut requester = RequesterInit::new(&certs[0].root_der, slots2);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+    requester.set_measurement_hash_type(MeasurementHashType::All);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    get_measurements(&mut requester, &mut responder, &mut data);
+    secured_messages(&mut requester, &mut responder, &mut data);
+
+    // The summary hash from CHALLENGE_AUTH covers all measurement blocks
+    assert_summary_hash_matches(&requester, |_| true);
+}
+
+// TH1 and TH2 are the hashes of the messages laid out in the "Key schedule"
+// section of the SPDM 1.1 spec. Build that transcript here from the messages
+// on the wire, and check that both sides derive their verify data and session
+// keys from its hashes.
+#[test]
+fn handshake_transcript_hashes() {
+    let mut data = Data::new();
+    let certs = create_certs_per_slot();
+    let mut responder =
+        Responder::new(create_slots(&certs), TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, create_slots(&certs));
+    let hash_algo = BaseHashAlgo::SHA_256;
+    let hash_len = hash_algo.get_digest_size() as usize;
+
+    // The VCA messages: GET_VERSION, VERSION, GET_CAPABILITIES,
+    // CAPABILITIES, NEGOTIATE_ALGORITHMS and ALGORITHMS
+    let mut expected = Vec::new();
+    for _ in 0..3 {
+        let req_data = requester.next_request(&mut data.req_buf).unwrap();
+        expected.extend_from_slice(req_data);
+        let (rsp_data, result) =
+            responder.handle_msg(req_data, &mut data.rsp_buf);
+        result.unwrap();
+        expected.extend_from_slice(rsp_data);
+        requester.handle_msg(rsp_data).unwrap();
+    }
+    assert_eq!(&expected[..], requester.transcript().vca());
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+
+    // Hash(Ct), the hash of the responder's certificate chain
+    let mut cert_chain = [0u8; MAX_CERT_CHAIN_SIZE];
+    let mut w = Writer::new("CERTIFICATE_CHAIN", &mut cert_chain);
+    let size = responder.slots()[0]
+        .as_ref()
+        .unwrap()
+        .cert_chain
+        .write(&mut w)
+        .unwrap();
+    expected.extend_from_slice(
+        DigestImpl::hash(hash_algo, &cert_chain[..size]).as_ref(),
+    );
+
+    // KEY_EXCHANGE, and KEY_EXCHANGE_RSP through the signature
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    expected.extend_from_slice(req_data);
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+    let verify_start = rsp_data.len() - hash_len;
+    expected.extend_from_slice(&rsp_data[..verify_start]);
+    let th1 = DigestImpl::hash(hash_algo, &expected);
+    requester.handle_msg(rsp_data).unwrap();
+    let session_id = match requester.state() {
+        requester::AllStates::Finish(state) => state.session_id,
+        _ => panic!("the requester is not in the Finish state"),
+    };
+    let secrets = &responder.session(session_id).unwrap().handshake_secrets;
+    let response_finished_key =
+        key_schedule::finished_key(hash_algo, &secrets.response);
+    assert_eq!(
+        key_schedule::hmac(
+            hash_algo,
+            response_finished_key.as_ref(),
+            th1.as_ref()
+        )
+        .as_ref(),
+        &rsp_data[verify_start..]
+    );
+
+    // TH2 adds the ResponderVerifyData, FINISH and FINISH_RSP. Without mutual
+    // authentication, FINISH has no signature, and FINISH_RSP has no verify
+    // data since the handshake is not in the clear.
+    expected.extend_from_slice(&rsp_data[verify_start..]);
+    assert_eq!(&expected[..], requester.transcript().get());
+    expected.extend_from_slice(&[0x11, 0xE5, 0x00, 0x00]);
+    let request_finished_key =
+        key_schedule::finished_key(hash_algo, &secrets.request);
+    let requester_verify_data = key_schedule::hmac(
+        hash_algo,
+        request_finished_key.as_ref(),
+        DigestImpl::hash(hash_algo, &expected).as_ref(),
+    );
+    expected.extend_from_slice(requester_verify_data.as_ref());
+    expected.extend_from_slice(&[0x11, 0x65, 0x00, 0x00]);
+    let th2 = DigestImpl::hash(hash_algo, &expected);
+    let session_keys = SessionKeys::new(
+        hash_algo,
+        AeadFixedAlgorithms::AES_256_GCM,
+        &secrets.handshake_secret,
+        th2.as_ref(),
+    );
+
+    let (rsp_data, result) = deliver(&mut requester, &mut responder, &mut data);
+    result.unwrap();
+    requester.handle_msg(rsp_data).unwrap();
+    let session = responder.session(session_id).unwrap();
+    assert_eq!(Some(&session_keys), session.session_keys.as_ref());
+    if let requester::AllStates::NewSession(ref req_state) = requester.state() {
+        assert_eq!(Some(&session_keys), req_state.session_keys.as_ref());
+    } else {
+        panic!("the requester is not in the NewSession state");
+    }
+}
+
+// A session cannot begin before initialization is complete
+#[test]
+fn begin_session_before_initialization() {
+    let certs = create_certs_per_slot();
+    let root_certs = [&certs[0].root_der[..]];
+    let requester: RequesterInit<_> =
+        RequesterInit::new(&root_certs, create_slots(&certs));
+    assert!(matches!(
+        requester.begin_session(),
+        Err(RequesterError::InitializationIncomplete)
+    ));
+}
+
+// Measurements retrieved within a session are covered by the transcript of
+// the session (L1/L2), and a failed request within the session leaves the
+// responder outside of the session untouched.
+#[test]
+fn measurements_within_session() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    let transcript = responder.transcript().get().to_vec();
+    let session_id = responder.sessions().iter().next().unwrap().id;
+
+    // Unsigned measurements extend the transcript of the session only
+    requester.request_measurements(MeasurementRequest::All, false).unwrap();
+    let size =
+        requester.next_secured_request(&mut data.req_buf, NOW).unwrap().len();
+    let (rsp_data, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    );
+    result.unwrap();
+    let mut rsp = rsp_data.to_vec();
+    assert!(requester.handle_secured_msg(&mut rsp, NOW).unwrap());
+    assert_eq!(transcript, responder.transcript().get());
+    let session = responder.session(session_id).unwrap();
+    assert!(session.transcript.len() > session.transcript.vca().len());
+    assert_eq!(responder.transcript().vca(), session.transcript.vca());
+    assert_eq!(requester.session_transcript().get(), session.transcript.get());
+
+    // The signature covers the unsigned measurements sent before it within
+    // the session, after which the transcript of the session is reset
+    requester.request_measurements(MeasurementRequest::All, true).unwrap();
+    let size =
+        requester.next_secured_request(&mut data.req_buf, NOW).unwrap().len();
+    let (rsp_data, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    );
+    result.unwrap();
+    let mut rsp = rsp_data.to_vec();
+    assert!(requester.handle_secured_msg(&mut rsp, NOW).unwrap());
+    assert_eq!(transcript, responder.transcript().get());
+    let session = responder.session(session_id).unwrap();
+    assert_eq!(session.transcript.vca(), session.transcript.get());
+    assert_eq!(requester.session_transcript().get(), session.transcript.get());
+
+    // An invalid request within the session neither moves the responder to
+    // the Error state, nor terminates the session
+    requester
+        .request_measurements(MeasurementRequest::Index(42), false)
+        .unwrap();
+    let size =
+        requester.next_secured_request(&mut data.req_buf, NOW).unwrap().len();
+    let (rsp_data, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    );
+    assert_eq!(Err(ResponderError::InvalidMeasurementIndex), result);
+    let mut rsp = rsp_data.to_vec();
+    assert!(requester.handle_secured_msg(&mut rsp, NOW).is_err());
+    assert_eq!("Measurements", responder.state().name());
+    assert_eq!(transcript, responder.transcript().get());
+    assert!(responder.session(session_id).is_some());
+
+    // Measurements can still be retrieved outside of the session
+    get_measurements(&mut requester, &mut responder, &mut data);
+}
+
+// Create a responder that also authenticates with the leaf key of `certs`,
+// provisioned on requesters as a raw public key
+fn provisioned_key_responder<'a>(
+    certs: &'a [Certs],
+    provisioned: &'a Certs,
+) -> Responder<'a, RingSigner, TestMeasurements> {
+    let mut responder =
+        Responder::new(create_slots(certs), TestMeasurements::default());
+    responder.set_provisioned_key(ProvisionedKey {
+        signing_algorithm: BaseAsymAlgo::ECDSA_ECC_NIST_P256,
+        public_key: &provisioned.leaf_public_key,
+        signer: new_signer(
+            BaseAsymAlgo::ECDSA_ECC_NIST_P256,
+            &provisioned.leaf_private_der,
+        )
+        .unwrap(),
+    });
+    responder
+}
+
+// Create a requester that authenticates the responder with `public_key`
+//
+// No root certs are needed, as no certificate chain is verified.
+fn provisioned_key_requester<'a>(
+    certs: &'a [Certs],
+    public_key: &'a [u8],
+) -> RequesterInit<'a, RingSigner> {
+    let mut requester = RequesterInit::new(&[], create_slots(certs));
+    requester.set_public_key(public_key).unwrap();
+    requester
+}
+
+// A requester with a provisioned public key challenges it as slot 0xF,
+// without retrieving certificates
+#[test]
+fn provisioned_public_key() {
+    let mut data = Data::new();
+    let certs = create_certs_per_slot();
+    let provisioned = Certs::new();
+    let mut responder = provisioned_key_responder(&certs, &provisioned);
+    let mut requester =
+        provisioned_key_requester(&certs, &provisioned.leaf_public_key);
+    requester.set_measurement_hash_type(MeasurementHashType::All);
+
+    // GET_VERSION, GET_CAPABILITIES and NEGOTIATE_ALGORITHMS are followed by
+    // CHALLENGE
+    let requests =
+        run_until(&mut requester, &mut responder, &mut data, "Challenge");
+    assert_eq!(3, requests);
+    assert_eq!("IdAuth", responder.state().name());
+    assert!(requester.cert_chain(0).is_none());
+
+    run_until(&mut requester, &mut responder, &mut data, "KeyExchange");
+    assert_eq!("Measurements", responder.state().name());
+    run_until(&mut requester, &mut responder, &mut data, "NewSession");
+
+    // Measurements are signed with the provisioned key
+    let mut requester = requester.begin_session().unwrap();
+    assert_eq!(None, requester.session().trust_anchor);
+    get_measurements(&mut requester, &mut responder, &mut data);
+    assert_summary_hash_matches(&requester, |_| true);
+}
+
+// A requester rejects signatures that don't verify with its provisioned key,
+// and a responder without a provisioned key rejects slot 0xF
+#[test]
+fn wrong_provisioned_public_key() {
+    let mut data = Data::new();
+    let certs = create_certs_per_slot();
+    let provisioned = Certs::new();
+    let mut responder = provisioned_key_responder(&certs, &provisioned);
+    let mut requester =
+        provisioned_key_requester(&certs, &certs[0].leaf_public_key);
+    run_until(&mut requester, &mut responder, &mut data, "Challenge");
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+    assert_eq!(
+        Err(RequesterError::BadChallengeAuth),
+        requester.handle_msg(rsp_data)
+    );
+
+    let mut responder =
+        Responder::new(create_slots(&certs), TestMeasurements::default());
+    let mut requester =
+        provisioned_key_requester(&certs, &provisioned.leaf_public_key);
+    run_until(&mut requester, &mut responder, &mut data, "Challenge");
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (_, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    assert_eq!(Err(ResponderError::InvalidSlot), result);
+
+    // A public key must fit in the certificate chain buffer
+    let public_key = [0u8; MAX_CERT_CHAIN_SIZE + 1];
+    assert_eq!(
+        Err(RequesterError::InvalidPublicKey),
+        requester.set_public_key(&public_key)
+    );
+}
+
+// The TCB measurement summary hash only covers TCB components
+#[test]
+fn tcb_measurement_summary_hash() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+    requester.set_measurement_hash_type(MeasurementHashType::Tcb);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    retrieve_measurements(
+        &mut requester,
+        &mut responder,
+        &mut data,
+        MeasurementRequest::All,
+        true,
+    );
+    assert_summary_hash_matches(&requester, |index| index == 1);
+}
+
+// A responder caps a CERTIFICATE portion at the size of its response buffer,
+// and rejects offsets beyond the end of the certificate chain
+#[test]
+fn certificate_portions() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let mut responder =
+        Responder::new(create_slots(&certs), TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, create_slots(&certs));
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+
+    let req = GetCertificate { slot: 0, offset: 0, length: 0xFFFF };
+    let size = req.write(&mut data.req_buf).unwrap();
+    let (rsp_data, result) =
+        responder.handle_msg(&data.req_buf[..size], &mut data.rsp_buf[..100]);
+    result.unwrap();
+    let cert = Certificate::<MAX_CERT_CHAIN_SIZE>::parse_body(
+        &rsp_data[HEADER_SIZE..],
+    )
+    .unwrap();
+    assert_eq!(92, cert.portion_length);
+    assert!(cert.remainder_length > 0);
+    assert_eq!("IdAuth", responder.state().name());
+
+    let total = cert.portion_length + cert.remainder_length;
+    let req = GetCertificate { slot: 0, offset: total + 1, length: 0xFFFF };
+    let size = req.write(&mut data.req_buf).unwrap();
+    let (rsp_data, result) =
+        responder.handle_msg(&data.req_buf[..size], &mut data.rsp_buf);
+    assert_eq!(Err(ResponderError::InvalidCertificateOffset), result);
+    assert_eq!(Ok(true), msgs::Error::parse_header(rsp_data));
+}
+
+// The requester retrieves the certificate chains of all slots, and challenges
+// the one rooted at its root cert
+#[test]
+fn multiple_cert_slots() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let mut responder = Responder::new(
+        create_slots_where(&certs, |_| true),
+        TestMeasurements::default(),
+    );
+    let last = NUM_SLOTS - 1;
+    let root_certs = [&certs[last].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, create_slots(&certs));
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    while requester.state().name() != "Challenge" {
+        let req_data = requester.next_request(&mut data.req_buf).unwrap();
+        let (rsp_data, result) =
+            responder.handle_msg(req_data, &mut data.rsp_buf);
+        result.unwrap();
+        requester.handle_msg(rsp_data).unwrap();
+    }
+    assert_eq!(requester.transcript().get(), responder.transcript().get());
+
+    for (i, slot) in responder.slots().iter().enumerate() {
+        let mut buf = [0u8; MAX_CERT_CHAIN_SIZE];
+        let mut w = Writer::new("CERTIFICATE_CHAIN", &mut buf);
+        let size = slot.as_ref().unwrap().cert_chain.write(&mut w).unwrap();
+        assert_eq!(Some(&buf[..size]), requester.cert_chain(i as u8));
+    }
+    if let requester::AllStates::Challenge(ref req_state) = requester.state() {
+        assert_eq!(last as u8, req_state.cert_slot);
+    } else {
+        panic!("the requester is not in the Challenge state");
+    }
+
+    // Only slots with a retrieved chain can be challenged
+    assert_eq!(
+        Err(RequesterError::InvalidSlot),
+        requester.select_cert_slot(NUM_SLOTS as u8)
+    );
+    requester.select_cert_slot(last as u8).unwrap();
+
+    challenge_auth(&mut requester, &mut responder, &mut data);
+}
+
+// Certificate chains cached by a previous requester, keyed by digest
+#[derive(Default)]
+pub struct TestCertCache {
+    chains: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl CertCache for TestCertCache {
+    fn get(&self, digest: &[u8]) -> Option<&[u8]> {
+        self.chains
+            .iter()
+            .find(|(d, _)| d == digest)
+            .map(|(_, chain)| &chain[..])
+    }
+
+    fn insert(&mut self, digest: &[u8], cert_chain: &[u8]) {
+        self.chains.retain(|(d, _)| d != digest);
+        self.chains.push((digest.to_vec(), cert_chain.to_vec()));
+    }
+}
+
+// Exchange messages until the requester reaches `state`, and return the
+// number of requests sent
+fn run_until<'a, S: Signer, C: CertCache, T: TimeSource>(
+    requester: &mut RequesterInit<'a, S, C, T>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+    state: &str,
+) -> usize {
+    let mut requests = 0;
+    while requester.state().name() != state {
+        let (rsp_data, result) = deliver(requester, responder, data);
+        result.unwrap();
+        requester.handle_msg(rsp_data).unwrap();
+        requests += 1;
+    }
+    requests
+}
+
+// A requester skips GET_CERTIFICATE for the certificate chains it cached
+#[test]
+fn cached_cert_chains() {
+    let mut data = Data::new();
+    let certs = create_certs_per_slot();
+    let mut cache = TestCertCache::default();
+
+    // The first requester retrieves and caches all chains
+    {
+        let mut responder = Responder::new(
+            create_slots_where(&certs, |_| true),
+            TestMeasurements::default(),
+        );
+        let root_certs = [&certs[0].root_der[..]];
+        let mut requester: RequesterInit<_, _> =
+            RequesterInit::new(&root_certs, create_slots(&certs));
+        requester.set_cert_cache(&mut cache);
+        run_until(&mut requester, &mut responder, &mut data, "IdAuth");
+        let requests =
+            run_until(&mut requester, &mut responder, &mut data, "Challenge");
+        assert!(requests > NUM_SLOTS);
+        assert_eq!(NUM_SLOTS, requester.cert_cache().unwrap().chains.len());
+    }
+
+    // The next one only sends GET_DIGESTS, and then CHALLENGE
+    let mut responder = Responder::new(
+        create_slots_where(&certs, |_| true),
+        TestMeasurements::default(),
+    );
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester: RequesterInit<_, _> =
+        RequesterInit::new(&root_certs, create_slots(&certs));
+    requester.set_cert_cache(&mut cache);
+    run_until(&mut requester, &mut responder, &mut data, "IdAuth");
+    let requests =
+        run_until(&mut requester, &mut responder, &mut data, "Challenge");
+    assert_eq!(1, requests);
+    assert_eq!("IdAuth", responder.state().name());
+    assert_eq!(requester.transcript().get(), responder.transcript().get());
+    for slot in 0..NUM_SLOTS {
+        assert!(requester.cert_chain(slot as u8).is_some());
+    }
+
+    // The responder accepts CHALLENGE right after DIGESTS
+    run_until(&mut requester, &mut responder, &mut data, "KeyExchange");
+    assert_eq!("Measurements", responder.state().name());
+
+    // Cached chains that don't match their digest are retrieved, and replaced
+    for (_, chain) in cache.chains.iter_mut() {
+        *chain.last_mut().unwrap() ^= 1;
+    }
+    let mut responder = Responder::new(
+        create_slots_where(&certs, |_| true),
+        TestMeasurements::default(),
+    );
+    let mut requester: RequesterInit<_, _> =
+        RequesterInit::new(&root_certs, create_slots(&certs));
+    requester.set_cert_cache(&mut cache);
+    run_until(&mut requester, &mut responder, &mut data, "IdAuth");
+    let requests =
+        run_until(&mut requester, &mut responder, &mut data, "Challenge");
+    assert!(requests > NUM_SLOTS);
+    for (digest, chain) in &requester.cert_cache().unwrap().chains {
+        assert_eq!(
+            digest[..],
+            *DigestImpl::hash(BaseHashAlgo::SHA_256, chain).as_ref()
+        );
+    }
+}
+
+// A requester only retrieves the certificate chains of the chosen slots
+#[test]
+fn chosen_cert_slots() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let mut responder = Responder::new(
+        create_slots_where(&certs, |_| true),
+        TestMeasurements::default(),
+    );
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, create_slots(&certs));
+    requester.set_cert_slots(0b1);
 
     negotiate_versions(&mut data, &mut requester, &mut responder);
     negotiate_capabilities(&mut requester, &mut responder, &mut data);
     negotiate_algorithms(&mut requester, &mut responder, &mut data);
     identify_responder(&mut requester, &mut responder, &mut data);
+    for slot in 1..NUM_SLOTS {
+        assert_eq!(None, requester.cert_chain(slot as u8));
+        assert_eq!(
+            Err(RequesterError::InvalidSlot),
+            requester.select_cert_slot(slot as u8)
+        );
+    }
     challenge_auth(&mut requester, &mut responder, &mut data);
+
+    // The responder has no chain in the chosen slots
+    let mut responder =
+        Responder::new(create_slots(&certs), TestMeasurements::default());
+    let mut requester = RequesterInit::new(&root_certs, create_slots(&certs));
+    requester.set_cert_slots(0b10);
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+    assert_eq!(
+        Err(RequesterError::NoCertChain),
+        requester.handle_msg(rsp_data)
+    );
+    assert_eq!("Error", requester.state().name());
+}
+
+// A requester trusts chains leading to any of its root certs, and reports
+// which one a chain leads to
+#[test]
+fn multiple_trust_anchors() {
+    let mut data = Data::new();
+    let certs = create_certs_per_slot();
+    let other = Certs::new();
+    let root_certs = [&other.root_der[..], &certs[0].root_der[..]];
+    let mut responder =
+        Responder::new(create_slots(&certs), TestMeasurements::default());
+    let mut requester: RequesterInit<_> =
+        RequesterInit::new(&root_certs, create_slots(&certs));
+    run_until(&mut requester, &mut responder, &mut data, "NewSession");
+    let requester = requester.begin_session().unwrap();
+    assert_eq!(Some(1), requester.session().trust_anchor);
+
+    // None of the root certs has the root hash of the responder's chain
+    let root_certs = [&other.root_der[..]];
+    let mut responder =
+        Responder::new(create_slots(&certs), TestMeasurements::default());
+    let mut requester: RequesterInit<_> =
+        RequesterInit::new(&root_certs, create_slots(&certs));
+    run_until(&mut requester, &mut responder, &mut data, "Challenge");
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+    assert_eq!(
+        Err(RequesterError::UntrustedRootHash),
+        requester.handle_msg(rsp_data)
+    );
+}
+
+// A chain whose root hash is that of a trusted root cert still has to lead to
+// it
+#[test]
+fn mis_rooted_cert_chain() {
+    let mut data = Data::new();
+    let certs = create_certs_per_slot();
+    let other = Certs::new();
+
+    // The responder's chain claims the root of `other`
+    let mut cert_chain = certs[0].cert_chain();
+    cert_chain.root_hash = other.root_hash.as_ref();
+    let mut slots = create_slots(&certs);
+    slots[0].as_mut().unwrap().cert_chain = cert_chain;
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+
+    let root_certs = [&other.root_der[..]];
+    let mut requester: RequesterInit<_> =
+        RequesterInit::new(&root_certs, create_slots(&certs));
+    run_until(&mut requester, &mut responder, &mut data, "Challenge");
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+    assert_eq!(
+        Err(RequesterError::InvalidCert),
+        requester.handle_msg(rsp_data)
+    );
+}
+
+// A time source returning a fixed time, or that the time is unknown
+struct TestTime(Option<u64>);
+
+impl TimeSource for TestTime {
+    fn now(&self) -> Option<u64> {
+        self.0
+    }
+}
+
+// Run a requester with the given time and unknown time policy until it
+// handles CHALLENGE_AUTH, and return the result of handling it
+fn challenge_at(
+    time: Option<u64>,
+    policy: UnknownTimePolicy,
+) -> Result<bool, RequesterError> {
+    let mut data = Data::new();
+    let certs = create_certs_per_slot();
+    let mut responder =
+        Responder::new(create_slots(&certs), TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester: RequesterInit<_, NoCertCache, TestTime> =
+        RequesterInit::new(&root_certs, create_slots(&certs));
+    requester.set_time_source(TestTime(time));
+    requester.set_unknown_time_policy(policy);
+    run_until(&mut requester, &mut responder, &mut data, "Challenge");
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+    requester.handle_msg(rsp_data)
+}
+
+// Certificate validity periods are checked at the time of the time source,
+// or according to the policy when it is unknown
+#[test]
+fn time_source() {
+    // The test certificates are valid from 2021-10-27
+    const BEFORE_VALIDITY: u64 = 1577836800; // 2020-01-01
+    const DURING_VALIDITY: u64 = 1893456000; // 2030-01-01
+
+    let policy = UnknownTimePolicy::default();
+    assert!(challenge_at(Some(DURING_VALIDITY), policy).is_ok());
+    assert_eq!(
+        Err(RequesterError::InvalidCert),
+        challenge_at(Some(BEFORE_VALIDITY), policy)
+    );
+
+    // The default policy checks validity at the build time floor
+    assert!(challenge_at(None, policy).is_ok());
+    assert_eq!(
+        Err(RequesterError::InvalidCert),
+        challenge_at(None, UnknownTimePolicy::Floor(BEFORE_VALIDITY))
+    );
+    assert!(challenge_at(None, UnknownTimePolicy::IssuanceTime).is_ok());
+}
+
+// Compare the verified summary hash to the hash of the retrieved measurement
+// blocks selected by `filter`.
+fn assert_summary_hash_matches<'a, S: Signer>(
+    requester: &RequesterSession<'a, S>,
+    filter: impl Fn(u8) -> bool,
+) {
+    let mut record = Vec::new();
+    for block in requester.measurements().unwrap() {
+        let block = block.unwrap();
+        if filter(block.index) {
+            let mut buf = vec![0u8; block.size()];
+            let mut w = Writer::new("MEASUREMENT_BLOCK", &mut buf);
+            block.write(&mut w).unwrap();
+            record.extend_from_slice(&buf);
+        }
+    }
+    let hash_algo = requester.session().algorithms.base_hash_algo_selected;
+    let expected = DigestImpl::hash(hash_algo, &record);
+    assert_eq!(Some(expected.as_ref()), requester.measurement_summary_hash());
+}
+
+// Measurements whose indices have gaps are all retrieved
+#[test]
+fn measurement_index_gaps() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let measurements =
+        TestMeasurements { config_index: 5, ..TestMeasurements::default() };
+    let mut responder = Responder::new(slots, measurements);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    let round_trips = retrieve_measurements(
+        &mut requester,
+        &mut responder,
+        &mut data,
+        MeasurementRequest::Each,
+        true,
+    );
+    // The count, then indices 1 through 5, where 2 to 4 are absent
+    assert_eq!(6, round_trips);
+    let indices: Vec<u8> = requester
+        .measurements()
+        .unwrap()
+        .map(|block| block.unwrap().index)
+        .collect();
+    assert_eq!(vec![1, 5], indices);
+
+    // The absent indices are not part of L1/L2, which the last response signed
+    assert_eq!(responder.transcript().vca(), responder.transcript().get());
+    assert_eq!(requester.transcript().get(), responder.transcript().get());
+}
+
+// A requester rejects a signed MEASUREMENTS response with a bad signature
+#[test]
+fn bad_measurements_signature() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    requester.request_measurements(MeasurementRequest::All, true).unwrap();
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+
+    // Corrupt the last byte of the signature
+    let mut rsp = rsp_data.to_vec();
+    *rsp.last_mut().unwrap() ^= 0xFF;
+    assert_eq!(
+        Err(RequesterError::BadMeasurements),
+        requester.handle_msg(&rsp)
+    );
+    assert!(requester.measurements().is_none());
+}
+
+// A requester rejects a KEY_EXCHANGE_RSP with bad ResponderVerifyData
+#[test]
+fn bad_key_exchange_verify_data() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+
+    // Corrupt the last byte of the verify data
+    let mut rsp = rsp_data.to_vec();
+    *rsp.last_mut().unwrap() ^= 0xFF;
+    assert_eq!(Err(RequesterError::BadKeyExchange), requester.handle_msg(&rsp));
+    assert_eq!("Error", requester.state().name());
+}
+
+// A responder rejects a FINISH with bad RequesterVerifyData and terminates
+// the session
+#[test]
+fn bad_finish_verify_data() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+
+    // Corrupt the last byte of the verify data
+    let (rsp_data, result) = deliver_tampered_finish(
+        &mut requester,
+        &mut responder,
+        &mut data,
+        |req| {
+            *req.last_mut().unwrap() ^= 0xFF;
+        },
+    );
+    assert_eq!(Err(ResponderError::InvalidVerifyData), result);
+    assert_eq!(Ok(true), msgs::Error::parse_header(rsp_data));
+    assert!(responder.sessions().is_empty());
+
+    // Only the session is terminated
+    assert_eq!("Measurements", responder.state().name());
+}
+
+// Deliver the FINISH request of the requester to the responder within the
+// session, after modifying it with `tamper`. The request is protected again
+// with the handshake keys of the responder, so that only its content is
+// rejected.
+fn deliver_tampered_finish<'a, 'b, S: Signer, P: PskProvider>(
+    requester: &mut RequesterInit<'a, S>,
+    responder: &mut Responder<
+        'a,
+        S,
+        TestMeasurements,
+        RingEphemeralKey,
+        RingAead,
+        P,
+    >,
+    data: &'b mut Data,
+    tamper: impl FnOnce(&mut [u8]),
+) -> (&'b [u8], Result<(), ResponderError>) {
+    let size = requester.next_request(&mut data.req_buf).unwrap().len();
+    let session_id = secured_message::session_id(&data.req_buf).unwrap();
+    let session = responder.session(session_id).unwrap();
+    let mut channels = session.channels.clone().unwrap();
+    let mut request = channels.request.clone();
+    let mut req = channels
+        .record_layer
+        .decode::<RingAead>(&mut channels.request, &mut data.req_buf[..size])
+        .unwrap()
+        .to_vec();
+    tamper(&mut req);
+    let size = channels
+        .record_layer
+        .encode::<RingAead>(&mut request, &req, &mut data.req_buf)
+        .unwrap();
+    responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    )
+}
+
+// A responder rejects a FINISH request sent in the clear, as the handshake
+// must be completed within the session, and terminates the session
+#[test]
+fn finish_outside_session() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+
+    // Unwrap the FINISH request from its record, and send it in the clear
+    assert!(requester.is_secured());
+    let size = requester.next_request(&mut data.req_buf).unwrap().len();
+    let session_id = secured_message::session_id(&data.req_buf).unwrap();
+    let session = responder.session(session_id).unwrap();
+    let mut channels = session.channels.clone().unwrap();
+    let req = channels
+        .record_layer
+        .decode::<RingAead>(&mut channels.request, &mut data.req_buf[..size])
+        .unwrap();
+    let (rsp_data, result) = responder.handle_msg(req, &mut data.rsp_buf);
+    assert_eq!(Err(ResponderError::HandshakeNotInTheClear), result);
+    assert_eq!(Ok(true), msgs::Error::parse_header(rsp_data));
+    assert!(responder.sessions().is_empty());
+}
+
+// A responder terminates the session when a secured message fails to decrypt
+#[test]
+fn bad_secured_message() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    requester.request_measurements(MeasurementRequest::All, true).unwrap();
+    let size =
+        requester.next_secured_request(&mut data.req_buf, NOW).unwrap().len();
+
+    // Corrupt the last byte of the MAC
+    data.req_buf[size - 1] ^= 0xFF;
+    let (rsp_data, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    );
+    assert_eq!(
+        Err(ResponderError::SecuredMessage(
+            secured_message::Error::DecryptFailed
+        )),
+        result
+    );
+
+    // The error is sent in the clear, as the session is gone
+    assert_eq!(Ok(true), msgs::Error::parse_header(rsp_data));
+    assert!(responder.sessions().is_empty());
+    assert_eq!("Measurements", responder.state().name());
+}
+
+// Create a responder that requires requesters to authenticate with the
+// certificate chain of `req_certs`, rooted at `root_cert`.
+fn mutual_auth_responder<'a>(
+    certs: &'a [Certs],
+    req_certs: &'a [Certs],
+    root_cert: &'a [u8],
+) -> Responder<'a, RingSigner, TestMeasurements> {
+    let mut responder =
+        Responder::new(create_slots(certs), TestMeasurements::default());
+    responder.set_mutual_auth(MutualAuth {
+        root_cert,
+        req_slot_id: 0,
+        cert_chain: Some(req_certs[0].cert_chain()),
+    });
+    responder
+}
+
+// A requester signs FINISH with its own key when the responder requests
+// mutual authentication
+#[test]
+fn mutual_auth() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let req_certs = create_certs_per_slot();
+    let mut responder =
+        mutual_auth_responder(&certs, &req_certs, &req_certs[0].root_der);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester =
+        RequesterInit::new(&root_certs, create_slots(&req_certs));
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+
+    if let requester::AllStates::Finish(ref req_state) = requester.state() {
+        assert_eq!(Some(0), req_state.req_slot_id);
+        let session = responder.session(req_state.session_id).unwrap();
+        assert_eq!(Some(0), session.req_slot_id);
+    } else {
+        panic!("the requester is not in the Finish state");
+    }
+
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    get_measurements(&mut requester, &mut responder, &mut data);
+    secured_messages(&mut requester, &mut responder, &mut data);
+}
+
+// A responder rejects a requester whose certificate chain is not rooted at
+// the trusted root, and terminates the session
+#[test]
+fn mutual_auth_untrusted_requester() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let req_certs = create_certs_per_slot();
+    let mut responder =
+        mutual_auth_responder(&certs, &req_certs, &certs[0].root_der);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester =
+        RequesterInit::new(&root_certs, create_slots(&req_certs));
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+
+    let (rsp_data, result) = deliver(&mut requester, &mut responder, &mut data);
+    assert_eq!(Err(ResponderError::RequesterAuthFailed), result);
+    assert_eq!(Ok(true), msgs::Error::parse_header(rsp_data));
+    assert!(responder.sessions().is_empty());
+}
+
+// A responder rejects a FINISH whose signature does not verify, and
+// terminates the session
+#[test]
+fn mutual_auth_bad_signature() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let req_certs = create_certs_per_slot();
+    let mut responder =
+        mutual_auth_responder(&certs, &req_certs, &req_certs[0].root_der);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester =
+        RequesterInit::new(&root_certs, create_slots(&req_certs));
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+
+    // Corrupt the first byte of the signature, which follows the header
+    let (rsp_data, result) = deliver_tampered_finish(
+        &mut requester,
+        &mut responder,
+        &mut data,
+        |req| {
+            req[4] ^= 0xFF;
+        },
+    );
+    assert_eq!(Err(ResponderError::RequesterAuthFailed), result);
+    assert_eq!(Ok(true), msgs::Error::parse_header(rsp_data));
+    assert!(responder.sessions().is_empty());
+}
+
+// A requester cannot sign FINISH for a slot it does not have
+#[test]
+fn mutual_auth_empty_requester_slot() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let req_certs = create_certs_per_slot();
+    let mut responder =
+        mutual_auth_responder(&certs, &req_certs, &req_certs[0].root_der);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester =
+        RequesterInit::new(&root_certs, core::array::from_fn(|_| None));
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+
+    assert_eq!(
+        Err(RequesterError::InvalidSlot),
+        requester.next_request(&mut data.req_buf).map(|_| ())
+    );
+}
+
+// Create a responder that retrieves the requester's certificate chain with
+// encapsulated requests after CHALLENGE, and requires it to be rooted at
+// `root_cert`
+fn basic_mutual_auth_responder<'a>(
+    certs: &'a [Certs],
+    root_cert: &'a [u8],
+) -> Responder<'a, RingSigner, TestMeasurements> {
+    let mut responder =
+        Responder::new(create_slots(certs), TestMeasurements::default());
+    responder.set_mutual_auth(MutualAuth {
+        root_cert,
+        req_slot_id: 0,
+        cert_chain: None,
+    });
+    responder
+}
+
+// Send CHALLENGE, after which the responder requests basic mutual
+// authentication
+fn challenge_mutual_auth<'a, S: Signer>(
+    requester: &mut RequesterInit<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+) {
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result.unwrap();
+    assert_eq!("MutAuth", responder.state().name());
+
+    let initialization_complete = requester.handle_msg(rsp_data).unwrap();
+    assert!(!initialization_complete);
+    assert_eq!("MutAuth", requester.state().name());
+}
+
+// Exchange GET_ENCAPSULATED_REQUEST and DELIVER_ENCAPSULATED_RESPONSE msgs
+// for the encapsulated GET_DIGESTS, GET_CERTIFICATE and CHALLENGE requests,
+// and return the result of the last exchange at the responder.
+fn encapsulated_requests<'a, S: Signer>(
+    requester: &mut RequesterInit<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+) -> Result<(), ResponderError> {
+    for _ in 0..3 {
+        let req_data = requester.next_request(&mut data.req_buf).unwrap();
+        let (rsp_data, result) =
+            responder.handle_msg(req_data, &mut data.rsp_buf);
+        result.unwrap();
+        assert_eq!("MutAuth", responder.state().name());
+        assert!(!requester.handle_msg(rsp_data).unwrap());
+        assert_eq!("MutAuth", requester.state().name());
+    }
+
+    // Deliver CHALLENGE_AUTH
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    result?;
+    assert!(!requester.handle_msg(rsp_data).unwrap());
+    Ok(())
+}
+
+// A responder authenticates the requester with encapsulated requests, and
+// then requires the retrieved certificate chain in the session handshake
+#[test]
+fn basic_mutual_auth() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let req_certs = create_certs_per_slot();
+    let mut responder =
+        basic_mutual_auth_responder(&certs, &req_certs[0].root_der);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester =
+        RequesterInit::new(&root_certs, create_slots(&req_certs));
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_mutual_auth(&mut requester, &mut responder, &mut data);
+    encapsulated_requests(&mut requester, &mut responder, &mut data).unwrap();
+
+    assert_eq!("Measurements", responder.state().name());
+    assert_eq!("KeyExchange", requester.state().name());
+
+    let mut buf = [0u8; MAX_CERT_CHAIN_SIZE];
+    let mut w = Writer::new("CERTIFICATE_CHAIN", &mut buf);
+    let size = req_certs[0].cert_chain().write(&mut w).unwrap();
+    assert_eq!(Some(&buf[..size]), responder.requester_cert_chain());
+
+    // Both sides start any subsequent transcript from the VCA messages
+    assert_eq!(requester.transcript().get(), responder.transcript().vca());
+
+    key_exchange(&mut requester, &mut responder, &mut data);
+    if let requester::AllStates::Finish(ref req_state) = requester.state() {
+        assert_eq!(Some(0), req_state.req_slot_id);
+    } else {
+        panic!("the requester is not in the Finish state");
+    }
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    get_measurements(&mut requester, &mut responder, &mut data);
+}
+
+// A responder rejects a requester whose certificate chain is not rooted at
+// the trusted root during basic mutual authentication
+#[test]
+fn basic_mutual_auth_untrusted_requester() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let req_certs = create_certs_per_slot();
+    let mut responder = basic_mutual_auth_responder(&certs, &certs[0].root_der);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester =
+        RequesterInit::new(&root_certs, create_slots(&req_certs));
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_mutual_auth(&mut requester, &mut responder, &mut data);
+
+    assert_eq!(
+        Err(ResponderError::RequesterAuthFailed),
+        encapsulated_requests(&mut requester, &mut responder, &mut data)
+    );
+    assert_eq!("Error", responder.state().name());
+    assert_eq!(None, responder.requester_cert_chain());
+}
+
+// Prepare another requester to establish a session with `responder`. The
+// VCA messages are negotiated with an identically configured responder, as
+// GET_VERSION would terminate all sessions of `responder`.
+fn another_requester<'a>(
+    certs: &'a [Certs],
+    root_certs: &'a [&'a [u8]],
+    data: &mut Data,
+) -> RequesterInit<'a, RingSigner> {
+    let mut other =
+        Responder::new(create_slots(certs), TestMeasurements::default());
+    let mut requester = RequesterInit::new(root_certs, create_slots(certs));
+    negotiate_versions(data, &mut requester, &mut other);
+    negotiate_capabilities(&mut requester, &mut other, data);
+    negotiate_algorithms(&mut requester, &mut other, data);
+    identify_responder(&mut requester, &mut other, data);
+    challenge_auth(&mut requester, &mut other, data);
+    requester
+}
+
+// Drive END_SESSION to completion within the secure session
+fn end_session<'a, S: Signer>(
+    requester: &mut RequesterSession<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+) {
+    requester.end_session(false).unwrap();
+    let size =
+        requester.next_secured_request(&mut data.req_buf, NOW).unwrap().len();
+    let (rsp_data, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    );
+    result.unwrap();
+    let mut rsp = rsp_data.to_vec();
+    assert!(requester.handle_secured_msg(&mut rsp, NOW).unwrap());
+    assert_eq!(Err(RequesterError::NoSecureSession), requester.channels());
+}
+
+// A responder holds several sessions at once, up to its limit
+#[test]
+fn multiple_sessions() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requesters = vec![requester.begin_session().unwrap()];
+    while requesters.len() < MAX_SESSIONS {
+        let mut requester = another_requester(&certs, &root_certs, &mut data);
+        key_exchange(&mut requester, &mut responder, &mut data);
+        finish(&mut requester, &mut responder, &mut data);
+        requesters.push(requester.begin_session().unwrap());
+    }
+    assert_eq!(MAX_SESSIONS, responder.sessions().len());
+
+    // Each session has its own keys
+    for requester in requesters.iter_mut() {
+        let session_id = requester.session().session_id.unwrap();
+        assert_eq!(
+            requester.channels().unwrap(),
+            responder.session(session_id).unwrap().channels.as_ref().unwrap()
+        );
+        let app_data = b"application specific request";
+        let size =
+            requester.send(app_data, &mut data.req_buf, NOW).unwrap().len();
+        let received = responder.recv(&mut data.req_buf[..size], NOW).unwrap();
+        assert_eq!(&app_data[..], received);
+        let size = responder
+            .send(session_id, app_data, &mut data.rsp_buf)
+            .unwrap()
+            .len();
+        let received = requester.recv(&mut data.rsp_buf[..size], NOW).unwrap();
+        assert_eq!(&app_data[..], received);
+    }
+
+    // The table is full
+    let mut requester = another_requester(&certs, &root_certs, &mut data);
+    let req_data = requester.next_request(&mut data.req_buf).unwrap();
+    let (rsp_data, result) = responder.handle_msg(req_data, &mut data.rsp_buf);
+    assert_eq!(Err(ResponderError::SessionLimitExceeded), result);
+    assert_eq!("Measurements", responder.state().name());
+    assert_eq!(
+        msgs::Error::SessionLimitExceeded,
+        msgs::Error::parse_body(&rsp_data[2..]).unwrap()
+    );
+
+    // Ending a session makes room for a new one
+    let session_id = requesters[0].session().session_id.unwrap();
+    end_session(&mut requesters[0], &mut responder, &mut data);
+    assert!(responder.session(session_id).is_none());
+    assert_eq!(MAX_SESSIONS - 1, responder.sessions().len());
+
+    let mut requester = another_requester(&certs, &root_certs, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+    assert_eq!(MAX_SESSIONS, responder.sessions().len());
+
+    // GET_VERSION terminates all sessions
+    let mut requester = RequesterInit::new(&root_certs, create_slots(&certs));
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    assert!(responder.sessions().is_empty());
+}
+
+// Drive a key update to completion within the secure session
+fn update_keys<'a, S: Signer>(
+    requester: &mut RequesterSession<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+    update_all: bool,
+) {
+    requester.update_keys(update_all).unwrap();
+    loop {
+        let size = requester
+            .next_secured_request(&mut data.req_buf, NOW)
+            .unwrap()
+            .len();
+        let (rsp_data, result) = responder.handle_secured_msg(
+            &mut data.req_buf[..size],
+            &mut data.rsp_buf,
+            NOW,
+        );
+        result.unwrap();
+        let mut rsp = rsp_data.to_vec();
+        let complete = requester.handle_secured_msg(&mut rsp, NOW).unwrap();
+
+        // The old keys are kept until the new keys are verified
+        let previous = &requester.channels().unwrap().previous;
+        assert_eq!(complete, previous.is_none());
+        if complete {
+            break;
+        }
+    }
+}
+
+// Both sides agree on the keys after a key update, and can still exchange
+// data.
+fn assert_channels_match<'a, S: Signer>(
+    requester: &mut RequesterSession<'a, S>,
+    responder: &mut Responder<'a, S, TestMeasurements>,
+    data: &mut Data,
+) {
+    assert_eq!(
+        requester.channels().unwrap(),
+        responder.sessions().iter().next().unwrap().channels.as_ref().unwrap()
+    );
+    let app_data = b"application specific request";
+    let size = requester.send(app_data, &mut data.req_buf, NOW).unwrap().len();
+    let received = responder.recv(&mut data.req_buf[..size], NOW).unwrap();
+    assert_eq!(&app_data[..], received);
+    let session_id = responder.sessions().iter().next().unwrap().id;
+    let size =
+        responder.send(session_id, app_data, &mut data.rsp_buf).unwrap().len();
+    let received = requester.recv(&mut data.rsp_buf[..size], NOW).unwrap();
+    assert_eq!(&app_data[..], received);
+}
+
+// The keys of a long lived session can be updated
+#[test]
+fn key_update() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    assert_channels_match(&mut requester, &mut responder, &mut data);
+    let before = requester.channels().unwrap().clone();
+
+    // Only the request keys change, and their sequence number restarts. The
+    // VerifyNewKey request is the only one sent with the new keys.
+    update_keys(&mut requester, &mut responder, &mut data, false);
+    let channels = requester.channels().unwrap();
+    assert_ne!(before.request.keys, channels.request.keys);
+    assert_eq!(1, channels.request.sequence_number);
+    assert_eq!(before.response.keys, channels.response.keys);
+    assert_eq!(3, channels.response.sequence_number);
+    assert_channels_match(&mut requester, &mut responder, &mut data);
+
+    // Both directions change
+    let before = requester.channels().unwrap().clone();
+    update_keys(&mut requester, &mut responder, &mut data, true);
+    let channels = requester.channels().unwrap();
+    assert_ne!(before.request.keys, channels.request.keys);
+    assert_ne!(before.response.keys, channels.response.keys);
+    assert_eq!(1, channels.request.sequence_number);
+    assert_eq!(2, channels.response.sequence_number);
+    assert_channels_match(&mut requester, &mut responder, &mut data);
+}
+
+// An unacknowledged key update is retried with the old keys
+#[test]
+fn key_update_retry() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    requester.update_keys(true).unwrap();
+
+    // The KEY_UPDATE_ACK is lost
+    let size =
+        requester.next_secured_request(&mut data.req_buf, NOW).unwrap().len();
+    let (_, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    );
+    result.unwrap();
+    assert!(responder
+        .sessions()
+        .iter()
+        .next()
+        .unwrap()
+        .channels
+        .as_ref()
+        .unwrap()
+        .previous
+        .is_some());
+
+    // The responder rolls back the update when the retry arrives
+    loop {
+        let size = requester
+            .next_secured_request(&mut data.req_buf, NOW)
+            .unwrap()
+            .len();
+        let (rsp_data, result) = responder.handle_secured_msg(
+            &mut data.req_buf[..size],
+            &mut data.rsp_buf,
+            NOW,
+        );
+        result.unwrap();
+        let mut rsp = rsp_data.to_vec();
+        if requester.handle_secured_msg(&mut rsp, NOW).unwrap() {
+            break;
+        }
+    }
+    assert_channels_match(&mut requester, &mut responder, &mut data);
+}
+
+// The heartbeat period is negotiated in KEY_EXCHANGE, and the application
+// drives the timers of both sides with its own clock.
+#[test]
+fn heartbeat() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder = Responder::new(slots, TestMeasurements::default());
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+
+    negotiate_versions(&mut data, &mut requester, &mut responder);
+    negotiate_capabilities(&mut requester, &mut responder, &mut data);
+    negotiate_algorithms(&mut requester, &mut responder, &mut data);
+    identify_responder(&mut requester, &mut responder, &mut data);
+    challenge_auth(&mut requester, &mut responder, &mut data);
+    key_exchange(&mut requester, &mut responder, &mut data);
+    finish(&mut requester, &mut responder, &mut data);
+
+    let mut requester = requester.begin_session().unwrap();
+    let period = Duration::from_secs(HEARTBEAT_PERIOD.into());
+    assert_eq!(Some(period), requester.heartbeat_period());
+    assert_eq!(
+        Some(period),
+        responder.sessions().iter().next().unwrap().heartbeat.period()
+    );
+
+    // The responder received FINISH at `NOW`, while the requester sent no
+    // secured message yet, and starts the period at its first check
+    let start = NOW;
+    assert!(!requester.heartbeat_due(start));
+    assert_eq!(0, responder.expire_sessions(start));
+    assert!(!requester.heartbeat_due(start + period / 2));
+    assert!(requester.heartbeat_due(start + period));
+
+    let now = start + period;
+    requester.send_heartbeat().unwrap();
+    let size =
+        requester.next_secured_request(&mut data.req_buf, now).unwrap().len();
+    let (rsp_data, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        now,
+    );
+    result.unwrap();
+    let mut rsp = rsp_data.to_vec();
+    assert!(requester.handle_secured_msg(&mut rsp, now).unwrap());
+    assert!(!requester.heartbeat_due(now + period / 2));
+    assert!(requester.heartbeat_due(now + period));
+
+    // Application data received by the requester counts from the time it
+    // was received, not from the next check
+    let session_id = responder.sessions().iter().next().unwrap().id;
+    let app_data = b"application data";
+    let size =
+        responder.send(session_id, app_data, &mut data.rsp_buf).unwrap().len();
+    let received =
+        requester.recv(&mut data.rsp_buf[..size], now + period / 2).unwrap();
+    assert_eq!(&app_data[..], received);
+    assert!(!requester.heartbeat_due(now + period));
+    assert!(requester.heartbeat_due(now + period + period / 2));
+
+    // Application data received by the responder keeps the session alive
+    let now = now + period;
+    let size = requester.send(app_data, &mut data.req_buf, now).unwrap().len();
+    responder.recv(&mut data.req_buf[..size], now).unwrap();
+    assert_eq!(0, responder.expire_sessions(now + 2 * period));
+
+    // Without further heartbeats, the session expires
+    assert!(!requester.session_expired(now + 2 * period));
+    assert!(
+        requester.session_expired(now + 2 * period + Duration::from_secs(1))
+    );
+    assert_eq!(
+        1,
+        responder.expire_sessions(now + 2 * period + Duration::from_secs(1))
+    );
+    assert!(responder.sessions().is_empty());
+}
+
+// Deliver the next request of the requester to the responder, and return
+// the response. PSK_FINISH is sent within the session.
+fn psk_round_trip<'b>(
+    requester: &mut RequesterInit<'_, RingSigner>,
+    responder: &mut PskResponder<'_>,
+    data: &'b mut Data,
+) -> (&'b [u8], Result<(), ResponderError>) {
+    let size = requester.next_request(&mut data.req_buf).unwrap().len();
+    if requester.is_secured() {
+        responder.handle_secured_msg(
+            &mut data.req_buf[..size],
+            &mut data.rsp_buf,
+            NOW,
+        )
+    } else {
+        responder.handle_msg(&data.req_buf[..size], &mut data.rsp_buf)
+    }
+}
+
+// Negotiate versions, capabilities, and algorithms, after which the requester
+// begins a PSK_EXCHANGE rather than asking for certificates.
+fn psk_negotiate(
+    requester: &mut RequesterInit<'_, RingSigner>,
+    responder: &mut PskResponder<'_>,
+    data: &mut Data,
+) {
+    for _ in 0..3 {
+        let (rsp_data, result) = psk_round_trip(requester, responder, data);
+        result.unwrap();
+        assert!(!requester.handle_msg(rsp_data).unwrap());
+    }
+    assert_eq!("IdAuth", responder.state().name());
+    assert_eq!("PskExchange", requester.state().name());
+    assert_eq!(requester.transcript(), responder.transcript());
+}
+
+// A session is established with a PSK, without any certificates or
+// CHALLENGE.
+#[test]
+fn psk_session() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder: PskResponder =
+        Responder::new(slots, TestMeasurements::default());
+    responder.set_psk_provider(TestPsk);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+    requester.set_measurement_hash_type(MeasurementHashType::All);
+    requester.set_psk(Psk { hint: PSK_HINT, key: &PSK });
+
+    psk_negotiate(&mut requester, &mut responder, &mut data);
+
+    // PSK_EXCHANGE creates the session. The responder provides a context, so
+    // PSK_FINISH is required to establish it.
+    let (rsp_data, result) =
+        psk_round_trip(&mut requester, &mut responder, &mut data);
+    result.unwrap();
+    assert_eq!("Measurements", responder.state().name());
+    let session = responder.sessions().iter().next().unwrap();
+    assert_eq!(responder::session::Phase::PskHandshake, session.phase);
+    assert!(!requester.handle_msg(rsp_data).unwrap());
+    assert_eq!("PskFinish", requester.state().name());
+    assert!(requester.is_secured());
+
+    // PSK_FINISH and PSK_FINISH_RSP are always sent within the session
+    let (rsp_data, result) =
+        psk_round_trip(&mut requester, &mut responder, &mut data);
+    result.unwrap();
+    let session = responder.sessions().iter().next().unwrap();
+    assert_eq!(responder::session::Phase::Established, session.phase);
+    let record_layer = session.channels.as_ref().unwrap().record_layer;
+    assert_eq!(Ok(session.id), secured_message::session_id(rsp_data));
+    assert_eq!(record_layer.record_size(4), rsp_data.len());
+    assert!(requester.handle_msg(rsp_data).unwrap());
+    assert_eq!("NewSession", requester.state().name());
+    if let requester::AllStates::NewSession(ref req_state) = requester.state() {
+        assert_eq!(Some(session.id), req_state.session_id);
+        assert!(req_state.session_keys.is_some());
+        assert_eq!(session.session_keys, req_state.session_keys);
+        assert_eq!(0, req_state.cert_chain_size);
+    } else {
+        panic!("the requester is not in the NewSession state");
+    }
+    assert_eq!(requester.transcript().get(), responder.transcript().vca());
+
+    // Application data can be exchanged in the session
+    let mut requester = requester.begin_session().unwrap();
+    let session_id = responder.sessions().iter().next().unwrap().id;
+    let app_data = b"application specific request";
+    let size = requester.send(app_data, &mut data.req_buf, NOW).unwrap().len();
+    let received = responder.recv(&mut data.req_buf[..size], NOW).unwrap();
+    assert_eq!(&app_data[..], received);
+    let size =
+        responder.send(session_id, app_data, &mut data.rsp_buf).unwrap().len();
+    let received = requester.recv(&mut data.rsp_buf[..size], NOW).unwrap();
+    assert_eq!(&app_data[..], received);
+
+    // The summary hash from PSK_EXCHANGE_RSP covers all measurement blocks
+    requester.request_measurements(MeasurementRequest::All, false).unwrap();
+    let size =
+        requester.next_secured_request(&mut data.req_buf, NOW).unwrap().len();
+    let (rsp_data, result) = responder.handle_secured_msg(
+        &mut data.req_buf[..size],
+        &mut data.rsp_buf,
+        NOW,
+    );
+    result.unwrap();
+    let mut rsp = rsp_data.to_vec();
+    assert!(requester.handle_secured_msg(&mut rsp, NOW).unwrap());
+    assert_summary_hash_matches(&requester, |_| true);
+}
+
+// A responder rejects a PSK_EXCHANGE for a PSK it doesn't have
+#[test]
+fn unknown_psk_hint() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder: PskResponder =
+        Responder::new(slots, TestMeasurements::default());
+    responder.set_psk_provider(TestPsk);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+    requester.set_psk(Psk { hint: b"unknown psk", key: &PSK });
+
+    psk_negotiate(&mut requester, &mut responder, &mut data);
+
+    let (rsp_data, result) =
+        psk_round_trip(&mut requester, &mut responder, &mut data);
+    assert_eq!(Err(ResponderError::UnknownPskHint), result);
+    assert_eq!(Ok(true), msgs::Error::parse_header(rsp_data));
+    assert!(responder.sessions().is_empty());
+}
+
+// A requester rejects a PSK_EXCHANGE_RSP if the PSKs differ, and a responder
+// rejects a PSK_FINISH with bad RequesterVerifyData
+#[test]
+fn bad_psk_verify_data() {
+    let mut data = Data::new();
+
+    let certs = create_certs_per_slot();
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+
+    let mut responder: PskResponder =
+        Responder::new(slots, TestMeasurements::default());
+    responder.set_psk_provider(TestPsk);
+    let root_certs = [&certs[0].root_der[..]];
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+    let wrong_psk = [0xA5; 32];
+    requester.set_psk(Psk { hint: PSK_HINT, key: &wrong_psk });
+
+    psk_negotiate(&mut requester, &mut responder, &mut data);
+    let (rsp_data, result) =
+        psk_round_trip(&mut requester, &mut responder, &mut data);
+    result.unwrap();
+    assert_eq!(
+        Err(RequesterError::BadPskExchange),
+        requester.handle_msg(rsp_data)
+    );
+    assert_eq!("Error", requester.state().name());
+
+    // Start over with the correct PSK
+    let slots = create_slots(&certs);
+    let slots2 = create_slots(&certs);
+    let mut responder: PskResponder =
+        Responder::new(slots, TestMeasurements::default());
+    responder.set_psk_provider(TestPsk);
+    let mut requester = RequesterInit::new(&root_certs, slots2);
+    requester.set_psk(Psk { hint: PSK_HINT, key: &PSK });
+
+    psk_negotiate(&mut requester, &mut responder, &mut data);
+    let (rsp_data, result) =
+        psk_round_trip(&mut requester, &mut responder, &mut data);
+    result.unwrap();
+    requester.handle_msg(rsp_data).unwrap();
+
+    // Corrupt the last byte of the verify data
+    let (rsp_data, result) = deliver_tampered_finish(
+        &mut requester,
+        &mut responder,
+        &mut data,
+        |req| {
+            *req.last_mut().unwrap() ^= 0xFF;
+        },
+    );
+    assert_eq!(Err(ResponderError::InvalidVerifyData), result);
+    assert_eq!(Ok(true), msgs::Error::parse_header(rsp_data));
+    assert!(responder.sessions().is_empty());
 }
 
 // A Responder will go back to `capabilities::State` if a requester sends a